@@ -0,0 +1,124 @@
+//! Notices mains power being connected or disconnected from the mains supply itself, separately
+//! from the battery's state, since some firmware takes a while to change that (or never does).
+
+use log::info;
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+use crate::command::{self, RateLimit};
+use crate::config::EventsConfig;
+use crate::notification::{SingleNotification, Sink, Style};
+use crate::system::AcState;
+
+pub struct AcMonitor {
+    commands: command::Context,
+    // As of the last check, or None before the first
+    last: Option<AcState>,
+    notif: SingleNotification,
+    connected_limit: RateLimit,
+    disconnected_limit: RateLimit,
+}
+
+impl AcMonitor {
+    pub fn new(events: &EventsConfig, commands: &command::Context, sink: &Sink) -> Self {
+        let mut mon = Self {
+            commands: commands.clone(),
+            last: None,
+            notif: SingleNotification::new(sink),
+            connected_limit: RateLimit::new(Duration::ZERO),
+            disconnected_limit: RateLimit::new(Duration::ZERO),
+        };
+        mon.set_limits(events);
+        mon
+    }
+
+    /// Starts the rate limits afresh from each event's min_interval_secs.
+    pub fn set_limits(&mut self, events: &EventsConfig) {
+        self.connected_limit =
+            RateLimit::new(Duration::from_secs(events.ac_connected.min_interval_secs));
+        self.disconnected_limit = RateLimit::new(Duration::from_secs(
+            events.ac_disconnected.min_interval_secs,
+        ));
+    }
+
+    /// Runs the ac_connected or ac_disconnected event if `ac` changed since last time. Nothing runs
+    /// for the first reading, or when we can't tell either way, and a command that ran less than
+    /// its min_interval_secs ago doesn't run again. A failed command is retried, waiting on `clock`.
+    pub fn update(
+        &mut self,
+        ac: AcState,
+        events: &EventsConfig,
+        style: Style,
+        now: Instant,
+        clock: &mut dyn Clock,
+    ) {
+        let prev = self.last.replace(ac);
+        let (name, event, summary, limit) = match (prev, ac) {
+            (Some(AcState::Offline), AcState::Online) => (
+                "ac_connected",
+                &events.ac_connected,
+                "Charger connected",
+                &mut self.connected_limit,
+            ),
+            (Some(AcState::Online), AcState::Offline) => (
+                "ac_disconnected",
+                &events.ac_disconnected,
+                "Charger disconnected",
+                &mut self.disconnected_limit,
+            ),
+            _ => return,
+        };
+        info!(event = name; "{summary}");
+        if event.notify {
+            self.notif.show(summary.to_string(), style);
+        } else {
+            self.notif.close();
+        }
+        if event.command.is_empty() {
+            return;
+        }
+        if !limit.allow(now) {
+            info!("Ran the {name} command less than min_interval_secs ago, not running it again");
+        } else {
+            event.command.run_with_retries(
+                &self.commands,
+                &[("BATTERY_NOTIFY_EVENT", name)],
+                event.retries,
+                Duration::from_secs(event.retry_delay_secs),
+                clock,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FakeClock;
+
+    #[test]
+    fn command_rate_limited() {
+        let out = std::env::temp_dir().join(format!("battery-notify-ac-{}", std::process::id()));
+        let mut events = EventsConfig::default();
+        events.ac_connected.command = format!("echo >> {}", out.display()).as_str().into();
+        let mut mon = AcMonitor::new(&events, &command::Context::default(), &Sink::default());
+        let clock = FakeClock::new();
+        let mut sleeper = clock.clone();
+        let style = crate::Config::default()
+            .notifications
+            .style(crate::notification::Category::Ac);
+        let mut plug = |ac| mon.update(ac, &events, style, clock.now(), &mut sleeper);
+
+        plug(AcState::Offline);
+        plug(AcState::Online);
+        plug(AcState::Offline);
+        plug(AcState::Online);
+        clock.advance(Duration::from_secs(10));
+        plug(AcState::Offline);
+        plug(AcState::Online);
+
+        let runs = std::fs::read_to_string(&out).unwrap().lines().count();
+        let _ = std::fs::remove_file(&out);
+        assert_eq!(runs, 2);
+    }
+}
@@ -0,0 +1,164 @@
+//! What to do for an event: either a shell command, or one of a few built in actions which ask
+//! logind directly, so that suspending at critical battery doesn't depend on $SHELL or PATH.
+
+use anyhow::Result;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+use crate::command;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Builtin {
+    Suspend,
+    Hibernate,
+    HybridSleep,
+    Shutdown,
+    LockSession,
+}
+
+const BUILTINS: &str = "suspend, hibernate, hybrid_sleep, shutdown or lock_session";
+
+#[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+impl Builtin {
+    /// The logind Manager method for this, and whether it takes the "interactive" argument.
+    const fn method(self) -> (&'static str, bool) {
+        match self {
+            Self::Suspend => ("Suspend", true),
+            Self::Hibernate => ("Hibernate", true),
+            Self::HybridSleep => ("HybridSleep", true),
+            Self::Shutdown => ("PowerOff", true),
+            Self::LockSession => ("LockSessions", false),
+        }
+    }
+}
+
+/// Written as a plain string for a shell command, or as `{ action = 'suspend' }` for a built in
+/// one. An empty command does nothing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged, try_from = "RawAction")]
+pub enum Action {
+    Builtin { action: Builtin },
+    Command(String),
+}
+
+/// An Action as written, so that an unknown action gets a better error than serde's "did not
+/// match any variant".
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawAction {
+    Builtin { action: String },
+    Command(String),
+}
+
+impl TryFrom<RawAction> for Action {
+    type Error = String;
+
+    fn try_from(raw: RawAction) -> Result<Self, String> {
+        match raw {
+            RawAction::Command(cmd) => Ok(Self::Command(cmd)),
+            RawAction::Builtin { action } => serde_plain::from_str(&action)
+                .map(|action| Self::Builtin { action })
+                .map_err(|_| format!("unknown action '{action}', expected {BUILTINS}")),
+        }
+    }
+}
+
+impl Default for Action {
+    fn default() -> Self {
+        Self::Command(String::new())
+    }
+}
+
+impl From<&str> for Action {
+    fn from(cmd: &str) -> Self {
+        Self::Command(cmd.to_string())
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Builtin { action } => {
+                write!(
+                    f,
+                    "{} (through logind)",
+                    serde_plain::to_string(action).unwrap()
+                )
+            }
+            Self::Command(cmd) => f.write_str(cmd),
+        }
+    }
+}
+
+impl Action {
+    pub fn is_empty(&self) -> bool {
+        matches!(self, Self::Command(cmd) if cmd.is_empty())
+    }
+
+    /// Runs the action once, logging how it went. Returns whether it succeeded. A command also gets
+    /// `env` on top of `commands`, like with command::Context::run_with_env, which built in actions
+    /// don't need.
+    pub fn run_with_env(&self, commands: &command::Context, env: &[(&str, &str)]) -> bool {
+        match self {
+            Self::Command(cmd) => commands.run_with_env(cmd, env),
+            Self::Builtin { action } => {
+                let start = Instant::now();
+                info!("Running built in action '{self}'");
+                match call_logind(*action) {
+                    Ok(()) => {
+                        info!("Action '{self}' finished in {:?}", start.elapsed());
+                        true
+                    }
+                    Err(err) => {
+                        error!("Failed to run action '{self}': {err:#}");
+                        false
+                    }
+                }
+            }
+        }
+    }
+
+    /// Like run_with_env(), retrying up to `retries` more times with `delay` in between if it
+    /// fails.
+    pub fn run_with_retries(
+        &self,
+        commands: &command::Context,
+        env: &[(&str, &str)],
+        retries: u32,
+        delay: Duration,
+        clock: &mut dyn Clock,
+    ) -> bool {
+        command::retry(&self.to_string(), retries, delay, clock, || {
+            self.run_with_env(commands, env)
+        })
+    }
+}
+
+#[cfg(feature = "dbus")]
+fn call_logind(action: Builtin) -> Result<()> {
+    use zbus::blocking::Connection;
+
+    let conn = Connection::system()?;
+    let (method, interactive) = action.method();
+    let (dest, path, iface) = (
+        Some("org.freedesktop.login1"),
+        "/org/freedesktop/login1",
+        Some("org.freedesktop.login1.Manager"),
+    );
+    // Never interactive, since there's nobody to answer a polkit prompt from here
+    if interactive {
+        conn.call_method(dest, path, iface, method, &(false,))?;
+    } else {
+        conn.call_method(dest, path, iface, method, &())?;
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "dbus"))]
+fn call_logind(_action: Builtin) -> Result<()> {
+    anyhow::bail!("built without D-Bus support, can't ask logind")
+}
@@ -0,0 +1,66 @@
+use anyhow::{Context, Result};
+use log::info;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Registers battery-notify to be started on login, for sessions not managed by systemd. Inside a
+/// sandbox we can't write to the host's autostart directory, so we ask the Background portal
+/// instead.
+pub fn install() -> Result<()> {
+    let exe = env::current_exe().context("failed to get path to battery-notify")?;
+
+    if Path::new("/.flatpak-info").exists() {
+        return request_background();
+    }
+
+    let dir = directories::BaseDirs::new()
+        .context("failed to find home directory")?
+        .config_dir()
+        .join("autostart");
+    fs::create_dir_all(&dir)?;
+    let path = dir.join("battery-notify.desktop");
+    fs::write(
+        &path,
+        format!(
+            "[Desktop Entry]\n\
+             Type=Application\n\
+             Name=battery-notify\n\
+             Comment={}\n\
+             Exec={}\n\
+             NoDisplay=true\n\
+             X-GNOME-Autostart-enabled=true\n",
+            env!("CARGO_PKG_DESCRIPTION"),
+            exe.display()
+        ),
+    )?;
+    info!("Wrote autostart entry to {}", path.display());
+    Ok(())
+}
+
+#[cfg(feature = "dbus")]
+fn request_background() -> Result<()> {
+    use std::collections::HashMap;
+    use zbus::zvariant::Value;
+
+    let conn = zbus::blocking::Connection::session()?;
+    let options: HashMap<&str, Value<'_>> = HashMap::from([
+        ("reason", Value::from("Notify about battery state changes")),
+        ("autostart", Value::from(true)),
+        ("commandline", Value::from(vec!["battery-notify"])),
+    ]);
+    conn.call_method(
+        Some("org.freedesktop.portal.Desktop"),
+        "/org/freedesktop/portal/desktop",
+        Some("org.freedesktop.portal.Background"),
+        "RequestBackground",
+        &("", options),
+    )?;
+    info!("Requested autostart from the Background portal");
+    Ok(())
+}
+
+#[cfg(not(feature = "dbus"))]
+fn request_background() -> Result<()> {
+    anyhow::bail!("running sandboxed, but built without D-Bus support to ask the Background portal")
+}
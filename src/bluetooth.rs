@@ -1,49 +1,633 @@
 use anyhow::Result;
+use hashbrown::HashMap;
+use log::{error, info};
+use notify_rust::Urgency;
+use std::fs;
+use std::time::{Duration, Instant};
 
-#[derive(Debug)]
+use crate::config::Config;
+use crate::heavy::Heavy;
+use crate::notification::{Category, SingleNotification, Sink, UrgencySetting};
+use crate::system::{self, BatteryState};
+
+#[derive(Debug, Clone)]
 pub struct BluetoothBattery {
     pub name: String,
+    /// Like "AA:BB:CC:DD:EE:FF".
+    pub address: Option<String>,
     pub level: u8,
+    /// The kind of device (like "mouse" or "headset"), derived from BlueZ's icon name.
+    pub class: Option<String>,
+    /// When BlueZ last passed on a level from the device, if it has since we started watching.
+    pub reported: Option<Instant>,
+    /// How long since the device last reported its level, or since it changed if we can't tell,
+    /// if we've been watching.
+    pub age: Option<Duration>,
+    /// Whether the level is older than bluetooth_stale_secs, likely because the device is asleep.
+    pub stale: bool,
+}
+
+impl BluetoothBattery {
+    /// Whether `pattern` matches the device's name or address, ignoring case. `*` in the pattern
+    /// matches any run of characters, so "*mouse*" matches "MX Master Mouse".
+    pub fn matches(&self, pattern: &str) -> bool {
+        let pattern = pattern.to_lowercase();
+        std::iter::once(&self.name)
+            .chain(&self.address)
+            .any(|s| glob_match(&pattern, &s.to_lowercase()))
+    }
+}
+
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // split always gives at least one part
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = s.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<_> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No wildcards
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+struct BluetoothAlert {
+    notif: SingleNotification,
+    // When the critical notification was last shown, if the device is currently critical
+    critical_shown: Option<Instant>,
+    milestone_notif: SingleNotification,
+    // The last seen level divided by bluetooth_milestone_step
+    milestone: Option<u8>,
+    // The last seen level, and when the device last reported it. That's when we first saw it at
+    // that level for readings which don't say
+    level: Option<u8>,
+    reported_at: Option<Instant>,
+    stale: bool,
+}
+
+impl BluetoothAlert {
+    fn new(sink: &Sink) -> Self {
+        Self {
+            notif: SingleNotification::new(sink),
+            critical_shown: None,
+            milestone_notif: SingleNotification::new(sink),
+            milestone: None,
+            level: None,
+            reported_at: None,
+            stale: false,
+        }
+    }
+}
+
+/// Notifies about Bluetooth devices running low, per bluetooth_low_pct and friends.
+pub struct BluetoothMonitor {
+    heavy: Heavy,
+    sink: Sink,
+    alerts: HashMap<String, BluetoothAlert>,
+    // As of the last check
+    devices: Vec<BluetoothBattery>,
+}
+
+impl BluetoothMonitor {
+    pub fn new(heavy: &Heavy, sink: &Sink) -> Self {
+        Self {
+            heavy: heavy.clone(),
+            sink: sink.clone(),
+            alerts: HashMap::new(),
+            devices: Vec::new(),
+        }
+    }
+
+    pub fn devices(&self) -> &[BluetoothBattery] {
+        &self.devices
+    }
+
+    pub fn update(&mut self, cfg: &Config, start: Instant) {
+        if cfg.bluetooth_low_pct == 0
+            && cfg.bluetooth_critical_pct == 0
+            && !cfg.bluetooth.has_overrides()
+        {
+            // Closes any notifications through Drop
+            self.alerts.clear();
+            self.devices.clear();
+            return;
+        }
+        let mut bbats = watched_battery_levels().unwrap_or_else(|err| {
+            error!("{err}");
+            Vec::new()
+        });
+        bbats.retain(|bbat| !cfg.bluetooth.ignored(bbat));
+        info!("Bluetooth battery status: {:?}", bbats);
+        let mut low_style = cfg.notifications.style(Category::BluetoothLow);
+        // Leave critical urgency to the critical tier if there is one, unless asked otherwise
+        if cfg.bluetooth_critical_pct != 0
+            && cfg.notifications.bluetooth_low.urgency == UrgencySetting::Default
+        {
+            low_style.urgency = Urgency::Normal;
+        }
+        let critical_style = cfg.notifications.style(Category::BluetoothCritical);
+        let repeat = Duration::from_secs(cfg.bluetooth_critical_repeat_secs);
+        let stale_after = Duration::from_secs(cfg.bluetooth_stale_secs);
+        for bbat in &mut bbats {
+            let (_, alert) = self
+                .alerts
+                .raw_entry_mut()
+                .from_key(&bbat.name)
+                .or_insert_with(|| (bbat.name.clone(), BluetoothAlert::new(&self.sink)));
+            let low_pct = cfg.bluetooth.low_pct(bbat, cfg.bluetooth_low_pct);
+            // BlueZ doesn't tell us whether a device is charging
+            alert.notif.set_level(bbat.level, BatteryState::Discharging);
+            if self.sink.templates_enabled() {
+                alert.notif.set_vars(vec![
+                    ("battery", bbat.name.clone()),
+                    ("level", bbat.level.to_string()),
+                ]);
+            }
+            match bbat.reported {
+                Some(reported) => alert.reported_at = Some(reported),
+                None if alert.level != Some(bbat.level) => alert.reported_at = Some(start),
+                None => {}
+            }
+            alert.level = Some(bbat.level);
+            let age = alert
+                .reported_at
+                .map_or(Duration::ZERO, |at| start.saturating_duration_since(at));
+            bbat.age = Some(age);
+            bbat.stale = !stale_after.is_zero() && age >= stale_after;
+            if bbat.stale != alert.stale {
+                alert.stale = bbat.stale;
+                if bbat.stale {
+                    info!(
+                        device = bbat.name.as_str(), level = bbat.level;
+                        "{} last reported {}% {} ago, ignoring it until it reports again",
+                        bbat.name, bbat.level, humantime::format_duration(age)
+                    );
+                }
+            }
+
+            if bbat.stale {
+                // Going by a level this old would likely mean notifying about a device that's
+                // since been charged, or put away
+                alert.critical_shown = None;
+                alert.notif.close();
+            } else if cfg.bluetooth_critical_pct != 0 && bbat.level <= cfg.bluetooth_critical_pct {
+                let repeat_due = !repeat.is_zero()
+                    && alert
+                        .critical_shown
+                        .is_some_and(|shown| start >= shown + repeat);
+                if alert.critical_shown.is_none() && !cfg.bluetooth_critical_command.is_empty() {
+                    self.heavy.run(
+                        "bluetooth_critical_command",
+                        &cfg.bluetooth_critical_command,
+                        &[
+                            ("BATTERY_NOTIFY_EVENT", "bluetooth_critical"),
+                            ("BATTERY_NOTIFY_BATTERY", &bbat.name),
+                            ("BATTERY_NOTIFY_LEVEL", &bbat.level.to_string()),
+                        ],
+                    );
+                }
+                if alert.critical_shown.is_none() || repeat_due {
+                    // Closing first makes sure the daemon shows it afresh
+                    alert.notif.close();
+                    alert
+                        .notif
+                        .show(format!("{} battery critical", bbat.name), critical_style);
+                    alert.critical_shown = Some(start);
+                }
+            } else if low_pct != 0 && bbat.level <= low_pct {
+                alert.critical_shown = None;
+                alert
+                    .notif
+                    .show(format!("{} battery low", bbat.name), low_style);
+            } else {
+                alert.critical_shown = None;
+                alert.notif.close();
+            }
+
+            if cfg.bluetooth_milestone_step != 0
+                && (cfg.bluetooth_milestone_devices.is_empty()
+                    || cfg.bluetooth_milestone_devices.contains(&bbat.name))
+            {
+                let milestone = bbat.level / cfg.bluetooth_milestone_step;
+                if alert.milestone.is_some_and(|prev| milestone < prev) {
+                    alert.milestone_notif.show(
+                        format!("{} battery at {}%", bbat.name, bbat.level),
+                        Urgency::Normal,
+                    );
+                }
+                alert.milestone = Some(milestone);
+            }
+        }
+
+        // Get rid of any non-present devices and close the notification through Drop
+        self.alerts
+            .retain(|key, _| bbats.iter().any(|b| b.name == *key));
+        self.devices = bbats;
+    }
+
+    /// Like "bluetooth_low:Mouse" or "bluetooth_critical:Headset", sorted.
+    pub fn alarms(&self) -> Vec<String> {
+        let mut alarms: Vec<_> = self
+            .alerts
+            .iter()
+            .filter(|(_, alert)| alert.notif.is_shown())
+            .map(|(name, alert)| match alert.critical_shown {
+                Some(_) => format!("bluetooth_critical:{name}"),
+                None => format!("bluetooth_low:{name}"),
+            })
+            .collect();
+        alarms.sort();
+        alarms
+    }
 }
 
 #[cfg(feature = "bluetooth")]
-pub fn get_battery_levels() -> Result<Vec<BluetoothBattery>> {
+mod bluez {
+    use anyhow::{anyhow, Result};
+    use log::{info, warn};
     use once_cell::sync::Lazy;
     use std::collections::HashMap;
-    use zbus::blocking::Connection;
-    use zbus::zvariant::{ObjectPath, Value};
+    use std::sync::Mutex;
+    use std::time::Instant;
+    use zbus::blocking::{Connection, MessageIterator};
+    use zbus::message::Type;
+    use zbus::zvariant::{OwnedObjectPath, OwnedValue};
+    use zbus::MatchRule;
 
-    type ManagedObjects<'a> = HashMap<ObjectPath<'a>, HashMap<String, HashMap<String, Value<'a>>>>;
+    use super::BluetoothBattery;
 
-    static CONN: Lazy<Connection> = Lazy::new(|| Connection::system().unwrap());
+    type Properties = HashMap<String, OwnedValue>;
+    type Interfaces = HashMap<String, Properties>;
+    type ManagedObjects = HashMap<OwnedObjectPath, Interfaces>;
 
-    let ret = CONN.call_method(
-        Some("org.bluez"),
-        "/",
-        Some("org.freedesktop.DBus.ObjectManager"),
-        "GetManagedObjects",
-        &(),
-    )?;
-    let body = ret.body();
-    let (devices,): (ManagedObjects<'_>,) = body.deserialize()?;
+    static CONN: Lazy<zbus::Result<Connection>> = Lazy::new(Connection::system);
 
-    Ok(devices
-        .iter()
-        .filter_map(|(_, ifs)| {
-            let bat = ifs.get("org.bluez.Battery1")?;
-            let level = bat
-                .get("Percentage")
-                .and_then(|p| p.clone().downcast::<u8>().ok())?;
-            let dev = ifs.get("org.bluez.Device1")?;
-            let name = dev
-                .get("Name")
-                .and_then(|n| n.clone().downcast::<String>().ok())?;
-            Some(BluetoothBattery { name, level })
+    fn conn() -> Result<&'static Connection> {
+        CONN.as_ref()
+            .map_err(|err| anyhow!("failed to connect to system bus: {err}"))
+    }
+
+    fn get_managed_objects() -> Result<ManagedObjects> {
+        let ret = conn()?.call_method(
+            Some("org.bluez"),
+            "/",
+            Some("org.freedesktop.DBus.ObjectManager"),
+            "GetManagedObjects",
+            &(),
+        )?;
+        let (objects,) = ret.body().deserialize()?;
+        Ok(objects)
+    }
+
+    fn string(props: &Properties, key: &str) -> Option<String> {
+        props
+            .get(key)
+            .and_then(|v| <&str>::try_from(v).ok())
+            .map(str::to_string)
+    }
+
+    /// Only devices with a battery and a name are of any interest.
+    fn device(ifs: &Interfaces) -> Option<BluetoothBattery> {
+        let level = ifs
+            .get("org.bluez.Battery1")?
+            .get("Percentage")
+            .and_then(|p| u8::try_from(p).ok())?;
+        let dev = ifs.get("org.bluez.Device1")?;
+        let class = string(dev, "Icon").map(|icon| {
+            icon.trim_start_matches("input-")
+                .trim_start_matches("audio-")
+                .to_string()
+        });
+        Some(BluetoothBattery {
+            name: string(dev, "Name")?,
+            address: string(dev, "Address"),
+            level,
+            class,
+            reported: None,
+            age: None,
+            stale: false,
+        })
+    }
+
+    /// Returns everything BlueZ knows, for diagnostics.
+    pub fn dump_managed_objects() -> Result<String> {
+        Ok(format!("{:#?}", get_managed_objects()?))
+    }
+
+    pub fn get_battery_levels() -> Result<Vec<BluetoothBattery>> {
+        Ok(get_managed_objects()?.values().filter_map(device).collect())
+    }
+
+    /// What BlueZ has told us about its objects, kept up to date from its signals.
+    #[derive(Default)]
+    struct Cache {
+        objects: ManagedObjects,
+        /// The lowest level each device reported since the last read, so that a dip between
+        /// checks still counts.
+        lowest: HashMap<OwnedObjectPath, u8>,
+        /// When each device last reported a level.
+        reported: HashMap<OwnedObjectPath, Instant>,
+    }
+
+    impl Cache {
+        fn note_level(&mut self, path: &OwnedObjectPath, props: &Properties) {
+            if let Some(level) = props.get("Percentage").and_then(|p| u8::try_from(p).ok()) {
+                let lowest = self.lowest.entry(path.clone()).or_insert(level);
+                *lowest = (*lowest).min(level);
+                self.reported.insert(path.clone(), Instant::now());
+            }
+        }
+
+        fn interfaces_added(&mut self, path: OwnedObjectPath, added: Interfaces) {
+            if let Some(bat) = added.get("org.bluez.Battery1") {
+                self.note_level(&path, bat);
+            }
+            self.objects.entry(path).or_default().extend(added);
+        }
+
+        fn interfaces_removed(&mut self, path: &OwnedObjectPath, removed: &[String]) {
+            let Some(ifs) = self.objects.get_mut(path) else {
+                return;
+            };
+            for iface in removed {
+                ifs.remove(iface);
+            }
+            if ifs.is_empty() {
+                self.objects.remove(path);
+                self.lowest.remove(path);
+                self.reported.remove(path);
+            }
+        }
+
+        fn properties_changed(
+            &mut self,
+            path: OwnedObjectPath,
+            iface: String,
+            changed: Properties,
+            invalidated: &[String],
+        ) {
+            if iface == "org.bluez.Battery1" {
+                self.note_level(&path, &changed);
+            }
+            let props = self
+                .objects
+                .entry(path)
+                .or_default()
+                .entry(iface)
+                .or_default();
+            for key in invalidated {
+                props.remove(key);
+            }
+            props.extend(changed);
+        }
+    }
+
+    /// None until we're watching, and again if we stop, so that the next read starts over.
+    static CACHE: Mutex<Option<Cache>> = Mutex::new(None);
+
+    fn handle_signal(msg: &zbus::Message) -> zbus::Result<()> {
+        let header = msg.header();
+        let body = msg.body();
+        let mut cache = CACHE.lock().unwrap();
+        let Some(cache) = cache.as_mut() else {
+            return Ok(());
+        };
+        match header.member().map(|m| m.as_str()) {
+            Some("InterfacesAdded") => {
+                let (path, added): (OwnedObjectPath, Interfaces) = body.deserialize()?;
+                cache.interfaces_added(path, added);
+            }
+            Some("InterfacesRemoved") => {
+                let (path, removed): (OwnedObjectPath, Vec<String>) = body.deserialize()?;
+                cache.interfaces_removed(&path, &removed);
+            }
+            Some("PropertiesChanged") => {
+                let Some(path) = header.path() else {
+                    return Ok(());
+                };
+                let (iface, changed, invalidated): (String, Properties, Vec<String>) =
+                    body.deserialize()?;
+                cache.properties_changed(path.clone().into(), iface, changed, &invalidated);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Subscribes to BlueZ's signals, and only then enumerates everything once, so that nothing
+    /// which changes in between is missed.
+    fn watch() -> Result<()> {
+        let conn = conn()?;
+        let rule = MatchRule::builder()
+            .msg_type(Type::Signal)
+            .sender("org.bluez")?
+            .build();
+        let signals = MessageIterator::for_match_rule(rule, conn, None)?;
+        let objects = get_managed_objects()?;
+        info!("Watching BlueZ for Bluetooth battery changes");
+        *CACHE.lock().unwrap() = Some(Cache {
+            objects,
+            ..Cache::default()
+        });
+        std::thread::Builder::new()
+            .name("bluez".to_string())
+            .spawn(move || {
+                for msg in signals {
+                    let res = msg.and_then(|msg| handle_signal(&msg));
+                    if let Err(err) = res {
+                        warn!("invalid signal from BlueZ: {err}");
+                    }
+                }
+                *CACHE.lock().unwrap() = None;
+                warn!("stopped watching BlueZ, will enumerate devices again");
+            })?;
+        Ok(())
+    }
+
+    /// Like get_battery_levels(), but from what BlueZ's signals have told us since the first call,
+    /// rather than asking for everything again. Each level is the lowest seen since the last call.
+    pub fn watched_battery_levels() -> Result<Vec<BluetoothBattery>> {
+        if CACHE.lock().unwrap().is_none() {
+            watch()?;
+        }
+        let mut cache = CACHE.lock().unwrap();
+        let Some(cache) = cache.as_mut() else {
+            // The watcher gave up already, we'll try again next time
+            return get_battery_levels();
+        };
+        let lowest = std::mem::take(&mut cache.lowest);
+        Ok(cache
+            .objects
+            .iter()
+            .filter_map(|(path, ifs)| {
+                let mut bbat = device(ifs)?;
+                if let Some(low) = lowest.get(path) {
+                    bbat.level = bbat.level.min(*low);
+                }
+                bbat.reported = cache.reported.get(path).copied();
+                Some(bbat)
+            })
+            .collect())
+    }
+}
+
+/// Peripherals the kernel exposes batteries for itself, like Logitech receivers through HID++
+/// (hidpp_battery_0) or other HID devices. These are in power_supply alongside the system's own
+/// batteries, but with scope "Device".
+fn get_sysfs_battery_levels() -> Vec<BluetoothBattery> {
+    let Ok(entries) = fs::read_dir(system::POWER_SUPPLY_DIR) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| {
+            let dir = entry.ok()?.path();
+            let read = |file| system::read_battery_file(&dir, file).ok();
+            if read("type")? != "Battery" || read("scope")? != "Device" {
+                return None;
+            }
+            let level = match read("capacity") {
+                Some(capacity) => capacity.parse::<u8>().ok()?.min(100),
+                // HID++ 1.0 devices only report a rough level
+                None => match read("capacity_level")?.as_str() {
+                    "Full" => 100,
+                    "High" => 80,
+                    "Normal" => 50,
+                    "Low" => 10,
+                    "Critical" => 5,
+                    _ => return None,
+                },
+            };
+            let name = match read("model_name").filter(|name| !name.is_empty()) {
+                Some(name) => name,
+                None => dir.file_name()?.to_string_lossy().into_owned(),
+            };
+            Some(BluetoothBattery {
+                name,
+                address: None,
+                level,
+                class: None,
+                reported: None,
+                age: None,
+                stale: false,
+            })
         })
-        .collect::<Vec<_>>())
+        .collect()
 }
 
-#[cfg(not(feature = "bluetooth"))]
+/// The address in a power_supply name like "hid-aa:bb:cc:dd:ee:ff-battery", which is how Bluetooth
+/// HID devices show up there.
+fn hid_address(name: &str) -> Option<&str> {
+    name.strip_prefix("hid-")?.strip_suffix("-battery")
+}
+
+/// Adds the devices from power_supply to those from BlueZ. Bluetooth HID devices can show up in
+/// both, so those are left to BlueZ. If BlueZ failed, the ones from power_supply are still worth
+/// having.
+fn merge(
+    bluez: Result<Vec<BluetoothBattery>>,
+    sysfs: Vec<BluetoothBattery>,
+) -> Result<Vec<BluetoothBattery>> {
+    let mut bbats = match bluez {
+        Ok(bbats) => bbats,
+        Err(err) if !sysfs.is_empty() => {
+            error!("{err}");
+            Vec::new()
+        }
+        Err(err) => return Err(err),
+    };
+    let addresses: Vec<_> = bbats
+        .iter()
+        .filter_map(|bbat| bbat.address.as_ref())
+        .map(|address| address.to_lowercase())
+        .collect();
+    bbats.extend(sysfs.into_iter().filter(|bbat| {
+        let name = bbat.name.to_lowercase();
+        let address = hid_address(&name).unwrap_or(&name);
+        !addresses.iter().any(|known| known == address)
+    }));
+    Ok(bbats)
+}
+
+/// Every peripheral with a battery, from BlueZ and power_supply.
 pub fn get_battery_levels() -> Result<Vec<BluetoothBattery>> {
-    Ok(Vec::new())
+    merge(bluez::get_battery_levels(), get_sysfs_battery_levels())
+}
+
+/// Like get_battery_levels(), but following BlueZ's signals rather than asking it for everything
+/// again. See bluez::watched_battery_levels().
+pub fn watched_battery_levels() -> Result<Vec<BluetoothBattery>> {
+    merge(bluez::watched_battery_levels(), get_sysfs_battery_levels())
+}
+
+#[cfg(feature = "bluetooth")]
+pub use bluez::dump_managed_objects;
+
+#[cfg(not(feature = "bluetooth"))]
+mod bluez {
+    use super::BluetoothBattery;
+
+    pub fn get_battery_levels() -> anyhow::Result<Vec<BluetoothBattery>> {
+        Ok(Vec::new())
+    }
+
+    pub fn watched_battery_levels() -> anyhow::Result<Vec<BluetoothBattery>> {
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(not(feature = "bluetooth"))]
+pub fn dump_managed_objects() -> Result<String> {
+    anyhow::bail!("built without bluetooth support")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bbat(name: &str, address: Option<&str>) -> BluetoothBattery {
+        BluetoothBattery {
+            name: name.to_string(),
+            address: address.map(str::to_string),
+            level: 50,
+            class: None,
+            reported: None,
+            age: None,
+            stale: false,
+        }
+    }
+
+    fn names(bbats: &[BluetoothBattery]) -> Vec<&str> {
+        bbats.iter().map(|bbat| bbat.name.as_str()).collect()
+    }
+
+    #[test]
+    fn merge_leaves_bluetooth_hid_devices_to_bluez() {
+        let bluez = vec![bbat("MX Master 3", Some("AA:BB:CC:DD:EE:FF"))];
+        let sysfs = vec![
+            bbat("hid-aa:bb:cc:dd:ee:ff-battery", None),
+            bbat("AA:BB:CC:DD:EE:FF", None),
+        ];
+        assert_eq!(names(&merge(Ok(bluez), sysfs).unwrap()), ["MX Master 3"]);
+    }
+
+    #[test]
+    fn merge_keeps_devices_which_only_share_part_of_a_name() {
+        let bluez = vec![bbat("MX", Some("AA:BB:CC:DD:EE:FF"))];
+        let sysfs = vec![
+            bbat("MX Anywhere 3", None),
+            bbat("hid-11:22:33:44:55:66-battery", None),
+        ];
+        assert_eq!(
+            names(&merge(Ok(bluez), sysfs).unwrap()),
+            ["MX", "MX Anywhere 3", "hid-11:22:33:44:55:66-battery"]
+        );
+    }
 }
@@ -0,0 +1,348 @@
+//! When running as a system service, we have no session bus of our own to notify on. Instead we
+//! spawn a relay (`battery-notify relay`) as each user with a graphical session, and forward every
+//! notification to all of them.
+
+use anyhow::{bail, Context, Result};
+use hashbrown::HashMap;
+use log::{error, info};
+use notify_rust::{Hint, Timeout, Urgency};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::CStr;
+use std::io::{self, BufRead, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{Mutex, OnceLock};
+
+use crate::notification::{Notifier, SingleNotification, Sink, Style};
+
+static BROADCASTER: OnceLock<Mutex<Broadcaster>> = OnceLock::new();
+
+/// Starts broadcasting, giving a sink which sends notifications to the relays rather than showing
+/// them ourselves.
+pub fn enable() -> Sink {
+    Sink::new(BROADCASTER.get_or_init(Mutex::default))
+}
+
+pub fn active() -> Option<&'static Mutex<Broadcaster>> {
+    BROADCASTER.get()
+}
+
+/// Starts relays for new sessions and stops them for ones which went away.
+pub fn refresh_sessions() {
+    if let Some(bc) = active() {
+        if let Err(err) = bc.lock().unwrap().refresh_sessions() {
+            error!("failed to update graphical sessions: {err}");
+        }
+    }
+}
+
+fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => out.push('\t'),
+                Some('n') => out.push('\n'),
+                Some(other) => out.push(other),
+                None => {}
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+struct Relay {
+    child: Child,
+    stdin: ChildStdin,
+}
+
+impl Relay {
+    fn spawn(uid: u32) -> Result<Self> {
+        let (name, gid, home) = passwd_entry(uid)?;
+        let mut child = Command::new(env::current_exe()?)
+            .arg("relay")
+            .uid(uid)
+            .gid(gid)
+            .env_clear()
+            .env("HOME", home)
+            .env("USER", &name)
+            .env(
+                "DBUS_SESSION_BUS_ADDRESS",
+                format!("unix:path=/run/user/{uid}/bus"),
+            )
+            .env("RUST_LOG", env::var("RUST_LOG").unwrap_or_default())
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to spawn relay for {name}"))?;
+        info!("Started notification relay for {name}");
+        let stdin = child.stdin.take().context("relay has no stdin")?;
+        Ok(Self { child, stdin })
+    }
+
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.stdin, "{line}")
+    }
+
+    /// Closes stdin, which tells the relay to close its notifications and exit. It's left to do
+    /// that in its own time, so the child has to be reaped later.
+    fn stop(self) -> Child {
+        drop(self.stdin);
+        self.child
+    }
+}
+
+#[derive(Default)]
+pub struct Broadcaster {
+    relays: HashMap<u32, Relay>,
+    // Relays we've stopped, which may not have exited yet
+    stopped: Vec<Child>,
+    // Notifications currently shown, so new relays can catch up
+    current: BTreeMap<u64, String>,
+}
+
+impl Broadcaster {
+    pub fn show(
+        &mut self,
+        key: u64,
+        summary: &str,
+        body: &str,
+        urgency: Urgency,
+        timeout: Timeout,
+        hints: &[Hint],
+    ) {
+        let line = format!(
+            "show\t{key}\t{}\t{}\t{}\t{}\t{}\t{}",
+            urgency as u64,
+            i32::from(timeout),
+            u8::from(hints.contains(&Hint::Transient(true))),
+            u8::from(hints.contains(&Hint::Resident(true))),
+            escape(summary),
+            escape(body)
+        );
+        self.send_all(&line);
+        self.current.insert(key, line);
+    }
+
+    pub fn close(&mut self, key: u64) {
+        if self.current.remove(&key).is_some() {
+            self.send_all(&format!("close\t{key}"));
+        }
+    }
+
+    fn send_all(&mut self, line: &str) {
+        let failed: Vec<_> = self
+            .relays
+            .iter_mut()
+            .filter_map(|(uid, relay)| match relay.send(line) {
+                Ok(()) => None,
+                Err(err) => {
+                    error!("failed to send to relay for uid {uid}, dropping it: {err}");
+                    Some(*uid)
+                }
+            })
+            .collect();
+        for uid in failed {
+            self.stop(uid);
+        }
+    }
+
+    fn stop(&mut self, uid: u32) {
+        if let Some(relay) = self.relays.remove(&uid) {
+            self.stopped.push(relay.stop());
+        }
+    }
+
+    /// Waits for any stopped relays which have exited since we last looked, without blocking on
+    /// ones which haven't.
+    fn reap(&mut self) {
+        self.stopped
+            .retain_mut(|child| matches!(child.try_wait(), Ok(None)));
+    }
+
+    fn refresh_sessions(&mut self) -> Result<()> {
+        self.reap();
+        let uids = graphical_session_uids()?;
+        let gone: Vec<_> = self
+            .relays
+            .keys()
+            .filter(|uid| !uids.contains(uid))
+            .copied()
+            .collect();
+        for uid in gone {
+            self.stop(uid);
+        }
+        for uid in uids {
+            if self.relays.contains_key(&uid) {
+                continue;
+            }
+            // One user's relay failing shouldn't keep the others from getting theirs
+            let mut relay = match Relay::spawn(uid) {
+                Ok(relay) => relay,
+                Err(err) => {
+                    error!("failed to start relay for uid {uid}: {err:#}");
+                    continue;
+                }
+            };
+            if let Err(err) = self.current.values().try_for_each(|line| relay.send(line)) {
+                error!("failed to catch up relay for uid {uid}, dropping it: {err}");
+                self.stopped.push(relay.stop());
+                continue;
+            }
+            self.relays.insert(uid, relay);
+        }
+        Ok(())
+    }
+}
+
+impl Notifier for &'static Mutex<Broadcaster> {
+    fn show(
+        &self,
+        key: u64,
+        summary: &str,
+        body: &str,
+        urgency: Urgency,
+        timeout: Timeout,
+        hints: &[Hint],
+    ) {
+        self.lock()
+            .unwrap()
+            .show(key, summary, body, urgency, timeout, hints);
+    }
+
+    fn close(&self, key: u64) {
+        self.lock().unwrap().close(key);
+    }
+}
+
+/// The user's name, primary group and home directory. This goes through NSS, so users from LDAP
+/// or systemd-homed are found as well as those in /etc/passwd.
+fn passwd_entry(uid: u32) -> Result<(String, u32, String)> {
+    let mut buf = vec![0u8; 1024];
+    loop {
+        // SAFETY: passwd is plain data, for which all zeroes is valid, and getpwuid_r only writes to
+        // it, result and buf, within buf.len()
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let ret = unsafe {
+            libc::getpwuid_r(
+                uid,
+                &mut pwd,
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        if ret == libc::ERANGE && buf.len() < 1 << 20 {
+            buf.resize(buf.len() * 2, 0);
+            continue;
+        }
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret))
+                .with_context(|| format!("failed to look up uid {uid}"));
+        }
+        if result.is_null() {
+            bail!("no passwd entry for uid {uid}");
+        }
+        // SAFETY: On success, pw_name and pw_dir point to nul terminated strings in buf
+        let (name, home) = unsafe { (CStr::from_ptr(pwd.pw_name), CStr::from_ptr(pwd.pw_dir)) };
+        return Ok((
+            name.to_string_lossy().into_owned(),
+            pwd.pw_gid,
+            home.to_string_lossy().into_owned(),
+        ));
+    }
+}
+
+#[cfg(feature = "dbus")]
+fn graphical_session_uids() -> Result<Vec<u32>> {
+    use zbus::blocking::Connection;
+    use zbus::zvariant::{OwnedObjectPath, OwnedValue};
+
+    let conn = Connection::system()?;
+    let ret = conn.call_method(
+        Some("org.freedesktop.login1"),
+        "/org/freedesktop/login1",
+        Some("org.freedesktop.login1.Manager"),
+        "ListSessions",
+        &(),
+    )?;
+    let sessions: Vec<(String, u32, String, String, OwnedObjectPath)> = ret.body().deserialize()?;
+
+    let mut uids = Vec::new();
+    for (_, uid, _, _, path) in sessions {
+        let ret = conn.call_method(
+            Some("org.freedesktop.login1"),
+            &path,
+            Some("org.freedesktop.DBus.Properties"),
+            "Get",
+            &("org.freedesktop.login1.Session", "Type"),
+        )?;
+        let kind: OwnedValue = ret.body().deserialize()?;
+        let kind = String::try_from(kind)?;
+        if matches!(kind.as_str(), "x11" | "wayland" | "mir") && !uids.contains(&uid) {
+            uids.push(uid);
+        }
+    }
+    Ok(uids)
+}
+
+#[cfg(not(feature = "dbus"))]
+fn graphical_session_uids() -> Result<Vec<u32>> {
+    bail!("system mode requires D-Bus support")
+}
+
+/// The other end: runs as the session user, and shows whatever the system instance tells us to.
+pub fn run_relay() -> Result<()> {
+    let mut notifs: HashMap<u64, SingleNotification> = HashMap::new();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let fields: Vec<_> = line.split('\t').collect();
+        match fields.as_slice() {
+            ["show", key, urgency, timeout, transient, resident, summary, body] => {
+                // A bad line only loses that notification, not the relay
+                let (Ok(key), Ok(urgency), Ok(timeout)) = (
+                    key.parse::<u64>(),
+                    urgency.parse::<u64>(),
+                    timeout.parse::<i32>(),
+                ) else {
+                    error!("invalid relay message: {line}");
+                    continue;
+                };
+                let notif = notifs.entry(key).or_insert_with(|| {
+                    SingleNotification::new(&Sink::default())
+                        .with_hint(Hint::Transient(*transient == "1"))
+                        .with_hint(Hint::Resident(*resident == "1"))
+                });
+                notif.show_with_body(
+                    unescape(summary),
+                    unescape(body),
+                    Style {
+                        urgency: Urgency::from(urgency),
+                        timeout: Timeout::from(timeout),
+                        category: None,
+                        stack: false,
+                    },
+                );
+            }
+            ["close", key] => match key.parse::<u64>() {
+                Ok(key) => {
+                    notifs.remove(&key);
+                }
+                Err(_) => error!("invalid relay message: {line}"),
+            },
+            _ => error!("invalid relay message: {line}"),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,147 @@
+//! Buttons on notifications, like "Suspend now" or "Snooze 15m". The notification server tells us
+//! about clicks with a signal, which we listen for on a thread of our own, handing anything the
+//! main loop has to do over to it as a request.
+
+use anyhow::{bail, Result};
+use cancellable_timer::Canceller;
+use hashbrown::HashMap;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::action::Action;
+use crate::command;
+use crate::service::Request;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Button {
+    pub label: String,
+    /// Run when clicked, if not empty.
+    pub command: Action,
+    /// Snooze notifications for this long when clicked, if not 0.
+    pub snooze_secs: u64,
+}
+
+/// Checks the buttons for a category, for Config::validate.
+pub fn validate(category: &str, buttons: &[Button]) -> Result<()> {
+    if buttons.iter().any(|button| button.label.is_empty()) {
+        bail!("every button in notifications.{category}.buttons needs a label");
+    }
+    Ok(())
+}
+
+/// Whether we're listening for clicks. Until we are, there's no point showing buttons.
+static LISTENING: AtomicBool = AtomicBool::new(false);
+
+pub fn listening() -> bool {
+    LISTENING.load(Ordering::Relaxed)
+}
+
+/// The buttons on each notification we've shown with any, by the server's id for it.
+static SHOWN: Mutex<Option<HashMap<u32, Vec<Button>>>> = Mutex::new(None);
+
+/// Remembers the buttons on notification `id`, so we know what to do when one's clicked. Their
+/// action keys are their index.
+pub fn shown(id: u32, buttons: Vec<Button>) {
+    SHOWN
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(id, buttons);
+}
+
+pub fn closed(id: u32) {
+    if let Some(shown) = SHOWN.lock().unwrap().as_mut() {
+        shown.remove(&id);
+    }
+}
+
+#[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+fn clicked(
+    id: u32,
+    key: &str,
+    tx: &Sender<Request>,
+    canceller: &Canceller,
+    commands: &command::Context,
+) {
+    let button = SHOWN
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|shown| shown.get(&id)?.get(key.parse::<usize>().ok()?).cloned());
+    // Also any clicks on notifications from other programs
+    let Some(button) = button else {
+        return;
+    };
+    info!("Notification button '{}' clicked", button.label);
+    if button.snooze_secs != 0 {
+        let duration = Duration::from_secs(button.snooze_secs);
+        if tx.send(Request::Snooze(Some(duration))).is_ok() {
+            // If we fail to cancel, we'll just snooze at the next interval
+            let _ = canceller.cancel();
+        }
+    }
+    if !button.command.is_empty() {
+        button
+            .command
+            .run_with_env(commands, &[("BATTERY_NOTIFY_EVENT", "button")]);
+    }
+}
+
+/// Starts listening for clicks on buttons, and for notifications being closed so we can forget
+/// their buttons.
+#[cfg(feature = "dbus")]
+pub fn spawn(tx: Sender<Request>, canceller: Canceller, commands: command::Context) -> Result<()> {
+    use log::warn;
+    use zbus::blocking::{Connection, MessageIterator};
+    use zbus::message::Type;
+    use zbus::MatchRule;
+
+    let conn = Connection::session()?;
+    let rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .interface("org.freedesktop.Notifications")?
+        .path("/org/freedesktop/Notifications")?
+        .build();
+    let signals = MessageIterator::for_match_rule(rule, &conn, None)?;
+    std::thread::Builder::new()
+        .name("buttons".to_string())
+        .spawn(move || {
+            for msg in signals {
+                let Ok(msg) = msg else {
+                    continue;
+                };
+                let header = msg.header();
+                let body = msg.body();
+                match header.member().map(|m| m.as_str()) {
+                    Some("ActionInvoked") => match body.deserialize::<(u32, String)>() {
+                        Ok((id, key)) => clicked(id, &key, &tx, &canceller, &commands),
+                        Err(err) => warn!("invalid ActionInvoked signal: {err}"),
+                    },
+                    Some("NotificationClosed") => {
+                        if let Ok((id, _reason)) = body.deserialize::<(u32, u32)>() {
+                            closed(id);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            LISTENING.store(false, Ordering::Relaxed);
+            warn!("stopped listening for notification button clicks");
+        })?;
+    LISTENING.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(not(feature = "dbus"))]
+pub fn spawn(
+    _tx: Sender<Request>,
+    _canceller: Canceller,
+    _commands: command::Context,
+) -> Result<()> {
+    bail!("built without D-Bus support")
+}
@@ -0,0 +1,171 @@
+//! Works out which charger is plugged in, from the USB Power Delivery identity of the port partner,
+//! so that people can be told about unfamiliar or known bad chargers.
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::notification::{SingleNotification, Sink, Style};
+use crate::system;
+
+const TYPEC_DIR: &str = "/sys/class/typec";
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChargerConfig {
+    /// What to call it in notifications and logs.
+    pub name: String,
+    /// Known to be bad, like one which can't keep up with the machine under load.
+    pub bad: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Charger {
+    /// The USB vendor and product ID, as "vid:pid" in hex, if the partner tells us.
+    pub id: Option<String>,
+    /// The most the charger offered, if the driver tells us.
+    pub max_watts: Option<f64>,
+}
+
+fn read_hex(path: &Path) -> Option<u32> {
+    let val = fs::read_to_string(path).ok()?;
+    u32::from_str_radix(val.trim().trim_start_matches("0x"), 16).ok()
+}
+
+/// The identity of the first port partner which has one. The ID header VDO has the vendor in its
+/// low 16 bits, and the product VDO has the product in its high 16 bits.
+fn partner_id() -> Option<String> {
+    fs::read_dir(TYPEC_DIR)
+        .ok()?
+        .filter_map(std::result::Result::ok)
+        .filter(|e| e.file_name().to_string_lossy().ends_with("-partner"))
+        .find_map(|e| {
+            let identity = e.path().join("identity");
+            let vid = read_hex(&identity.join("id_header"))? & 0xffff;
+            let pid = read_hex(&identity.join("product"))? >> 16;
+            (vid != 0).then(|| format!("{vid:04x}:{pid:04x}"))
+        })
+}
+
+/// The highest power offered by any online USB supply, from voltage_max and current_max.
+fn max_watts() -> Option<f64> {
+    fs::read_dir(POWER_SUPPLY_DIR)
+        .ok()?
+        .filter_map(std::result::Result::ok)
+        .map(|e| e.path())
+        .filter(|dir| {
+            system::read_battery_file(dir, "type").is_ok_and(|t| t.starts_with("USB"))
+                && system::read_battery_file(dir, "online").is_ok_and(|o| o == "1")
+        })
+        .filter_map(|dir| {
+            let uv: f64 = system::read_battery_file(&dir, "voltage_max")
+                .ok()?
+                .parse()
+                .ok()?;
+            let ua: f64 = system::read_battery_file(&dir, "current_max")
+                .ok()?
+                .parse()
+                .ok()?;
+            Some(uv * ua / 1e12)
+        })
+        .reduce(f64::max)
+}
+
+pub fn get_charger() -> Charger {
+    Charger {
+        id: partner_id(),
+        max_watts: max_watts(),
+    }
+}
+
+/// Tells the user about chargers worth knowing about, once each time one is plugged in.
+pub struct ChargerMonitor {
+    known: BTreeMap<String, ChargerConfig>,
+    notify_unknown: bool,
+    min_watts: f64,
+    // The charger currently plugged in, if any
+    current: Option<Charger>,
+    notif: SingleNotification,
+}
+
+impl ChargerMonitor {
+    pub fn new(
+        known: BTreeMap<String, ChargerConfig>,
+        notify_unknown: bool,
+        min_watts: f64,
+        sink: &Sink,
+    ) -> Self {
+        Self {
+            known,
+            notify_unknown,
+            min_watts,
+            current: None,
+            notif: SingleNotification::new(sink),
+        }
+    }
+
+    pub const fn is_active(&self) -> bool {
+        self.notif.is_shown()
+    }
+
+    /// What, if anything, to tell the user about `charger`.
+    fn complaint(&self, charger: &Charger) -> Option<(String, String)> {
+        let config = charger.id.as_ref().and_then(|id| self.known.get(id));
+        let name = match (config, &charger.id) {
+            (Some(cfg), _) if !cfg.name.is_empty() => cfg.name.clone(),
+            (_, Some(id)) => id.clone(),
+            (_, None) => "unidentified".to_string(),
+        };
+        if config.is_some_and(|cfg| cfg.bad) {
+            return Some((
+                "Known bad charger connected".to_string(),
+                format!("{name} is marked as bad"),
+            ));
+        }
+        if let Some(watts) = charger.max_watts.filter(|w| *w < self.min_watts) {
+            return Some((
+                "Low wattage charger connected".to_string(),
+                format!("{name} only offers {watts:.0} W"),
+            ));
+        }
+        if self.notify_unknown && config.is_none() {
+            return Some((
+                "Unknown charger connected".to_string(),
+                format!("{name}, add it to [chargers] if you trust it"),
+            ));
+        }
+        None
+    }
+
+    pub fn update(&mut self, ac_online: Option<bool>, style: Style) {
+        if ac_online != Some(true) {
+            if self.current.take().is_some() {
+                info!("Charger disconnected");
+            }
+            self.notif.close();
+            return;
+        }
+
+        let charger = get_charger();
+        // The wattage can change as the charger renegotiates, which isn't worth telling anyone about
+        if self.current.as_ref().is_some_and(|c| c.id == charger.id) {
+            return;
+        }
+        info!(
+            charger = charger.id.as_deref().unwrap_or("unidentified"),
+            max_watts:? = charger.max_watts;
+            "Charger connected: {charger:?}"
+        );
+        match self.complaint(&charger) {
+            Some((summary, body)) => {
+                warn!("{summary}: {body}");
+                self.notif.show_with_body(summary, body, style);
+            }
+            None => self.notif.close(),
+        }
+        self.current = Some(charger);
+    }
+}
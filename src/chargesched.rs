@@ -0,0 +1,126 @@
+//! Holds charging at a lower threshold most of the time, like phones do, and only tops up to 100%
+//! shortly before the battery needs to be full.
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use std::time::{Duration, SystemTime};
+
+use crate::clock::{local_secs_of_day, parse_time_of_day, DAY_SECS};
+use crate::notification::{SingleNotification, Sink, Style};
+use crate::system::{self, Battery, BatteryState};
+
+pub struct ChargeScheduler {
+    /// When the battery needs to be full every day, in seconds after local midnight.
+    daily: Option<u64>,
+    /// A one-off deadline, asked for over D-Bus, which replaces the daily one until it passes.
+    one_off: Option<SystemTime>,
+    hold_pct: u8,
+    top_up: Duration,
+    // Cleared once writing the threshold fails, after which we ask the user to unplug instead
+    can_write: bool,
+    notif: SingleNotification,
+}
+
+impl ChargeScheduler {
+    pub fn new(full_by: &str, hold_pct: u8, top_up: Duration, sink: &Sink) -> Result<Self> {
+        if !(1..=100).contains(&hold_pct) {
+            bail!("charge_hold_pct must be between 1 and 100, not {hold_pct}");
+        }
+        Ok(Self {
+            daily: parse_time_of_day(full_by).context("invalid charge_full_by")?,
+            one_off: None,
+            hold_pct,
+            top_up,
+            can_write: true,
+            notif: SingleNotification::new(sink),
+        })
+    }
+
+    pub fn set_one_off(&mut self, at: Option<SystemTime>) {
+        self.one_off = at;
+    }
+
+    pub const fn is_active(&self) -> bool {
+        self.notif.is_shown()
+    }
+
+    /// How long until the battery needs to be full, if there's a deadline at all.
+    fn until_deadline(&mut self, now: SystemTime) -> Option<Duration> {
+        if let Some(at) = self.one_off {
+            match at.duration_since(now) {
+                Ok(until) => return Some(until),
+                Err(_) => {
+                    info!("One-off charging deadline passed");
+                    self.one_off = None;
+                }
+            }
+        }
+        let daily = self.daily?;
+        let now = local_secs_of_day(now)?;
+        Some(Duration::from_secs((daily + DAY_SECS - now) % DAY_SECS))
+    }
+
+    /// Moves the charge thresholds of `batteries` to where they should be at `now`, or if we can't,
+    /// asks the user to unplug once we're past where we'd hold the charge.
+    pub fn update(
+        &mut self,
+        batteries: &[Battery],
+        global: &Battery,
+        now: SystemTime,
+        style: Style,
+    ) {
+        let Some(until) = self.until_deadline(now) else {
+            self.notif.close();
+            return;
+        };
+        let target = if until <= self.top_up {
+            100
+        } else {
+            self.hold_pct
+        };
+
+        let controllable: Vec<_> = batteries
+            .iter()
+            .filter(|b| b.end_threshold.is_some())
+            .collect();
+        if self.can_write && !controllable.is_empty() {
+            for bat in controllable
+                .iter()
+                .filter(|b| b.end_threshold != Some(target))
+            {
+                match system::set_end_threshold(&bat.name, target) {
+                    Ok(()) => info!(
+                        battery = bat.name.as_str(), threshold = target;
+                        "Set {} charge threshold to {target}%", bat.name
+                    ),
+                    Err(err) => {
+                        warn!(
+                            "failed to set {} charge threshold, asking to unplug instead: {err:#}",
+                            bat.name
+                        );
+                        self.can_write = false;
+                    }
+                }
+            }
+            if self.can_write {
+                self.notif.close();
+                return;
+            }
+        }
+
+        let level = global.level();
+        if target < 100 && global.state == BatteryState::Charging && level >= target {
+            let top_up_in = Duration::from_secs((until - self.top_up).as_secs() / 60 * 60);
+            self.notif.show_with_body(
+                "Unplug to preserve the battery".to_string(),
+                format!(
+                    "At {level}%, and it doesn't need to charge further for another {}",
+                    humantime::format_duration(top_up_in)
+                ),
+                style,
+            );
+        } else {
+            self.notif.close();
+        }
+    }
+}
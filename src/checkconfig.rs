@@ -0,0 +1,230 @@
+//! check-config, which finds every problem with the config file, along with the line each is on,
+//! rather than stopping at the first like loading it does.
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::ErrorKind;
+
+use crate::{config, migrate, overrides, Config};
+
+/// Prints every problem with the config file, and if there are none, the config with environment
+/// and command line overrides applied.
+pub fn run(cli_overrides: Vec<overrides::Override>) -> Result<()> {
+    let path = confy::get_configuration_file_path("battery-notify", "config")?;
+    migrate::migrate_file(&path)?;
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        // It's written with the defaults when first loaded, so there's nothing to get wrong yet
+        Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err).with_context(|| format!("failed to read {}", path.display())),
+    };
+
+    let problems = check(&text);
+    for problem in &problems {
+        eprintln!("{}: {problem}\n", path.display());
+    }
+    if !problems.is_empty() {
+        bail!("found {} problem(s) in {}", problems.len(), path.display());
+    }
+
+    // The file's fine, but overrides can still be wrong
+    let cfg = config::load(cli_overrides)?;
+    let problems = cfg.problems();
+    for problem in &problems {
+        eprintln!("{}: {:#}", problem.key, problem.error);
+    }
+    if !problems.is_empty() {
+        bail!("found {} problem(s) with overrides applied", problems.len());
+    }
+    print!("{}", toml::to_string(&cfg)?);
+    Ok(())
+}
+
+/// Every problem with the config file `text`, each showing the line it's on where we can tell.
+///
+/// Deserializing stops at the first unknown key or mistyped value, so each time it fails we blank
+/// out the line it failed on (or the whole table, for an unknown one) and go again, which leaves
+/// that setting at its default like it would be if it were left out.
+fn check(text: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut lines: Vec<&str> = text.lines().collect();
+    let cfg = loop {
+        let current = lines.join("\n");
+        if let Err(err) = current.parse::<toml::Table>() {
+            // Blanking out a line can break a multiline value around it, which is just noise after
+            // the problem that caused it
+            if problems.is_empty() {
+                problems.push(err.to_string().trim_end().to_string());
+            }
+            return problems;
+        }
+        match toml::from_str::<Config>(&current) {
+            Ok(cfg) => break cfg,
+            Err(err) => {
+                let line = err
+                    .span()
+                    .map(|span| current[..span.start].matches('\n').count());
+                problems.push(tidy(err.message(), line, &lines));
+                if !line.is_some_and(|line| blank(&mut lines, line)) {
+                    return problems;
+                }
+            }
+        }
+    };
+
+    let lines: Vec<&str> = text.lines().collect();
+    for problem in cfg.problems() {
+        let line = find_key(&lines, &problem.key);
+        problems.push(tidy(&format!("{:#}", problem.error), line, &lines));
+    }
+    problems
+}
+
+/// Formats `msg` with line `line` (from 0) under it like TOML parse errors, and cuts serde's list
+/// of every field we do know about down to the one that was most likely meant.
+fn tidy(msg: &str, line: Option<usize>, lines: &[&str]) -> String {
+    let msg = match msg.split_once(", expected one of ") {
+        Some((unknown, expected)) => {
+            let field = unknown
+                .rsplit_once('`')
+                .and_then(|(rest, _)| rest.rsplit_once('`'))
+                .map(|(_, field)| field);
+            let closest = field.and_then(|field| {
+                expected
+                    .split(", ")
+                    .map(|known| known.trim().trim_matches('`'))
+                    .map(|known| (distance(field, known), known))
+                    .filter(|(dist, _)| *dist <= 2)
+                    .min()
+            });
+            match closest {
+                Some((_, known)) => format!("{unknown}, did you mean `{known}`?"),
+                None => unknown.to_string(),
+            }
+        }
+        None => msg.trim_end().to_string(),
+    };
+    match line.and_then(|line| Some((line + 1, lines.get(line)?))) {
+        Some((num, text)) => format!("line {num}:\n  |\n{num} | {text}\n  |\n{msg}"),
+        None => msg,
+    }
+}
+
+/// Blanks out line `line`, along with the rest of its table if it's a table header. Returns whether
+/// anything changed, so we don't go round forever on the same error.
+fn blank(lines: &mut [&str], line: usize) -> bool {
+    let Some(first) = lines.get(line) else {
+        return false;
+    };
+    if first.trim().is_empty() {
+        return false;
+    }
+    let end = if first.trim_start().starts_with('[') {
+        lines[line + 1..]
+            .iter()
+            .position(|l| l.trim_start().starts_with('['))
+            .map_or(lines.len(), |pos| line + 1 + pos)
+    } else {
+        line + 1
+    };
+    lines[line..end].fill("");
+    true
+}
+
+/// The line (from 0) setting `key`, like "low_pct" or "notifications.low.buttons", or its table's
+/// header if it's a table.
+fn find_key(lines: &[&str], key: &str) -> Option<usize> {
+    let (table, leaf) = key.rsplit_once('.').unwrap_or(("", key));
+    let mut current = String::new();
+    for (num, line) in lines.iter().enumerate() {
+        let line = line.trim();
+        if let Some(header) = line.strip_prefix('[') {
+            current = header
+                .trim_matches(['[', ']'])
+                .split('.')
+                .map(|part| part.trim().trim_matches('"'))
+                .collect::<Vec<_>>()
+                .join(".");
+            if current == key {
+                return Some(num);
+            }
+        } else if current == table
+            && line
+                .strip_prefix(leaf)
+                .is_some_and(|rest| rest.trim_start().starts_with('='))
+        {
+            return Some(num);
+        }
+    }
+    None
+}
+
+/// Levenshtein distance, for suggesting the key that was probably meant.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let sub = prev[j] + usize::from(ca != *cb);
+            cur.push(sub.min(prev[j + 1] + 1).min(cur[j] + 1));
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_problem_with_its_line() {
+        let problems = check(
+            "low_cpt = 30\n\
+             interval_secs = \"soon\"\n\
+             sleep_pct = 20\n\
+             low_pct = 10\n\
+             \n\
+             [nonsense]\n\
+             foo = 1\n\
+             \n\
+             [speech]\n\
+             enabled = true\n",
+        );
+        assert_eq!(problems.len(), 4, "{problems:#?}");
+        assert!(problems[0].starts_with("line 1:"), "{}", problems[0]);
+        assert!(problems[0].contains("did you mean `low_pct`?"));
+        assert!(problems[1].starts_with("line 2:"), "{}", problems[1]);
+        assert!(problems[2].starts_with("line 6:"), "{}", problems[2]);
+        assert!(problems[3].starts_with("line 4:"), "{}", problems[3]);
+        assert!(problems[3].contains("must be above sleep_pct"));
+    }
+
+    #[test]
+    fn syntax_error() {
+        let problems = check("low_pct = = 3\n");
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].contains("line 1"), "{}", problems[0]);
+    }
+
+    #[test]
+    fn no_problems() {
+        assert!(check("").is_empty());
+        assert!(check("low_pct = 30\n[ups]\nlow_pct = 20\n").is_empty());
+    }
+
+    #[test]
+    fn find_key_line() {
+        let lines = [
+            "low_pct = 3",
+            "[notifications.low]",
+            "buttons = []",
+            "[ups]",
+        ];
+        assert_eq!(find_key(&lines, "low_pct"), Some(0));
+        assert_eq!(find_key(&lines, "notifications.low.buttons"), Some(2));
+        assert_eq!(find_key(&lines, "ups"), Some(3));
+        assert_eq!(find_key(&lines, "sleep_pct"), None);
+    }
+}
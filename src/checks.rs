@@ -0,0 +1,398 @@
+//! Everything we watch beyond the batteries themselves, like monitors, Bluetooth devices and remote
+//! machines. Each is a Check, which the engine runs in turn after dealing with the batteries, so a
+//! new one only needs adding to Registry::new.
+
+use anyhow::Result;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::engine::Report;
+use crate::heavy::Heavy;
+use crate::notification::{Category, Sink};
+use crate::system::{self, Battery};
+use crate::{
+    ac, bluetooth, charger, chargesched, command, drain, monitors, remote, service, slowcharge,
+    thresholds, timing, ups,
+};
+
+/// What the engine worked out this cycle, for the checks to go on.
+pub struct Inputs<'a> {
+    pub cfg: &'a Config,
+    pub start: Instant,
+    pub wall: SystemTime,
+    /// For waiting between retries of event commands.
+    pub clock: &'a mut dyn Clock,
+    pub batteries: &'a [Battery],
+    pub global: &'a Battery,
+    pub ac_online: Option<bool>,
+    pub drain_rate: Option<f64>,
+}
+
+pub trait Check {
+    /// For logs and cycle timings, like "bluetooth".
+    fn name(&self) -> &'static str;
+
+    /// How long to leave between runs. Zero runs it every cycle.
+    fn interval(&self, _cfg: &Config) -> Duration {
+        Duration::ZERO
+    }
+
+    /// Checks once, notifying as needed. Checks turned off in the config should close anything
+    /// they have shown.
+    fn run(&mut self, inputs: &mut Inputs);
+
+    /// Whether this has something to watch on a machine without batteries of its own, like a
+    /// desktop on a UPS, so the engine should keep checking without any.
+    fn standalone(&self, _cfg: &Config) -> bool {
+        false
+    }
+
+    /// Takes a new config, with `old` being the one before, so only what changed needs redoing.
+    fn reload(&mut self, _cfg: &Config, _old: &Config) -> Result<()> {
+        Ok(())
+    }
+
+    /// Handles a request from D-Bus, before the engine does.
+    fn handle(&mut self, _req: &service::Request, _wall: SystemTime) {}
+
+    /// Alerts live as of the last run, like "monitors" or "bluetooth_low:Mouse".
+    fn alarms(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Adds anything else seen in the last run to the cycle's report.
+    fn report(&self, _report: &mut Report) {}
+}
+
+struct Entry {
+    check: Box<dyn Check>,
+    last_run: Option<Instant>,
+}
+
+pub struct Registry {
+    entries: Vec<Entry>,
+}
+
+impl Registry {
+    pub fn new(
+        cfg: &Config,
+        commands: &command::Context,
+        heavy: &Heavy,
+        sink: &Sink,
+    ) -> Result<Self> {
+        let checks: Vec<Box<dyn Check>> = vec![
+            Box::new(Charging::new(cfg, commands, sink)?),
+            Box::new(monitors::MonitorWarning::new(sink)),
+            Box::new(bluetooth::BluetoothMonitor::new(heavy, sink)),
+            Box::new(remote::RemoteMonitor::new(&cfg.remotes, heavy, sink)),
+            Box::new(ups::UpsMonitor::new(&cfg.ups, commands, sink)),
+        ];
+        let entries = checks
+            .into_iter()
+            .map(|check| Entry {
+                check,
+                last_run: None,
+            })
+            .collect();
+        Ok(Self { entries })
+    }
+
+    /// Runs every check which is due, marking each in `cycle`.
+    pub fn run(&mut self, inputs: &mut Inputs, cycle: &mut timing::CycleTimer) {
+        for entry in &mut self.entries {
+            let interval = entry.check.interval(inputs.cfg);
+            let due = entry
+                .last_run
+                .map_or(true, |last| inputs.start.duration_since(last) >= interval);
+            if due {
+                entry.check.run(inputs);
+                entry.last_run = Some(inputs.start);
+            }
+            cycle.mark(entry.check.name());
+        }
+    }
+
+    /// Whether any check has something to watch without batteries of our own.
+    pub fn standalone(&self, cfg: &Config) -> bool {
+        self.entries.iter().any(|entry| entry.check.standalone(cfg))
+    }
+
+    pub fn reload(&mut self, cfg: &Config, old: &Config) -> Result<()> {
+        for entry in &mut self.entries {
+            entry.check.reload(cfg, old)?;
+        }
+        Ok(())
+    }
+
+    pub fn handle(&mut self, req: &service::Request, wall: SystemTime) {
+        for entry in &mut self.entries {
+            entry.check.handle(req, wall);
+        }
+    }
+
+    /// Adds every check's alarms and anything else it saw to `report`.
+    pub fn report(&self, report: &mut Report) {
+        for entry in &self.entries {
+            report.alarms.extend(entry.check.alarms());
+            entry.check.report(report);
+        }
+    }
+}
+
+/// Everything to do with charging and the charger, which is cheap enough to just do together.
+struct Charging {
+    // For the monitors recreated on reload
+    sink: Sink,
+    slow_charge: slowcharge::SlowChargeMonitor,
+    fast_drain: drain::FastDrainMonitor,
+    charge_sched: chargesched::ChargeScheduler,
+    threshold_enforcer: thresholds::ThresholdEnforcer,
+    ac_mon: ac::AcMonitor,
+    charger_mon: charger::ChargerMonitor,
+}
+
+impl Charging {
+    fn new(cfg: &Config, commands: &command::Context, sink: &Sink) -> Result<Self> {
+        Ok(Self {
+            sink: sink.clone(),
+            slow_charge: slowcharge::SlowChargeMonitor::new(
+                cfg.slow_charge_watts,
+                Duration::from_secs(cfg.slow_charge_secs),
+                sink,
+            ),
+            fast_drain: drain::FastDrainMonitor::new(
+                cfg.fast_drain_pct_per_hour,
+                cfg.fast_drain_top_processes,
+                sink,
+            ),
+            charge_sched: chargesched::ChargeScheduler::new(
+                &cfg.charge_full_by,
+                cfg.charge_hold_pct,
+                Duration::from_secs(cfg.charge_top_up_secs),
+                sink,
+            )?,
+            threshold_enforcer: thresholds::ThresholdEnforcer::new(
+                cfg.charge_start_threshold,
+                cfg.charge_end_threshold,
+            )?,
+            ac_mon: ac::AcMonitor::new(&cfg.events, commands, sink),
+            charger_mon: charger::ChargerMonitor::new(
+                cfg.chargers.clone(),
+                cfg.notify_unknown_chargers,
+                cfg.min_charger_watts,
+                sink,
+            ),
+        })
+    }
+}
+
+impl Check for Charging {
+    fn name(&self) -> &'static str {
+        "charging"
+    }
+
+    fn run(&mut self, inputs: &mut Inputs) {
+        let cfg = inputs.cfg;
+        self.slow_charge.update(
+            inputs.global,
+            inputs.start,
+            cfg.notifications.style(Category::SlowCharge),
+        );
+        self.fast_drain.update(
+            inputs.drain_rate,
+            cfg.notifications.style(Category::FastDrain),
+        );
+        self.charge_sched.update(
+            inputs.batteries,
+            inputs.global,
+            inputs.wall,
+            cfg.notifications.style(Category::OptimizedCharging),
+        );
+        self.threshold_enforcer.update(inputs.batteries);
+        self.ac_mon.update(
+            system::AcState::from(inputs.ac_online),
+            &cfg.events,
+            cfg.notifications.style(Category::Ac),
+            inputs.start,
+            inputs.clock,
+        );
+        self.charger_mon
+            .update(inputs.ac_online, cfg.notifications.style(Category::Charger));
+    }
+
+    fn reload(&mut self, cfg: &Config, old: &Config) -> Result<()> {
+        if cfg.fast_drain_pct_per_hour != old.fast_drain_pct_per_hour
+            || cfg.fast_drain_top_processes != old.fast_drain_top_processes
+        {
+            self.fast_drain = drain::FastDrainMonitor::new(
+                cfg.fast_drain_pct_per_hour,
+                cfg.fast_drain_top_processes,
+                &self.sink,
+            );
+        }
+        if cfg.slow_charge_watts != old.slow_charge_watts
+            || cfg.slow_charge_secs != old.slow_charge_secs
+        {
+            self.slow_charge = slowcharge::SlowChargeMonitor::new(
+                cfg.slow_charge_watts,
+                Duration::from_secs(cfg.slow_charge_secs),
+                &self.sink,
+            );
+        }
+        if cfg.charge_full_by != old.charge_full_by
+            || cfg.charge_hold_pct != old.charge_hold_pct
+            || cfg.charge_top_up_secs != old.charge_top_up_secs
+        {
+            self.charge_sched = chargesched::ChargeScheduler::new(
+                &cfg.charge_full_by,
+                cfg.charge_hold_pct,
+                Duration::from_secs(cfg.charge_top_up_secs),
+                &self.sink,
+            )?;
+        }
+        if cfg.charge_start_threshold != old.charge_start_threshold
+            || cfg.charge_end_threshold != old.charge_end_threshold
+        {
+            self.threshold_enforcer = thresholds::ThresholdEnforcer::new(
+                cfg.charge_start_threshold,
+                cfg.charge_end_threshold,
+            )?;
+        }
+        if cfg.events.ac_connected.min_interval_secs != old.events.ac_connected.min_interval_secs
+            || cfg.events.ac_disconnected.min_interval_secs
+                != old.events.ac_disconnected.min_interval_secs
+        {
+            self.ac_mon.set_limits(&cfg.events);
+        }
+        if cfg.chargers != old.chargers
+            || cfg.notify_unknown_chargers != old.notify_unknown_chargers
+            || cfg.min_charger_watts != old.min_charger_watts
+        {
+            self.charger_mon = charger::ChargerMonitor::new(
+                cfg.chargers.clone(),
+                cfg.notify_unknown_chargers,
+                cfg.min_charger_watts,
+                &self.sink,
+            );
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, req: &service::Request, wall: SystemTime) {
+        if let service::Request::ChargeFullBy(after) = req {
+            self.charge_sched
+                .set_one_off(after.map(|after| wall + after));
+        }
+    }
+
+    fn alarms(&self) -> Vec<String> {
+        [
+            (self.slow_charge.is_active(), "slow_charge"),
+            (self.fast_drain.is_active(), "fast_drain"),
+            (self.charge_sched.is_active(), "optimized_charging"),
+            (self.charger_mon.is_active(), "charger"),
+        ]
+        .into_iter()
+        .filter(|(active, _)| *active)
+        .map(|(_, name)| name.to_string())
+        .collect()
+    }
+}
+
+impl Check for monitors::MonitorWarning {
+    fn name(&self) -> &'static str {
+        "monitors"
+    }
+
+    fn run(&mut self, inputs: &mut Inputs) {
+        self.update(inputs.cfg, inputs.global);
+    }
+
+    fn alarms(&self) -> Vec<String> {
+        if self.is_active() {
+            vec!["monitors".to_string()]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+impl Check for bluetooth::BluetoothMonitor {
+    fn name(&self) -> &'static str {
+        "bluetooth"
+    }
+
+    fn run(&mut self, inputs: &mut Inputs) {
+        self.update(inputs.cfg, inputs.start);
+    }
+
+    fn standalone(&self, cfg: &Config) -> bool {
+        cfg.bluetooth_low_pct != 0
+            || cfg.bluetooth_critical_pct != 0
+            || cfg.bluetooth.has_overrides()
+    }
+
+    fn alarms(&self) -> Vec<String> {
+        self.alarms()
+    }
+
+    fn report(&self, report: &mut Report) {
+        report.bluetooth = self.devices().to_vec();
+    }
+}
+
+impl Check for remote::RemoteMonitor {
+    fn name(&self) -> &'static str {
+        "remotes"
+    }
+
+    fn run(&mut self, inputs: &mut Inputs) {
+        self.update(inputs.cfg.notifications.style(Category::Remote));
+    }
+
+    fn standalone(&self, cfg: &Config) -> bool {
+        !cfg.remotes.is_empty()
+    }
+
+    fn reload(&mut self, cfg: &Config, old: &Config) -> Result<()> {
+        if cfg.remotes != old.remotes {
+            *self = Self::new(&cfg.remotes, self.heavy(), self.sink());
+        }
+        Ok(())
+    }
+
+    fn alarms(&self) -> Vec<String> {
+        self.alarms()
+    }
+}
+
+impl Check for ups::UpsMonitor {
+    fn name(&self) -> &'static str {
+        "ups"
+    }
+
+    fn run(&mut self, inputs: &mut Inputs) {
+        self.update(
+            &inputs.cfg.ups,
+            &inputs.cfg.events,
+            inputs.cfg.notifications.style(Category::Ups),
+            inputs.clock,
+        );
+    }
+
+    fn standalone(&self, cfg: &Config) -> bool {
+        !cfg.ups.nut.is_empty() || system::get_upses().is_ok_and(|upses| !upses.is_empty())
+    }
+
+    fn reload(&mut self, cfg: &Config, old: &Config) -> Result<()> {
+        if cfg.ups.nut != old.ups.nut || cfg.ups.nut_interval_secs != old.ups.nut_interval_secs {
+            *self = Self::new(&cfg.ups, self.commands(), self.sink());
+        }
+        Ok(())
+    }
+
+    fn alarms(&self) -> Vec<String> {
+        self.alarms()
+    }
+}
@@ -0,0 +1,318 @@
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use std::time::Duration;
+
+use crate::overrides::Override;
+
+/// Environment variables set for every command we run, for --help and the man page. Their values
+/// are from the latest check, except for commands run for a UPS or Bluetooth device, where
+/// BATTERY_NOTIFY_BATTERY and BATTERY_NOTIFY_LEVEL are about that instead. overrides::from_env
+/// skips these, so a command can run battery-notify without them being taken for settings.
+pub const EVENT_ENV: &[(&str, &str)] = &[
+    (
+        "BATTERY_NOTIFY_EVENT",
+        "What the command was run for, like sleep, after_wake, tier, ac_connected, \
+         ac_disconnected, ups_on_battery, ups_low_runtime, bluetooth_critical, stale_data or \
+         button. Unset for other commands",
+    ),
+    (
+        "BATTERY_NOTIFY_LEVEL",
+        "The combined battery level, as a whole percentage",
+    ),
+    (
+        "BATTERY_NOTIFY_STATE",
+        "The combined state, like discharging, charging or full",
+    ),
+    (
+        "BATTERY_NOTIFY_BATTERY",
+        "The battery the command is about, or for the combined level, the lowest one",
+    ),
+    (
+        "BATTERY_NOTIFY_TIME_REMAINING",
+        "Estimated seconds until empty (or full, while charging), or empty if unknown",
+    ),
+    (
+        "BATTERY_NOTIFY_AC",
+        "1 if on AC power, 0 if not, or empty if unknown",
+    ),
+];
+
+const HELP_WIDTH: usize = 78;
+const HELP_INDENT: usize = 21;
+
+/// Formats a list of names and descriptions, with descriptions wrapped in a column.
+fn push_entries(out: &mut String, entries: &[(&str, &str)]) {
+    for (name, desc) in entries {
+        let mut line = format!("  {name:<width$}", width = HELP_INDENT - 2);
+        // Names too long for the column get the description on the next line
+        if name.len() + 3 > HELP_INDENT {
+            out.push_str(&line);
+            out.push('\n');
+            line = " ".repeat(HELP_INDENT);
+        }
+        for word in desc.split(' ') {
+            if line.len() > HELP_INDENT && line.len() + 1 + word.len() > HELP_WIDTH {
+                out.push_str(&line);
+                out.push('\n');
+                line = " ".repeat(HELP_INDENT);
+            } else if line.len() > HELP_INDENT {
+                line.push(' ');
+            }
+            line.push_str(word);
+        }
+        out.push_str(&line);
+        out.push('\n');
+    }
+}
+
+/// EVENT_ENV, for the end of --help.
+fn event_env_help() -> String {
+    let mut out = "Event commands are run with:\n".to_string();
+    push_entries(&mut out, EVENT_ENV);
+    out.pop();
+    out
+}
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "battery-notify",
+    version,
+    about,
+    disable_version_flag = true,
+    after_help = event_env_help()
+)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Run the daemon in the background, logging to
+    /// ~/.local/state/battery-notify/battery-notify.log, for use without a service manager
+    #[arg(long)]
+    pub daemonize: bool,
+
+    /// Also print a JSON line with the battery level, state, time remaining, each battery and
+    /// Bluetooth device, and the active alarms every check, for status bars like waybar
+    #[arg(long, value_name = "format")]
+    pub output: Option<Output>,
+
+    /// Override a config setting, like --set sleep_pct=10 or
+    /// --set events.sleep.command='systemctl hibernate'. Can be given multiple times
+    #[arg(
+        long = "set",
+        value_name = "key=value",
+        value_parser = parse_set,
+        global = true
+    )]
+    pub overrides: Vec<Override>,
+
+    /// Print the version
+    #[arg(short = 'V', long)]
+    pub version: bool,
+
+    /// With --version, also print the git commit, which optional features were built in, and
+    /// what would be used for notifications, monitors, Bluetooth and so on
+    #[arg(short = 'v', long, requires = "version")]
+    pub verbose: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum Output {
+    Json,
+}
+
+#[derive(Debug, Eq, PartialEq, Subcommand)]
+pub enum Command {
+    /// Monitor batteries and send notifications (default)
+    Daemon,
+    /// Detect what this machine has, ask a few questions, and write an initial config
+    Init,
+    /// Start battery-notify on login without systemd
+    InstallAutostart,
+    /// Check connectivity, show test notifications, and list commands which would be run
+    SelfTest,
+    /// Write sysfs, BlueZ, RandR, config and recent log state to a JSON file for bug reports
+    DebugDump,
+    /// Stop the running daemon's checks and actions, for example during firmware updates or
+    /// battery swaps, for a duration like 10m or until resumed
+    Pause {
+        #[arg(value_name = "duration", value_parser = humantime::parse_duration)]
+        duration: Option<Duration>,
+    },
+    /// Undo pause
+    Resume,
+    /// Have the running daemon hold back all but critical notifications for a duration like 30m,
+    /// or stop doing so early with off
+    Snooze {
+        #[arg(value_name = "duration|off", value_parser = parse_snooze)]
+        duration: Snooze,
+    },
+    /// Have the running daemon use a profile from [profiles] whatever its conditions, or go back
+    /// to picking one by them with auto
+    Profile {
+        #[arg(value_name = "name|auto")]
+        name: String,
+    },
+    /// Set the firmware's charge start and stop thresholds on all batteries which have them, like
+    /// set-threshold 60 80. Usually needs root
+    SetThreshold {
+        #[arg(value_name = "start")]
+        start: u8,
+        #[arg(value_name = "end")]
+        end: u8,
+    },
+    /// Check everything a single time, notifying and running commands like the daemon would
+    Once,
+    /// Check the config for mistakes, listing every unknown key, mistyped value and invalid
+    /// setting with the line it's on, and if there are none, print it with environment and --set
+    /// overrides applied
+    CheckConfig,
+    /// Show batteries, AC, Bluetooth devices and monitors, the last suspend, and whether the
+    /// running daemon is paused or snoozed, its version and backends, and its active alarms
+    Status {
+        /// Also show the running daemon's recent notifications
+        #[arg(long)]
+        history: bool,
+        /// Print it all as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show each discharge recorded with history_interval_secs over the last day (or --since),
+    /// with its curve, and the average drain
+    History {
+        /// How far back to go, like 7d
+        #[arg(
+            long,
+            value_name = "duration",
+            value_parser = humantime::parse_duration,
+            default_value = "1d"
+        )]
+        since: Duration,
+    },
+    /// Print a man page, including all config settings
+    Man,
+    /// Started by the system instance for each graphical session
+    #[command(hide = true)]
+    Relay,
+}
+
+/// What to snooze for, with None for off.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Snooze(pub Option<Duration>);
+
+fn parse_snooze(arg: &str) -> Result<Snooze, humantime::DurationError> {
+    if arg == "off" {
+        return Ok(Snooze(None));
+    }
+    humantime::parse_duration(arg).map(|d| Snooze(Some(d)))
+}
+
+fn parse_set(arg: &str) -> Result<Override, String> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("needs key=value, got {arg}"))?;
+    Ok(Override {
+        key: key.to_string(),
+        value: value.to_string(),
+        source: "--set".to_string(),
+    })
+}
+
+/// Parses the command line, including the program name, checking the options which only make sense
+/// for some commands.
+pub fn parse<I, T>(args: I) -> Result<Args, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let args = Args::try_parse_from(args)?;
+    let daemon = matches!(args.command, None | Some(Command::Daemon));
+    let conflict = if args.version && args.command.is_some() {
+        Some("--version doesn't take a command")
+    } else if args.daemonize && !daemon {
+        Some("--daemonize only applies to the daemon command")
+    } else if args.output.is_some() && (args.daemonize || !daemon) {
+        Some("--output only applies to the daemon command in the foreground")
+    } else {
+        None
+    };
+    match conflict {
+        Some(msg) => Err(Args::command().error(ErrorKind::ArgumentConflict, msg)),
+        None => Ok(args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Args, clap::Error> {
+        parse(std::iter::once("battery-notify").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn definition_is_valid() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(args(&[]).unwrap().command, None);
+        assert_eq!(
+            args(&["pause", "5m"]).unwrap().command,
+            Some(Command::Pause {
+                duration: Some(Duration::from_secs(300))
+            })
+        );
+        assert_eq!(
+            args(&["snooze", "off"]).unwrap().command,
+            Some(Command::Snooze {
+                duration: Snooze(None)
+            })
+        );
+        assert_eq!(
+            args(&["set-threshold", "40", "80"]).unwrap().command,
+            Some(Command::SetThreshold { start: 40, end: 80 })
+        );
+        assert_eq!(
+            args(&["status", "--json"]).unwrap().command,
+            Some(Command::Status {
+                history: false,
+                json: true
+            })
+        );
+        assert_eq!(
+            args(&["history"]).unwrap().command,
+            Some(Command::History {
+                since: Duration::from_secs(24 * 60 * 60)
+            })
+        );
+
+        let parsed = args(&["--set", "low_pct=30", "--set=sleep_pct=10", "--daemonize"]).unwrap();
+        assert!(parsed.daemonize);
+        let keys: Vec<_> = parsed.overrides.iter().map(|o| o.key.as_str()).collect();
+        assert_eq!(keys, ["low_pct", "sleep_pct"]);
+
+        let parsed = args(&["-V", "-v"]).unwrap();
+        assert!(parsed.version && parsed.verbose);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for bad in [
+            &["frobnicate"][..],
+            &["pause", "soon"],
+            &["set-threshold", "40"],
+            &["status", "--verbose"],
+            &["resume", "now"],
+            &["--set", "low_pct"],
+            &["status", "--daemonize"],
+            &["--daemonize", "status"],
+            &["--output", "json", "once"],
+            &["--output", "yaml"],
+            &["--version", "status"],
+            &["--verbose"],
+        ] {
+            assert!(args(bad).is_err(), "{bad:?} should fail");
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//! Where the time comes from, and how we wait for it to pass. Everything time based goes through a
+//! Clock, so that tests can step through hours of backoff and intervals without really waiting.
+
+use anyhow::{Context, Result};
+use std::ffi::CString;
+use std::io;
+use std::sync::{Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const DAY_SECS: u64 = 24 * 60 * 60;
+
+pub trait Clock {
+    fn now(&self) -> Instant;
+    /// Wall clock time, for things scheduled by time of day and for the state file.
+    fn wall(&self) -> SystemTime;
+    /// Waits for `duration`, or less if woken early. Waking early isn't an error.
+    fn sleep(&mut self, duration: Duration) -> io::Result<()>;
+}
+
+/// The real time. Sleeps can be cut short through the timer's canceller, if it has one.
+#[derive(Default)]
+pub struct SystemClock {
+    timer: Option<cancellable_timer::Timer>,
+}
+
+impl SystemClock {
+    pub const fn cancellable(timer: cancellable_timer::Timer) -> Self {
+        Self { timer: Some(timer) }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&mut self, duration: Duration) -> io::Result<()> {
+        match self.timer.as_mut() {
+            Some(timer) => match timer.sleep(duration) {
+                Err(err) if err.kind() != io::ErrorKind::Interrupted => Err(err),
+                _ => Ok(()),
+            },
+            None => {
+                thread::sleep(duration);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Sleeps until `next_wake` unless woken early, and returns when to wake up next after that. If
+/// woken early that's still `next_wake`, so that the check we were woken for doesn't push the
+/// scheduled one back by a whole interval.
+pub fn sleep_until(
+    clock: &mut dyn Clock,
+    next_wake: Instant,
+    interval: Duration,
+) -> io::Result<Instant> {
+    let now = clock.now();
+    if now < next_wake {
+        clock.sleep(next_wake - now)?;
+        if clock.now() < next_wake {
+            return Ok(next_wake);
+        }
+        Ok(next_wake + interval)
+    } else {
+        // Avoid spamming with more runs
+        Ok(now + interval)
+    }
+}
+
+/// Parses "HH:MM" into seconds after midnight, or None if empty.
+pub fn parse_time_of_day(time: &str) -> Result<Option<u64>> {
+    if time.is_empty() {
+        return Ok(None);
+    }
+    let parsed = time.split_once(':').and_then(|(h, m)| {
+        let (h, m): (u64, u64) = (h.parse().ok()?, m.parse().ok()?);
+        (h < 24 && m < 60).then_some(h * 3600 + m * 60)
+    });
+    parsed
+        .map(Some)
+        .with_context(|| format!("invalid time '{time}', expected HH:MM"))
+}
+
+/// Seconds since local midnight.
+pub fn local_secs_of_day(now: SystemTime) -> Option<u64> {
+    let secs: libc::time_t = now
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs()
+        .try_into()
+        .ok()?;
+    // SAFETY: tm is plain data, for which all zeroes is valid, and localtime_r only writes to it
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return None;
+    }
+    Some(tm.tm_hour as u64 * 3600 + tm.tm_min as u64 * 60 + tm.tm_sec as u64)
+}
+
+static TIME_FORMAT: Mutex<String> = Mutex::new(String::new());
+
+/// Sets the strftime format for local_time, from time_format in the config.
+pub fn set_time_format(format: &str) {
+    static LOCALE: Once = Once::new();
+    // For "%c" and month and day names in the user's language. Only LC_TIME, so nothing else we
+    // parse or print changes under us
+    // SAFETY: This happens while loading the config, before we start any threads of our own
+    LOCALE.call_once(|| unsafe {
+        libc::setlocale(libc::LC_TIME, c"".as_ptr());
+    });
+    *TIME_FORMAT.lock().unwrap() = format.to_string();
+}
+
+/// `time` as RFC 3339 in UTC, for anything read by other programs.
+pub fn utc_time(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+/// `time` in the local timezone with time_format, for people to read. Falls back to utc_time if
+/// time_format is empty or doesn't work.
+pub fn local_time(time: SystemTime) -> String {
+    let format = TIME_FORMAT.lock().unwrap().clone();
+    format_local(time, &format).unwrap_or_else(|| utc_time(time))
+}
+
+/// Like local_time, but for a time we already have as RFC 3339, like from the state file or the
+/// daemon. Anything else is left alone.
+pub fn localize(rfc3339: &str) -> String {
+    humantime::parse_rfc3339_weak(rfc3339).map_or_else(|_| rfc3339.to_string(), local_time)
+}
+
+fn format_local(time: SystemTime, format: &str) -> Option<String> {
+    if format.is_empty() {
+        return None;
+    }
+    let secs: libc::time_t = time
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs()
+        .try_into()
+        .ok()?;
+    let format = CString::new(format).ok()?;
+    // SAFETY: As in local_secs_of_day
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return None;
+    }
+    let mut buf = [0u8; 256];
+    // SAFETY: strftime writes at most buf.len() bytes, including the nul, and returns how many it
+    // wrote before it, or 0 if they didn't fit
+    let len = unsafe { libc::strftime(buf.as_mut_ptr().cast(), buf.len(), format.as_ptr(), &tm) };
+    (len > 0).then(|| String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+/// A clock which only moves when slept on or advanced, and remembers every sleep. Clones share the
+/// same time, so a test can keep one while the code under test owns another.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct FakeClock(std::sync::Arc<std::sync::Mutex<FakeTime>>);
+
+#[cfg(test)]
+struct FakeTime {
+    start: Instant,
+    wall_start: SystemTime,
+    elapsed: Duration,
+    slept: Vec<Duration>,
+}
+
+#[cfg(test)]
+impl FakeClock {
+    pub fn new() -> Self {
+        Self(std::sync::Arc::new(std::sync::Mutex::new(FakeTime {
+            start: Instant::now(),
+            wall_start: SystemTime::now(),
+            elapsed: Duration::ZERO,
+            slept: Vec::new(),
+        })))
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.0.lock().unwrap().elapsed += duration;
+    }
+
+    /// Every sleep so far, oldest first.
+    pub fn slept(&self) -> Vec<Duration> {
+        self.0.lock().unwrap().slept.clone()
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        let time = self.0.lock().unwrap();
+        time.start + time.elapsed
+    }
+
+    fn wall(&self) -> SystemTime {
+        let time = self.0.lock().unwrap();
+        time.wall_start + time.elapsed
+    }
+
+    fn sleep(&mut self, duration: Duration) -> io::Result<()> {
+        let mut time = self.0.lock().unwrap();
+        time.elapsed += duration;
+        time.slept.push(duration);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(30);
+
+    #[test]
+    fn sleep_until_keeps_schedule() {
+        let mut clock = FakeClock::new();
+        let start = clock.now();
+        clock.advance(Duration::from_secs(5));
+        let next = sleep_until(&mut clock, start + INTERVAL, INTERVAL).unwrap();
+        // The time spent checking comes out of the sleep, rather than delaying every check after
+        assert_eq!(clock.slept(), [Duration::from_secs(25)]);
+        assert_eq!(next, start + INTERVAL * 2);
+    }
+
+    #[test]
+    fn sleep_until_late_skips_missed_runs() {
+        let mut clock = FakeClock::new();
+        let start = clock.now();
+        clock.advance(INTERVAL * 3 + Duration::from_secs(1));
+        let next = sleep_until(&mut clock, start + INTERVAL, INTERVAL).unwrap();
+        assert!(clock.slept().is_empty());
+        assert_eq!(next, clock.now() + INTERVAL);
+    }
+
+    /// Wakes up halfway through every sleep, like a reload or a udev event would.
+    struct EarlyClock(FakeClock);
+
+    impl Clock for EarlyClock {
+        fn now(&self) -> Instant {
+            self.0.now()
+        }
+
+        fn wall(&self) -> SystemTime {
+            self.0.wall()
+        }
+
+        fn sleep(&mut self, duration: Duration) -> io::Result<()> {
+            self.0.sleep(duration / 2)
+        }
+    }
+
+    #[test]
+    fn sleep_until_woken_early_keeps_next_wake() {
+        let mut clock = EarlyClock(FakeClock::new());
+        let start = clock.now();
+        let next = sleep_until(&mut clock, start + INTERVAL, INTERVAL).unwrap();
+        assert_eq!(clock.now(), start + INTERVAL / 2);
+        assert_eq!(next, start + INTERVAL);
+    }
+
+    #[test]
+    fn fake_wall_follows_monotonic() {
+        let mut clock = FakeClock::new();
+        let wall = clock.wall();
+        clock.sleep(Duration::from_secs(90)).unwrap();
+        assert_eq!(clock.wall(), wall + Duration::from_secs(90));
+    }
+
+    #[test]
+    fn parse_time_of_day_accepts_hh_mm() {
+        assert_eq!(parse_time_of_day("").unwrap(), None);
+        assert_eq!(parse_time_of_day("00:00").unwrap(), Some(0));
+        assert_eq!(parse_time_of_day("7:05").unwrap(), Some(7 * 3600 + 5 * 60));
+        assert_eq!(parse_time_of_day("23:59").unwrap(), Some(86340));
+    }
+
+    #[test]
+    fn parse_time_of_day_rejects_nonsense() {
+        for time in ["24:00", "12:60", "12", "noon", "-1:00", "12:30:00"] {
+            assert!(parse_time_of_day(time).is_err(), "{time}");
+        }
+    }
+}
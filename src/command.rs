@@ -0,0 +1,224 @@
+use log::{error, info, warn};
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+
+/// How much of a command's output to keep in the log.
+const MAX_OUTPUT_LEN: usize = 512;
+
+/// What the last check found, as environment variables for every command we run. This is cheap to
+/// clone, so everything which runs commands keeps its own, like a notification::Sink.
+#[derive(Clone, Default)]
+pub struct Context(Arc<Mutex<Vec<(&'static str, String)>>>);
+
+impl Context {
+    /// Sets the environment variables every command gets from now on, from cli::EVENT_ENV.
+    pub fn set(&self, vars: Vec<(&'static str, String)>) {
+        *self.0.lock().unwrap() = vars;
+    }
+
+    /// Runs an event command through the shell, logging what was run, how it went, how long it
+    /// took, and what it said. Returns whether the command succeeded.
+    pub fn run(&self, cmd: &str) -> bool {
+        self.run_with_env(cmd, &[])
+    }
+
+    /// Like run(), with extra environment variables telling the command what it's about, which win
+    /// over the ones from set().
+    pub fn run_with_env(&self, cmd: &str, env: &[(&str, &str)]) -> bool {
+        let context = self.0.lock().unwrap().clone();
+        run_with_env(cmd, &context, env)
+    }
+}
+
+fn truncate_output(output: &[u8], dropped: bool) -> String {
+    let output = String::from_utf8_lossy(output);
+    let output = output.trim();
+    match output.char_indices().nth(MAX_OUTPUT_LEN) {
+        Some((idx, _)) => format!("{}... (truncated)", &output[..idx]),
+        None if dropped => format!("{output}... (truncated)"),
+        None => output.to_string(),
+    }
+}
+
+/// Reads everything from `pipe`, keeping only as much as we'd log.
+fn read_capped(mut pipe: impl Read) -> (Vec<u8>, bool) {
+    // Enough bytes for MAX_OUTPUT_LEN characters however they're encoded
+    let cap = MAX_OUTPUT_LEN * 4;
+    let (mut kept, mut dropped) = (Vec::new(), false);
+    let mut buf = [0; 4096];
+    loop {
+        match pipe.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                let take = n.min(cap - kept.len());
+                kept.extend_from_slice(&buf[..take]);
+                dropped |= take < n;
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(_) => break,
+        }
+    }
+    (kept, dropped)
+}
+
+/// Logs what the command writes to `pipe` once it's closed. That happens on its own thread, since
+/// anything the command leaves running in the background keeps the pipe open long after the
+/// command itself has finished, and we mustn't wait for that.
+fn log_output(cmd: &str, name: &'static str, pipe: impl Read + Send + 'static) {
+    let cmd = cmd.to_string();
+    let spawned = thread::Builder::new()
+        .name(format!("command-{name}"))
+        .spawn(move || {
+            let (content, dropped) = read_capped(pipe);
+            if !content.is_empty() {
+                info!(
+                    "Command '{cmd}' {name}: {}",
+                    truncate_output(&content, dropped)
+                );
+            }
+        });
+    if let Err(err) = spawned {
+        error!("failed to read command {name}: {err}");
+    }
+}
+
+fn run_with_env(cmd: &str, context: &[(&str, String)], env: &[(&str, &str)]) -> bool {
+    let start = Instant::now();
+    info!("Running command '{cmd}'");
+    let spawned = Command::new("sh")
+        .args(["-c", cmd])
+        .envs(context.iter().map(|(k, v)| (k, v)))
+        .envs(env.iter().copied())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let status = spawned.and_then(|mut child| {
+        if let Some(stdout) = child.stdout.take() {
+            log_output(cmd, "stdout", stdout);
+        }
+        if let Some(stderr) = child.stderr.take() {
+            log_output(cmd, "stderr", stderr);
+        }
+        child.wait()
+    });
+    match status {
+        Ok(status) => {
+            info!(
+                "Command '{cmd}' finished in {:?}: {status}",
+                start.elapsed()
+            );
+            status.success()
+        }
+        Err(err) => {
+            error!("Failed to run command '{cmd}': {err}");
+            false
+        }
+    }
+}
+
+/// Calls `attempt` until it succeeds, up to `retries` more times with `delay` in between. `what` is
+/// what it does, for the log.
+pub fn retry(
+    what: &str,
+    retries: u32,
+    delay: Duration,
+    clock: &mut dyn Clock,
+    mut attempt: impl FnMut() -> bool,
+) -> bool {
+    for n in 0..=retries {
+        if n > 0 {
+            warn!("Retrying '{what}' in {delay:?} (retry {n}/{retries})");
+            if let Err(err) = clock.sleep(delay) {
+                error!("failed to wait before retrying: {err}");
+            }
+        }
+        if attempt() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Stops an event command from being run more than once every `min_interval`, so that flapping
+/// states can't hammer whatever it kicks off.
+pub struct RateLimit {
+    min_interval: Duration,
+    next_allowed: Option<Instant>,
+}
+
+impl RateLimit {
+    pub const fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            next_allowed: None,
+        }
+    }
+
+    pub fn allow(&mut self, now: Instant) -> bool {
+        if self.next_allowed.is_some_and(|next| now < next) {
+            return false;
+        }
+        self.next_allowed = Some(now + self.min_interval);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FakeClock;
+
+    const DELAY: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn retries_wait_between_attempts() {
+        let mut clock = FakeClock::new();
+        assert!(!retry("false", 3, DELAY, &mut clock, || Context::default()
+            .run("false")));
+        assert_eq!(clock.slept(), [DELAY; 3]);
+    }
+
+    #[test]
+    fn no_retry_after_success() {
+        let mut clock = FakeClock::new();
+        let mut attempts = 0;
+        assert!(retry("true", 3, DELAY, &mut clock, || {
+            attempts += 1;
+            Context::default().run("true")
+        }));
+        assert_eq!(attempts, 1);
+        assert!(clock.slept().is_empty());
+    }
+
+    #[test]
+    fn background_children_do_not_hold_up_the_command() {
+        let start = Instant::now();
+        assert!(Context::default().run("sleep 5 & echo started"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn output_is_capped() {
+        let (kept, dropped) = read_capped(&[b'x'; 10_000][..]);
+        assert_eq!(kept.len(), MAX_OUTPUT_LEN * 4);
+        assert!(dropped);
+        assert!(truncate_output(&kept, dropped).ends_with("... (truncated)"));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let clock = FakeClock::new();
+        let mut limit = RateLimit::new(Duration::from_secs(60));
+        assert!(limit.allow(clock.now()));
+        clock.advance(Duration::from_secs(59));
+        assert!(!limit.allow(clock.now()));
+        clock.advance(Duration::from_secs(1));
+        assert!(limit.allow(clock.now()));
+    }
+}
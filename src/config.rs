@@ -0,0 +1,800 @@
+//! The config file, and everything in it.
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use toml::{Table, Value};
+
+use crate::{
+    action, bluetooth, buttons, charger, chargesched, clock, heavy, logging, migrate, monitors,
+    notification, overrides, remote, snooze, speech, theme, thresholds, ups,
+};
+
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    Sum,
+    Active,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BluetoothConfig {
+    /// Devices, by name or address pattern, to never check or notify about.
+    pub ignore: Vec<String>,
+    /// bluetooth_low_pct by name or address pattern, which wins over classes.
+    pub devices: BTreeMap<String, u8>,
+    pub classes: BTreeMap<String, u8>,
+}
+
+impl BluetoothConfig {
+    /// Whether anything here would have us check Bluetooth devices, even with bluetooth_low_pct
+    /// and bluetooth_critical_pct both 0.
+    pub fn has_overrides(&self) -> bool {
+        !self.classes.is_empty() || !self.devices.is_empty()
+    }
+
+    pub fn ignored(&self, bbat: &bluetooth::BluetoothBattery) -> bool {
+        self.ignore.iter().any(|pattern| bbat.matches(pattern))
+    }
+
+    /// The threshold for the device itself if there is one, otherwise for its class, otherwise
+    /// `default`. An exact name or address wins over a pattern, and otherwise the first matching
+    /// pattern (in sorted order) does.
+    pub fn low_pct(&self, bbat: &bluetooth::BluetoothBattery, default: u8) -> u8 {
+        let exact = std::iter::once(&bbat.name)
+            .chain(&bbat.address)
+            .find_map(|key| self.devices.get(key));
+        exact
+            .or_else(|| {
+                self.devices
+                    .iter()
+                    .find(|(pattern, _)| bbat.matches(pattern))
+                    .map(|(_, pct)| pct)
+            })
+            .or_else(|| {
+                bbat.class
+                    .as_ref()
+                    .and_then(|class| self.classes.get(class))
+            })
+            .copied()
+            .unwrap_or(default)
+    }
+}
+
+/// An extra threshold for the combined level, which notifies (and optionally runs a command) once
+/// per discharge.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tier {
+    pub pct: u8,
+    pub urgency: notification::UrgencySetting,
+    pub timeout_secs: u64,
+    pub command: String,
+    pub retries: u32,
+    pub retry_delay_secs: u64,
+}
+
+impl Default for Tier {
+    fn default() -> Self {
+        Self {
+            pct: 0,
+            urgency: notification::UrgencySetting::default(),
+            timeout_secs: 0,
+            command: String::new(),
+            retries: 0,
+            retry_delay_secs: 5,
+        }
+    }
+}
+
+/// Thresholds for a single battery, on top of the ones for the combined level.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatteryThresholds {
+    pub low_pct: u8,
+    pub critical_pct: u8,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SleepEvent {
+    pub command: action::Action,
+    pub retries: u32,
+    pub retry_delay_secs: u64,
+    pub min_interval_secs: u64,
+    pub rtc_wake_secs: u64,
+    pub after_wake_command: action::Action,
+    pub escalate_after_resume: bool,
+    pub defer_while_active_secs: u64,
+    pub respect_inhibitors: bool,
+    pub inhibit_command: String,
+    pub inhibit_floor_pct: u8,
+}
+
+impl Default for SleepEvent {
+    fn default() -> Self {
+        Self {
+            command: "systemctl suspend".into(),
+            retries: 0,
+            retry_delay_secs: 5,
+            min_interval_secs: 60,
+            rtc_wake_secs: 0,
+            after_wake_command: "systemctl hibernate".into(),
+            escalate_after_resume: true,
+            defer_while_active_secs: 0,
+            respect_inhibitors: true,
+            inhibit_command: String::new(),
+            inhibit_floor_pct: 5,
+        }
+    }
+}
+
+/// Run when mains power is connected or disconnected, as the mains supply reports it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AcEvent {
+    pub command: action::Action,
+    pub retries: u32,
+    pub retry_delay_secs: u64,
+    pub notify: bool,
+    pub min_interval_secs: u64,
+}
+
+impl Default for AcEvent {
+    fn default() -> Self {
+        Self {
+            command: action::Action::default(),
+            retries: 0,
+            retry_delay_secs: 5,
+            notify: false,
+            min_interval_secs: 10,
+        }
+    }
+}
+
+/// Run when a UPS goes on battery or runs low, as it reports it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpsEvent {
+    pub command: action::Action,
+    pub retries: u32,
+    pub retry_delay_secs: u64,
+}
+
+impl Default for UpsEvent {
+    fn default() -> Self {
+        Self {
+            command: action::Action::default(),
+            retries: 0,
+            retry_delay_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    pub sleep: SleepEvent,
+    pub ac_connected: AcEvent,
+    pub ac_disconnected: AcEvent,
+    pub ups_on_battery: UpsEvent,
+    pub ups_low_runtime: UpsEvent,
+}
+
+/// Settings which only take effect at startup, or choose profiles themselves, so can't be in one.
+const NOT_IN_PROFILES: &[&str] = &[
+    "version",
+    "profile",
+    "profiles",
+    "watch_uevents",
+    "watchdog_secs",
+    "startup_grace_secs",
+    "restore_state",
+    "dbus_service",
+    "system_mode",
+    "log_format",
+    "time_format",
+];
+
+/// Settings applied on top of the rest of the config while all of its conditions hold, or while
+/// chosen by hand.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// Only on mains power (true) or battery (false), or either if unset.
+    pub ac: Option<bool>,
+    /// Only with at least this many monitors connected. 0 doesn't care.
+    pub min_monitors: usize,
+    /// Only on the machine with this hostname. Empty doesn't care.
+    pub hostname: String,
+    /// Config settings, as they'd be written at the top of the config.
+    pub settings: Table,
+}
+
+impl Profile {
+    /// Profiles without any conditions are only ever chosen by hand.
+    fn matches(&self, now: &Conditions) -> bool {
+        let any = self.ac.is_some() || self.min_monitors != 0 || !self.hostname.is_empty();
+        any && self.ac.map_or(true, |ac| now.ac_online == Some(ac))
+            && (self.min_monitors == 0 || now.monitors.is_some_and(|n| n >= self.min_monitors))
+            && (self.hostname.is_empty() || now.hostname.as_deref() == Some(&self.hostname))
+    }
+}
+
+/// What profiles' conditions are checked against. Anything we couldn't find out is None, and
+/// doesn't match any condition on it.
+#[derive(Debug, Default)]
+pub struct Conditions {
+    pub ac_online: Option<bool>,
+    pub monitors: Option<usize>,
+    pub hostname: Option<String>,
+}
+
+/// Puts `overlay` on top of `base`, so tables only replace the keys they have. `path` is the
+/// dotted path of `base`, for errors and to know which tables take arbitrary keys.
+fn merge(base: &mut Table, overlay: &Table, path: &str) -> Result<()> {
+    for (key, value) in overlay {
+        let full = match path {
+            "" => key.clone(),
+            path => format!("{path}.{key}"),
+        };
+        match (base.get_mut(key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => merge(base, overlay, &full)?,
+            (None, _) if !overrides::MAPS.contains(&path) => bail!("unknown config key {full}"),
+            // Anything else replaces what was there, including commands going between a string
+            // and a built in action
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub version: u32,
+    pub interval_secs: u64,
+    pub watch_uevents: bool,
+    pub watchdog_secs: u64,
+    pub cycle_budget_pct: u8,
+    pub sleep_pct: u8,
+    pub low_pct: u8,
+    pub low_hysteresis_pct: u8,
+    pub tiers: Vec<Tier>,
+    pub aggregation: Aggregation,
+    pub warn_on_mons_with_no_ac: usize,
+    pub monitors_backend: monitors::Backend,
+    pub bluetooth_low_pct: u8,
+    pub bluetooth_critical_pct: u8,
+    pub bluetooth_critical_repeat_secs: u64,
+    pub bluetooth_critical_command: String,
+    pub bluetooth_milestone_step: u8,
+    pub bluetooth_milestone_devices: Vec<String>,
+    pub bluetooth_stale_secs: u64,
+    pub gamemode_defer_notifications: bool,
+    pub transient_state_notifications: bool,
+    pub close_reverted_state_secs: u64,
+    pub unknown_state_diag_cycles: u32,
+    pub stale_data_diag_cycles: u32,
+    pub stale_data_command: String,
+    pub health_warn_pct: u8,
+    pub heavy_commands: Vec<String>,
+    pub pause_writes_below_sleep_pct: bool,
+    pub resident_low_notifications: bool,
+    pub slow_charge_watts: f64,
+    pub slow_charge_secs: u64,
+    pub fast_drain_pct_per_hour: f64,
+    pub fast_drain_top_processes: usize,
+    pub charge_full_by: String,
+    pub charge_hold_pct: u8,
+    pub charge_top_up_secs: u64,
+    pub charge_start_threshold: u8,
+    pub charge_end_threshold: u8,
+    pub notify_unknown_chargers: bool,
+    pub min_charger_watts: f64,
+    pub osd_command: String,
+    pub critical_led: String,
+    pub critical_led_blink_ms: u64,
+    pub startup_grace_secs: u64,
+    pub restore_state: bool,
+    pub quiet_hours: String,
+    pub history_interval_secs: u64,
+    pub history_retention_days: u64,
+    pub dbus_service: bool,
+    pub system_mode: bool,
+    pub log_format: logging::LogFormat,
+    pub time_format: String,
+    pub batteries: BTreeMap<String, BatteryThresholds>,
+    pub chargers: BTreeMap<String, charger::ChargerConfig>,
+    pub remotes: BTreeMap<String, remote::RemoteConfig>,
+    pub ups: ups::UpsConfig,
+    pub theme: theme::ThemeConfig,
+    pub speech: speech::SpeechConfig,
+    pub notifications: notification::NotificationsConfig,
+    pub events: EventsConfig,
+    pub bluetooth: BluetoothConfig,
+    pub profile: String,
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            version: migrate::CURRENT_VERSION,
+            interval_secs: 30,
+            watch_uevents: true,
+            watchdog_secs: 60,
+            cycle_budget_pct: 25,
+            sleep_pct: 15,
+            low_pct: 40,
+            low_hysteresis_pct: 3,
+            tiers: Vec::new(),
+            aggregation: Aggregation::Sum,
+            warn_on_mons_with_no_ac: 2,
+            monitors_backend: monitors::Backend::Auto,
+            bluetooth_low_pct: 40,
+            bluetooth_critical_pct: 0,
+            bluetooth_critical_repeat_secs: 0,
+            bluetooth_critical_command: String::new(),
+            bluetooth_milestone_step: 0,
+            bluetooth_milestone_devices: Vec::new(),
+            bluetooth_stale_secs: 0,
+            gamemode_defer_notifications: false,
+            transient_state_notifications: true,
+            close_reverted_state_secs: 0,
+            unknown_state_diag_cycles: 20,
+            stale_data_diag_cycles: 60,
+            stale_data_command: String::new(),
+            health_warn_pct: 0,
+            heavy_commands: Vec::new(),
+            pause_writes_below_sleep_pct: true,
+            resident_low_notifications: true,
+            slow_charge_watts: 0.0,
+            slow_charge_secs: 300,
+            fast_drain_pct_per_hour: 0.0,
+            fast_drain_top_processes: 3,
+            charge_full_by: String::new(),
+            charge_hold_pct: 80,
+            charge_top_up_secs: 2 * 60 * 60,
+            charge_start_threshold: 0,
+            charge_end_threshold: 0,
+            notify_unknown_chargers: false,
+            min_charger_watts: 0.0,
+            osd_command: String::new(),
+            critical_led: String::new(),
+            critical_led_blink_ms: 500,
+            startup_grace_secs: 0,
+            restore_state: true,
+            quiet_hours: String::new(),
+            history_interval_secs: 0,
+            history_retention_days: 30,
+            dbus_service: true,
+            system_mode: false,
+            log_format: logging::LogFormat::Text,
+            time_format: "%Y-%m-%d %H:%M:%S %Z".to_string(),
+            batteries: BTreeMap::new(),
+            chargers: BTreeMap::new(),
+            remotes: BTreeMap::new(),
+            ups: ups::UpsConfig::default(),
+            theme: theme::ThemeConfig::default(),
+            speech: speech::SpeechConfig::default(),
+            notifications: notification::NotificationsConfig::default(),
+            events: EventsConfig::default(),
+            bluetooth: BluetoothConfig::default(),
+            profile: String::new(),
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+/// A mistake found by Config::problems, with the key it's about so check-config can point at it.
+#[derive(Debug)]
+pub struct Problem {
+    /// Like "low_pct" or "notifications.low.buttons".
+    pub key: String,
+    pub error: anyhow::Error,
+}
+
+impl Config {
+    /// Checks for mistakes which parsing alone doesn't catch, like settings which conflict, and
+    /// returns the first.
+    pub fn validate(&self) -> Result<()> {
+        match self.problems().into_iter().next() {
+            Some(problem) => Err(problem.error),
+            None => Ok(()),
+        }
+    }
+
+    /// Every mistake validate would find, rather than just the first.
+    pub fn problems(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        let mut check = |key: &str, res: Result<()>| {
+            if let Err(error) = res {
+                problems.push(Problem {
+                    key: key.to_string(),
+                    error,
+                });
+            }
+        };
+
+        check(
+            "charge_full_by",
+            chargesched::ChargeScheduler::new(
+                &self.charge_full_by,
+                self.charge_hold_pct,
+                Duration::from_secs(self.charge_top_up_secs),
+                &notification::Sink::default(),
+            )
+            .map(|_| ()),
+        );
+        check(
+            "charge_start_threshold",
+            thresholds::validate(self.charge_start_threshold, self.charge_end_threshold),
+        );
+        check("quiet_hours", snooze::validate(&self.quiet_hours));
+        check("heavy_commands", heavy::validate(&self.heavy_commands));
+        check("speech", speech::validate(&self.speech));
+        check("ups", ups::validate(&self.ups));
+        check(
+            "monitors_backend",
+            monitors::validate(self.monitors_backend),
+        );
+        for category in notification::Category::ALL {
+            let name = serde_plain::to_string(category).unwrap_or_default();
+            check(
+                &format!("notifications.{name}.buttons"),
+                buttons::validate(&name, &self.notifications.get(*category).buttons),
+            );
+        }
+        if !self.charge_full_by.is_empty() && self.charge_end_threshold != 0 {
+            check(
+                "charge_end_threshold",
+                Err(anyhow::anyhow!(
+                    "charge_end_threshold can't be used with charge_full_by, which manages it \
+                     itself"
+                )),
+            );
+        }
+
+        if self.interval_secs == 0 {
+            check(
+                "interval_secs",
+                Err(anyhow::anyhow!("interval_secs must be at least 1")),
+            );
+        }
+        // 0 turns the watchdog off, anything else is sent to systemd in microseconds
+        if self.watchdog_secs > u64::MAX / 1_000_000 {
+            check(
+                "watchdog_secs",
+                Err(anyhow::anyhow!(
+                    "watchdog_secs is {}, which is too long for systemd",
+                    self.watchdog_secs
+                )),
+            );
+        }
+        for (key, pct) in [
+            ("sleep_pct", self.sleep_pct),
+            ("low_pct", self.low_pct),
+            ("cycle_budget_pct", self.cycle_budget_pct),
+            ("bluetooth_low_pct", self.bluetooth_low_pct),
+            ("bluetooth_critical_pct", self.bluetooth_critical_pct),
+            ("health_warn_pct", self.health_warn_pct),
+            ("charge_hold_pct", self.charge_hold_pct),
+            ("ups.low_pct", self.ups.low_pct),
+            (
+                "events.sleep.inhibit_floor_pct",
+                self.events.sleep.inhibit_floor_pct,
+            ),
+        ] {
+            if pct > 100 {
+                check(key, Err(anyhow::anyhow!("{key} is {pct}, above 100%")));
+            }
+        }
+        if self.low_pct != 0 && self.low_pct <= self.sleep_pct {
+            check(
+                "low_pct",
+                Err(anyhow::anyhow!(
+                    "low_pct ({}) must be above sleep_pct ({}), or there's no warning before \
+                     sleeping",
+                    self.low_pct,
+                    self.sleep_pct
+                )),
+            );
+        }
+        if let Some(tier) = self.tiers.iter().find(|tier| tier.pct > 100) {
+            check(
+                "tiers",
+                Err(anyhow::anyhow!("a tier's pct is {}, above 100%", tier.pct)),
+            );
+        }
+        // Retries happen within a check, so they have to be over before the watchdog gives up on it
+        let events = &self.events;
+        let retried = [
+            (
+                "events.sleep",
+                events.sleep.retries,
+                events.sleep.retry_delay_secs,
+            ),
+            (
+                "events.ac_connected",
+                events.ac_connected.retries,
+                events.ac_connected.retry_delay_secs,
+            ),
+            (
+                "events.ac_disconnected",
+                events.ac_disconnected.retries,
+                events.ac_disconnected.retry_delay_secs,
+            ),
+            (
+                "events.ups_on_battery",
+                events.ups_on_battery.retries,
+                events.ups_on_battery.retry_delay_secs,
+            ),
+            (
+                "events.ups_low_runtime",
+                events.ups_low_runtime.retries,
+                events.ups_low_runtime.retry_delay_secs,
+            ),
+        ]
+        .into_iter()
+        .chain(
+            self.tiers
+                .iter()
+                .map(|tier| ("tiers", tier.retries, tier.retry_delay_secs)),
+        );
+        for (key, retries, delay) in retried {
+            let waiting = u64::from(retries).saturating_mul(delay);
+            if self.watchdog_secs != 0 && waiting >= self.watchdog_secs {
+                check(
+                    key,
+                    Err(anyhow::anyhow!(
+                        "{key} retries wait {waiting}s in total, which is at least watchdog_secs \
+                         ({}s)",
+                        self.watchdog_secs
+                    )),
+                );
+            }
+        }
+        for (name, battery) in &self.batteries {
+            if battery.low_pct > 100 || battery.critical_pct > 100 {
+                check(
+                    &format!("batteries.{name}"),
+                    Err(anyhow::anyhow!("batteries.{name} has a pct above 100%")),
+                );
+            }
+        }
+
+        if !self.profile.is_empty() && !self.profiles.contains_key(&self.profile) {
+            check(
+                "profile",
+                Err(anyhow::anyhow!(
+                    "profile is '{}', but there's no such profile",
+                    self.profile
+                )),
+            );
+        }
+        for name in self.profiles.keys() {
+            let res = self.with_profile(Some(name)).and_then(|cfg| cfg.validate());
+            check(
+                &format!("profiles.{name}"),
+                res.with_context(|| format!("invalid config with profile {name}")),
+            );
+        }
+        problems
+    }
+
+    /// A copy of this config with profile `name`'s settings on top, or without any if None. The
+    /// copy has no profiles of its own, since they've been dealt with.
+    pub fn with_profile(&self, name: Option<&str>) -> Result<Self> {
+        let mut table = Table::try_from(self)?;
+        table.remove("profile");
+        table.remove("profiles");
+        if let Some(name) = name {
+            let Some(profile) = self.profiles.get(name) else {
+                bail!("no profile named '{name}'");
+            };
+            if let Some(key) = profile
+                .settings
+                .keys()
+                .find(|key| NOT_IN_PROFILES.contains(&key.as_str()))
+            {
+                bail!("{key} can't be set in a profile, but profile {name} does");
+            }
+            merge(&mut table, &profile.settings, "")
+                .with_context(|| format!("invalid settings in profile {name}"))?;
+        }
+        Value::Table(table)
+            .try_into()
+            .with_context(|| format!("invalid config with profile {}", name.unwrap_or("none")))
+    }
+
+    /// The profile to use under `now`: the one chosen with `profile` if any, otherwise the first
+    /// by name whose conditions all hold.
+    pub fn pick_profile(&self, now: &Conditions) -> Option<&str> {
+        if !self.profile.is_empty() {
+            return Some(&self.profile);
+        }
+        self.profiles
+            .iter()
+            .find(|(_, profile)| profile.matches(now))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Whether picking a profile needs the number of monitors, which can take a while to find.
+    pub fn profiles_need_monitors(&self) -> bool {
+        self.profile.is_empty() && self.profiles.values().any(|p| p.min_monitors != 0)
+    }
+}
+
+/// Loads the config file, with environment and then command line overrides applied on top.
+pub fn load(cli_overrides: Vec<overrides::Override>) -> Result<Config> {
+    let path = confy::get_configuration_file_path("battery-notify", "config")?;
+    migrate::migrate_file(&path)?;
+    let cfg: Config = confy::load_path(&path)?;
+    let mut overrides = overrides::from_env();
+    overrides.extend(cli_overrides);
+    let cfg = overrides::apply(cfg, &overrides)?;
+    logging::set_format(cfg.log_format);
+    clock::set_time_format(&cfg.time_format);
+    for ov in &overrides {
+        info!("Overriding {} from {}", ov.key, ov.source);
+    }
+    Ok(cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn conditions(ac_online: Option<bool>, monitors: Option<usize>) -> Conditions {
+        Conditions {
+            ac_online,
+            monitors,
+            hostname: Some("laptop".to_string()),
+        }
+    }
+
+    #[test]
+    fn profile_settings_merge_over_config() {
+        let cfg = config(
+            r#"
+            low_pct = 30
+            sleep_pct = 10
+
+            [events.sleep]
+            command = "systemctl hibernate"
+            retries = 2
+
+            [profiles.travel.settings]
+            low_pct = 50
+
+            [profiles.travel.settings.events.sleep]
+            command = { action = "suspend" }
+            "#,
+        );
+        let travel = cfg.with_profile(Some("travel")).unwrap();
+        assert_eq!(travel.low_pct, 50);
+        assert_eq!(travel.sleep_pct, 10);
+        assert_eq!(
+            travel.events.sleep.command.to_string(),
+            "suspend (through logind)"
+        );
+        // Tables only replace the keys they have
+        assert_eq!(travel.events.sleep.retries, 2);
+        assert!(travel.profiles.is_empty());
+
+        let none = cfg.with_profile(None).unwrap();
+        assert_eq!(none.low_pct, 30);
+        assert_eq!(none.events.sleep.command.to_string(), "systemctl hibernate");
+    }
+
+    #[test]
+    fn profile_settings_add_to_maps() {
+        let cfg = config(
+            r#"
+            [batteries.BAT0]
+            low_pct = 20
+
+            [profiles.docked.settings.batteries.BAT1]
+            low_pct = 30
+            "#,
+        );
+        let docked = cfg.with_profile(Some("docked")).unwrap();
+        assert_eq!(docked.batteries["BAT0"].low_pct, 20);
+        assert_eq!(docked.batteries["BAT1"].low_pct, 30);
+    }
+
+    #[test]
+    fn profile_settings_checked() {
+        let unknown = config("[profiles.typo.settings]\nlow_cpt = 50");
+        assert!(unknown.with_profile(Some("typo")).is_err());
+        assert!(unknown.validate().is_err());
+
+        let startup = config("[profiles.quiet.settings]\ndbus_service = false");
+        assert!(startup.with_profile(Some("quiet")).is_err());
+
+        let invalid = config("[profiles.bad.settings]\nquiet_hours = 'whenever'");
+        assert!(invalid.with_profile(Some("bad")).is_ok());
+        assert!(invalid.validate().is_err());
+
+        let missing = config("profile = 'nowhere'");
+        assert!(missing.validate().is_err());
+    }
+
+    #[test]
+    fn retries_must_fit_within_watchdog() {
+        let fits = config("[events.ac_connected]\nretries = 5\nretry_delay_secs = 10");
+        assert!(fits.problems().is_empty());
+
+        let tier = config("tiers = [{ pct = 10, retries = 6, retry_delay_secs = 10 }]");
+        let keys: Vec<_> = tier.problems().into_iter().map(|p| p.key).collect();
+        assert_eq!(keys, ["tiers"]);
+
+        let unwatched = config("watchdog_secs = 0\n[events.sleep]\nretries = 100");
+        assert!(unwatched.problems().is_empty());
+    }
+
+    #[test]
+    fn profile_picked_by_conditions() {
+        let cfg = config(
+            r#"
+            [profiles.docked]
+            ac = true
+            min_monitors = 2
+
+            [profiles.travel]
+            ac = false
+
+            [profiles.work]
+            hostname = "desktop"
+
+            [profiles.manual]
+            "#,
+        );
+        assert_eq!(
+            cfg.pick_profile(&conditions(Some(true), Some(2))),
+            Some("docked")
+        );
+        assert_eq!(cfg.pick_profile(&conditions(Some(true), Some(1))), None);
+        assert_eq!(cfg.pick_profile(&conditions(Some(true), None)), None);
+        assert_eq!(
+            cfg.pick_profile(&conditions(Some(false), Some(2))),
+            Some("travel")
+        );
+        assert_eq!(cfg.pick_profile(&conditions(None, None)), None);
+        assert!(cfg.profiles_need_monitors());
+
+        let mut desktop = conditions(None, None);
+        desktop.hostname = Some("desktop".to_string());
+        assert_eq!(cfg.pick_profile(&desktop), Some("work"));
+    }
+
+    #[test]
+    fn profile_chosen_by_hand_wins() {
+        let cfg = config(
+            r#"
+            profile = "manual"
+
+            [profiles.travel]
+            ac = false
+
+            [profiles.manual]
+            "#,
+        );
+        assert_eq!(
+            cfg.pick_profile(&conditions(Some(false), None)),
+            Some("manual")
+        );
+        assert!(!cfg.profiles_need_monitors());
+        cfg.validate().unwrap();
+    }
+}
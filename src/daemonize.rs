@@ -0,0 +1,101 @@
+//! Detaching from the terminal, for people starting us from things like .xinitrc without anything
+//! to supervise us.
+
+use anyhow::{bail, Context, Result};
+use log::info;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+/// Removes the pidfile when the daemon exits.
+pub struct PidFile(PathBuf);
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn fork() -> Result<libc::pid_t> {
+    // SAFETY: we're still single threaded at this point, so the child can do whatever it likes
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()).context("fork failed"),
+        pid => Ok(pid),
+    }
+}
+
+fn redirect(file: &File, fd: libc::c_int) -> Result<()> {
+    // SAFETY: both fds are valid for the duration of the call
+    if unsafe { libc::dup2(file.as_raw_fd(), fd) } == -1 {
+        return Err(io::Error::last_os_error()).context("dup2 failed");
+    }
+    Ok(())
+}
+
+fn check_not_running(pidfile: &Path) -> Result<()> {
+    let Ok(pid) = fs::read_to_string(pidfile) else {
+        return Ok(());
+    };
+    if let Ok(pid) = pid.trim().parse::<libc::pid_t>() {
+        // SAFETY: signal 0 only checks whether the process exists
+        if pid > 0 && unsafe { libc::kill(pid, 0) } == 0 {
+            bail!(
+                "already running as pid {pid} (according to {})",
+                pidfile.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Double forks into the background with a new session, sending stdout and stderr to a log file,
+/// and writes a pidfile. Only the final grandchild returns. Must be called before starting any
+/// threads.
+pub fn daemonize() -> Result<PidFile> {
+    let dirs = directories::BaseDirs::new().context("failed to find home directory")?;
+    let pidfile = dirs
+        .runtime_dir()
+        .unwrap_or_else(|| dirs.cache_dir())
+        .join("battery-notify.pid");
+    let log_dir = dirs
+        .state_dir()
+        .unwrap_or_else(|| dirs.cache_dir())
+        .join("battery-notify");
+    check_not_running(&pidfile)?;
+
+    fs::create_dir_all(&log_dir)?;
+    let log_path = log_dir.join("battery-notify.log");
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .with_context(|| format!("failed to open {}", log_path.display()))?;
+    let null = File::open("/dev/null")?;
+    info!(
+        "Daemonizing, logging to {} with pidfile {}",
+        log_path.display(),
+        pidfile.display()
+    );
+
+    if fork()? != 0 {
+        std::process::exit(0);
+    }
+    // SAFETY: no preconditions, and we're not a process group leader since we just forked
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error()).context("setsid failed");
+    }
+    // Fork again so that we can never reacquire a controlling terminal
+    if fork()? != 0 {
+        std::process::exit(0);
+    }
+
+    std::env::set_current_dir("/")?;
+    redirect(&null, libc::STDIN_FILENO)?;
+    redirect(&log, libc::STDOUT_FILENO)?;
+    redirect(&log, libc::STDERR_FILENO)?;
+
+    fs::write(&pidfile, format!("{}\n", std::process::id()))
+        .with_context(|| format!("failed to write {}", pidfile.display()))?;
+    Ok(PidFile(pidfile))
+}
@@ -0,0 +1,70 @@
+use anyhow::Result;
+use log::info;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::json::{or_error, Json};
+use crate::{bluetooth, monitors, system, version, Config};
+
+fn power_supplies() -> Result<Json> {
+    let mut supplies = Vec::new();
+    for name in system::get_power_supply_names()? {
+        let attrs = or_error(system::dump_power_supply(&name), |attrs| {
+            Json::obj(attrs.into_iter().map(|(k, v)| (k, Json::from(v))))
+        });
+        supplies.push((name, attrs));
+    }
+    Ok(Json::Obj(supplies))
+}
+
+#[cfg(feature = "dbus")]
+fn daemon_logs() -> Result<Vec<String>> {
+    let ret = crate::service::call("RecentLogs", &())?;
+    Ok(ret.body().deserialize()?)
+}
+
+#[cfg(not(feature = "dbus"))]
+fn daemon_logs() -> Result<Vec<String>> {
+    anyhow::bail!("built without D-Bus support, can't ask the daemon for its logs")
+}
+
+/// Writes everything a maintainer might want to reproduce a driver quirk into a single JSON file
+/// in the current directory.
+pub fn run(cfg: &Config) -> Result<()> {
+    let dump = Json::obj([
+        ("version", Json::from(version::VERSION)),
+        ("git_hash", Json::from(version::GIT_HASH)),
+        ("features", Json::from(version::enabled_features())),
+        (
+            "backends",
+            Json::obj(
+                version::backends(cfg)
+                    .into_iter()
+                    .map(|(kind, backend)| (kind, Json::from(backend))),
+            ),
+        ),
+        ("config", Json::from(toml::to_string(cfg)?)),
+        ("power_supply", or_error(power_supplies(), |ps| ps)),
+        (
+            "bluez",
+            or_error(bluetooth::dump_managed_objects(), Json::from),
+        ),
+        (
+            "monitors",
+            or_error(monitors::get_outputs(cfg.monitors_backend), |outputs| {
+                Json::obj(
+                    outputs
+                        .into_iter()
+                        .map(|(name, connected)| (name, Json::from(connected))),
+                )
+            }),
+        ),
+        ("daemon_logs", or_error(daemon_logs(), Json::from)),
+    ]);
+
+    let ts = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let path = format!("battery-notify-debug-{ts}.json");
+    fs::write(&path, dump.to_string())?;
+    info!("Wrote debug dump to {path}");
+    Ok(())
+}
@@ -0,0 +1,248 @@
+use crate::heavy::Heavy;
+use crate::notification::{SingleNotification, Sink};
+use crate::state::State;
+use crate::system::{self, Battery, BatteryState};
+use hashbrown::{HashMap, HashSet};
+use log::{error, info, warn};
+use notify_rust::Urgency;
+
+fn log_power_supply(name: &str) {
+    match system::dump_power_supply(name) {
+        Ok(attrs) => {
+            for (attr, val) in attrs {
+                warn!("{name}: {attr} = {val}");
+            }
+        }
+        Err(err) => error!("failed to dump {name}: {err}"),
+    }
+}
+
+/// Watches for batteries which keep reporting Unknown even though we know we're not on AC, which
+/// usually means a driver bug. Each battery is only diagnosed once per run.
+pub struct UnknownStateDiagnostics {
+    max_cycles: u32,
+    cycles: HashMap<String, u32>,
+    diagnosed: HashSet<String>,
+    notif: SingleNotification,
+}
+
+impl UnknownStateDiagnostics {
+    pub fn new(max_cycles: u32, sink: &Sink) -> Self {
+        Self {
+            max_cycles,
+            cycles: HashMap::new(),
+            diagnosed: HashSet::new(),
+            notif: SingleNotification::new(sink),
+        }
+    }
+
+    pub fn update(&mut self, batteries: &[Battery], ac_online: Option<bool>) {
+        if self.max_cycles == 0 {
+            return;
+        }
+
+        for bat in batteries {
+            // Some laptops legitimately report Unknown on AC when held at a charge threshold, so
+            // only being off AC tells us anything
+            if bat.state != BatteryState::Unknown || ac_online != Some(false) {
+                self.cycles.remove(&bat.name);
+                continue;
+            }
+
+            let cycles = self.cycles.entry_ref(&bat.name).or_insert(0);
+            *cycles += 1;
+            if *cycles < self.max_cycles || self.diagnosed.contains(&bat.name) {
+                continue;
+            }
+
+            self.diagnosed.insert(bat.name.clone());
+            warn!(
+                "{} has reported Unknown for {} cycles while AC is offline, this is likely a driver bug",
+                bat.name, cycles,
+            );
+            log_power_supply(&bat.name);
+            self.notif.show_with_body(
+                format!("{} state stuck at unknown", bat.name),
+                "Your battery driver may be misbehaving, check the log for details".to_string(),
+                Urgency::Normal,
+            );
+        }
+    }
+}
+
+/// Watches for batteries whose readings don't change at all for a long time while discharging,
+/// which usually means the embedded controller or driver has wedged and we're being fed old data.
+pub struct StaleDataDiagnostics {
+    max_cycles: u32,
+    command: String,
+    heavy: Heavy,
+    // The last reading of each battery, and for how many cycles it's been the same
+    readings: HashMap<String, ((u64, Option<u64>), u32)>,
+    notif: SingleNotification,
+}
+
+impl StaleDataDiagnostics {
+    pub fn new(max_cycles: u32, command: String, heavy: &Heavy, sink: &Sink) -> Self {
+        Self {
+            max_cycles,
+            command,
+            heavy: heavy.clone(),
+            readings: HashMap::new(),
+            notif: SingleNotification::new(sink),
+        }
+    }
+
+    pub fn update(&mut self, batteries: &[Battery]) {
+        if self.max_cycles == 0 {
+            return;
+        }
+
+        for bat in batteries {
+            if bat.state != BatteryState::Discharging {
+                self.readings.remove(&bat.name);
+                continue;
+            }
+
+            let reading = (bat.now_uwh(), bat.power_uw);
+            let (last, cycles) = self.readings.entry_ref(&bat.name).or_insert((reading, 0));
+            if *last != reading {
+                if *cycles >= self.max_cycles {
+                    info!("{} readings are changing again", bat.name);
+                }
+                *last = reading;
+                *cycles = 0;
+                continue;
+            }
+            *cycles += 1;
+            // Only act once per episode
+            if *cycles != self.max_cycles {
+                continue;
+            }
+
+            warn!(
+                "{} readings haven't changed for {} cycles while discharging, the driver or EC may be wedged",
+                bat.name, cycles,
+            );
+            log_power_supply(&bat.name);
+            self.notif.show_with_body(
+                format!("{} readings are stale", bat.name),
+                "Battery levels may be out of date, check the log for details".to_string(),
+                Urgency::Normal,
+            );
+            if !self.command.is_empty() {
+                self.heavy.run(
+                    "stale_data_command",
+                    &self.command,
+                    &[
+                        ("BATTERY_NOTIFY_EVENT", "stale_data"),
+                        ("BATTERY_NOTIFY_BATTERY", &bat.name),
+                    ],
+                );
+            }
+        }
+    }
+}
+
+/// Warns once per battery per run when it reports an implausible full capacity, which we've
+/// replaced with its design capacity. This usually means the pack or its gauge is failing.
+pub struct CapacityDiagnostics {
+    diagnosed: HashSet<String>,
+    notif: SingleNotification,
+}
+
+impl CapacityDiagnostics {
+    pub fn new(sink: &Sink) -> Self {
+        Self {
+            diagnosed: HashSet::new(),
+            notif: SingleNotification::new(sink),
+        }
+    }
+
+    pub fn update(&mut self, batteries: &[Battery]) {
+        for bat in batteries {
+            let Some(reported) = bat.implausible_full_uwh else {
+                continue;
+            };
+            if !self.diagnosed.insert(bat.name.clone()) {
+                continue;
+            }
+            let wh = |uwh: u64| uwh as f64 / 1_000_000.0;
+            warn!(
+                "{} reports a full capacity of {:.1}Wh against a design capacity of {:.1}Wh, using the design capacity instead",
+                bat.name,
+                wh(reported),
+                wh(bat.full_uwh()),
+            );
+            log_power_supply(&bat.name);
+            self.notif.show_with_body(
+                format!("{} reports an implausible capacity", bat.name),
+                format!(
+                    "Full capacity of {:.1}Wh against {:.1}Wh by design, levels are based on the \
+                     design capacity. The battery may be failing",
+                    wh(reported),
+                    wh(bat.full_uwh()),
+                ),
+                Urgency::Normal,
+            );
+        }
+    }
+}
+
+/// How far health has to recover above health_warn_pct before we forget having warned, so that a
+/// replaced pack is warned about in its turn without a wobbling full capacity warning again.
+const HEALTH_RECOVERED_MARGIN_PCT: u8 = 10;
+
+/// Warns once per battery when its health drops below `warn_pct`, remembering that in the state file
+/// across restarts.
+pub struct HealthDiagnostics {
+    notif: SingleNotification,
+}
+
+impl HealthDiagnostics {
+    pub fn new(sink: &Sink) -> Self {
+        Self {
+            notif: SingleNotification::new(sink),
+        }
+    }
+
+    pub fn update(&mut self, batteries: &[Battery], warn_pct: u8, state: &mut State) {
+        if warn_pct == 0 {
+            return;
+        }
+        let mut changed = false;
+        for bat in batteries {
+            let Some(health) = bat.health() else {
+                continue;
+            };
+            let warned = state.health_warned.contains_key(&bat.name);
+            if health < warn_pct && !warned {
+                warn!(
+                    battery = bat.name.as_str(), health;
+                    "{} is down to {health}% of its design capacity", bat.name
+                );
+                self.notif.show_with_body(
+                    format!("{} battery health {health}%", bat.name),
+                    format!(
+                        "It now holds {health}% of what it did new, so expect shorter runtimes. \
+                         It may be time to replace it"
+                    ),
+                    Urgency::Normal,
+                );
+                state.health_warned.insert(bat.name.clone(), health);
+                changed = true;
+            } else if warned && health >= warn_pct.saturating_add(HEALTH_RECOVERED_MARGIN_PCT) {
+                info!(
+                    battery = bat.name.as_str(), health;
+                    "{} is back to {health}% health, likely a new pack", bat.name
+                );
+                state.health_warned.remove(&bat.name);
+                changed = true;
+            }
+        }
+        if changed {
+            if let Err(err) = state.save() {
+                error!("failed to save state: {err}");
+            }
+        }
+    }
+}
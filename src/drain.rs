@@ -0,0 +1,162 @@
+//! Measures how fast the battery is draining from how its level changes over time, in percentage
+//! points per hour. Some people find that easier to reason about than watts.
+
+use hashbrown::HashMap;
+use log::{info, warn};
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+use crate::notification::{SingleNotification, Sink, Style};
+use crate::system::{Battery, BatteryState};
+
+/// How far back to look. Long enough to smooth over the level only moving in whole steps on some
+/// drivers, short enough to follow changes in load.
+const WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Until we've seen this much, the rate is mostly noise.
+const MIN_SPAN: Duration = Duration::from_secs(2 * 60);
+
+#[derive(Default)]
+pub struct DrainRate {
+    /// Wall clock time, since unlike Instant it keeps counting while suspended, and the battery
+    /// keeps draining.
+    samples: VecDeque<(SystemTime, f64)>,
+}
+
+impl DrainRate {
+    pub fn update(&mut self, global: &Battery, now: SystemTime) {
+        let went_backwards = self.samples.back().is_some_and(|(t, _)| *t > now);
+        if global.state != BatteryState::Discharging || went_backwards {
+            self.samples.clear();
+            return;
+        }
+        self.samples.push_back((now, global.level_precise()));
+        while self
+            .samples
+            .front()
+            .and_then(|(t, _)| now.duration_since(*t).ok())
+            .is_some_and(|age| age > WINDOW)
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Forgets every sample, for when something happened between them which would skew the rate.
+    pub fn restart(&mut self) {
+        self.samples.clear();
+    }
+
+    /// The drain rate, if we've been discharging long enough to tell.
+    pub fn pct_per_hour(&self) -> Option<f64> {
+        let (first_time, first_level) = self.samples.front()?;
+        let (last_time, last_level) = self.samples.back()?;
+        let span = last_time.duration_since(*first_time).ok()?;
+        if span < MIN_SPAN {
+            return None;
+        }
+        Some(((first_level - last_level) * 3600.0 / span.as_secs_f64()).max(0.0))
+    }
+}
+
+/// Cumulative CPU time of each process, by pid, along with its name.
+fn cpu_times() -> HashMap<u32, (String, u64)> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return HashMap::new();
+    };
+    entries
+        .filter_map(std::result::Result::ok)
+        .filter_map(|e| {
+            let pid = e.file_name().to_str()?.parse().ok()?;
+            let stat = fs::read_to_string(e.path().join("stat")).ok()?;
+            // comm can contain anything, including spaces and parens, so split at the last paren
+            let (head, tail) = stat.rsplit_once(')')?;
+            let (_, comm) = head.split_once('(')?;
+            let fields: Vec<_> = tail.split_whitespace().collect();
+            // utime and stime, fields 14 and 15 counting from pid
+            let ticks =
+                fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+            Some((pid, (comm.to_string(), ticks)))
+        })
+        .collect()
+}
+
+/// Warns when the battery drains much faster than usual, to catch runaway processes well before
+/// low_pct.
+pub struct FastDrainMonitor {
+    max_pct_per_hour: f64,
+    top_processes: usize,
+    // CPU times as of the last cycle, while we're draining fast
+    last_cpu: Option<HashMap<u32, (String, u64)>>,
+    notif: SingleNotification,
+}
+
+impl FastDrainMonitor {
+    pub fn new(max_pct_per_hour: f64, top_processes: usize, sink: &Sink) -> Self {
+        Self {
+            max_pct_per_hour,
+            top_processes,
+            last_cpu: None,
+            notif: SingleNotification::new(sink),
+        }
+    }
+
+    pub const fn is_active(&self) -> bool {
+        self.notif.is_shown()
+    }
+
+    /// The processes which used the most CPU since the last cycle, busiest first.
+    fn top_consumers(&mut self) -> Vec<String> {
+        if self.top_processes == 0 {
+            return Vec::new();
+        }
+        let now = cpu_times();
+        let mut used: Vec<_> = match &self.last_cpu {
+            Some(last) => now
+                .iter()
+                .filter_map(|(pid, (comm, ticks))| {
+                    let (_, last_ticks) = last.get(pid)?;
+                    let delta = ticks.checked_sub(*last_ticks).filter(|d| *d > 0)?;
+                    Some((delta, comm.clone()))
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        self.last_cpu = Some(now);
+        used.sort_by_key(|(delta, _)| Reverse(*delta));
+        used.into_iter()
+            .take(self.top_processes)
+            .map(|(_, comm)| comm)
+            .collect()
+    }
+
+    pub fn update(&mut self, rate: Option<f64>, style: Style) {
+        let rate = match rate {
+            Some(rate) if self.max_pct_per_hour > 0.0 && rate >= self.max_pct_per_hour => rate,
+            _ => {
+                if self.notif.is_shown() {
+                    info!("Drain rate back to normal");
+                }
+                self.last_cpu = None;
+                self.notif.close();
+                return;
+            }
+        };
+
+        let top = self.top_consumers();
+        if !self.notif.is_shown() {
+            warn!(
+                event = "fast_drain", drain_pct_per_hour = rate;
+                "Battery draining at {rate:.1}%/h, above {:.1}%/h",
+                self.max_pct_per_hour
+            );
+        }
+        let mut body = format!("Draining at {rate:.1}%/h");
+        if !top.is_empty() {
+            body.push_str(&format!(", busiest processes: {}", top.join(", ")));
+        }
+        self.notif
+            .show_with_body("Battery draining unusually fast".to_string(), body, style);
+    }
+}
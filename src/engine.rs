@@ -0,0 +1,1769 @@
+//! One check of everything we watch, and the state carried between checks. The daemon runs this
+//! every interval, and `once` runs it a single time.
+
+use anyhow::{bail, Context, Result};
+use hashbrown::HashMap;
+use log::{error, info};
+use notify_rust::{Hint, Urgency};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::action::Action;
+use crate::clock::Clock;
+use crate::config::{self, Aggregation, Config, Tier};
+use crate::notification::{self, Category, SingleNotification, Sink};
+use crate::{
+    bluetooth, checks, command, diagnostics, drain, estimate, gamemode, heavy, history, idle, led,
+    monitors, osd, rtc, service, snooze, state, system, timing,
+};
+
+/// After the sleep command, how often to check whether we've resumed yet, and for how long.
+const RESUME_POLL: Duration = Duration::from_secs(2);
+const RESUME_WATCH: Duration = Duration::from_secs(60);
+/// How much longer than before the sleep command we need to have been suspended for to count it as
+/// a resume, since reading the two clocks isn't atomic.
+const RESUME_MIN_SUSPENDED: Duration = Duration::from_secs(1);
+/// How early before the RTC wake alarm we can wake and still count it as the alarm, since the RTC
+/// only has whole seconds and resuming takes a moment to get to our first check.
+const RTC_WAKE_SLACK: Duration = Duration::from_secs(5);
+
+/// How many intervals can pass between checks before we stop trusting the rates measured across
+/// them. Longer gaps mean we were suspended, stopped or starved, and the battery drained (or didn't)
+/// in ways the samples on either side say nothing about.
+const MAX_SAMPLE_GAP_INTERVALS: u32 = 3;
+
+/// How far the drain rate has to move, in %/h, before the low notification shows the new one.
+/// Every change to its body updates the notification, and the rate wobbles every check.
+const DRAIN_RATE_STEP: u32 = 2;
+
+/// Why we're running a sleep command, for the state file.
+struct SleepCause<'a> {
+    reason: String,
+    /// The combined battery level, if we'd read it.
+    level: Option<u8>,
+    /// What held it off last, and how long since we first wanted to run it.
+    deferred: Option<(&'a str, Duration)>,
+}
+
+/// Runs a sleep command (usually events.sleep.command), recording its `cause` in the state file.
+/// `event` is for BATTERY_NOTIFY_EVENT.
+fn run_sleep_command(
+    cfg: &Config,
+    commands: &command::Context,
+    clock: &mut dyn Clock,
+    state: &mut state::State,
+    command: &Action,
+    event: &str,
+    cause: SleepCause,
+) -> bool {
+    if command.is_empty() {
+        info!("No sleep command configured, not running anything");
+        return false;
+    }
+    let time = crate::clock::utc_time(clock.wall());
+    let inhibitors = if cfg.events.sleep.respect_inhibitors {
+        idle::sleep_inhibitors().unwrap_or_else(|err| {
+            error!("failed to list sleep inhibitors: {err}");
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    let succeeded = command.run_with_retries(
+        commands,
+        &[("BATTERY_NOTIFY_EVENT", event)],
+        cfg.events.sleep.retries,
+        Duration::from_secs(cfg.events.sleep.retry_delay_secs),
+        clock,
+    );
+    state.last_suspend = Some(state::LastSuspend {
+        time,
+        reason: cause.reason,
+        level: cause.level,
+        sleep_pct: cfg.sleep_pct,
+        succeeded,
+        deferred_by: cause.deferred.map(|(why, _)| why.to_string()),
+        deferred_secs: cause.deferred.map(|(_, time)| time.as_secs()),
+        inhibitors,
+    });
+    if let Err(err) = state.save() {
+        error!("failed to save state: {err}");
+    }
+    succeeded
+}
+
+/// Forgets the RTC wake alarm we set, if any, and clears it so it doesn't wake the machine later
+/// for no reason, like after charging has started or the user woke it themselves.
+fn disarm_rtc_wake(rtc_wake: &mut Option<SystemTime>) {
+    if rtc_wake.take().is_some() {
+        if let Err(err) = rtc::clear_wake_alarm() {
+            error!("failed to clear RTC wake alarm: {err:#}");
+        }
+    }
+}
+
+/// Explains which firmware charge thresholds are holding each battery, so that "at threshold" isn't
+/// just a bare state name.
+fn threshold_body(batteries: &[system::Battery]) -> String {
+    batteries
+        .iter()
+        .filter_map(|bat| {
+            let name = &bat.name;
+            match (bat.start_threshold, bat.end_threshold) {
+                (Some(start), Some(end)) => Some(format!(
+                    "{name} held at {end}% by charge limit, charging resumes below {start}%"
+                )),
+                (None, Some(end)) => Some(format!("{name} held at {end}% by charge limit")),
+                (Some(start), None) => Some(format!("{name} charging resumes below {start}%")),
+                (None, None) => None,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Why the automatic sleep should wait at `level`, if it should. We only wait for an active session
+/// until `deferred_since` plus defer_while_active_secs, however, and for inhibitors until we're
+/// down to inhibit_floor_pct.
+fn sleep_deferral(
+    cfg: &Config,
+    commands: &command::Context,
+    level: u8,
+    deferred_since: Instant,
+    now: Instant,
+) -> Option<String> {
+    // Inhibitors aren't subject to the deadline, since whoever took them asked for exactly this,
+    // but they can't keep us up until the battery dies
+    let floor = level <= cfg.events.sleep.inhibit_floor_pct;
+    if cfg.events.sleep.respect_inhibitors && !floor {
+        match idle::sleep_inhibitors() {
+            Ok(who) if !who.is_empty() => {
+                return Some(format!("Sleep inhibited by {}", who.join(", ")));
+            }
+            Ok(_) => {}
+            Err(err) => error!("failed to list sleep inhibitors: {err}"),
+        }
+    }
+    // Nor is inhibit_command, for the same reason
+    let inhibit_command = &cfg.events.sleep.inhibit_command;
+    if !inhibit_command.is_empty() && !floor && commands.run(inhibit_command) {
+        return Some(format!("Sleep inhibited by '{inhibit_command}'"));
+    }
+    let limit = Duration::from_secs(cfg.events.sleep.defer_while_active_secs);
+    let remaining = (deferred_since + limit).saturating_duration_since(now);
+    if remaining.is_zero() {
+        return None;
+    }
+    match idle::is_idle() {
+        Ok(true) => None,
+        Ok(false) => Some(format!(
+            "Sleeping once idle, or in {} at the latest",
+            humantime::format_duration(Duration::from_secs(remaining.as_secs()))
+        )),
+        Err(err) => {
+            error!("failed to check whether the session is idle: {err}");
+            None
+        }
+    }
+}
+
+/// The drain rate to show in the low notification, in whole %/h. This only moves from the one
+/// already shown once the new rate is DRAIN_RATE_STEP away from it.
+fn quantize_drain_rate(shown: &mut Option<u32>, rate: Option<f64>) -> Option<u32> {
+    // Rounded and clamped, so the cast can't truncate
+    let rate = rate.map(|rate| rate.round().clamp(0.0, f64::from(u32::MAX)) as u32);
+    *shown = match (*shown, rate) {
+        (Some(old), Some(new)) if old.abs_diff(new) < DRAIN_RATE_STEP => Some(old),
+        _ => rate,
+    };
+    *shown
+}
+
+/// Whether the battery is low, with hysteresis so that a reading jittering around the threshold
+/// doesn't make the notification flap open and closed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct LowLatch {
+    low: bool,
+}
+
+impl LowLatch {
+    /// Becomes low at or below `threshold`, and only stops being low once charging or more than
+    /// `margin` above it.
+    fn update(&mut self, level: u8, threshold: u8, margin: u8, charging: bool) -> bool {
+        self.low = if charging {
+            false
+        } else if level <= threshold {
+            true
+        } else {
+            self.low && level <= threshold.saturating_add(margin)
+        };
+        self.low
+    }
+}
+
+struct BatteryAlert {
+    notif: SingleNotification,
+    // "low" or "critical", if we're notifying
+    event: Option<&'static str>,
+}
+
+impl BatteryAlert {
+    fn new(sink: &Sink) -> Self {
+        Self {
+            notif: SingleNotification::new(sink),
+            event: None,
+        }
+    }
+}
+
+/// What a cycle saw, and the alerts which were live at the end of it.
+pub struct Report {
+    pub batteries: Vec<system::Battery>,
+    pub global: system::Battery,
+    pub remaining: Option<Duration>,
+    pub drain_rate: Option<f64>,
+    pub ac: system::AcState,
+    /// Empty unless Bluetooth checks are enabled.
+    pub bluetooth: Vec<bluetooth::BluetoothBattery>,
+    /// Like "low", "monitors" or "bluetooth_low:Mouse".
+    pub alarms: Vec<String>,
+}
+
+pub struct Engine {
+    // The config as loaded, and what we're using, which is that with any profile on top
+    base: Config,
+    cfg: Config,
+    // The profile in cfg, and one asked for over D-Bus, which wins over any other
+    profile: Option<String>,
+    requested_profile: Option<String>,
+    // low_pct as set over D-Bus, which wins over the config's until restarted, whatever profile
+    // or reload
+    low_pct_override: Option<u8>,
+    clock: Box<dyn Clock>,
+    // Where notifications go, for those made after startup, and the templates and so on they use
+    sink: Sink,
+    // The environment for every command we run, and which of them we're holding off on
+    commands: command::Context,
+    heavy: heavy::Heavy,
+    state_notif: SingleNotification,
+    low_notif: SingleNotification,
+    low_latch: LowLatch,
+    tier_notif: SingleNotification,
+    // Highest first, so the deepest one reached is the last which matches
+    tiers: Vec<Tier>,
+    // How many tiers have fired since we last charged
+    tiers_fired: usize,
+    test_notif: SingleNotification,
+    osd: osd::Osd,
+    critical_led: led::CriticalLed,
+    // The last state we notified about (or would have, if not for the startup grace period)
+    shown_state: Option<system::BatteryState>,
+    // The state before the one the state notification is showing, and when it was shown, so we
+    // can tell when a change is quickly undone
+    superseded_state: Option<(system::BatteryState, Instant)>,
+    last_low_event: Option<&'static str>,
+    // When we set the RTC to wake us up for when we last ran the sleep command, if we did
+    rtc_wake: Option<SystemTime>,
+    // After the sleep command, how long we'd been suspended before it and when it ran, so we can
+    // tell once we've resumed
+    awaiting_resume: Option<(Duration, Instant)>,
+    // Wall clock time of the last check, to spot gaps between samples
+    last_sample: Option<SystemTime>,
+    drain: drain::DrainRate,
+    // What quantize_drain_rate last gave for the low notification
+    shown_drain_rate: Option<u32>,
+    estimate: estimate::TimeEstimate,
+    // When we first wanted to sleep but held off, while we're still critical
+    sleep_deferred_since: Option<Instant>,
+    // The last reason we held off, if we did
+    sleep_deferred_by: Option<String>,
+    sleep_limit: command::RateLimit,
+    bat_alerts: HashMap<String, BatteryAlert>,
+    unknown_diag: diagnostics::UnknownStateDiagnostics,
+    stale_diag: diagnostics::StaleDataDiagnostics,
+    capacity_diag: diagnostics::CapacityDiagnostics,
+    health_diag: diagnostics::HealthDiagnostics,
+    checks: checks::Registry,
+    history: history::Recorder,
+    battery_info: HashMap<String, system::BatteryInfo>,
+    state: state::State,
+    grace_end: Instant,
+    silence: snooze::Silence,
+    // When the current snooze ends, in seconds since the Unix epoch, for restore_state
+    snooze_until: Option<u64>,
+    boot_id: Option<String>,
+    // What we last saved for restore_state, so we only write when something changed
+    saved_runtime: Option<state::Runtime>,
+    // Whether non-essential writes are paused, per pause_writes_below_sleep_pct
+    writes_paused: bool,
+}
+
+impl Engine {
+    /// Notifies through `sink`, and runs commands with `commands`, updating both as it goes.
+    pub fn new(
+        base: Config,
+        clock: Box<dyn Clock>,
+        sink: Sink,
+        commands: command::Context,
+    ) -> Result<Self> {
+        base.validate()?;
+        // The first cycle picks a profile, before anything could be shown
+        let cfg = base.with_profile(None)?;
+        sink.set_templates(&cfg.notifications);
+        sink.set_speech(&cfg.speech);
+        let heavy = heavy::Heavy::new(&commands);
+        heavy.set_heavy(&cfg.heavy_commands);
+        let mut tiers = cfg.tiers.clone();
+        tiers.sort_by_key(|tier| std::cmp::Reverse(tier.pct));
+        let state = state::load().unwrap_or_else(|err| {
+            error!("failed to load state, starting afresh: {err}");
+            state::State::default()
+        });
+        if let Some(last) = &state.last_suspend {
+            info!("Last suspend by battery-notify: {last:?}");
+        }
+        let mut engine = Self {
+            state_notif: SingleNotification::new(&sink)
+                .with_hint(Hint::Transient(cfg.transient_state_notifications)),
+            low_notif: SingleNotification::new(&sink)
+                .with_hint(Hint::Resident(cfg.resident_low_notifications)),
+            low_latch: LowLatch::default(),
+            tier_notif: SingleNotification::new(&sink)
+                .with_hint(Hint::Resident(cfg.resident_low_notifications)),
+            tiers,
+            tiers_fired: 0,
+            test_notif: SingleNotification::new(&sink),
+            osd: osd::Osd::new(cfg.osd_command.clone()),
+            critical_led: led::CriticalLed::new(
+                &cfg.critical_led,
+                Duration::from_millis(cfg.critical_led_blink_ms),
+            ),
+            shown_state: None,
+            superseded_state: None,
+            last_low_event: None,
+            rtc_wake: None,
+            awaiting_resume: None,
+            last_sample: None,
+            drain: drain::DrainRate::default(),
+            shown_drain_rate: None,
+            estimate: estimate::TimeEstimate::default(),
+            sleep_deferred_since: None,
+            sleep_deferred_by: None,
+            sleep_limit: command::RateLimit::new(Duration::from_secs(
+                cfg.events.sleep.min_interval_secs,
+            )),
+            bat_alerts: HashMap::new(),
+            unknown_diag: diagnostics::UnknownStateDiagnostics::new(
+                cfg.unknown_state_diag_cycles,
+                &sink,
+            ),
+            stale_diag: diagnostics::StaleDataDiagnostics::new(
+                cfg.stale_data_diag_cycles,
+                cfg.stale_data_command.clone(),
+                &heavy,
+                &sink,
+            ),
+            capacity_diag: diagnostics::CapacityDiagnostics::new(&sink),
+            health_diag: diagnostics::HealthDiagnostics::new(&sink),
+            checks: checks::Registry::new(&cfg, &commands, &heavy, &sink)?,
+            history: history::Recorder::new(
+                Duration::from_secs(cfg.history_interval_secs),
+                Duration::from_secs(cfg.history_retention_days * 24 * 60 * 60),
+            ),
+            battery_info: HashMap::new(),
+            state,
+            grace_end: clock.now() + Duration::from_secs(cfg.startup_grace_secs),
+            silence: snooze::Silence::new(&cfg.quiet_hours, &sink)?,
+            snooze_until: None,
+            boot_id: state::boot_id(),
+            saved_runtime: None,
+            writes_paused: false,
+            base,
+            cfg,
+            profile: None,
+            requested_profile: None,
+            low_pct_override: None,
+            clock,
+            sink,
+            commands,
+            heavy,
+        };
+        if engine.cfg.restore_state {
+            engine.restore();
+        }
+        Ok(engine)
+    }
+
+    /// The notifications worth keeping across restarts, by the name they're saved under.
+    fn persistent_notifications(&mut self) -> [(&'static str, &mut SingleNotification); 3] {
+        [
+            ("state", &mut self.state_notif),
+            ("low", &mut self.low_notif),
+            ("tier", &mut self.tier_notif),
+        ]
+    }
+
+    /// Picks up where the last run left off, if it was during this boot.
+    fn restore(&mut self) {
+        let Some(runtime) = self.state.runtime.clone() else {
+            return;
+        };
+        if self.boot_id.is_none() || self.boot_id.as_ref() != Some(&runtime.boot_id) {
+            info!("Saved state is from a previous boot, not restoring it");
+            return;
+        }
+        info!("Restoring state from the last run: {runtime:?}");
+        self.shown_state = runtime.shown_state;
+        self.low_latch.low = runtime.low;
+        self.last_low_event = match runtime.low_event.as_deref() {
+            Some("low") => Some("low"),
+            Some("critical") => Some("critical"),
+            _ => None,
+        };
+        self.tiers_fired = runtime.tiers_fired.min(self.tiers.len());
+        let wall = self.clock.wall();
+        if let Some(left) = runtime.snooze_until.and_then(|until| {
+            (UNIX_EPOCH + Duration::from_secs(until))
+                .duration_since(wall)
+                .ok()
+        }) {
+            self.silence.snooze(Some(left), self.clock.now());
+            self.snooze_until = runtime.snooze_until;
+        }
+        for (name, notif) in self.persistent_notifications() {
+            if let Some(id) = runtime.notifications.get(name) {
+                notif.adopt(*id);
+            }
+        }
+        self.saved_runtime = Some(runtime);
+    }
+
+    /// Saves what restore() needs, if it changed since last time.
+    fn persist(&mut self) {
+        if !self.cfg.restore_state || self.writes_paused {
+            return;
+        }
+        let runtime = state::Runtime {
+            boot_id: self.boot_id.clone().unwrap_or_default(),
+            shown_state: self.shown_state,
+            low: self.low_latch.low,
+            low_event: self.last_low_event.map(str::to_string),
+            tiers_fired: self.tiers_fired,
+            snooze_until: self.silence.snooze_end().and(self.snooze_until),
+            notifications: self
+                .persistent_notifications()
+                .into_iter()
+                .filter_map(|(name, notif)| Some((name.to_string(), notif.id()?)))
+                .collect(),
+        };
+        if self.saved_runtime.as_ref() == Some(&runtime) {
+            return;
+        }
+        self.state.runtime = Some(runtime.clone());
+        if let Err(err) = self.state.save() {
+            error!("failed to save state: {err}");
+        }
+        self.saved_runtime = Some(runtime);
+    }
+
+    /// Handles a request from D-Bus. Pausing is up to the caller, since it stops whole cycles.
+    pub fn handle(&mut self, req: service::Request, now: Instant) {
+        self.checks.handle(&req, self.clock.wall());
+        match req {
+            // We're about to check anyway, the checks have had ChargeFullBy, and the rest are up to
+            // the caller
+            service::Request::Refresh
+            | service::Request::Pause(_)
+            | service::Request::Resume
+            | service::Request::Reload
+            | service::Request::ChargeFullBy(_) => {}
+            service::Request::Suspend => {
+                // Treat this like an automatic sleep, so we don't immediately do it again
+                self.sleep_limit.allow(now);
+                run_sleep_command(
+                    &self.cfg,
+                    &self.commands,
+                    &mut *self.clock,
+                    &mut self.state,
+                    &self.cfg.events.sleep.command,
+                    "sleep",
+                    SleepCause {
+                        reason: "requested over D-Bus".to_string(),
+                        level: None,
+                        deferred: None,
+                    },
+                );
+            }
+            service::Request::TestNotify(category) => self.test_notif.show(
+                format!(
+                    "Test {} notification",
+                    serde_plain::to_string(&category).unwrap()
+                ),
+                self.cfg.notifications.style(category),
+            ),
+            service::Request::SetProfile(name) => {
+                if let Some(name) = name
+                    .as_ref()
+                    .filter(|n| !self.base.profiles.contains_key(*n))
+                {
+                    error!("Asked to use profile {name}, but there's no such profile");
+                    return;
+                }
+                self.requested_profile = name;
+            }
+            service::Request::SetLowPct(pct) => {
+                // The service checks this too, but the config may have changed since
+                if pct != 0 && pct <= self.cfg.sleep_pct {
+                    error!(
+                        "Asked to use low_pct {pct}%, but that's not above sleep_pct ({}%)",
+                        self.cfg.sleep_pct
+                    );
+                    return;
+                }
+                info!(
+                    "Using low_pct {pct}% until restarted, instead of {}%",
+                    self.cfg.low_pct
+                );
+                self.low_pct_override = Some(pct);
+                self.cfg.low_pct = pct;
+            }
+            service::Request::Snooze(duration) => {
+                self.snooze_until = duration.map(|duration| {
+                    let until = self.clock.wall() + duration;
+                    until
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs()
+                });
+                self.silence.snooze(duration, now);
+                self.silence.update(now, self.clock.wall());
+            }
+        }
+    }
+
+    /// Drops the engine without closing the notifications it's showing, for one-off runs whose
+    /// notifications should stay up after we exit. Everything else is cleaned up as usual.
+    pub fn detach(self) {
+        self.sink.leave_open();
+        drop(self);
+    }
+
+    /// Whether notifications are snoozed or in quiet hours, for status.
+    pub fn silence(&self) -> String {
+        self.silence.status()
+    }
+
+    /// The low_pct in use, which may have been changed over D-Bus.
+    pub const fn low_pct(&self) -> u8 {
+        self.cfg.low_pct
+    }
+
+    pub const fn config(&self) -> &Config {
+        &self.cfg
+    }
+
+    /// How many times a heavy command was deferred or skipped since we started.
+    pub fn held_heavy_commands(&self) -> u64 {
+        self.heavy.held()
+    }
+
+    /// The profile in use, if any.
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// Takes a newly loaded config, keeping the same profile (if it's still there) until the next
+    /// cycle picks again.
+    pub fn reload(&mut self, base: Config) -> Result<()> {
+        base.validate()?;
+        let exists = |name: &Option<String>| {
+            name.as_ref()
+                .map_or(true, |n| base.profiles.contains_key(n))
+        };
+        if !exists(&self.requested_profile) {
+            self.requested_profile = None;
+        }
+        if !exists(&self.profile) {
+            self.profile = None;
+        }
+        let cfg = base.with_profile(self.profile.as_deref())?;
+        self.base = base;
+        self.switch(cfg)
+    }
+
+    /// Switches to the profile which applies now, if that's changed.
+    fn select_profile(&mut self, ac_online: Option<bool>) {
+        if self.base.profiles.is_empty() {
+            return;
+        }
+        let now = config::Conditions {
+            ac_online,
+            monitors: self
+                .base
+                .profiles_need_monitors()
+                .then(|| monitors::get_nr_connected(self.cfg.monitors_backend).ok())
+                .flatten(),
+            hostname: system::hostname(),
+        };
+        let wanted = self
+            .requested_profile
+            .clone()
+            .or_else(|| self.base.pick_profile(&now).map(str::to_string));
+        if wanted == self.profile {
+            return;
+        }
+        let res = self
+            .base
+            .with_profile(wanted.as_deref())
+            .and_then(|cfg| self.switch(cfg));
+        match (res, &wanted) {
+            (Err(err), _) => error!("failed to switch profiles, keeping the old one: {err:#}"),
+            (Ok(()), Some(name)) => info!(profile = name.as_str(); "Using profile {name}"),
+            (Ok(()), None) => info!(profile = ""; "No longer using a profile"),
+        }
+        self.profile = wanted;
+    }
+
+    /// Switches to a new config, keeping whatever is shown and all other state. Parts which only
+    /// take their settings when created are only recreated if those settings changed, since that
+    /// closes their notifications and forgets what they've seen.
+    fn switch(&mut self, mut cfg: Config) -> Result<()> {
+        cfg.validate()?;
+        match self.low_pct_override {
+            Some(pct) if pct != 0 && pct <= cfg.sleep_pct => {
+                error!(
+                    "Dropping low_pct {pct}% set over D-Bus, since it's not above the new sleep_pct \
+                     ({}%)",
+                    cfg.sleep_pct
+                );
+                self.low_pct_override = None;
+            }
+            Some(pct) => cfg.low_pct = pct,
+            None => {}
+        }
+        let old = std::mem::replace(&mut self.cfg, cfg);
+        let cfg = &self.cfg;
+        self.sink.set_templates(&cfg.notifications);
+        self.sink.set_speech(&cfg.speech);
+        self.heavy.set_heavy(&cfg.heavy_commands);
+
+        self.state_notif
+            .set_hints(vec![Hint::Transient(cfg.transient_state_notifications)]);
+        self.low_notif
+            .set_hints(vec![Hint::Resident(cfg.resident_low_notifications)]);
+        self.tier_notif
+            .set_hints(vec![Hint::Resident(cfg.resident_low_notifications)]);
+        if cfg.tiers != old.tiers {
+            self.tiers.clone_from(&cfg.tiers);
+            self.tiers.sort_by_key(|tier| std::cmp::Reverse(tier.pct));
+            // We can't tell which of the new tiers were already passed, so the deepest one reached
+            // is shown again, replacing the old tier notification
+            self.tiers_fired = 0;
+        }
+        if cfg.osd_command != old.osd_command {
+            self.osd = osd::Osd::new(cfg.osd_command.clone());
+        }
+        if cfg.critical_led != old.critical_led
+            || cfg.critical_led_blink_ms != old.critical_led_blink_ms
+        {
+            // The old one goes back to how it was, and the new one starts at the next check
+            self.critical_led = led::CriticalLed::new(
+                &cfg.critical_led,
+                Duration::from_millis(cfg.critical_led_blink_ms),
+            );
+        }
+        if cfg.events.sleep.min_interval_secs != old.events.sleep.min_interval_secs {
+            self.sleep_limit =
+                command::RateLimit::new(Duration::from_secs(cfg.events.sleep.min_interval_secs));
+        }
+        if cfg.unknown_state_diag_cycles != old.unknown_state_diag_cycles {
+            self.unknown_diag = diagnostics::UnknownStateDiagnostics::new(
+                cfg.unknown_state_diag_cycles,
+                &self.sink,
+            );
+        }
+        if cfg.stale_data_diag_cycles != old.stale_data_diag_cycles
+            || cfg.stale_data_command != old.stale_data_command
+        {
+            self.stale_diag = diagnostics::StaleDataDiagnostics::new(
+                cfg.stale_data_diag_cycles,
+                cfg.stale_data_command.clone(),
+                &self.heavy,
+                &self.sink,
+            );
+        }
+        if cfg.quiet_hours != old.quiet_hours {
+            // Any snooze carries on regardless
+            self.silence.set_quiet_hours(&cfg.quiet_hours)?;
+        }
+        if cfg.history_interval_secs != old.history_interval_secs
+            || cfg.history_retention_days != old.history_retention_days
+        {
+            self.history = history::Recorder::new(
+                Duration::from_secs(cfg.history_interval_secs),
+                Duration::from_secs(cfg.history_retention_days * 24 * 60 * 60),
+            );
+        }
+        self.checks.reload(cfg, &old)?;
+        // Batteries without thresholds are skipped entirely, so close any which lost them here
+        self.bat_alerts
+            .retain(|name, _| cfg.batteries.contains_key(name));
+        Ok(())
+    }
+
+    /// When to check again sooner than the interval, if there's reason to.
+    pub fn early_check(&self) -> Option<Instant> {
+        // The sleep command usually returns before we're actually suspended, and the interval
+        // doesn't count time spent suspended, so check more often until we've seen a resume
+        let now = self.clock.now();
+        let resume = self
+            .awaiting_resume
+            .filter(|(_, ran)| now.duration_since(*ran) < RESUME_WATCH)
+            .map(|_| now + RESUME_POLL);
+        // Catch up on anything held back as soon as a snooze is over
+        resume.into_iter().chain(self.silence.snooze_end()).min()
+    }
+
+    /// Checks everything once, notifying and running commands as needed, and returns what it saw.
+    pub fn cycle(&mut self, start: Instant, cycle: &mut timing::CycleTimer) -> Result<Report> {
+        // Slow drivers show up here first, so keep track of how long reading everything takes
+        let collect_start = Instant::now();
+        let batteries = system::get_batteries().context("failed to get list of batteries")?;
+
+        for bat in &batteries {
+            let info = system::get_battery_info(&bat.name);
+            if self.battery_info.get(&bat.name) != Some(&info) {
+                let field: fn(&Option<String>) -> &str = |val| val.as_deref().unwrap_or("unknown");
+                info!(
+                    battery = bat.name.as_str(),
+                    technology = field(&info.technology),
+                    manufacturer = field(&info.manufacturer),
+                    model = field(&info.model_name),
+                    serial = field(&info.serial_number);
+                    "Battery pack: {info:?}"
+                );
+                self.battery_info.insert(bat.name.clone(), info);
+            }
+            info!(
+                battery = bat.name.as_str(), level = bat.level(), state:? = bat.state;
+                "Battery status: {bat:?}"
+            );
+        }
+
+        let ac_online = system::get_ac_online().unwrap_or_else(|err| {
+            error!("failed to get AC state: {err}");
+            None
+        });
+        let collect_us = timing::duration_us(collect_start.elapsed());
+        self.select_profile(ac_online);
+        let report = self.evaluate(start, cycle, batteries, ac_online, collect_us)?;
+        self.persist();
+        Ok(report)
+    }
+
+    /// Everything after reading the power supplies, which tests can feed their own.
+    fn evaluate(
+        &mut self,
+        start: Instant,
+        cycle: &mut timing::CycleTimer,
+        batteries: Vec<system::Battery>,
+        ac_online: Option<bool>,
+        collect_us: u64,
+    ) -> Result<Report> {
+        // GameMode and X11 live in the user's session, which we can't see in system mode
+        let gaming = self.cfg.gamemode_defer_notifications
+            && !self.cfg.system_mode
+            && gamemode::is_active().unwrap_or_else(|err| {
+                error!("{err}");
+                false
+            });
+        self.silence.set_gaming(gaming);
+        let silenced = self.silence.update(start, self.clock.wall());
+
+        if batteries.is_empty() {
+            // Desktops can still have a UPS, remote machines or peripherals to watch
+            if !self.checks.standalone(&self.cfg) {
+                bail!("no batteries detected");
+            }
+            return Ok(self.evaluate_checks(start, cycle, ac_online));
+        }
+        let Self {
+            base: _,
+            cfg,
+            profile: _,
+            requested_profile: _,
+            low_pct_override: _,
+            clock,
+            sink,
+            commands,
+            heavy,
+            state_notif,
+            low_notif,
+            low_latch,
+            tier_notif,
+            tiers,
+            tiers_fired,
+            test_notif: _,
+            osd,
+            critical_led,
+            shown_state,
+            superseded_state,
+            last_low_event,
+            rtc_wake,
+            awaiting_resume,
+            last_sample,
+            drain,
+            shown_drain_rate,
+            estimate,
+            sleep_deferred_since,
+            sleep_deferred_by,
+            sleep_limit,
+            bat_alerts,
+            unknown_diag,
+            stale_diag,
+            capacity_diag,
+            health_diag,
+            checks,
+            history,
+            battery_info: _,
+            state,
+            grace_end,
+            silence: _,
+            snooze_until: _,
+            boot_id: _,
+            saved_runtime: _,
+            writes_paused,
+        } = self;
+        let cfg = &*cfg;
+
+        let global = system::get_global_battery(&batteries);
+        sink.set_battery(global.level(), global.state);
+        heavy.set_conserving(
+            global.level() <= cfg.sleep_pct
+                && global.state == system::BatteryState::Discharging
+                && ac_online != Some(true),
+            &mut **clock,
+        );
+        let pause_writes = cfg.pause_writes_below_sleep_pct && heavy.conserving();
+        if pause_writes != *writes_paused {
+            if pause_writes {
+                info!("At or below sleep_pct, pausing history and state writes");
+            } else {
+                info!("Resuming history and state writes");
+            }
+            *writes_paused = pause_writes;
+        }
+
+        unknown_diag.update(&batteries, ac_online);
+        stale_diag.update(&batteries);
+        capacity_diag.update(&batteries);
+        health_diag.update(&batteries, cfg.health_warn_pct, state);
+
+        let wall = clock.wall();
+        let max_gap = Duration::from_secs(cfg.interval_secs) * MAX_SAMPLE_GAP_INTERVALS;
+        if let Some(gap) = last_sample
+            .and_then(|last| wall.duration_since(last).ok())
+            .filter(|gap| *gap > max_gap)
+        {
+            info!(
+                gap_secs = gap.as_secs();
+                "{} since the last check, restarting drain rate and time estimates",
+                humantime::format_duration(Duration::from_secs(gap.as_secs()))
+            );
+            drain.restart();
+            estimate.restart();
+        }
+        *last_sample = Some(wall);
+        drain.update(&global, wall);
+        if !*writes_paused {
+            history.record(start, wall, &global);
+        }
+        let drain_rate = drain.pct_per_hour();
+        let remaining = estimate.update(&global, drain_rate);
+        info!(
+            battery = "global", level = global.level(), state:? = global.state,
+            drain_pct_per_hour:? = drain_rate, remaining_secs:? = remaining.map(|r| r.as_secs()),
+            collect_us = collect_us;
+            "Global status: {global:?}"
+        );
+        // Only templates use these, so don't bother building them otherwise
+        if sink.templates_enabled() {
+            sink.set_context(vec![
+                ("level", global.level().to_string()),
+                (
+                    "state",
+                    system::battery_state_to_lowercase_name(global.state).to_string(),
+                ),
+                (
+                    "battery",
+                    batteries
+                        .iter()
+                        .map(|b| b.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ),
+                (
+                    "time_remaining",
+                    remaining.map_or("unknown".to_string(), |r| {
+                        humantime::format_duration(r).to_string()
+                    }),
+                ),
+                (
+                    "drain_rate",
+                    drain_rate.map_or("unknown".to_string(), |r| format!("{r:.1}%/h")),
+                ),
+            ]);
+        }
+        commands.set(vec![
+            ("BATTERY_NOTIFY_LEVEL", global.level().to_string()),
+            (
+                "BATTERY_NOTIFY_STATE",
+                system::battery_state_to_lowercase_name(global.state).to_string(),
+            ),
+            (
+                "BATTERY_NOTIFY_BATTERY",
+                batteries
+                    .iter()
+                    .min_by_key(|bat| bat.level())
+                    .map(|bat| bat.name.clone())
+                    .unwrap_or_default(),
+            ),
+            (
+                "BATTERY_NOTIFY_TIME_REMAINING",
+                remaining
+                    .map(|r| r.as_secs().to_string())
+                    .unwrap_or_default(),
+            ),
+            (
+                "BATTERY_NOTIFY_AC",
+                match ac_online {
+                    Some(true) => "1",
+                    Some(false) => "0",
+                    None => "",
+                }
+                .to_string(),
+            ),
+        ]);
+        let remaining_text = remaining
+            .filter(|_| global.state == system::BatteryState::Discharging)
+            .map(|r| format!(" (about {} remaining)", humantime::format_duration(r)))
+            .unwrap_or_default();
+        cycle.mark("batteries");
+
+        if silenced && *shown_state != Some(global.state) {
+            // Leave the old notification around, so the state at the end is shown rather than lost
+            info!("Notifications silenced, deferring the state notification");
+        } else if *shown_state != Some(global.state) {
+            let window = Duration::from_secs(cfg.close_reverted_state_secs);
+            let reverted = superseded_state.is_some_and(|(prev, shown)| {
+                prev == global.state && start.duration_since(shown) <= window
+            });
+            if reverted && state_notif.is_shown() {
+                // Nothing changed as far as the user is concerned, so just get rid of the old one
+                info!(
+                    "Battery back to {} within {window:?}, closing the state notification",
+                    system::battery_state_to_lowercase_name(global.state)
+                );
+                state_notif.close();
+                *superseded_state = None;
+            } else if start < *grace_end {
+                // Still track it, so we don't notify about the state we started in once it's over
+                info!("In startup grace period, not notifying about battery state");
+            } else {
+                let body = if global.state == system::BatteryState::AtThreshold {
+                    threshold_body(&batteries)
+                } else {
+                    String::new()
+                };
+                state_notif.show_with_body(
+                    format!(
+                        "Battery now {}",
+                        system::battery_state_to_lowercase_name(global.state)
+                    ),
+                    body,
+                    cfg.notifications.style(Category::State),
+                );
+                osd.show(global.level());
+                *superseded_state = shown_state.map(|prev| (prev, start));
+            }
+            *shown_state = Some(global.state);
+        }
+
+        cycle.mark("state");
+
+        let level = global.level();
+        let (low_level, mut low_body) = match system::get_active_battery(&batteries) {
+            Some((active, reserve)) if cfg.aggregation == Aggregation::Active => {
+                info!("Active battery: {} ({reserve}% in reserve)", active.name);
+                (
+                    active.level(),
+                    format!(
+                        "{} at {}% ({reserve}% in reserve)",
+                        active.name,
+                        active.level()
+                    ),
+                )
+            }
+            _ => (level, format!("{level}%")),
+        };
+        if let Some(rate) = quantize_drain_rate(shown_drain_rate, drain_rate) {
+            low_body.push_str(&format!(", draining {rate}%/h"));
+        }
+        low_body.push_str(&remaining_text);
+
+        // sleep_pct still applies with low_pct disabled
+        let is_low = low_latch.update(
+            level.min(low_level),
+            cfg.low_pct.max(cfg.sleep_pct),
+            cfg.low_hysteresis_pct,
+            global.state == system::BatteryState::Charging,
+        );
+        let low_event = if !is_low {
+            low_notif.close();
+            disarm_rtc_wake(rtc_wake);
+            *awaiting_resume = None;
+            *sleep_deferred_since = None;
+            *sleep_deferred_by = None;
+            None
+        } else if level <= cfg.sleep_pct {
+            let deferred_since = *sleep_deferred_since.get_or_insert(start);
+            let deferral = sleep_deferral(cfg, commands, level, deferred_since, start);
+            low_notif.show_with_body(
+                "Battery critical".to_string(),
+                match &deferral {
+                    Some(why) => format!("{level}%{remaining_text}. {why}"),
+                    None => format!("{level}%{remaining_text}"),
+                },
+                cfg.notifications.style(Category::Critical),
+            );
+            // Just in case we've gone loco, don't do this too often
+            let allowed = deferral.is_none() && sleep_limit.allow(start);
+            let resumed = awaiting_resume.is_some_and(|(before, _)| {
+                rtc::suspended_time().is_some_and(|now| now > before + RESUME_MIN_SUSPENDED)
+            });
+            let charging =
+                ac_online == Some(true) || global.state == system::BatteryState::Charging;
+            // Only the alarm going off means we drained while asleep, not being woken before it
+            let rtc_woke = rtc_wake.is_some_and(|at| clock.wall() + RTC_WAKE_SLACK >= at);
+            if resumed && rtc_wake.is_some() && !rtc_woke {
+                info!("Woken before the RTC wake alarm, clearing it");
+                disarm_rtc_wake(rtc_wake);
+            }
+            if let Some(why) = deferral {
+                info!("Deferring sleep command at {level}%: {why}");
+                *sleep_deferred_by = Some(why);
+            } else if resumed
+                && !charging
+                && cfg.events.sleep.escalate_after_resume
+                && !cfg.events.sleep.after_wake_command.is_empty()
+            {
+                // Suspending again would likely just drain the rest of the battery while asleep,
+                // so don't wait for min_interval_secs to do that
+                info!(
+                    event = "sleep_escalate", level = level;
+                    "Still at {level}% with no charger after resuming, running after_wake_command"
+                );
+                disarm_rtc_wake(rtc_wake);
+                *awaiting_resume = None;
+                run_sleep_command(
+                    cfg,
+                    commands,
+                    &mut **clock,
+                    state,
+                    &cfg.events.sleep.after_wake_command,
+                    "after_wake",
+                    SleepCause {
+                        reason: format!("battery still at {level}% with no charger after resuming"),
+                        level: Some(level),
+                        deferred: None,
+                    },
+                );
+            } else if allowed && rtc_woke && !cfg.events.sleep.after_wake_command.is_empty() {
+                // The RTC woke us up and we're still critical, so we probably kept draining while
+                // asleep
+                info!(
+                    event = "sleep_after_wake", level = level;
+                    "Still at {level}% after waking, running after_wake_command"
+                );
+                disarm_rtc_wake(rtc_wake);
+                *awaiting_resume = None;
+                run_sleep_command(
+                    cfg,
+                    commands,
+                    &mut **clock,
+                    state,
+                    &cfg.events.sleep.after_wake_command,
+                    "after_wake",
+                    SleepCause {
+                        reason: format!("battery still at {level}% after waking from suspend"),
+                        level: Some(level),
+                        deferred: None,
+                    },
+                );
+            } else if allowed {
+                info!(event = "sleep", level = level; "Running sleep command at {level}%");
+                if cfg.events.sleep.rtc_wake_secs != 0 {
+                    let after = Duration::from_secs(cfg.events.sleep.rtc_wake_secs);
+                    match rtc::set_wake_alarm(after) {
+                        Ok(()) => {
+                            info!(
+                                "Set RTC to wake up in {}",
+                                humantime::format_duration(after)
+                            );
+                            *rtc_wake = Some(clock.wall() + after);
+                        }
+                        Err(err) => error!("failed to set RTC wake alarm: {err:#}"),
+                    }
+                }
+                let suspended_before = rtc::suspended_time();
+                let succeeded = run_sleep_command(
+                    cfg,
+                    commands,
+                    &mut **clock,
+                    state,
+                    &cfg.events.sleep.command,
+                    "sleep",
+                    SleepCause {
+                        reason: format!("battery at {level}%, at or below sleep_pct"),
+                        level: Some(level),
+                        deferred: sleep_deferred_by
+                            .as_deref()
+                            .map(|why| (why, start.saturating_duration_since(deferred_since))),
+                    },
+                );
+                *awaiting_resume = suspended_before
+                    .filter(|_| succeeded)
+                    .map(|before| (before, clock.now()));
+            }
+            Some("critical")
+        } else {
+            disarm_rtc_wake(rtc_wake);
+            *awaiting_resume = None;
+            *sleep_deferred_since = None;
+            *sleep_deferred_by = None;
+            low_notif.show_with_body(
+                "Battery low".to_string(),
+                low_body,
+                cfg.notifications.style(Category::Low),
+            );
+            Some("low")
+        };
+        let charging = ac_online == Some(true) || global.state == system::BatteryState::Charging;
+        critical_led.update(low_event == Some("critical") && !charging);
+        if low_event != *last_low_event {
+            if let Some(event) = low_event {
+                info!(event = event, level = level; "Battery {event} at {level}%");
+            }
+            *last_low_event = low_event;
+        }
+
+        cycle.mark("low");
+
+        if global.state == system::BatteryState::Charging {
+            tier_notif.close();
+            *tiers_fired = 0;
+        } else if let Some(reached) = tiers.iter().rposition(|tier| level <= tier.pct) {
+            if reached >= *tiers_fired {
+                let tier = &tiers[reached];
+                info!(
+                    event = "tier", level = level, tier_pct = tier.pct;
+                    "Battery at {level}%, reached the {}% tier", tier.pct
+                );
+                let mut style = cfg.notifications.style(Category::Low);
+                style.urgency = tier.urgency.resolve(Urgency::Critical);
+                if tier.timeout_secs != 0 {
+                    style.timeout = notification::timeout_from_secs(tier.timeout_secs);
+                }
+                tier_notif.show_with_body(
+                    format!("Battery below {}%", tier.pct),
+                    format!("{level}%{remaining_text}"),
+                    style,
+                );
+                if !tier.command.is_empty() {
+                    heavy.run_with_retries(
+                        "tiers",
+                        &tier.command,
+                        &[("BATTERY_NOTIFY_EVENT", "tier")],
+                        tier.retries,
+                        Duration::from_secs(tier.retry_delay_secs),
+                        &mut **clock,
+                    );
+                }
+                *tiers_fired = reached + 1;
+            }
+        }
+
+        for bat in &batteries {
+            let Some(thresholds) = cfg.batteries.get(&bat.name) else {
+                continue;
+            };
+            let alert = bat_alerts
+                .entry(bat.name.clone())
+                .or_insert_with(|| BatteryAlert::new(sink));
+            let bat_level = bat.level();
+            alert.notif.set_level(bat_level, bat.state);
+            if sink.templates_enabled() {
+                alert.notif.set_vars(vec![
+                    ("battery", bat.name.clone()),
+                    ("level", bat_level.to_string()),
+                ]);
+            }
+            // Sleeping is still left to the combined level, since the others may have plenty left
+            let event = if bat.state == system::BatteryState::Charging {
+                None
+            } else if bat_level <= thresholds.critical_pct {
+                Some(("critical", Category::Critical))
+            } else if bat_level <= thresholds.low_pct {
+                Some(("low", Category::Low))
+            } else {
+                None
+            };
+            match event {
+                Some((name, category)) => alert.notif.show_with_body(
+                    format!("{} {name}", bat.name),
+                    format!("{bat_level}%"),
+                    cfg.notifications.style(category),
+                ),
+                None => alert.notif.close(),
+            }
+            let event = event.map(|(name, _)| name);
+            if event != alert.event {
+                if let Some(event) = event {
+                    info!(
+                        event = event, battery = bat.name.as_str(), level = bat_level;
+                        "{} {event} at {bat_level}%", bat.name
+                    );
+                }
+                alert.event = event;
+            }
+        }
+        // Batteries can be hot swapped, so close notifications for ones which have gone
+        bat_alerts.retain(|name, _| batteries.iter().any(|b| b.name == *name));
+        cycle.mark("thresholds");
+
+        checks.run(
+            &mut checks::Inputs {
+                cfg,
+                start,
+                wall: clock.wall(),
+                clock: &mut **clock,
+                batteries: &batteries,
+                global: &global,
+                ac_online,
+                drain_rate,
+            },
+            cycle,
+        );
+
+        let alarms: Vec<String> = [
+            (low_notif.is_shown(), last_low_event.unwrap_or_default()),
+            (tier_notif.is_shown(), "tier"),
+        ]
+        .into_iter()
+        .filter(|(active, _)| *active)
+        .map(|(_, name)| name.to_string())
+        .collect();
+        let mut bat_alarms: Vec<_> = bat_alerts
+            .iter()
+            .filter_map(|(name, alert)| Some(format!("{}:{name}", alert.event?)))
+            .collect();
+        bat_alarms.sort();
+        let mut report = Report {
+            batteries,
+            global,
+            remaining,
+            drain_rate,
+            ac: system::AcState::from(ac_online),
+            bluetooth: Vec::new(),
+            alarms,
+        };
+        report.alarms.extend(bat_alarms);
+        checks.report(&mut report);
+        Ok(report)
+    }
+
+    /// Like evaluate(), with no batteries of our own, so only the checks have anything to do.
+    fn evaluate_checks(
+        &mut self,
+        start: Instant,
+        cycle: &mut timing::CycleTimer,
+        ac_online: Option<bool>,
+    ) -> Report {
+        let global = system::get_global_battery(&[]);
+        self.checks.run(
+            &mut checks::Inputs {
+                cfg: &self.cfg,
+                start,
+                wall: self.clock.wall(),
+                clock: &mut *self.clock,
+                batteries: &[],
+                global: &global,
+                ac_online,
+                drain_rate: None,
+            },
+            cycle,
+        );
+        let mut report = Report {
+            batteries: Vec::new(),
+            global,
+            remaining: None,
+            drain_rate: None,
+            ac: system::AcState::from(ac_online),
+            bluetooth: Vec::new(),
+            alarms: Vec::new(),
+        };
+        self.checks.report(&mut report);
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify_rust::Timeout;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    use crate::clock::FakeClock;
+    use crate::config::BatteryThresholds;
+    use crate::system::{Battery, BatteryState};
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum Event {
+        Show(String),
+        // Shown again with new text, replacing what the same notification showed before
+        Update(String),
+        Close(String),
+    }
+
+    /// Records everything shown and closed, instead of talking to a notification server.
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<Event>,
+        // What's currently up, by key, so closes can be told apart
+        open: BTreeMap<u64, String>,
+    }
+
+    impl notification::Notifier for Arc<Mutex<Recorder>> {
+        fn show(
+            &self,
+            key: u64,
+            summary: &str,
+            _body: &str,
+            _urgency: Urgency,
+            _timeout: Timeout,
+            _hints: &[Hint],
+        ) {
+            let mut rec = self.lock().unwrap();
+            let event = if rec.open.insert(key, summary.to_string()).is_some() {
+                Event::Update(summary.to_string())
+            } else {
+                Event::Show(summary.to_string())
+            };
+            rec.events.push(event);
+        }
+
+        fn close(&self, key: u64) {
+            let mut rec = self.lock().unwrap();
+            if let Some(summary) = rec.open.remove(&key) {
+                rec.events.push(Event::Close(summary));
+            }
+        }
+    }
+
+    /// Drives an engine with made up batteries, a cycle at a time.
+    struct Harness {
+        engine: Engine,
+        recorder: Arc<Mutex<Recorder>>,
+        clock: FakeClock,
+    }
+
+    impl Harness {
+        fn new(cfg: Config) -> Self {
+            let recorder = Arc::new(Mutex::new(Recorder::default()));
+            let clock = FakeClock::new();
+            let sink = Sink::new(recorder.clone());
+            Self {
+                engine: Engine::new(
+                    cfg,
+                    Box::new(clock.clone()),
+                    sink,
+                    command::Context::default(),
+                )
+                .unwrap(),
+                recorder,
+                clock,
+            }
+        }
+
+        /// Runs a cycle `secs` after the last one, returning what was shown or closed in it.
+        fn step(&mut self, secs: u64, batteries: Vec<Battery>) -> Vec<Event> {
+            self.clock.advance(Duration::from_secs(secs));
+            let now = self.clock.now();
+            let mut cycle = timing::CycleTimer::new(now);
+            self.engine
+                .evaluate(now, &mut cycle, batteries, None, 0)
+                .unwrap();
+            std::mem::take(&mut self.recorder.lock().unwrap().events)
+        }
+
+        /// Like step(), for a single battery.
+        fn level(&mut self, state: BatteryState, level: u8) -> Vec<Event> {
+            self.step(30, vec![Battery::new("BAT0", state, level)])
+        }
+
+        fn open(&self) -> Vec<String> {
+            self.recorder
+                .lock()
+                .unwrap()
+                .open
+                .values()
+                .cloned()
+                .collect()
+        }
+    }
+
+    /// Nothing outside of the notifications we're checking: no monitors, Bluetooth, or sleeping.
+    fn test_config() -> Config {
+        let mut cfg = Config {
+            warn_on_mons_with_no_ac: 0,
+            bluetooth_low_pct: 0,
+            unknown_state_diag_cycles: 0,
+            stale_data_diag_cycles: 0,
+            // Tests shouldn't touch the real state file
+            restore_state: false,
+            ..Default::default()
+        };
+        cfg.events.sleep.command = Action::default();
+        cfg.events.sleep.after_wake_command = Action::default();
+        cfg.events.sleep.respect_inhibitors = false;
+        cfg
+    }
+
+    fn show(summary: &str) -> Event {
+        Event::Show(summary.to_string())
+    }
+
+    fn update(summary: &str) -> Event {
+        Event::Update(summary.to_string())
+    }
+
+    fn close(summary: &str) -> Event {
+        Event::Close(summary.to_string())
+    }
+
+    #[test]
+    fn low_shown_at_threshold_and_held_by_hysteresis() {
+        let mut h = Harness::new(test_config());
+        assert_eq!(
+            h.level(BatteryState::Discharging, 41),
+            [show("Battery now discharging")]
+        );
+        assert_eq!(
+            h.level(BatteryState::Discharging, 40),
+            [show("Battery low")]
+        );
+        // Jitter within low_hysteresis_pct keeps it up, rather than flapping
+        for level in [41, 43, 40, 42] {
+            assert_eq!(
+                h.level(BatteryState::Discharging, level),
+                [update("Battery low")],
+                "at {level}%"
+            );
+        }
+        assert_eq!(
+            h.level(BatteryState::Discharging, 44),
+            [close("Battery low")]
+        );
+        assert_eq!(h.level(BatteryState::Discharging, 42), []);
+    }
+
+    #[test]
+    fn low_closed_on_charging() {
+        let mut h = Harness::new(test_config());
+        h.level(BatteryState::Discharging, 30);
+        assert!(h.open().contains(&"Battery low".to_string()));
+        let events = h.level(BatteryState::Charging, 30);
+        assert!(events.contains(&close("Battery low")), "{events:?}");
+        assert!(
+            events.contains(&update("Battery now charging")),
+            "{events:?}"
+        );
+        assert_eq!(h.open(), ["Battery now charging"]);
+    }
+
+    #[test]
+    fn critical_replaces_low() {
+        let mut h = Harness::new(test_config());
+        h.level(BatteryState::Discharging, 20);
+        assert_eq!(
+            h.level(BatteryState::Discharging, 15),
+            [update("Battery critical")]
+        );
+        assert_eq!(h.open(), ["Battery now discharging", "Battery critical"]);
+        // Back above sleep_pct but still low
+        assert_eq!(
+            h.level(BatteryState::Discharging, 16),
+            [update("Battery low")]
+        );
+        assert_eq!(h.open(), ["Battery now discharging", "Battery low"]);
+    }
+
+    #[test]
+    fn state_notification_replaced_in_place() {
+        let mut h = Harness::new(test_config());
+        h.level(BatteryState::Charging, 80);
+        assert_eq!(h.level(BatteryState::Charging, 81), []);
+        assert_eq!(
+            h.level(BatteryState::Full, 100),
+            [update("Battery now full")]
+        );
+        assert_eq!(h.open(), ["Battery now full"]);
+    }
+
+    #[test]
+    fn startup_grace_skips_initial_state() {
+        let mut cfg = test_config();
+        cfg.startup_grace_secs = 60;
+        let mut h = Harness::new(cfg);
+        assert_eq!(h.level(BatteryState::Discharging, 80), []);
+        // The state we started in was still tracked, so the grace period ending isn't a change
+        assert_eq!(
+            h.step(
+                60,
+                vec![Battery::new("BAT0", BatteryState::Discharging, 80)]
+            ),
+            []
+        );
+        assert_eq!(
+            h.level(BatteryState::Charging, 80),
+            [show("Battery now charging")]
+        );
+    }
+
+    #[test]
+    fn reverted_state_closes_notification() {
+        let mut cfg = test_config();
+        cfg.close_reverted_state_secs = 60;
+        let mut h = Harness::new(cfg);
+        h.level(BatteryState::Charging, 80);
+        assert_eq!(
+            h.level(BatteryState::Discharging, 80),
+            [update("Battery now discharging")]
+        );
+        assert_eq!(
+            h.level(BatteryState::Charging, 80),
+            [close("Battery now discharging")]
+        );
+        // Outside the window, it's a real change
+        h.level(BatteryState::Discharging, 80);
+        assert_eq!(
+            h.step(90, vec![Battery::new("BAT0", BatteryState::Charging, 80)]),
+            [update("Battery now charging")]
+        );
+    }
+
+    #[test]
+    fn tiers_fire_once_per_discharge() {
+        let mut cfg = test_config();
+        cfg.low_pct = 0;
+        cfg.tiers = [30, 20]
+            .map(|pct| Tier {
+                pct,
+                ..Default::default()
+            })
+            .to_vec();
+        let mut h = Harness::new(cfg);
+        h.level(BatteryState::Discharging, 31);
+        assert_eq!(
+            h.level(BatteryState::Discharging, 30),
+            [show("Battery below 30%")]
+        );
+        assert_eq!(h.level(BatteryState::Discharging, 29), []);
+        // Going back over a tier doesn't rearm it
+        assert_eq!(h.level(BatteryState::Discharging, 31), []);
+        assert_eq!(h.level(BatteryState::Discharging, 30), []);
+        assert_eq!(
+            h.level(BatteryState::Discharging, 20),
+            [update("Battery below 20%")]
+        );
+        let events = h.level(BatteryState::Charging, 20);
+        assert!(events.contains(&close("Battery below 20%")), "{events:?}");
+        h.level(BatteryState::Discharging, 35);
+        assert_eq!(
+            h.level(BatteryState::Discharging, 19),
+            [show("Battery below 20%")]
+        );
+    }
+
+    #[test]
+    fn per_battery_thresholds() {
+        let mut cfg = test_config();
+        cfg.low_pct = 0;
+        cfg.sleep_pct = 0;
+        cfg.batteries.insert(
+            "BAT1".to_string(),
+            BatteryThresholds {
+                low_pct: 20,
+                critical_pct: 10,
+            },
+        );
+        let mut h = Harness::new(cfg);
+        let bats = |bat1| {
+            vec![
+                Battery::new("BAT0", BatteryState::Discharging, 90),
+                Battery::new("BAT1", BatteryState::Discharging, bat1),
+            ]
+        };
+        h.step(30, bats(50));
+        assert_eq!(h.step(30, bats(20)), [show("BAT1 low")]);
+        assert_eq!(h.step(30, bats(10)), [update("BAT1 critical")]);
+        assert_eq!(h.step(30, bats(21)), [close("BAT1 critical")]);
+        // Hot unplugging the battery closes its notification too
+        h.step(30, bats(5));
+        let events = h.step(
+            30,
+            vec![Battery::new("BAT0", BatteryState::Discharging, 90)],
+        );
+        assert!(events.contains(&close("BAT1 critical")), "{events:?}");
+    }
+
+    #[test]
+    fn low_pct_override_survives_reload() {
+        let mut h = Harness::new(test_config());
+        let now = h.clock.now();
+        h.engine.handle(service::Request::SetLowPct(5), now);
+        assert_eq!(h.engine.low_pct(), 40, "not above sleep_pct");
+        h.engine.handle(service::Request::SetLowPct(30), now);
+        assert_eq!(h.engine.low_pct(), 30);
+        h.engine.reload(test_config()).unwrap();
+        assert_eq!(h.engine.low_pct(), 30);
+
+        let mut cfg = test_config();
+        cfg.sleep_pct = 30;
+        cfg.low_pct = 50;
+        h.engine.reload(cfg).unwrap();
+        assert_eq!(h.engine.low_pct(), 50, "no longer above sleep_pct");
+    }
+
+    #[test]
+    fn checks_run_without_batteries() {
+        let mut h = Harness::new(test_config());
+        let ups = |online| {
+            system::FAKE_UPSES.with(|fake| {
+                *fake.borrow_mut() = Some(vec![system::Ups {
+                    name: "ups0".to_string(),
+                    level: Some(80),
+                    state: if online {
+                        BatteryState::Charging
+                    } else {
+                        BatteryState::Discharging
+                    },
+                    online: Some(online),
+                    time_to_empty_secs: Some(3600),
+                }]);
+            });
+        };
+        ups(false);
+        assert_eq!(h.step(30, Vec::new()), [show("UPS ups0 on battery")]);
+        ups(true);
+        assert_eq!(h.step(30, Vec::new()), [close("UPS ups0 on battery")]);
+
+        // With nothing at all to watch, there's no point carrying on
+        system::FAKE_UPSES.with(|fake| *fake.borrow_mut() = Some(Vec::new()));
+        let now = h.clock.now();
+        let mut cycle = timing::CycleTimer::new(now);
+        assert!(h
+            .engine
+            .evaluate(now, &mut cycle, Vec::new(), None, 0)
+            .is_err());
+    }
+
+    #[test]
+    fn remotes_checked_without_batteries() {
+        let mut cfg = test_config();
+        cfg.remotes.insert(
+            "buildbox".to_string(),
+            crate::remote::RemoteConfig {
+                command: "echo 10 discharging".to_string(),
+                ..Default::default()
+            },
+        );
+        let mut h = Harness::new(cfg);
+        // The command runs on its own thread, so give it a moment
+        let mut events = Vec::new();
+        for _ in 0..100 {
+            events = h.step(30, Vec::new());
+            if !events.is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(events, [show("buildbox battery low")]);
+    }
+
+    #[test]
+    fn inhibitors_ignored_below_floor() {
+        let mut cfg = test_config();
+        cfg.events.sleep.inhibit_command = "true".to_string();
+        cfg.events.sleep.inhibit_floor_pct = 5;
+        let commands = command::Context::default();
+        let now = Instant::now();
+        assert_eq!(
+            sleep_deferral(&cfg, &commands, 10, now, now).as_deref(),
+            Some("Sleep inhibited by 'true'")
+        );
+        assert_eq!(sleep_deferral(&cfg, &commands, 5, now, now), None);
+    }
+
+    #[test]
+    fn drain_rate_shown_in_steps() {
+        let mut shown = None;
+        assert_eq!(quantize_drain_rate(&mut shown, Some(10.4)), Some(10));
+        assert_eq!(quantize_drain_rate(&mut shown, Some(11.4)), Some(10));
+        assert_eq!(quantize_drain_rate(&mut shown, Some(8.6)), Some(10));
+        assert_eq!(quantize_drain_rate(&mut shown, Some(12.2)), Some(12));
+        assert_eq!(quantize_drain_rate(&mut shown, None), None);
+        assert_eq!(quantize_drain_rate(&mut shown, Some(11.0)), Some(11));
+    }
+
+    #[test]
+    fn low_latch_enters_at_threshold() {
+        let mut latch = LowLatch::default();
+        assert!(!latch.update(21, 20, 3, false));
+        assert!(latch.update(20, 20, 3, false));
+    }
+
+    #[test]
+    fn low_latch_holds_within_margin() {
+        let mut latch = LowLatch::default();
+        assert!(latch.update(20, 20, 3, false));
+        for level in [21, 22, 23, 21, 23] {
+            assert!(latch.update(level, 20, 3, false), "released at {level}%");
+        }
+        assert!(!latch.update(24, 20, 3, false));
+        // And once released, jitter above the threshold doesn't bring it back
+        assert!(!latch.update(22, 20, 3, false));
+    }
+
+    #[test]
+    fn low_latch_releases_on_charging() {
+        let mut latch = LowLatch::default();
+        assert!(latch.update(15, 20, 3, false));
+        assert!(!latch.update(15, 20, 3, true));
+        assert!(!latch.update(21, 20, 3, false));
+    }
+
+    #[test]
+    fn low_latch_without_margin() {
+        let mut latch = LowLatch::default();
+        assert!(latch.update(20, 20, 0, false));
+        assert!(!latch.update(21, 20, 0, false));
+    }
+
+    #[test]
+    fn low_latch_margin_saturates() {
+        let mut latch = LowLatch::default();
+        assert!(latch.update(250, 250, 10, false));
+        assert!(latch.update(255, 250, 10, false));
+    }
+}
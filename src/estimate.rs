@@ -0,0 +1,107 @@
+//! Estimates how long until the battery is empty or full, for notifications like "Battery low
+//! (about 42m remaining)".
+
+use std::time::Duration;
+
+use crate::system::{Battery, BatteryState};
+
+/// How much each new power reading counts towards the smoothed rate. Readings jump around a lot
+/// with load, so keep this low.
+const SMOOTHING: f64 = 0.2;
+
+/// Readings needed after a state change before the smoothed rate means anything.
+const MIN_SAMPLES: u32 = 3;
+
+/// Only change the estimate we show when the new one differs by more than this fraction, or
+/// MIN_CHANGE, whichever is bigger, so it doesn't jump around with every reading.
+const CHANGE_FRACTION: f64 = 0.1;
+const MIN_CHANGE: Duration = Duration::from_secs(2 * 60);
+
+#[derive(Default)]
+pub struct TimeEstimate {
+    state: Option<BatteryState>,
+    smoothed_uw: Option<f64>,
+    samples: u32,
+    shown: Option<Duration>,
+}
+
+impl TimeEstimate {
+    fn reset(&mut self, state: BatteryState) {
+        *self = Self {
+            state: Some(state),
+            ..Self::default()
+        };
+    }
+
+    /// Forgets the smoothed rate and shown estimate, for when we can't trust how they were reached.
+    pub fn restart(&mut self) {
+        *self = Self::default();
+    }
+
+    /// The raw estimate from the smoothed power draw, or failing that, `drain_pct_per_hour`.
+    fn estimate(&self, global: &Battery, drain_pct_per_hour: Option<f64>) -> Option<Duration> {
+        let uwh = match global.state {
+            BatteryState::Discharging => global.now_uwh(),
+            BatteryState::Charging => global.full_uwh().saturating_sub(global.now_uwh()),
+            _ => return None,
+        };
+        let hours = match self.smoothed_uw {
+            Some(uw) if self.samples >= MIN_SAMPLES && uw > 0.0 => uwh as f64 / uw,
+            _ if global.state == BatteryState::Discharging => {
+                global.level_precise() / drain_pct_per_hour.filter(|r| *r > 0.0)?
+            }
+            _ => return None,
+        };
+        Some(Duration::from_secs_f64(
+            (hours * 3600.0).min(u32::MAX.into()),
+        ))
+    }
+
+    /// Takes in this cycle's readings, and returns how long until the battery is empty (when
+    /// discharging) or full (when charging), if we can tell.
+    pub fn update(
+        &mut self,
+        global: &Battery,
+        drain_pct_per_hour: Option<f64>,
+    ) -> Option<Duration> {
+        if self.state != Some(global.state) {
+            self.reset(global.state);
+        }
+        if let Some(uw) = global.power_uw {
+            let uw = uw as f64;
+            self.smoothed_uw = Some(match self.smoothed_uw {
+                Some(prev) => prev + SMOOTHING * (uw - prev),
+                None => uw,
+            });
+            self.samples += 1;
+        }
+
+        let Some(new) = self.estimate(global, drain_pct_per_hour) else {
+            self.shown = None;
+            return None;
+        };
+        let changed = self.shown.map_or(true, |shown| {
+            let threshold = MIN_CHANGE.max(shown.mul_f64(CHANGE_FRACTION));
+            new.max(shown) - new.min(shown) > threshold
+        });
+        if changed {
+            // Nobody needs the seconds
+            self.shown = Some(Duration::from_secs(new.as_secs() / 60 * 60));
+        }
+        self.shown
+    }
+}
+
+/// An estimate from the current power reading alone, for one-off checks like `battery-notify
+/// status` which have no history to smooth over.
+pub fn one_off(global: &Battery) -> Option<Duration> {
+    let uw = global.power_uw.filter(|uw| *uw > 0)?;
+    let estimate = TimeEstimate {
+        state: Some(global.state),
+        smoothed_uw: Some(uw as f64),
+        samples: MIN_SAMPLES,
+        shown: None,
+    };
+    let remaining = estimate.estimate(global, None)?;
+    Some(Duration::from_secs(remaining.as_secs() / 60 * 60))
+}
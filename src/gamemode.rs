@@ -0,0 +1,46 @@
+use anyhow::Result;
+
+#[cfg(feature = "gamemode")]
+pub fn is_active() -> Result<bool> {
+    use anyhow::anyhow;
+    use once_cell::sync::Lazy;
+    use zbus::blocking::Connection;
+    use zbus::zvariant::OwnedValue;
+
+    const NAME: &str = "com.feralinteractive.GameMode";
+    const PATH: &str = "/com/feralinteractive/GameMode";
+
+    static CONN: Lazy<zbus::Result<Connection>> = Lazy::new(Connection::session);
+
+    let conn = CONN
+        .as_ref()
+        .map_err(|err| anyhow!("failed to connect to session bus: {err}"))?;
+
+    // GameMode is activated on demand, so don't treat it not being around as an error
+    let ret = conn.call_method(
+        Some("org.freedesktop.DBus"),
+        "/org/freedesktop/DBus",
+        Some("org.freedesktop.DBus"),
+        "NameHasOwner",
+        &(NAME,),
+    )?;
+    let has_owner: bool = ret.body().deserialize()?;
+    if !has_owner {
+        return Ok(false);
+    }
+
+    let ret = conn.call_method(
+        Some(NAME),
+        PATH,
+        Some("org.freedesktop.DBus.Properties"),
+        "Get",
+        &(NAME, "ClientCount"),
+    )?;
+    let count: OwnedValue = ret.body().deserialize()?;
+    Ok(i32::try_from(count)? > 0)
+}
+
+#[cfg(not(feature = "gamemode"))]
+pub fn is_active() -> Result<bool> {
+    Ok(false)
+}
@@ -0,0 +1,186 @@
+//! Commands declared heavy in heavy_commands, which we hold off on while at or below sleep_pct and
+//! discharging, since every joule left is better spent getting to a charger or suspending cleanly.
+//! One-off commands are deferred until we're above sleep_pct or charging again, and periodic ones
+//! (like remote checks) are skipped until then.
+
+use anyhow::{bail, Result};
+use log::info;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::clock::Clock;
+use crate::command;
+
+/// The config keys of commands which can be declared heavy.
+pub const KEYS: &[&str] = &[
+    "tiers",
+    "bluetooth_critical_command",
+    "stale_data_command",
+    "remotes",
+];
+
+/// Checks heavy_commands from the config, for Config::validate.
+pub fn validate(keys: &[String]) -> Result<()> {
+    for key in keys {
+        if !KEYS.contains(&key.as_str()) {
+            bail!(
+                "unknown heavy_commands entry '{key}', expected one of {}",
+                KEYS.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+/// A one-off command held off while conserving, with its environment and how to retry it.
+struct Deferred {
+    key: &'static str,
+    cmd: String,
+    env: Vec<(String, String)>,
+    retries: u32,
+    delay: Duration,
+}
+
+#[derive(Default)]
+struct State {
+    keys: Mutex<Vec<String>>,
+    conserving: AtomicBool,
+    /// How many times we've held off on a heavy command, for the HeldHeavyCommands property.
+    held: AtomicU64,
+    /// Oldest first.
+    deferred: Mutex<Vec<Deferred>>,
+}
+
+/// Which commands are heavy, and whether we're holding them off. This is cheap to clone, so
+/// everything which runs heavy commands keeps its own, including threads like the remote checkers.
+#[derive(Clone)]
+pub struct Heavy {
+    state: Arc<State>,
+    commands: command::Context,
+}
+
+impl Heavy {
+    /// Runs commands with the environment from `commands`.
+    pub fn new(commands: &command::Context) -> Self {
+        Self {
+            state: Arc::default(),
+            commands: commands.clone(),
+        }
+    }
+
+    pub fn set_heavy(&self, keys: &[String]) {
+        keys.clone_into(&mut self.state.keys.lock().unwrap());
+    }
+
+    /// Whether the command under `key` should be held off on right now.
+    fn holding(&self, key: &str) -> bool {
+        self.conserving() && self.state.keys.lock().unwrap().iter().any(|k| k == key)
+    }
+
+    /// Whether we're at or below sleep_pct and discharging, as of the last check.
+    pub fn conserving(&self) -> bool {
+        self.state.conserving.load(Ordering::Relaxed)
+    }
+
+    /// Starts or stops conserving. Once we stop, anything deferred is run, waiting on `clock`
+    /// between retries.
+    pub fn set_conserving(&self, conserving: bool, clock: &mut dyn Clock) {
+        if self.state.conserving.swap(conserving, Ordering::Relaxed) == conserving {
+            return;
+        }
+        if conserving {
+            info!("At or below sleep_pct, holding off on heavy commands");
+            return;
+        }
+        let deferred = std::mem::take(&mut *self.state.deferred.lock().unwrap());
+        info!(
+            "Above sleep_pct or charging again, no longer holding off on heavy commands ({} \
+             deferred)",
+            deferred.len()
+        );
+        for cmd in deferred {
+            let env: Vec<_> = cmd
+                .env
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect();
+            command::retry(&cmd.cmd, cmd.retries, cmd.delay, clock, || {
+                self.commands.run_with_env(&cmd.cmd, &env)
+            });
+        }
+    }
+
+    /// Defers a one-off command if it's heavy and we're conserving, returning whether it did. The
+    /// same command is only deferred once.
+    fn defer(
+        &self,
+        key: &'static str,
+        cmd: &str,
+        env: &[(&str, &str)],
+        retries: u32,
+        delay: Duration,
+    ) -> bool {
+        if !self.holding(key) {
+            return false;
+        }
+        self.state.held.fetch_add(1, Ordering::Relaxed);
+        let mut deferred = self.state.deferred.lock().unwrap();
+        if deferred.iter().any(|d| d.key == key && d.cmd == cmd) {
+            return true;
+        }
+        info!(command = key, event = "heavy_deferred"; "Deferring heavy command '{cmd}' ({key})");
+        deferred.push(Deferred {
+            key,
+            cmd: cmd.to_string(),
+            env: env
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            retries,
+            delay,
+        });
+        true
+    }
+
+    /// Runs a one-off command like command::Context::run_with_env, unless it's heavy and we're
+    /// conserving, in which case it's deferred.
+    pub fn run(&self, key: &'static str, cmd: &str, env: &[(&str, &str)]) {
+        if !self.defer(key, cmd, env, 0, Duration::ZERO) {
+            self.commands.run_with_env(cmd, env);
+        }
+    }
+
+    /// Like run(), retrying up to `retries` more times with `delay` in between if it fails, whether
+    /// it runs now or once deferred.
+    pub fn run_with_retries(
+        &self,
+        key: &'static str,
+        cmd: &str,
+        env: &[(&str, &str)],
+        retries: u32,
+        delay: Duration,
+        clock: &mut dyn Clock,
+    ) {
+        if !self.defer(key, cmd, env, retries, delay) {
+            command::retry(cmd, retries, delay, clock, || {
+                self.commands.run_with_env(cmd, env)
+            });
+        }
+    }
+
+    /// For periodic commands: whether to skip this run, logging and counting it if so.
+    pub fn skip(&self, key: &str, cmd: &str) -> bool {
+        if !self.holding(key) {
+            return false;
+        }
+        self.state.held.fetch_add(1, Ordering::Relaxed);
+        info!(command = key, event = "heavy_skipped"; "Skipping heavy command '{cmd}' ({key})");
+        true
+    }
+
+    /// How many times a heavy command was deferred or skipped since we started.
+    pub fn held(&self) -> u64 {
+        self.state.held.load(Ordering::Relaxed)
+    }
+}
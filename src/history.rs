@@ -0,0 +1,275 @@
+//! A record of the combined battery level over time, kept in
+//! `$XDG_STATE_HOME/battery-notify/history.csv`, and what `battery-notify history` makes of it.
+
+use anyhow::{Context, Result};
+use log::{error, info};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::clock;
+use crate::system::{self, Battery, BatteryState};
+
+const HEADER: &str = "time,level,state,power_w";
+
+/// Samples further apart than this are from separate discharges, usually with a suspend between.
+const MAX_GAP: Duration = Duration::from_secs(15 * 60);
+
+/// How often to drop samples older than history_retention_days while recording.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(clock::DAY_SECS);
+
+/// How many characters wide to draw each discharge curve.
+const CURVE_WIDTH: usize = 60;
+
+fn path() -> Result<PathBuf> {
+    let dirs = directories::BaseDirs::new().context("failed to find home directory")?;
+    Ok(dirs
+        .state_dir()
+        .unwrap_or_else(|| dirs.cache_dir())
+        .join("battery-notify")
+        .join("history.csv"))
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Sample {
+    time: u64,
+    level: f64,
+    state: String,
+    power_w: Option<f64>,
+}
+
+impl Sample {
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split(',');
+        let sample = Self {
+            time: fields.next()?.parse().ok()?,
+            level: fields.next()?.parse().ok()?,
+            state: fields.next()?.to_string(),
+            power_w: fields.next()?.parse().ok(),
+        };
+        Some(sample)
+    }
+}
+
+/// Everything in the history file from `since` onwards, skipping lines we can't make sense of.
+fn read_since(since: u64) -> Result<Vec<Sample>> {
+    let path = path()?;
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err).with_context(|| format!("failed to read {}", path.display())),
+    };
+    Ok(content
+        .lines()
+        .filter_map(Sample::parse)
+        .filter(|sample| sample.time >= since)
+        .collect())
+}
+
+/// Drops samples older than `retention`, via a temporary file so that we never leave a partial one
+/// behind.
+fn prune(retention: Duration) -> Result<()> {
+    let path = path()?;
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let cutoff = unix_secs(SystemTime::now()).saturating_sub(retention.as_secs());
+    let keep: Vec<_> = content
+        .lines()
+        .filter(|line| Sample::parse(line).is_some_and(|sample| sample.time >= cutoff))
+        .collect();
+    // The header doesn't parse as a sample
+    if keep.len() + 1 >= content.lines().count() {
+        return Ok(());
+    }
+    info!(
+        "Pruning {} history samples older than {}",
+        content.lines().count() - keep.len() - 1,
+        humantime::format_duration(retention)
+    );
+    let tmp = path.with_extension("csv.tmp");
+    let mut out = String::from(HEADER);
+    out.push('\n');
+    for line in keep {
+        out.push_str(line);
+        out.push('\n');
+    }
+    fs::write(&tmp, out)?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+/// Appends a sample of the combined battery every `interval`, if enabled.
+pub struct Recorder {
+    interval: Duration,
+    retention: Duration,
+    last: Option<Instant>,
+    last_prune: Option<Instant>,
+}
+
+impl Recorder {
+    /// Disabled if `interval` is zero. Otherwise prunes samples older than `retention` (unless
+    /// that's zero too) with the first sample and daily after, so the file doesn't grow forever.
+    pub const fn new(interval: Duration, retention: Duration) -> Self {
+        Self {
+            interval,
+            retention,
+            last: None,
+            last_prune: None,
+        }
+    }
+
+    pub fn record(&mut self, now: Instant, wall: SystemTime, global: &Battery) {
+        if self.interval.is_zero()
+            || self
+                .last
+                .is_some_and(|last| now.duration_since(last) < self.interval)
+        {
+            return;
+        }
+        self.last = Some(now);
+        if !self.retention.is_zero()
+            && self
+                .last_prune
+                .map_or(true, |last| now.duration_since(last) >= PRUNE_INTERVAL)
+        {
+            self.last_prune = Some(now);
+            if let Err(err) = prune(self.retention) {
+                error!("failed to prune history: {err:#}");
+            }
+        }
+        if let Err(err) = append(wall, global) {
+            error!("failed to record history: {err:#}");
+        }
+    }
+}
+
+fn append(wall: SystemTime, global: &Battery) -> Result<()> {
+    let path = path()?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    if file.metadata()?.len() == 0 {
+        writeln!(file, "{HEADER}")?;
+    }
+    let power_w = global
+        .power_uw
+        .map(|uw| format!("{:.2}", uw as f64 / 1_000_000.0))
+        .unwrap_or_default();
+    writeln!(
+        file,
+        "{},{:.1},{},{power_w}",
+        unix_secs(wall),
+        global.level_precise(),
+        system::battery_state_to_lowercase_name(global.state),
+    )?;
+    Ok(())
+}
+
+/// Splits samples into runs of discharging without long gaps.
+fn discharges(samples: &[Sample]) -> Vec<&[Sample]> {
+    let discharging = system::battery_state_to_lowercase_name(BatteryState::Discharging);
+    samples
+        .chunk_by(|a, b| {
+            a.state == discharging
+                && b.state == discharging
+                && b.time.saturating_sub(a.time) <= MAX_GAP.as_secs()
+        })
+        .filter(|run| run.len() > 1 && run[0].state == discharging)
+        .collect()
+}
+
+/// Draws levels from 0 to 100% as a line of block characters, so curves can be compared by eye.
+fn curve(samples: &[Sample]) -> String {
+    const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let step = samples.len().div_ceil(CURVE_WIDTH);
+    samples
+        .chunks(step)
+        .map(|chunk| {
+            let level = chunk.last().map_or(0.0, |s| s.level).clamp(0.0, 100.0);
+            BLOCKS[((level / 100.0 * 7.0).round() as usize).min(7)]
+        })
+        .collect()
+}
+
+fn format_time(secs: u64) -> String {
+    clock::local_time(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Rounded to the minute, since nobody cares about seconds here.
+fn format_span(secs: u64) -> String {
+    humantime::format_duration(Duration::from_secs(secs / 60 * 60)).to_string()
+}
+
+fn average_power(samples: &[Sample]) -> Option<f64> {
+    let powers: Vec<_> = samples.iter().filter_map(|s| s.power_w).collect();
+    (!powers.is_empty()).then(|| powers.iter().sum::<f64>() / powers.len() as f64)
+}
+
+/// Prints each discharge since `since` ago with its curve, and the average drain across them.
+pub fn run(since: Duration) -> Result<()> {
+    let start = unix_secs(SystemTime::now()).saturating_sub(since.as_secs());
+    let samples = read_since(start)?;
+    println!(
+        "History since {} ({} samples)",
+        format_time(start),
+        samples.len()
+    );
+    if samples.is_empty() {
+        println!("\nNo samples, is history_interval_secs set?");
+        return Ok(());
+    }
+
+    let runs = discharges(&samples);
+    let (mut total_drop, mut total_secs) = (0.0, 0);
+    println!("\nDischarges:");
+    if runs.is_empty() {
+        println!("  none");
+    }
+    for run in &runs {
+        let (first, last) = (&run[0], &run[run.len() - 1]);
+        let secs = last.time - first.time;
+        let drop = first.level - last.level;
+        total_drop += drop;
+        total_secs += secs;
+        let rate = if secs > 0 {
+            format!(", {:.1}%/h", drop * 3600.0 / secs as f64)
+        } else {
+            String::new()
+        };
+        let power = average_power(run)
+            .map(|w| format!(", {w:.1}W"))
+            .unwrap_or_default();
+        println!(
+            "  {} for {}, {:.0}% to {:.0}%{rate}{power}",
+            format_time(first.time),
+            format_span(secs),
+            first.level,
+            last.level
+        );
+        println!("    {}", curve(run));
+    }
+
+    if total_secs > 0 {
+        println!(
+            "\nAverage drain: {:.1}%/h over {}",
+            total_drop * 3600.0 / total_secs as f64,
+            format_span(total_secs)
+        );
+    }
+    let discharging: Vec<_> = runs.iter().flat_map(|run| run.iter()).cloned().collect();
+    if let Some(power) = average_power(&discharging) {
+        println!("Average draw while discharging: {power:.1}W");
+    }
+    Ok(())
+}
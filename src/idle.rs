@@ -0,0 +1,63 @@
+use anyhow::Result;
+
+/// Whether all sessions are idle, according to logind. This relies on something in the session
+/// (like the desktop environment, or swayidle with `idlehint`) telling logind about it.
+#[cfg(feature = "dbus")]
+pub fn is_idle() -> Result<bool> {
+    use zbus::blocking::Connection;
+    use zbus::zvariant::OwnedValue;
+
+    let conn = Connection::system()?;
+    let ret = conn.call_method(
+        Some("org.freedesktop.login1"),
+        "/org/freedesktop/login1",
+        Some("org.freedesktop.DBus.Properties"),
+        "Get",
+        &("org.freedesktop.login1.Manager", "IdleHint"),
+    )?;
+    let idle: OwnedValue = ret.body().deserialize()?;
+    Ok(bool::try_from(idle)?)
+}
+
+#[cfg(not(feature = "dbus"))]
+pub fn is_idle() -> Result<bool> {
+    anyhow::bail!("built without D-Bus support, can't ask logind")
+}
+
+/// The applications holding logind block inhibitors on sleep, like a backup run under
+/// systemd-inhibit.
+#[cfg(feature = "dbus")]
+pub fn sleep_inhibitors() -> Result<Vec<String>> {
+    use zbus::blocking::Connection;
+
+    let conn = Connection::system()?;
+    let ret = conn.call_method(
+        Some("org.freedesktop.login1"),
+        "/org/freedesktop/login1",
+        Some("org.freedesktop.login1.Manager"),
+        "ListInhibitors",
+        &(),
+    )?;
+    // (what, who, why, mode, uid, pid)
+    let inhibitors: Vec<(String, String, String, String, u32, u32)> = ret.body().deserialize()?;
+    let mut who: Vec<_> = inhibitors
+        .into_iter()
+        .filter(|(what, _, _, mode, _, _)| mode == "block" && what.split(':').any(|w| w == "sleep"))
+        .map(|(_, who, why, _, _, _)| {
+            if why.is_empty() {
+                who
+            } else {
+                format!("{who} ({why})")
+            }
+        })
+        .collect();
+    // One program often takes several, which logind lists in the order they were taken
+    who.sort();
+    who.dedup();
+    Ok(who)
+}
+
+#[cfg(not(feature = "dbus"))]
+pub fn sleep_inhibitors() -> Result<Vec<String>> {
+    anyhow::bail!("built without D-Bus support, can't ask logind")
+}
@@ -0,0 +1,140 @@
+use anyhow::{bail, Result};
+use std::env;
+use std::fmt::Display;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+use crate::action::Action;
+use crate::{bluetooth, system, Config};
+
+/// Asks a question on the terminal, returning `default` if the answer is empty. Asks again if the
+/// answer doesn't parse.
+fn ask<T: FromStr + Display>(question: &str, default: T) -> Result<T> {
+    let stdin = io::stdin();
+    loop {
+        print!("{question} [{default}]: ");
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        if stdin.lock().read_line(&mut answer)? == 0 {
+            bail!("no answer given");
+        }
+        let answer = answer.trim();
+        if answer.is_empty() {
+            return Ok(default);
+        }
+        match answer.parse() {
+            Ok(val) => return Ok(val),
+            Err(_) => println!("Sorry, I didn't understand '{answer}'."),
+        }
+    }
+}
+
+fn ask_yes_no(question: &str, default: bool) -> Result<bool> {
+    loop {
+        let answer: String = ask(question, if default { "y" } else { "n" }.to_string())?;
+        match answer.to_lowercase().as_str() {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("Please answer y or n."),
+        }
+    }
+}
+
+/// Works out what this machine has, asks a few questions, and writes an initial config to match.
+pub fn run() -> Result<()> {
+    let path = confy::get_configuration_file_path("battery-notify", "config")?;
+    if path.exists() && !ask_yes_no(&format!("{} exists, overwrite it?", path.display()), false)? {
+        return Ok(());
+    }
+    let mut cfg = Config::default();
+
+    println!("Detected:");
+    let batteries = system::get_batteries().unwrap_or_default();
+    if batteries.is_empty() {
+        println!("  no batteries -- battery-notify won't have much to do here");
+    }
+    for bat in &batteries {
+        let threshold = match (bat.start_threshold, bat.end_threshold) {
+            (_, Some(end)) => format!("charge limit at {end}%"),
+            (Some(start), None) => format!("charging resumes at {start}%"),
+            (None, None) => "no charge threshold support".to_string(),
+        };
+        println!("  {}: {}%, {threshold}", bat.name, bat.level());
+    }
+    if batteries.len() > 1 {
+        println!("  multiple batteries, which may be drained one at a time");
+    }
+
+    let wayland = env::var_os("WAYLAND_DISPLAY").is_some();
+    let x11 = env::var_os("DISPLAY").is_some();
+    let desktop = env::var("XDG_CURRENT_DESKTOP").unwrap_or_else(|_| "unknown".to_string());
+    match (wayland, x11) {
+        (true, _) => println!("  Wayland session ({desktop})"),
+        (false, true) => println!("  X11 session ({desktop})"),
+        (false, false) => println!("  no graphical session"),
+    }
+
+    let bluez = bluetooth::get_battery_levels();
+    match &bluez {
+        Ok(bbats) => println!("  BlueZ, with {} devices reporting battery", bbats.len()),
+        Err(err) => println!("  no BlueZ ({err})"),
+    }
+    println!();
+
+    cfg.low_pct = ask("Notify about low battery at what percentage?", cfg.low_pct)?;
+    cfg.sleep_pct = ask(
+        "Notify about critical battery (and sleep) at what percentage?",
+        cfg.sleep_pct,
+    )?;
+    let action: String = ask(
+        "At critical battery, suspend, hibernate, or do nothing?",
+        "suspend".to_string(),
+    )?;
+    cfg.events.sleep.command = match action.to_lowercase().as_str() {
+        "hibernate" => "systemctl hibernate".into(),
+        "nothing" | "none" => Action::default(),
+        _ => "systemctl suspend".into(),
+    };
+
+    if batteries.len() > 1
+        && ask_yes_no(
+            "Do your batteries drain one at a time (like dual-battery ThinkPads)?",
+            false,
+        )?
+    {
+        cfg.aggregation = crate::config::Aggregation::Active;
+    }
+
+    if wayland || x11 {
+        if !ask_yes_no(
+            "Warn when on battery with monitors connected (for example, at a desk)?",
+            true,
+        )? {
+            cfg.warn_on_mons_with_no_ac = 0;
+        }
+    } else {
+        cfg.warn_on_mons_with_no_ac = 0;
+    }
+
+    if bluez.is_ok() {
+        if !ask_yes_no("Notify about low bluetooth device batteries?", true)? {
+            cfg.bluetooth_low_pct = 0;
+        }
+    } else {
+        cfg.bluetooth_low_pct = 0;
+    }
+
+    if !wayland && !x11 {
+        cfg.system_mode = ask_yes_no(
+            "Run as a system-wide instance relaying to all graphical sessions?",
+            false,
+        )?;
+    }
+
+    confy::store_path(&path, &cfg)?;
+    println!(
+        "\nWrote {}. Run 'battery-notify self-test' to check it works.",
+        path.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,336 @@
+use anyhow::{bail, Context, Result};
+use std::fmt::{self, Display, Formatter, Write};
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Just enough JSON to write out our own machine-readable output, and read it back from other
+/// machines.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn obj<K: Into<String>>(fields: impl IntoIterator<Item = (K, Json)>) -> Self {
+        Self::Obj(fields.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    /// The value of `key`, if this is an object which has it.
+    pub fn get(&self, key: &str) -> Option<&Self> {
+        match self {
+            Self::Obj(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub const fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut chars = text.chars().peekable();
+        let value = parse_value(&mut chars, 0)?;
+        skip_whitespace(&mut chars);
+        if let Some(c) = chars.next() {
+            bail!("unexpected '{c}' after JSON value");
+        }
+        Ok(value)
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+}
+
+fn expect(chars: &mut Peekable<Chars>, word: &str) -> Result<()> {
+    for want in word.chars() {
+        if chars.next() != Some(want) {
+            bail!("expected '{word}'");
+        }
+    }
+    Ok(())
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String> {
+    expect(chars, "\"")?;
+    let mut out = String::new();
+    loop {
+        match chars.next().context("unterminated string")? {
+            '"' => return Ok(out),
+            '\\' => match chars.next().context("unterminated string")? {
+                'n' => out.push('\n'),
+                'r' => out.push('\r'),
+                't' => out.push('\t'),
+                'b' => out.push('\u{8}'),
+                'f' => out.push('\u{c}'),
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    let code = u32::from_str_radix(&hex, 16).context("invalid \\u escape")?;
+                    // Surrogate pairs never come up in what we read, so don't bother with them
+                    out.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+                c => out.push(c),
+            },
+            c => out.push(c),
+        }
+    }
+}
+
+/// How deeply arrays and objects can nest. What we read is only a few levels deep, and this keeps
+/// hostile or broken input from a remote machine from overflowing the stack.
+const MAX_DEPTH: usize = 64;
+
+fn parse_value(chars: &mut Peekable<Chars>, depth: usize) -> Result<Json> {
+    skip_whitespace(chars);
+    let next = chars.peek().context("unexpected end of JSON")?;
+    if matches!(next, '[' | '{') && depth >= MAX_DEPTH {
+        bail!("JSON nested more than {MAX_DEPTH} deep");
+    }
+    match next {
+        'n' => expect(chars, "null").map(|()| Json::Null),
+        't' => expect(chars, "true").map(|()| Json::Bool(true)),
+        'f' => expect(chars, "false").map(|()| Json::Bool(false)),
+        '"' => parse_string(chars).map(Json::Str),
+        '[' => {
+            chars.next();
+            let mut items = Vec::new();
+            skip_whitespace(chars);
+            if chars.next_if_eq(&']').is_some() {
+                return Ok(Json::Arr(items));
+            }
+            loop {
+                items.push(parse_value(chars, depth + 1)?);
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => {}
+                    Some(']') => return Ok(Json::Arr(items)),
+                    _ => bail!("expected ',' or ']' in array"),
+                }
+            }
+        }
+        '{' => {
+            chars.next();
+            let mut fields = Vec::new();
+            skip_whitespace(chars);
+            if chars.next_if_eq(&'}').is_some() {
+                return Ok(Json::Obj(fields));
+            }
+            loop {
+                skip_whitespace(chars);
+                let key = parse_string(chars)?;
+                skip_whitespace(chars);
+                expect(chars, ":")?;
+                fields.push((key, parse_value(chars, depth + 1)?));
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => {}
+                    Some('}') => return Ok(Json::Obj(fields)),
+                    _ => bail!("expected ',' or '}}' in object"),
+                }
+            }
+        }
+        _ => {
+            let mut num = String::new();
+            while let Some(c) =
+                chars.next_if(|c| matches!(c, '0'..='9' | '-' | '+' | '.' | 'e' | 'E'))
+            {
+                num.push(c);
+            }
+            if let Ok(i) = num.parse() {
+                Ok(Json::Int(i))
+            } else {
+                num.parse()
+                    .map(Json::Float)
+                    .with_context(|| format!("invalid JSON value '{num}'"))
+            }
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Self::Str(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Self::Str(s)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Self::Bool(b)
+    }
+}
+
+impl From<f64> for Json {
+    fn from(f: f64) -> Self {
+        Self::Float(f)
+    }
+}
+
+macro_rules! impl_from_int {
+    ($($ty:ty),*) => {
+        $(
+            impl From<$ty> for Json {
+                fn from(i: $ty) -> Self {
+                    Self::Int(i64::try_from(i).unwrap_or(i64::MAX))
+                }
+            }
+        )*
+    };
+}
+
+impl_from_int!(u8, u32, u64, usize, i32, i64);
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(opt: Option<T>) -> Self {
+        opt.map_or(Self::Null, Into::into)
+    }
+}
+
+impl<T: Into<Json>> From<Vec<T>> for Json {
+    fn from(vec: Vec<T>) -> Self {
+        Self::Arr(vec.into_iter().map(Into::into).collect())
+    }
+}
+
+/// Describes `res` with `describe` if it's Ok, or as {"error": "..."} otherwise.
+pub fn or_error<T>(res: anyhow::Result<T>, describe: impl FnOnce(T) -> Json) -> Json {
+    match res {
+        Ok(val) => describe(val),
+        Err(err) => Json::obj([("error", Json::from(err.to_string()))]),
+    }
+}
+
+fn write_str(f: &mut Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Null => f.write_str("null"),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Int(i) => write!(f, "{i}"),
+            Self::Float(x) if x.is_finite() => write!(f, "{x}"),
+            Self::Float(_) => f.write_str("null"),
+            Self::Str(s) => write_str(f, s),
+            Self::Arr(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_char(']')
+            }
+            Self::Obj(fields) => {
+                f.write_char('{')?;
+                for (i, (key, val)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_str(f, key)?;
+                    write!(f, ":{val}")?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let value = Json::obj([
+            ("level", Json::from(42u8)),
+            ("rate", Json::from(1.5)),
+            ("name", Json::from("BAT0 \"main\"\n")),
+            ("ac", Json::from(None::<bool>)),
+            ("alarms", Json::from(vec!["low", "bluetooth"])),
+            ("nested", Json::obj([("ok", Json::from(true))])),
+        ]);
+        assert_eq!(Json::parse(&value.to_string()).unwrap(), value);
+    }
+
+    #[test]
+    fn parse_whitespace_and_escapes() {
+        let value = Json::parse(" { \"a\" : [ 1 , -2.5e1 , \"\\u00e9\\t\" ] } ").unwrap();
+        assert_eq!(
+            value.get("a"),
+            Some(&Json::Arr(vec![
+                Json::Int(1),
+                Json::Float(-25.0),
+                Json::from("\u{e9}\t")
+            ]))
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        for bad in [
+            "",
+            "[1,",
+            "{\"a\" 1}",
+            "\"open",
+            "tru",
+            "1 2",
+            "[1]]",
+            "{1: 2}",
+        ] {
+            assert!(Json::parse(bad).is_err(), "{bad:?} should fail");
+        }
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let ok = format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH));
+        assert!(Json::parse(&ok).is_ok());
+        let deep = format!("{}{}", "[".repeat(MAX_DEPTH + 1), "]".repeat(MAX_DEPTH + 1));
+        assert!(Json::parse(&deep).is_err());
+        // Far deeper than the stack could take without the limit
+        assert!(Json::parse(&"{\"a\":[".repeat(1_000_000)).is_err());
+    }
+}
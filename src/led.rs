@@ -0,0 +1,111 @@
+//! Blinks an LED (like caps lock, or a laptop's power or mute LED) while critical, as a cue which
+//! doesn't depend on the notification daemon, or even the compositor, still working.
+
+use anyhow::{Context, Result};
+use log::{error, info};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const LEDS_DIR: &str = "/sys/class/leds";
+
+pub struct CriticalLed {
+    dir: Option<PathBuf>,
+    blink: Duration,
+    // Whether we're meant to be blinking, even if setting the LED up failed
+    active: bool,
+    // The trigger and brightness from before we started blinking, to put back after
+    saved: Option<(String, String)>,
+}
+
+impl CriticalLed {
+    /// Blinks the LED `name` in /sys/class/leds, or nothing if it's empty.
+    pub fn new(name: &str, blink: Duration) -> Self {
+        Self {
+            dir: (!name.is_empty()).then(|| PathBuf::from(LEDS_DIR).join(name)),
+            blink,
+            active: false,
+            saved: None,
+        }
+    }
+
+    /// Starts or stops blinking.
+    pub fn update(&mut self, blinking: bool) {
+        if self.active == blinking {
+            return;
+        }
+        self.active = blinking;
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        if blinking {
+            match start(dir, self.blink) {
+                Ok(saved) => {
+                    info!("Blinking LED {}", dir.display());
+                    self.saved = Some(saved);
+                }
+                Err(err) => error!("failed to blink LED {}: {err:#}", dir.display()),
+            }
+        } else if let Some((trigger, brightness)) = self.saved.take() {
+            info!("No longer blinking LED {}", dir.display());
+            if let Err(err) = restore(dir, &trigger, &brightness) {
+                error!("failed to restore LED {}: {err:#}", dir.display());
+            }
+        }
+    }
+}
+
+impl Drop for CriticalLed {
+    fn drop(&mut self) {
+        self.update(false);
+    }
+}
+
+fn write(dir: &Path, file: &str, value: &str) -> Result<()> {
+    let path = dir.join(file);
+    fs::write(&path, value).with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Blinks with the pattern trigger, or the older timer trigger if that's not available, and
+/// returns the trigger and brightness from before.
+fn start(dir: &Path, blink: Duration) -> Result<(String, String)> {
+    let read = |file: &str| {
+        let path = dir.join(file);
+        fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))
+            .map(|val| val.trim().to_string())
+    };
+    // Like "none [kbd-capslock] timer", with the one in use in brackets
+    let triggers = read("trigger")?;
+    let trigger = triggers
+        .split_whitespace()
+        .find_map(|t| t.strip_prefix('[')?.strip_suffix(']'))
+        .unwrap_or("none")
+        .to_string();
+    let brightness = read("brightness")?;
+    let max = read("max_brightness").unwrap_or_else(|_| "1".to_string());
+    let ms = blink.as_millis().to_string();
+
+    if triggers
+        .split_whitespace()
+        .any(|t| t.trim_matches(['[', ']']) == "pattern")
+    {
+        write(dir, "trigger", "pattern")?;
+        write(dir, "pattern", &format!("{max} {ms} 0 {ms}"))?;
+    } else {
+        write(dir, "trigger", "timer")?;
+        write(dir, "delay_on", &ms)?;
+        write(dir, "delay_off", &ms)?;
+    }
+    Ok((trigger, brightness))
+}
+
+fn restore(dir: &Path, trigger: &str, brightness: &str) -> Result<()> {
+    write(dir, "trigger", trigger)?;
+    // Setting a trigger drives the brightness itself, but without one it's left however the
+    // blinking left it
+    if trigger == "none" {
+        write(dir, "brightness", brightness)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,186 @@
+use log::kv::{self, Key, Value, VisitSource};
+use log::{Level, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::env;
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::json::Json;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// How many recent log lines to keep around for debug dumps.
+const RECENT_CAPACITY: usize = 500;
+
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+static JSON_FORMAT: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line on stderr, for log shippers.
+    Json,
+}
+
+/// Switches the output format. Logging starts before we have a config, so this is separate from
+/// `init()`.
+pub fn set_format(format: LogFormat) {
+    JSON_FORMAT.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+/// Appends a field in the journal's native protocol.
+fn push_journal_field(buf: &mut Vec<u8>, key: &str, val: &str) {
+    buf.extend_from_slice(key.as_bytes());
+    if val.contains('\n') {
+        // Multiline values need to be length prefixed instead
+        buf.push(b'\n');
+        buf.extend_from_slice(&(val.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(val.as_bytes());
+    buf.push(b'\n');
+}
+
+struct JournalFields<'a>(&'a mut Vec<u8>);
+
+impl<'kvs> VisitSource<'kvs> for JournalFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, val: Value<'kvs>) -> Result<(), kv::Error> {
+        push_journal_field(self.0, &key.as_str().to_uppercase(), &val.to_string());
+        Ok(())
+    }
+}
+
+const fn journal_priority(level: Level) -> &'static str {
+    match level {
+        Level::Error => "3",
+        Level::Warn => "4",
+        Level::Info => "6",
+        Level::Debug | Level::Trace => "7",
+    }
+}
+
+/// Sends a record to the journal, including any key-values attached to it as structured fields.
+fn log_to_journal(sock: &UnixDatagram, record: &Record<'_>) -> std::io::Result<()> {
+    let mut buf = Vec::new();
+    push_journal_field(&mut buf, "MESSAGE", &record.args().to_string());
+    push_journal_field(&mut buf, "PRIORITY", journal_priority(record.level()));
+    push_journal_field(&mut buf, "SYSLOG_IDENTIFIER", "battery-notify");
+    push_journal_field(&mut buf, "TARGET", record.target());
+    // Visiting only fails if we return an error, which we never do
+    let _ = record.key_values().visit(&mut JournalFields(&mut buf));
+    sock.send_to(&buf, JOURNAL_SOCKET)?;
+    Ok(())
+}
+
+struct JsonFields<'a>(&'a mut Vec<(String, Json)>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, val: Value<'kvs>) -> Result<(), kv::Error> {
+        let val = if let Some(n) = val.to_i64() {
+            Json::Int(n)
+        } else if let Some(b) = val.to_bool() {
+            Json::Bool(b)
+        } else {
+            Json::Str(val.to_string())
+        };
+        self.0.push((key.as_str().to_string(), val));
+        Ok(())
+    }
+}
+
+/// Formats a record as a single line JSON object. Key-values go under "fields", since names like
+/// "level" would otherwise clash with our own.
+fn format_json(record: &Record<'_>) -> String {
+    let mut fields = Vec::new();
+    let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+    Json::obj([
+        (
+            "timestamp",
+            Json::from(humantime::format_rfc3339_millis(SystemTime::now()).to_string()),
+        ),
+        ("level", Json::from(record.level().as_str())),
+        ("target", Json::from(record.target())),
+        ("message", Json::from(record.args().to_string())),
+        ("fields", Json::Obj(fields)),
+    ])
+    .to_string()
+}
+
+/// Wraps env_logger, additionally keeping the most recent lines in memory. When our output is
+/// going to the journal anyway, we talk to it directly instead, so that we can attach fields.
+struct Logger {
+    inner: env_logger::Logger,
+    journal: Option<UnixDatagram>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        match &self.journal {
+            _ if JSON_FORMAT.load(Ordering::Relaxed) => {
+                let _ = writeln!(std::io::stderr().lock(), "{}", format_json(record));
+            }
+            Some(sock) => {
+                if log_to_journal(sock, record).is_err() {
+                    self.inner.log(record);
+                }
+            }
+            None => self.inner.log(record),
+        }
+
+        let mut recent = RECENT.lock().unwrap();
+        if recent.len() == RECENT_CAPACITY {
+            recent.pop_front();
+        }
+        recent.push_back(format!(
+            "[{} {} {}] {}",
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            record.level(),
+            record.target(),
+            record.args()
+        ));
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Where logs are going: "json" (on stderr), "journal" or "stderr".
+pub fn destination() -> &'static str {
+    if JSON_FORMAT.load(Ordering::Relaxed) {
+        "json"
+    } else if env::var_os("JOURNAL_STREAM").is_some() {
+        "journal"
+    } else {
+        "stderr"
+    }
+}
+
+pub fn init() {
+    let inner =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).build();
+    // systemd sets JOURNAL_STREAM when our stderr is connected to the journal
+    let journal = env::var_os("JOURNAL_STREAM").and_then(|_| UnixDatagram::unbound().ok());
+    log::set_max_level(inner.filter());
+    log::set_boxed_logger(Box::new(Logger { inner, journal })).expect("Failed to set logger");
+}
+
+#[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+pub fn recent() -> Vec<String> {
+    RECENT.lock().unwrap().iter().cloned().collect()
+}
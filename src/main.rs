@@ -1,67 +1,235 @@
-use anyhow::{bail, Context, Result};
-use hashbrown::HashMap;
-use log::{error, info};
-use notify_rust::Urgency;
-use serde::{Deserialize, Serialize};
-
-use std::io;
-
-use std::process::Command;
+use anyhow::Result;
+use log::{error, info, warn};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
+mod ac;
+mod action;
+mod autostart;
 mod bluetooth;
+mod broadcast;
+mod buttons;
+mod charger;
+mod chargesched;
+mod checkconfig;
+mod checks;
+mod cli;
+mod clock;
+mod command;
+mod config;
+mod daemonize;
+mod debugdump;
+mod diagnostics;
+mod drain;
+mod engine;
+mod estimate;
+mod gamemode;
+mod heavy;
+mod history;
+mod idle;
+mod init;
+mod json;
+mod led;
+mod logging;
+mod man;
+mod migrate;
 mod monitors;
 mod notification;
+mod osd;
+mod overrides;
+mod reload;
+mod remote;
+mod rtc;
+mod selftest;
+mod service;
+mod slowcharge;
+mod snooze;
+mod speech;
+mod state;
+mod status;
 mod system;
+mod template;
+mod theme;
+mod thresholds;
+mod timing;
+mod udev;
+mod ups;
+mod version;
+mod watchdog;
+
+use clock::Clock;
+use config::Config;
 
-use notification::SingleNotification;
-
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(default)]
-struct Config {
-    sleep_command: String,
-    interval_secs: u64,
-    sleep_pct: u8,
-    low_pct: u8,
-    warn_on_mons_with_no_ac: usize,
-    bluetooth_low_pct: u8,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pause {
+    Running,
+    Until(Instant),
+    Indefinitely,
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            sleep_command: "systemctl suspend".to_string(),
-            interval_secs: 30,
-            sleep_pct: 15,
-            low_pct: 40,
-            warn_on_mons_with_no_ac: 2,
-            bluetooth_low_pct: 40,
+impl Pause {
+    fn new(duration: Option<Duration>, now: Instant) -> Self {
+        match duration {
+            Some(duration) => Self::Until(now + duration),
+            None => Self::Indefinitely,
+        }
+    }
+
+    fn status(self) -> String {
+        match self {
+            Self::Running => "Monitoring".to_string(),
+            Self::Until(until) => format!(
+                "Paused until {}",
+                clock::local_time(
+                    SystemTime::now() + until.saturating_duration_since(Instant::now())
+                )
+            ),
+            Self::Indefinitely => "Paused".to_string(),
+        }
+    }
+
+    /// Whether we're paused at `now`, resuming first if a timed pause is over.
+    fn active(&mut self, now: Instant) -> bool {
+        if matches!(*self, Self::Until(until) if now >= until) {
+            info!("Pause expired, resuming");
+            self.set(Self::Running);
+        }
+        *self != Self::Running
+    }
+
+    fn set(&mut self, new: Self) {
+        *self = new;
+        let status = new.status();
+        info!("{status}");
+        if let Err(err) = sd_notify::notify(false, &[sd_notify::NotifyState::Status(&status)]) {
+            error!("failed to update sd_notify status: {err}");
         }
     }
 }
 
-fn run_sleep_command(cmd: &str) {
-    if let Err(err) = Command::new("sh").args(["-c", cmd]).status() {
-        error!("Failed to run sleep command '{cmd}': {err}");
+/// Tells systemd we're reloading, so `systemctl reload` waits until we say we're ready again.
+/// systemd wants to know when we started, so it can tell this apart from an earlier reload.
+fn notify_reloading() {
+    let Some(now) = rtc::monotonic() else {
+        return;
+    };
+    let usec = format!("MONOTONIC_USEC={}", now.as_micros());
+    let states = [
+        sd_notify::NotifyState::Reloading,
+        sd_notify::NotifyState::Custom(&usec),
+    ];
+    if let Err(err) = sd_notify::notify(false, &states) {
+        error!("failed to tell systemd we're reloading: {err}");
     }
 }
 
-fn main() -> Result<()> {
-    let cfg: Config = confy::load("battery-notify", "config")?;
+/// Loads the config again for `engine`, reapplying the same command line overrides. Settings which
+/// only take effect at startup are left as they were.
+fn reload_config(engine: &mut engine::Engine, cli_overrides: &[overrides::Override]) -> Result<()> {
+    let mut cfg = config::load(cli_overrides.to_vec())?;
+    let old = engine.config();
+    for (key, changed) in [
+        ("watch_uevents", cfg.watch_uevents != old.watch_uevents),
+        ("watchdog_secs", cfg.watchdog_secs != old.watchdog_secs),
+        (
+            "startup_grace_secs",
+            cfg.startup_grace_secs != old.startup_grace_secs,
+        ),
+        ("dbus_service", cfg.dbus_service != old.dbus_service),
+        ("system_mode", cfg.system_mode != old.system_mode),
+    ] {
+        if changed {
+            warn!("{key} only changes on restart, keeping the old value for now");
+        }
+    }
+    cfg.watch_uevents = old.watch_uevents;
+    cfg.watchdog_secs = old.watchdog_secs;
+    cfg.startup_grace_secs = old.startup_grace_secs;
+    cfg.dbus_service = old.dbus_service;
+    cfg.system_mode = old.system_mode;
+    engine.reload(cfg)?;
+    info!("Reloaded config:\n\n{:#?}\n", engine.config());
+    Ok(())
+}
+
+/// How often to check, and how long a check can take before we warn about it.
+fn interval_and_budget(cfg: &Config) -> (Duration, Duration) {
     let interval = Duration::from_secs(cfg.interval_secs);
-    let mut state_notif = SingleNotification::default();
-    let mut low_notif = SingleNotification::default();
-    let mut mon_notif = SingleNotification::default();
-    let sleep_backoff = Duration::from_secs(60);
-    let mut next_sleep_epoch = Instant::now();
+    (interval, interval * u32::from(cfg.cycle_budget_pct) / 100)
+}
+
+/// Takes up any change to the interval from reloading or switching profiles, waiting the new one
+/// from `start`. Returns the budget for a cycle.
+fn retime(
+    cfg: &Config,
+    start: Instant,
+    interval: &mut Duration,
+    next_wake: &mut Instant,
+) -> Duration {
+    let (new_interval, budget) = interval_and_budget(cfg);
+    if new_interval != *interval {
+        *interval = new_interval;
+        *next_wake = start + new_interval;
+    }
+    budget
+}
+
+/// Runs checks every interval until we're told to stop, reloading the config when it changes.
+/// With `json_output`, each check is also printed to stdout as a line of JSON.
+fn run_daemon(
+    cfg: Config,
+    cli_overrides: Vec<overrides::Override>,
+    json_output: bool,
+) -> Result<()> {
+    let (mut interval, _) = interval_and_budget(&cfg);
     let should_term = Arc::new(AtomicBool::new(false));
     let st_for_hnd = should_term.clone();
-    let (mut timer, canceller) = cancellable_timer::Timer::new2()?;
-    let mut bbat_notifs = HashMap::new();
+    let (timer, canceller) = cancellable_timer::Timer::new2()?;
+    let mut clock = clock::SystemClock::cancellable(timer);
+    let mut pause = Pause::Running;
+    let mut published_pause = None;
 
-    env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
+    let cfg_path = confy::get_configuration_file_path("battery-notify", "config")?;
+    info!(
+        "Config (configurable at {}):\n\n{:#?}\n",
+        cfg_path.display(),
+        cfg
+    );
+
+    let (req_tx, req_rx) = mpsc::channel();
+    let status = service::SharedStatus::default();
+    {
+        let mut status = status.lock().unwrap();
+        status.backends = version::backends(&cfg);
+        status.sleep_pct = cfg.sleep_pct;
+    }
+    let (reload_tx, reload_canceller) = (req_tx.clone(), canceller.clone());
+    let (buttons_tx, buttons_canceller) = (req_tx.clone(), canceller.clone());
+    // Shared by button commands and the engine, which sets what they're run with
+    let commands = command::Context::default();
+    let service = if cfg.dbus_service {
+        service::start(req_tx, canceller.clone(), status.clone()).unwrap_or_else(|err| {
+            error!("failed to start D-Bus service: {err}");
+            None
+        })
+    } else {
+        None
+    };
+
+    if cfg.watch_uevents {
+        udev::spawn(canceller.clone()).unwrap_or_else(|err| {
+            error!("failed to watch for uevents, only polling: {err:#}");
+        });
+    }
+
+    // Relayed notifications don't have buttons, since the relays show them
+    if !cfg.system_mode {
+        buttons::spawn(buttons_tx, buttons_canceller, commands.clone()).unwrap_or_else(|err| {
+            info!("Can't listen for notification button clicks, not showing buttons: {err:#}");
+        });
+    }
 
     ctrlc::set_handler(move || {
         st_for_hnd.store(true, Ordering::Relaxed);
@@ -70,111 +238,174 @@ fn main() -> Result<()> {
     })
     .expect("Failed to set signal handler");
 
-    info!(
-        "Config (configurable at {}):\n\n{:#?}\n",
-        confy::get_configuration_file_path("battery-notify", "config")?.display(),
-        cfg
-    );
+    // This takes SIGHUP back from ctrlc, so must come after it
+    reload::spawn(&cfg_path, reload_tx, reload_canceller).unwrap_or_else(|err| {
+        error!("failed to watch for config changes, only reloading on restart: {err:#}");
+    });
 
-    let mut next_wake = Instant::now() + interval;
+    let watchdog = watchdog::Watchdog::spawn(Duration::from_secs(cfg.watchdog_secs))?;
+    let sink = notification_sink(&cfg);
+    let mut engine =
+        engine::Engine::new(cfg, Box::new(clock::SystemClock::default()), sink, commands)?;
+    let mut next_wake = clock.now() + interval;
 
-    sd_notify::notify(
-        false,
-        &[
-            sd_notify::NotifyState::Ready,
-            // Grace period in case interval takes too long
-            sd_notify::NotifyState::WatchdogUsec((interval * 2).as_micros().try_into()?),
-        ],
-    )?;
+    sd_notify::notify(false, &[sd_notify::NotifyState::Ready])?;
 
     while !should_term.load(Ordering::Relaxed) {
-        sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog])?;
-        let start = Instant::now();
-        let batteries = system::get_batteries().context("failed to get list of batteries")?;
-
-        if batteries.is_empty() {
-            bail!("no batteries detected");
-        }
+        watchdog.busy();
+        let start = clock.now();
+        let mut cycle = timing::CycleTimer::new(start);
+        broadcast::refresh_sessions();
 
-        info!("Battery status: {:?}", &batteries);
-
-        let global = system::get_global_battery(&batteries);
-        info!("Global status: {:?}", &global);
-        state_notif.show(
-            format!(
-                "Battery now {}",
-                system::battery_state_to_name(global.state).to_lowercase()
-            ),
-            Urgency::Normal,
-        );
-
-        let level = global.level();
-
-        if global.state == system::BatteryState::Charging || level > cfg.low_pct {
-            low_notif.close();
-        } else if level <= cfg.sleep_pct {
-            low_notif.show("Battery critical".to_string(), Urgency::Critical);
-            // Just in case we've gone loco, don't do this more than once a minute
-            if start > next_sleep_epoch {
-                next_sleep_epoch = start + sleep_backoff;
-                run_sleep_command(&cfg.sleep_command);
+        // Editors can write the file several times in a row, so only reload once
+        let mut reload = false;
+        for req in req_rx.try_iter() {
+            info!("Handling request: {req:?}");
+            match req {
+                service::Request::Pause(duration) => pause.set(Pause::new(duration, start)),
+                service::Request::Resume => pause.set(Pause::Running),
+                service::Request::Reload => reload = true,
+                req => engine.handle(req, start),
             }
-        } else if level <= cfg.low_pct {
-            low_notif.show("Battery low".to_string(), Urgency::Critical);
-        }
-
-        if cfg.warn_on_mons_with_no_ac > 0 && global.state == system::BatteryState::Discharging {
-            let conn = monitors::get_nr_connected().unwrap_or_else(|err| {
-                error!("{err}");
-                0
-            });
-            info!("Current connected monitors: {conn}");
-            if conn >= cfg.warn_on_mons_with_no_ac {
-                mon_notif.show(
-                    format!("Connected to {} monitors but not AC", conn),
-                    Urgency::Critical,
-                );
-            } else {
-                mon_notif.close()
+        }
+        if reload {
+            notify_reloading();
+            if let Err(err) = reload_config(&mut engine, &cli_overrides) {
+                error!("failed to reload config, keeping the old one: {err:#}");
             }
-        } else {
-            mon_notif.close();
-        }
-
-        if cfg.bluetooth_low_pct != 0 {
-            let bbats = bluetooth::get_battery_levels().unwrap_or_else(|err| {
-                error!("{err}");
-                Vec::new()
-            });
-            info!("Bluetooth battery status: {:?}", bbats);
-            for bbat in &bbats {
-                let (_, notif) = bbat_notifs
-                    .raw_entry_mut()
-                    .from_key(&bbat.name)
-                    .or_insert_with(|| (bbat.name.clone(), SingleNotification::default()));
-                if bbat.level <= cfg.bluetooth_low_pct {
-                    notif.show(format!("{} battery low", bbat.name), Urgency::Critical);
-                } else {
-                    notif.close();
-                }
+            if let Err(err) = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]) {
+                error!("failed to tell systemd we're done reloading: {err}");
             }
+        }
+        retime(engine.config(), start, &mut interval, &mut next_wake);
+
+        cycle.mark("requests");
 
-            // Get rid of any non-present devices and close the notification through Drop
-            bbat_notifs.retain(|key, _| bbats.iter().any(|b| b.name == *key));
+        let paused = pause.active(start);
+        if published_pause != Some(pause) {
+            status.lock().unwrap().pause = pause.status();
+            published_pause = Some(pause);
+        }
+        if paused {
+            watchdog.idle();
+            next_wake = clock::sleep_until(&mut clock, next_wake, interval)?;
+            continue;
         }
 
-        let now = Instant::now();
-        if now < next_wake {
-            match timer.sleep(next_wake - now) {
-                Err(err) if err.kind() != io::ErrorKind::Interrupted => Err(err),
-                _ => Ok(()),
-            }?;
-            next_wake += interval;
-        } else {
-            // Avoid spamming with more runs
-            next_wake = now + interval;
+        let report = engine.cycle(start, &mut cycle)?;
+        // The cycle may have switched profiles
+        let budget = retime(engine.config(), start, &mut interval, &mut next_wake);
+        if json_output {
+            println!("{}", status::report_json(&report, &engine.config().theme));
+        }
+
+        cycle.mark("status");
+        let timings = cycle.finish(budget);
+        {
+            let mut status = status.lock().unwrap();
+            status.level = report.global.level();
+            status.state = system::battery_state_to_lowercase_name(report.global.state).to_string();
+            status.low_pct = engine.low_pct();
+            status.sleep_pct = engine.config().sleep_pct;
+            status.silence = engine.silence();
+            status.profile = engine.profile().unwrap_or_default().to_string();
+            status.held_heavy_commands = engine.held_heavy_commands();
+            status.alarms = report.alarms;
+            status.cycle_timings = timings;
+        }
+        if let Some(service) = &service {
+            service.publish(&status);
         }
+
+        watchdog.idle();
+        if let Some(early) = engine.early_check() {
+            next_wake = next_wake.min(early);
+        }
+        next_wake = clock::sleep_until(&mut clock, next_wake, interval)?;
+    }
+
+    Ok(())
+}
+
+/// Where notifications go: to the relays in system mode, or our own session otherwise.
+fn notification_sink(cfg: &Config) -> notification::Sink {
+    if cfg.system_mode {
+        info!("Running in system mode, relaying notifications to graphical sessions");
+        broadcast::enable()
+    } else {
+        notification::Sink::default()
     }
+}
 
+/// Runs a single cycle, for `battery-notify once`.
+fn run_once(mut cfg: Config) -> Result<()> {
+    // A one-off run isn't carrying anything on, and shouldn't disturb what the daemon saved
+    cfg.restore_state = false;
+    let start = Instant::now();
+    let mut cycle = timing::CycleTimer::new(start);
+    let sink = notification_sink(&cfg);
+    let mut engine = engine::Engine::new(
+        cfg,
+        Box::new(clock::SystemClock::default()),
+        sink,
+        command::Context::default(),
+    )?;
+    broadcast::refresh_sessions();
+    let alarms = engine.cycle(start, &mut cycle)?.alarms;
+    if alarms.is_empty() {
+        println!("Active alarms: none");
+    } else {
+        println!("Active alarms: {}", alarms.join(", "));
+    }
+    // Dropping the engine as usual would close every notification it just showed
+    engine.detach();
     Ok(())
 }
+
+fn main() -> Result<()> {
+    logging::init();
+
+    let args = cli::parse(std::env::args_os()).unwrap_or_else(|err| err.exit());
+    if args.version {
+        if args.verbose {
+            println!("{}", version::verbose(&config::load(args.overrides)?));
+        } else {
+            println!("{}", version::short());
+        }
+        return Ok(());
+    }
+    match args.command.unwrap_or(cli::Command::Daemon) {
+        cli::Command::Daemon => {
+            let cfg = config::load(args.overrides.clone())?;
+            let _pidfile = if args.daemonize {
+                Some(daemonize::daemonize()?)
+            } else {
+                None
+            };
+            run_daemon(cfg, args.overrides, args.output.is_some())
+        }
+        cli::Command::SelfTest => selftest::run(&config::load(args.overrides)?),
+        cli::Command::DebugDump => debugdump::run(&config::load(args.overrides)?),
+        cli::Command::Init => init::run(),
+        cli::Command::InstallAutostart => autostart::install(),
+        cli::Command::Pause { duration } => service::pause(duration),
+        cli::Command::Resume => service::resume(),
+        cli::Command::Snooze { duration } => service::snooze(duration.0),
+        cli::Command::Profile { name } => {
+            service::set_profile((name != "auto").then_some(name.as_str()))
+        }
+        cli::Command::History { since } => {
+            // Nothing else from the config matters here, but times are shown with time_format
+            config::load(args.overrides)?;
+            history::run(since)
+        }
+        cli::Command::SetThreshold { start, end } => thresholds::run(start, end),
+        cli::Command::Once => run_once(config::load(args.overrides)?),
+        cli::Command::CheckConfig => checkconfig::run(args.overrides),
+        cli::Command::Status { history, json } => {
+            status::run(&config::load(args.overrides)?, history, json)
+        }
+        cli::Command::Man => man::run(Config::default()),
+        cli::Command::Relay => broadcast::run_relay(),
+    }
+}
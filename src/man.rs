@@ -0,0 +1,217 @@
+//! Generates a man page. The synopsis and options come from the command line definition, config
+//! settings from the defaults themselves, and their descriptions from the comments in the README's
+//! default config, so none of them can drift from the code.
+
+use anyhow::Result;
+use clap::CommandFactory;
+use hashbrown::HashMap;
+use serde::Serialize;
+use toml::{Table, Value};
+
+use crate::cli;
+
+const README: &str = include_str!("../README.md");
+
+/// Escapes text for roff, including things which would otherwise be taken as requests at the start
+/// of a line.
+fn escape(text: &str) -> String {
+    let text = text.replace('\\', "\\e").replace('-', "\\-");
+    if text.starts_with(['.', '\'']) {
+        format!("\\&{text}")
+    } else {
+        text
+    }
+}
+
+/// Pulls the description of each setting out of the comments in the README's default config, keyed
+/// by dotted path. Settings listed together without a blank line share the comment above them.
+fn readme_descriptions() -> HashMap<String, String> {
+    let mut descs = HashMap::new();
+    let Some((_, block)) = README.split_once("```toml\n") else {
+        return descs;
+    };
+    let block = block.split("```").next().unwrap_or_default();
+
+    let mut table = String::new();
+    let mut comment: Vec<&str> = Vec::new();
+    let mut last_desc = None;
+    for line in block.lines() {
+        let line = line.trim();
+        if let Some(text) = line.strip_prefix('#') {
+            comment.push(text.strip_prefix(' ').unwrap_or(text));
+            last_desc = None;
+        } else if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            table = format!("{name}.");
+            descs.insert(name.to_string(), comment.join("\n"));
+            comment.clear();
+        } else if let Some((key, _)) = line.split_once('=') {
+            let desc = last_desc.take().unwrap_or_else(|| comment.join("\n"));
+            descs.insert(format!("{table}{}", key.trim()), desc.clone());
+            last_desc = Some(desc);
+            comment.clear();
+        } else if line.is_empty() {
+            last_desc = None;
+        }
+    }
+    descs
+}
+
+/// Flattens settings into dotted paths and their defaults. Empty tables are kept, since those are
+/// ones which take arbitrary keys.
+fn flatten(prefix: &str, table: &Table, out: &mut Vec<(String, Option<Value>)>) {
+    let (tables, values): (Vec<_>, Vec<_>) = table.iter().partition(|(_, v)| v.is_table());
+    for (key, val) in values {
+        out.push((format!("{prefix}{key}"), Some(val.clone())));
+    }
+    for (key, val) in tables {
+        let sub = val.as_table().expect("partitioned on is_table");
+        if sub.is_empty() {
+            out.push((format!("{prefix}{key}"), None));
+        } else {
+            flatten(&format!("{prefix}{key}."), sub, out);
+        }
+    }
+}
+
+/// Looks up the description for a setting. Tables with the same shape, like the ones under
+/// [notifications], are only commented once in the README, so fall back to the same key in a
+/// sibling table.
+fn describe<'a>(descs: &'a HashMap<String, String>, key: &str) -> Option<&'a String> {
+    let nonempty = |d: &&String| !d.is_empty();
+    descs.get(key).filter(nonempty).or_else(|| {
+        let (table, leaf) = key.rsplit_once('.')?;
+        let (parent, _) = table.rsplit_once('.')?;
+        descs
+            .iter()
+            .filter(|(k, _)| {
+                k.starts_with(&format!("{parent}.")) && k.ends_with(&format!(".{leaf}"))
+            })
+            .filter(|(k, _)| k.matches('.').count() == key.matches('.').count())
+            .map(|(_, d)| d)
+            .find(nonempty)
+    })
+}
+
+fn push_entries(out: &mut String, entries: &[(&str, &str)]) {
+    for (name, desc) in entries {
+        out.push_str(&format!(".TP\n.B {}\n{}\n", escape(name), escape(desc)));
+    }
+}
+
+/// A subcommand with its arguments, like "status [--history] [--json]".
+fn usage(cmd: &clap::Command) -> String {
+    let mut usage = cmd.get_name().to_string();
+    for arg in cmd.get_arguments() {
+        if arg.is_hide_set() || arg.is_global_set() || arg.get_id() == "help" {
+            continue;
+        }
+        let value = arg
+            .get_value_names()
+            .filter(|_| arg.get_action().takes_values())
+            .map(|names| names.join(" "))
+            .unwrap_or_default();
+        let arg = match arg.get_long() {
+            Some(long) if value.is_empty() => format!("[--{long}]"),
+            Some(long) => format!("[--{long} {value}]"),
+            None if arg.is_required_set() => value,
+            None => format!("[{value}]"),
+        };
+        usage.push(' ');
+        usage.push_str(&arg);
+    }
+    usage
+}
+
+pub fn render(defaults: &Table) -> Result<String> {
+    let man = clap_mangen::Man::new(cli::Args::command())
+        .title("BATTERY-NOTIFY")
+        .manual("User Commands");
+    let mut buf = Vec::new();
+    man.render_title(&mut buf)?;
+    man.render_name_section(&mut buf)?;
+    man.render_synopsis_section(&mut buf)?;
+    let mut out = String::from_utf8(buf)?;
+
+    out.push_str(".SH COMMANDS\n");
+    let cmd = cli::Args::command();
+    for sub in cmd.get_subcommands().filter(|sub| !sub.is_hide_set()) {
+        let about = sub.get_about().map(|a| a.to_string()).unwrap_or_default();
+        push_entries(&mut out, &[(&usage(sub), &about)]);
+    }
+    let mut buf = Vec::new();
+    man.render_options_section(&mut buf)?;
+    out.push_str(&String::from_utf8(buf)?);
+
+    out.push_str(
+        ".SH CONFIGURATION\n\
+         Settings are read from \\fI~/.config/battery\\-notify/config.toml\\fR. Settings inside \
+         tables are shown with a dot, like \\fBevents.sleep.command\\fR, which is also how to \
+         refer to them with \\fB\\-\\-set\\fR.\n",
+    );
+    let descs = readme_descriptions();
+    let mut settings = Vec::new();
+    flatten("", defaults, &mut settings);
+    for (key, default) in settings {
+        out.push_str(&format!(".TP\n.B {}\n", escape(&key)));
+        if let Some(default) = default {
+            out.push_str(&format!("Default: {}\n.br\n", escape(&default.to_string())));
+        }
+        if let Some(desc) = describe(&descs, &key) {
+            for line in desc.lines() {
+                out.push_str(&if line.is_empty() {
+                    ".sp\n".to_string()
+                } else {
+                    format!("{}\n", escape(line))
+                });
+            }
+        }
+    }
+
+    out.push_str(
+        ".SH ENVIRONMENT\n\
+         .TP\n\
+         .B BATTERY_NOTIFY_*\n\
+         Override a config setting, named in upper case. Settings inside tables are separated \
+         with a double underscore, like \\fBBATTERY_NOTIFY_EVENTS__SLEEP__COMMAND\\fR.\n\
+         .TP\n\
+         .B RUST_LOG\n\
+         Log filter, like \\fBdebug\\fR or \\fBnone\\fR. Defaults to \\fBinfo\\fR.\n",
+    );
+    out.push_str(".SH EVENT COMMANDS\nCommands we run get these environment variables.\n");
+    push_entries(&mut out, cli::EVENT_ENV);
+    out.push_str(&format!(
+        ".SH SEE ALSO\n{}\n",
+        escape(env!("CARGO_PKG_REPOSITORY"))
+    ));
+    Ok(out)
+}
+
+pub fn run(defaults: impl Serialize) -> Result<()> {
+    print!("{}", render(&Table::try_from(defaults)?)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_setting_described() {
+        let descs = readme_descriptions();
+        let mut settings = Vec::new();
+        flatten(
+            "",
+            &Table::try_from(crate::Config::default()).unwrap(),
+            &mut settings,
+        );
+        let missing: Vec<_> = settings
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| describe(&descs, key).is_none())
+            .collect();
+        assert!(
+            missing.is_empty(),
+            "not described in the README: {missing:?}"
+        );
+    }
+}
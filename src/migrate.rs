@@ -0,0 +1,152 @@
+//! Upgrades configs written for older versions of battery-notify, so that renaming or moving keys
+//! doesn't silently reset people to the defaults.
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use std::fs;
+use std::io;
+use std::path::Path;
+use toml_edit::{value, DocumentMut, Item, Key, Table};
+
+pub const CURRENT_VERSION: u32 = 2;
+
+/// `MIGRATIONS[n]` upgrades a config from version `n + 1` to `n + 2`. Configs from before
+/// versioning existed have no version key, and are treated as version 1. These edit the document
+/// in place, so that comments and formatting survive.
+const MIGRATIONS: &[fn(&mut DocumentMut)] = &[v1_sleep_event];
+
+/// Moves sleep_command and friends into [events.sleep].
+fn v1_sleep_event(cfg: &mut DocumentMut) {
+    let mut sleep = Table::new();
+    for (old, new) in [
+        ("sleep_command", "command"),
+        ("sleep_command_retries", "retries"),
+        ("sleep_command_retry_delay_secs", "retry_delay_secs"),
+        ("sleep_command_min_interval_secs", "min_interval_secs"),
+    ] {
+        if let Some((key, val)) = cfg.remove_entry(old) {
+            // Comments above the key are part of its decor, so bring them along
+            let key = Key::new(new).with_leaf_decor(key.leaf_decor().clone());
+            sleep.insert_formatted(&key, val);
+        }
+    }
+    if sleep.is_empty() {
+        return;
+    }
+    let events = cfg.entry("events").or_insert_with(|| {
+        // Only there to hold [events.sleep], so don't write out an empty [events]
+        let mut events = Table::new();
+        events.set_implicit(true);
+        Item::Table(events)
+    });
+    if let Some(events) = events.as_table_mut() {
+        events.insert("sleep", Item::Table(sleep));
+    }
+}
+
+fn version(cfg: &DocumentMut) -> Result<u32> {
+    match cfg.get("version") {
+        None => Ok(1),
+        Some(val) => val
+            .as_integer()
+            .and_then(|v| u32::try_from(v).ok())
+            .filter(|&v| v > 0)
+            .context("config version must be a positive integer"),
+    }
+}
+
+/// Migrates the config at `path` to the current version if it's older, keeping the original next
+/// to it with a `.v<version>.bak` suffix.
+pub fn migrate_file(path: &Path) -> Result<()> {
+    let orig = match fs::read_to_string(path) {
+        Ok(orig) => orig,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut cfg: DocumentMut = orig
+        .parse()
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+
+    let from = version(&cfg)?;
+    if from == CURRENT_VERSION {
+        return Ok(());
+    }
+    if from > CURRENT_VERSION {
+        warn!(
+            "Config version {from} is newer than this battery-notify supports \
+             ({CURRENT_VERSION}), some settings may be ignored"
+        );
+        return Ok(());
+    }
+
+    for migration in &MIGRATIONS[from as usize - 1..] {
+        migration(&mut cfg);
+    }
+    cfg.insert("version", value(i64::from(CURRENT_VERSION)));
+
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".v{from}.bak"));
+    if Path::new(&backup).exists() {
+        bail!(
+            "not migrating config, backup {} already exists",
+            Path::new(&backup).display()
+        );
+    }
+    fs::write(&backup, orig)?;
+    fs::write(path, cfg.to_string())?;
+    info!(
+        "Migrated config from version {from} to {CURRENT_VERSION}, original saved to {}",
+        Path::new(&backup).display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc(s: &str) -> DocumentMut {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn version_key() {
+        assert_eq!(version(&doc("")).unwrap(), 1);
+        assert_eq!(version(&doc("version = 2")).unwrap(), 2);
+        assert!(version(&doc("version = 0")).is_err());
+        assert!(version(&doc("version = 'two'")).is_err());
+    }
+
+    #[test]
+    fn migrate_file_moves_sleep_keys() {
+        let dir =
+            std::env::temp_dir().join(format!("battery-notify-migrate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let orig = "# Warn early\nlow_pct = 30\n# Hibernate instead\nsleep_command = 'true'\nsleep_command_retries = 2\n";
+        fs::write(&path, orig).unwrap();
+
+        migrate_file(&path).unwrap();
+        let migrated = fs::read_to_string(&path).unwrap();
+        let backup = fs::read_to_string(dir.join("config.toml.v1.bak")).unwrap();
+        // Nothing to do the second time round
+        migrate_file(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(backup, orig);
+        assert_eq!(
+            migrated,
+            "# Warn early\nlow_pct = 30\nversion = 2\n\n[events.sleep]\n# Hibernate instead\ncommand = 'true'\nretries = 2\n"
+        );
+    }
+
+    #[test]
+    fn v1_sleep_event_existing_events() {
+        let mut cfg = doc("sleep_command = 'true'\n\n[events.ac_connected]\ncommand = 'x'\n");
+        v1_sleep_event(&mut cfg);
+        assert_eq!(
+            cfg.to_string(),
+            "\n[events.ac_connected]\ncommand = 'x'\n\n[events.sleep]\ncommand = 'true'\n"
+        );
+    }
+}
@@ -1,32 +1,459 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::config::Config;
+use crate::notification::{Category, SingleNotification, Sink};
+use crate::system::{Battery, BatteryState};
+use crate::timing;
 
 #[cfg(feature = "mons")]
-pub fn get_nr_connected() -> Result<usize> {
+mod x11 {
+    use anyhow::{anyhow, Result};
     use once_cell::sync::Lazy;
+    use x11rb::errors::ConnectError;
     use x11rb::{connection::Connection, protocol::randr, rust_connection::RustConnection};
 
-    static CONN_AND_ROOT: Lazy<(RustConnection, u32)> = Lazy::new(|| {
-        x11rb::connect(None)
-            .map(|(c, screen)| {
-                let root = c.setup().roots[screen].root;
-                (c, root)
-            })
-            .unwrap()
+    static CONN_AND_ROOT: Lazy<Result<(RustConnection, u32), ConnectError>> = Lazy::new(|| {
+        x11rb::connect(None).map(|(c, screen)| {
+            let root = c.setup().roots[screen].root;
+            (c, root)
+        })
     });
 
-    let (conn, root) = Lazy::force(&CONN_AND_ROOT);
-    let resources = randr::get_screen_resources(conn, *root)?;
-    let mut nr_connected = 0;
-    for output in resources.reply()?.outputs {
-        let output_info = randr::get_output_info(conn, output, 0)?.reply()?;
-        if output_info.connection == randr::Connection::CONNECTED {
-            nr_connected += 1;
+    /// Returns the name of each output, and whether it is connected.
+    pub fn get_outputs() -> Result<Vec<(String, bool)>> {
+        let (conn, root) = Lazy::force(&CONN_AND_ROOT)
+            .as_ref()
+            .map_err(|err| anyhow!("failed to connect to X server: {err}"))?;
+        let resources = randr::get_screen_resources(conn, *root)?;
+        // Send all the requests before waiting on any replies, so we only pay for one round trip
+        let cookies = resources
+            .reply()?
+            .outputs
+            .into_iter()
+            .map(|output| randr::get_output_info(conn, output, 0))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut outputs = Vec::new();
+        for cookie in cookies {
+            let output_info = cookie.reply()?;
+            outputs.push((
+                String::from_utf8_lossy(&output_info.name).into_owned(),
+                output_info.connection == randr::Connection::CONNECTED,
+            ));
+        }
+        Ok(outputs)
+    }
+}
+
+/// Just enough of the Wayland wire protocol to list outputs, the same way wayland-info does. This
+/// works on any compositor, but they only advertise outputs which are enabled.
+#[cfg(feature = "wayland")]
+mod wayland {
+    use anyhow::{bail, Context, Result};
+    use std::env;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    const DISPLAY_ID: u32 = 1;
+    const REGISTRY_ID: u32 = 2;
+    const FIRST_FREE_ID: u32 = 3;
+
+    // Opcodes, from wayland.xml
+    const DISPLAY_SYNC: u16 = 0;
+    const DISPLAY_GET_REGISTRY: u16 = 1;
+    const DISPLAY_ERROR: u16 = 0;
+    const REGISTRY_BIND: u16 = 0;
+    const REGISTRY_GLOBAL: u16 = 0;
+    const CALLBACK_DONE: u16 = 0;
+    const OUTPUT_NAME: u16 = 4;
+
+    /// wl_output.name only exists from version 4.
+    const OUTPUT_NAME_VERSION: u32 = 4;
+
+    fn socket_path() -> Result<PathBuf> {
+        let display = env::var_os("WAYLAND_DISPLAY").context("WAYLAND_DISPLAY is not set")?;
+        let display = PathBuf::from(display);
+        if display.is_absolute() {
+            return Ok(display);
+        }
+        let runtime = env::var_os("XDG_RUNTIME_DIR").context("XDG_RUNTIME_DIR is not set")?;
+        Ok(PathBuf::from(runtime).join(display))
+    }
+
+    struct Message {
+        object: u32,
+        opcode: u16,
+        args: Vec<u8>,
+    }
+
+    /// Reads arguments from an event, in order.
+    struct Args<'a>(&'a [u8]);
+
+    impl Args<'_> {
+        fn uint(&mut self) -> Result<u32> {
+            let Some((val, rest)) = self.0.split_first_chunk() else {
+                bail!("truncated Wayland message");
+            };
+            self.0 = rest;
+            Ok(u32::from_ne_bytes(*val))
+        }
+
+        /// Strings are length prefixed, including a trailing NUL, and padded to 32 bits.
+        fn string(&mut self) -> Result<String> {
+            let len = self.uint()? as usize;
+            let padded = len.next_multiple_of(4);
+            if padded > self.0.len() || len == 0 {
+                bail!("truncated Wayland message");
+            }
+            let val = String::from_utf8_lossy(&self.0[..len - 1]).into_owned();
+            self.0 = &self.0[padded..];
+            Ok(val)
+        }
+    }
+
+    fn push_string(args: &mut Vec<u8>, val: &str) {
+        args.extend(u32::try_from(val.len() + 1).unwrap().to_ne_bytes());
+        args.extend(val.as_bytes());
+        args.push(0);
+        args.resize(args.len().next_multiple_of(4), 0);
+    }
+
+    struct Client {
+        stream: UnixStream,
+        next_id: u32,
+    }
+
+    impl Client {
+        fn connect() -> Result<Self> {
+            let stream = UnixStream::connect(socket_path()?)
+                .context("failed to connect to Wayland compositor")?;
+            // Don't hang the main loop on a wedged compositor
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+            Ok(Self {
+                stream,
+                next_id: FIRST_FREE_ID,
+            })
+        }
+
+        fn new_id(&mut self) -> u32 {
+            self.next_id += 1;
+            self.next_id - 1
+        }
+
+        fn request(&mut self, object: u32, opcode: u16, args: &[u8]) -> Result<()> {
+            let size = u32::try_from(8 + args.len())?;
+            let mut msg = Vec::with_capacity(size as usize);
+            msg.extend(object.to_ne_bytes());
+            msg.extend((size << 16 | u32::from(opcode)).to_ne_bytes());
+            msg.extend(args);
+            Ok(self.stream.write_all(&msg)?)
+        }
+
+        fn read(&mut self) -> Result<Message> {
+            let mut header = [0; 8];
+            self.stream.read_exact(&mut header)?;
+            let object = u32::from_ne_bytes(header[..4].try_into()?);
+            let size_opcode = u32::from_ne_bytes(header[4..].try_into()?);
+            let mut args = vec![0; ((size_opcode >> 16) as usize).saturating_sub(8)];
+            self.stream.read_exact(&mut args)?;
+            Ok(Message {
+                object,
+                opcode: (size_opcode & 0xffff) as u16,
+                args,
+            })
+        }
+
+        /// Handles events until the compositor has processed everything we've sent so far.
+        fn roundtrip(&mut self, mut handle: impl FnMut(Message) -> Result<()>) -> Result<()> {
+            let callback = self.new_id();
+            self.request(DISPLAY_ID, DISPLAY_SYNC, &callback.to_ne_bytes())?;
+            loop {
+                let msg = self.read()?;
+                match (msg.object, msg.opcode) {
+                    (id, CALLBACK_DONE) if id == callback => return Ok(()),
+                    (DISPLAY_ID, DISPLAY_ERROR) => {
+                        let mut args = Args(&msg.args);
+                        let (_, code) = (args.uint()?, args.uint()?);
+                        bail!("Wayland error {code}: {}", args.string()?);
+                    }
+                    _ => handle(msg)?,
+                }
+            }
+        }
+    }
+
+    /// Returns the name of each output, all of which are connected.
+    pub fn get_outputs() -> Result<Vec<(String, bool)>> {
+        let mut client = Client::connect()?;
+        client.request(DISPLAY_ID, DISPLAY_GET_REGISTRY, &REGISTRY_ID.to_ne_bytes())?;
+        let mut globals = Vec::new();
+        client.roundtrip(|msg| {
+            if msg.object == REGISTRY_ID && msg.opcode == REGISTRY_GLOBAL {
+                let mut args = Args(&msg.args);
+                let (name, interface, version) = (args.uint()?, args.string()?, args.uint()?);
+                if interface == "wl_output" {
+                    globals.push((name, version));
+                }
+            }
+            Ok(())
+        })?;
+
+        // Outputs only tell us their names once bound
+        let mut outputs = Vec::new();
+        for (name, version) in globals {
+            let id = client.new_id();
+            let mut args = name.to_ne_bytes().to_vec();
+            push_string(&mut args, "wl_output");
+            args.extend(version.min(OUTPUT_NAME_VERSION).to_ne_bytes());
+            args.extend(id.to_ne_bytes());
+            client.request(REGISTRY_ID, REGISTRY_BIND, &args)?;
+            outputs.push((id, format!("wl_output-{name}")));
+        }
+        if !outputs.is_empty() {
+            client.roundtrip(|msg| {
+                if msg.opcode == OUTPUT_NAME {
+                    if let Some((_, name)) = outputs.iter_mut().find(|(id, _)| *id == msg.object) {
+                        *name = Args(&msg.args).string()?;
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        Ok(outputs.into_iter().map(|(_, name)| (name, true)).collect())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn args_decoding() {
+            let mut buf = 7u32.to_ne_bytes().to_vec();
+            push_string(&mut buf, "wl_output");
+            push_string(&mut buf, "abc");
+            buf.extend(4u32.to_ne_bytes());
+            assert_eq!(buf.len(), 4 + (4 + 12) + (4 + 4) + 4);
+
+            let mut args = Args(&buf);
+            assert_eq!(args.uint().unwrap(), 7);
+            assert_eq!(args.string().unwrap(), "wl_output");
+            assert_eq!(args.string().unwrap(), "abc");
+            assert_eq!(args.uint().unwrap(), 4);
+            assert!(args.uint().is_err());
+        }
+
+        #[test]
+        fn args_truncated() {
+            let mut buf = Vec::new();
+            push_string(&mut buf, "wl_output");
+            assert!(Args(&buf[..8]).string().is_err());
+            assert!(Args(&0u32.to_ne_bytes()).string().is_err());
+            assert!(Args(&[1, 2]).uint().is_err());
+        }
+    }
+}
+
+/// Connectors from the kernel's DRM drivers in sysfs. This needs no display server at all, but
+/// can't tell which monitors the session has turned off.
+mod drm {
+    use anyhow::{Context, Result};
+    use std::fs;
+
+    const DRM_DIR: &str = "/sys/class/drm";
+
+    /// Returns the name of each connector, and whether something is plugged into it.
+    pub fn get_outputs() -> Result<Vec<(String, bool)>> {
+        let mut outputs = Vec::new();
+        for entry in fs::read_dir(DRM_DIR).with_context(|| format!("failed to read {DRM_DIR}"))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Connectors are like card0-eDP-1, next to the cards themselves and render nodes
+            let Some((card, connector)) = name.split_once('-') else {
+                continue;
+            };
+            if !card.starts_with("card") {
+                continue;
+            }
+            let Ok(status) = fs::read_to_string(entry.path().join("status")) else {
+                continue;
+            };
+            outputs.push((connector.to_string(), status.trim() == "connected"));
+        }
+        outputs.sort();
+        Ok(outputs)
+    }
+}
+
+/// Where to find out which monitors are connected.
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Whichever of the others works first, in the order below.
+    #[default]
+    Auto,
+    Wayland,
+    Randr,
+    Drm,
+}
+
+/// What auto used last time, so we only log when it changes.
+static CHOSEN: Mutex<Option<Backend>> = Mutex::new(None);
+
+impl Backend {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Wayland => "wayland",
+            Self::Randr => "randr",
+            Self::Drm => "drm",
+        }
+    }
+
+    const fn built(self) -> bool {
+        match self {
+            Self::Wayland => cfg!(feature = "wayland"),
+            Self::Randr => cfg!(feature = "mons"),
+            Self::Auto | Self::Drm => true,
+        }
+    }
+
+    /// What auto tries in this session, in order. DRM always works, so it's always last.
+    fn candidates() -> Vec<Self> {
+        let session = |var| std::env::var_os(var).is_some();
+        [
+            (Self::Wayland, session("WAYLAND_DISPLAY")),
+            (Self::Randr, session("DISPLAY")),
+            (Self::Drm, true),
+        ]
+        .into_iter()
+        .filter(|(backend, usable)| *usable && backend.built())
+        .map(|(backend, _)| backend)
+        .collect()
+    }
+
+    fn get_outputs(self) -> Result<Vec<(String, bool)>> {
+        match self {
+            #[cfg(feature = "wayland")]
+            Self::Wayland => wayland::get_outputs(),
+            #[cfg(feature = "mons")]
+            Self::Randr => x11::get_outputs(),
+            Self::Drm => drm::get_outputs(),
+            Self::Auto => auto_outputs(),
+            #[allow(unreachable_patterns)]
+            other => bail!(
+                "built without support for monitors_backend {}",
+                other.name()
+            ),
         }
     }
-    Ok(nr_connected)
 }
 
-#[cfg(not(feature = "mons"))]
-pub fn get_nr_connected() -> Result<usize> {
-    Ok(0)
+/// Tries each of Backend::candidates() until one works.
+fn auto_outputs() -> Result<Vec<(String, bool)>> {
+    let mut errors = Vec::new();
+    for backend in Backend::candidates() {
+        match backend.get_outputs() {
+            Ok(outputs) => {
+                let mut chosen = CHOSEN.lock().unwrap();
+                if *chosen != Some(backend) {
+                    info!(backend = backend.name(); "Using {} to find monitors", backend.name());
+                    *chosen = Some(backend);
+                }
+                return Ok(outputs);
+            }
+            Err(err) => {
+                debug!("Couldn't find monitors with {}: {err:#}", backend.name());
+                errors.push(format!("{}: {err:#}", backend.name()));
+            }
+        }
+    }
+    bail!("failed to find monitors ({})", errors.join(", "))
+}
+
+/// Returns the name of each output, and whether it is connected, from `backend`.
+pub fn get_outputs(backend: Backend) -> Result<Vec<(String, bool)>> {
+    backend.get_outputs()
+}
+
+/// Which backend get_outputs() uses for `backend`: for auto, the one which worked last, or if
+/// there hasn't been one yet, the one it'll try first.
+pub fn backend(backend: Backend) -> &'static str {
+    match backend {
+        Backend::Auto => CHOSEN
+            .lock()
+            .unwrap()
+            .or_else(|| Backend::candidates().first().copied())
+            .map_or("none", Backend::name),
+        backend if backend.built() => backend.name(),
+        _ => "not built",
+    }
+}
+
+/// Checks monitors_backend from the config, for Config::validate.
+pub fn validate(backend: Backend) -> Result<()> {
+    if !backend.built() {
+        bail!(
+            "monitors_backend is {}, but built without it",
+            backend.name()
+        );
+    }
+    Ok(())
+}
+
+pub fn get_nr_connected(backend: Backend) -> Result<usize> {
+    Ok(get_outputs(backend)?
+        .iter()
+        .filter(|(_, connected)| *connected)
+        .count())
+}
+
+/// Warns about running enough monitors on battery to drain it fast, per warn_on_mons_with_no_ac.
+pub struct MonitorWarning {
+    notif: SingleNotification,
+}
+
+impl MonitorWarning {
+    pub fn new(sink: &Sink) -> Self {
+        Self {
+            notif: SingleNotification::new(sink),
+        }
+    }
+
+    pub const fn is_active(&self) -> bool {
+        self.notif.is_shown()
+    }
+
+    pub fn update(&mut self, cfg: &Config, global: &Battery) {
+        // X11 and Wayland live in the user's session, which we can't see in system mode
+        if cfg.warn_on_mons_with_no_ac == 0
+            || cfg.system_mode
+            || global.state != BatteryState::Discharging
+        {
+            self.notif.close();
+            return;
+        }
+        let start = Instant::now();
+        let conn = get_nr_connected(cfg.monitors_backend).unwrap_or_else(|err| {
+            error!("{err}");
+            0
+        });
+        info!(
+            monitors = conn,
+            collect_us = timing::duration_us(start.elapsed());
+            "Current connected monitors: {conn}"
+        );
+        if conn >= cfg.warn_on_mons_with_no_ac {
+            self.notif.show(
+                format!("Connected to {} monitors but not AC", conn),
+                cfg.notifications.style(Category::Monitors),
+            );
+        } else {
+            self.notif.close();
+        }
+    }
 }
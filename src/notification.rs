@@ -1,39 +1,728 @@
-use log::{error, trace};
-use notify_rust::{Notification, NotificationHandle, Urgency};
+use log::{error, info, trace};
+use notify_rust::{Hint, Notification, NotificationHandle, Timeout, Urgency};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
 
+use crate::buttons::{self, Button};
+use crate::speech::{self, SpeechConfig};
+use crate::system::BatteryState;
+use crate::template;
+
+/// The kinds of notification we show.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    State,
+    Low,
+    Critical,
+    Monitors,
+    BluetoothLow,
+    BluetoothCritical,
+    SlowCharge,
+    FastDrain,
+    OptimizedCharging,
+    Charger,
+    Remote,
+    Ac,
+    Ups,
+}
+
+impl Category {
+    pub const ALL: &'static [Self] = &[
+        Self::State,
+        Self::Low,
+        Self::Critical,
+        Self::Monitors,
+        Self::BluetoothLow,
+        Self::BluetoothCritical,
+        Self::SlowCharge,
+        Self::FastDrain,
+        Self::OptimizedCharging,
+        Self::Charger,
+        Self::Remote,
+        Self::Ac,
+        Self::Ups,
+    ];
+
+    pub const fn urgency(self) -> Urgency {
+        match self {
+            Self::State
+            | Self::SlowCharge
+            | Self::FastDrain
+            | Self::OptimizedCharging
+            | Self::Charger
+            | Self::Ac => Urgency::Normal,
+            _ => Urgency::Critical,
+        }
+    }
+}
+
+/// How to show a particular notification.
+#[derive(Debug, Clone, Copy)]
+pub struct Style {
+    pub urgency: Urgency,
+    pub timeout: Timeout,
+    /// What it's about, for the history. Notifications we only relay don't have one.
+    pub category: Option<Category>,
+    /// Show changes as a new bubble, leaving the old one be, rather than replacing it.
+    pub stack: bool,
+}
+
+impl From<Urgency> for Style {
+    fn from(urgency: Urgency) -> Self {
+        Self {
+            urgency,
+            timeout: Timeout::Default,
+            category: None,
+            stack: false,
+        }
+    }
+}
+
+/// A freedesktop icon name for a battery at `level` in `state`, like "battery-low-charging".
+pub fn battery_icon(level: u8, state: BatteryState) -> String {
+    if state == BatteryState::Full {
+        return "battery-full-charged".to_string();
+    }
+    let icon = match level {
+        0..=5 => "battery-empty",
+        6..=15 => "battery-caution",
+        16..=40 => "battery-low",
+        41..=80 => "battery-good",
+        _ => "battery-full",
+    };
+    if state == BatteryState::Charging {
+        format!("{icon}-charging")
+    } else {
+        icon.to_string()
+    }
+}
+
+/// The icon and "value" hint for a notification in `category` about a battery at `level`. There's
+/// no icon if the server can't show one.
+fn appearance(
+    shared: &Shared,
+    category: Option<Category>,
+    level: Option<(u8, BatteryState)>,
+) -> (Option<String>, Option<u8>) {
+    let templates = shared.templates.lock().unwrap();
+    let cfg = category.and_then(|c| Some(templates.as_ref()?.get(c)));
+    let icon = match cfg.map_or("", |cfg| cfg.icon.as_str()) {
+        "" => level.map(|(level, state)| battery_icon(level, state)),
+        "none" => None,
+        icon => Some(icon.to_string()),
+    }
+    .filter(|_| capabilities().has("icon-static"));
+    let value = level
+        .filter(|_| cfg.map_or(true, |cfg| cfg.progress))
+        .map(|(level, _)| level);
+    (icon, value)
+}
+
+/// The buttons configured for `category`, if we can show them.
+fn buttons_for(shared: &Shared, category: Option<Category>) -> Vec<Button> {
+    if !buttons::listening() || !capabilities().has("actions") {
+        return Vec::new();
+    }
+    let templates = shared.templates.lock().unwrap();
+    category
+        .and_then(|c| Some(templates.as_ref()?.get(c).buttons.clone()))
+        .unwrap_or_default()
+}
+
+/// Applies any configured templates for `category`. `vars` are specific to this notification, and
+/// take precedence over the context. The text we'd have shown is available as {summary} and
+/// {body}.
+fn apply_templates(
+    shared: &Shared,
+    category: Option<Category>,
+    summary: String,
+    body: String,
+    vars: &[(&'static str, String)],
+) -> (String, String) {
+    let templates = shared.templates.lock().unwrap();
+    let Some(cfg) = category.and_then(|c| Some(templates.as_ref()?.get(c))) else {
+        return (summary, body);
+    };
+    if cfg.summary.is_empty() && cfg.body.is_empty() {
+        return (summary, body);
+    }
+    let mut all = shared.context.lock().unwrap().clone();
+    all.extend(vars.iter().cloned());
+    all.push(("summary", summary.clone()));
+    all.push(("body", body.clone()));
+    let render = |template: &str, default| {
+        if template.is_empty() {
+            default
+        } else {
+            template::render(template, &all)
+        }
+    };
+    (render(&cfg.summary, summary), render(&cfg.body, body))
+}
+
+/// How many notifications to remember, so people can check whether one fired while they were away.
+const HISTORY_CAPACITY: usize = 100;
+
+/// Something that happened to a notification.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+pub struct HistoryEntry {
+    pub time: SystemTime,
+    pub category: Option<Category>,
+    pub summary: String,
+    /// "shown", "updated", "relayed", "silenced", "failed" or "closed".
+    pub outcome: &'static str,
+}
+
+static HISTORY: Mutex<VecDeque<HistoryEntry>> = Mutex::new(VecDeque::new());
+
+fn record(category: Option<Category>, summary: &str, outcome: &'static str) {
+    let mut history = HISTORY.lock().unwrap();
+    if history.len() == HISTORY_CAPACITY {
+        history.pop_front();
+    }
+    history.push_back(HistoryEntry {
+        time: SystemTime::now(),
+        category,
+        summary: summary.to_string(),
+        outcome,
+    });
+}
+
+/// The most recent notifications, oldest first.
+#[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+pub fn history() -> Vec<HistoryEntry> {
+    HISTORY.lock().unwrap().iter().cloned().collect()
+}
+
+#[derive(Debug, Default, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UrgencySetting {
+    /// Whatever we'd normally use for the category.
+    #[default]
+    Default,
+    Low,
+    Normal,
+    Critical,
+}
+
+impl UrgencySetting {
+    pub const fn resolve(self, default: Urgency) -> Urgency {
+        match self {
+            Self::Default => default,
+            Self::Low => Urgency::Low,
+            Self::Normal => Urgency::Normal,
+            Self::Critical => Urgency::Critical,
+        }
+    }
+}
+
+/// 0 leaves it to the notification server.
+pub fn timeout_from_secs(secs: u64) -> Timeout {
+    match secs {
+        0 => Timeout::Default,
+        secs => Timeout::Milliseconds(u32::try_from(secs * 1000).unwrap_or(u32::MAX)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CategoryConfig {
+    pub urgency: UrgencySetting,
+    /// 0 leaves it to the notification server.
+    pub timeout_secs: u64,
+    /// Show each change as a new bubble rather than replacing the last one.
+    pub stack: bool,
+    /// Shown as buttons on the notification, if the server supports them.
+    pub buttons: Vec<Button>,
+    /// An icon name or path, "none", or empty to pick one from the level.
+    pub icon: String,
+    /// Set the "value" hint to the level, which some servers show as a progress bar.
+    pub progress: bool,
+    /// Templates replacing the text we'd normally show, or empty to keep it.
+    pub summary: String,
+    pub body: String,
+}
+
+impl Default for CategoryConfig {
+    fn default() -> Self {
+        Self {
+            urgency: UrgencySetting::Default,
+            timeout_secs: 0,
+            stack: false,
+            buttons: Vec::new(),
+            icon: String::new(),
+            progress: true,
+            summary: String::new(),
+            body: String::new(),
+        }
+    }
+}
+
+/// Per category settings. New sources of notifications get their own entry here, rather than
+/// borrowing the semantics of an existing one.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationsConfig {
+    pub state: CategoryConfig,
+    pub low: CategoryConfig,
+    pub critical: CategoryConfig,
+    pub monitors: CategoryConfig,
+    pub bluetooth_low: CategoryConfig,
+    pub bluetooth_critical: CategoryConfig,
+    pub slow_charge: CategoryConfig,
+    pub fast_drain: CategoryConfig,
+    pub optimized_charging: CategoryConfig,
+    pub charger: CategoryConfig,
+    pub remote: CategoryConfig,
+    pub ac: CategoryConfig,
+    pub ups: CategoryConfig,
+}
+
+impl NotificationsConfig {
+    pub const fn get(&self, category: Category) -> &CategoryConfig {
+        match category {
+            Category::State => &self.state,
+            Category::Low => &self.low,
+            Category::Critical => &self.critical,
+            Category::Monitors => &self.monitors,
+            Category::BluetoothLow => &self.bluetooth_low,
+            Category::BluetoothCritical => &self.bluetooth_critical,
+            Category::SlowCharge => &self.slow_charge,
+            Category::FastDrain => &self.fast_drain,
+            Category::OptimizedCharging => &self.optimized_charging,
+            Category::Charger => &self.charger,
+            Category::Remote => &self.remote,
+            Category::Ac => &self.ac,
+            Category::Ups => &self.ups,
+        }
+    }
+
+    pub fn style(&self, category: Category) -> Style {
+        let cfg = self.get(category);
+        Style {
+            urgency: cfg.urgency.resolve(category.urgency()),
+            timeout: timeout_from_secs(cfg.timeout_secs),
+            category: Some(category),
+            stack: cfg.stack,
+        }
+    }
+}
+
+/// Features we would like from the notification server, but can live without.
+const WANTED_CAPABILITIES: &[&str] = &["body", "body-markup", "actions", "icon-static"];
+
+pub struct Capabilities(Vec<String>);
+
+impl Capabilities {
+    pub fn has(&self, cap: &str) -> bool {
+        self.0.iter().any(|c| c == cap)
+    }
+}
+
+/// Queries the server's capabilities once, and logs anything we will have to do without.
+pub fn capabilities() -> &'static Capabilities {
+    static CAPS: OnceLock<Capabilities> = OnceLock::new();
+    CAPS.get_or_init(|| {
+        let caps = Capabilities(notify_rust::get_capabilities().unwrap_or_else(|err| {
+            error!("failed to get notification server capabilities: {err}");
+            // Body support is basically universal, everything else we can't assume
+            vec!["body".to_string()]
+        }));
+        for cap in WANTED_CAPABILITIES.iter().filter(|c| !caps.has(c)) {
+            info!("Notification server doesn't support {cap}, disabling");
+        }
+        caps
+    })
+}
+
+fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Adapts a summary and body to what the server is able to display.
+fn render(summary: &str, body: &str) -> (String, String) {
+    let caps = capabilities();
+    if body.is_empty() {
+        (summary.to_string(), String::new())
+    } else if !caps.has("body") {
+        (format!("{summary} ({body})"), String::new())
+    } else if caps.has("body-markup") {
+        (summary.to_string(), escape_markup(body))
+    } else {
+        (summary.to_string(), body.to_string())
+    }
+}
+
+/// Somewhere other than our own session's notification server to send notifications, like the
+/// relays when broadcasting, or a recorder in tests.
+pub trait Notifier: Send + Sync {
+    fn show(
+        &self,
+        key: u64,
+        summary: &str,
+        body: &str,
+        urgency: Urgency,
+        timeout: Timeout,
+        hints: &[Hint],
+    );
+    fn close(&self, key: u64);
+}
+
+/// What every notification going through a Sink (and its clones) goes by.
 #[derive(Default)]
+struct Shared {
+    /// Per category templates, set once the config is loaded.
+    templates: Mutex<Option<NotificationsConfig>>,
+    /// Template variables which apply to every notification, like the combined battery level,
+    /// updated each cycle.
+    context: Mutex<Vec<(&'static str, String)>>,
+    /// The combined battery level and state as of the last check, for notifications which aren't
+    /// about a particular battery.
+    battery: Mutex<Option<(u8, BatteryState)>>,
+    speech: Mutex<Option<SpeechConfig>>,
+    silenced: AtomicBool,
+    // Set when we're exiting with notifications meant to outlive us
+    leave_open: AtomicBool,
+}
+
+/// Where notifications go: our own session's notification server by default, or a Notifier. This
+/// is cheap to clone, so everything which notifies keeps its own. Clones share templates, whether
+/// we're silenced and so on, which whoever made the Sink keeps up to date.
+#[derive(Clone, Default)]
+pub struct Sink {
+    notifier: Option<Arc<dyn Notifier>>,
+    shared: Arc<Shared>,
+}
+
+impl Sink {
+    pub fn new(notifier: impl Notifier + 'static) -> Self {
+        Self {
+            notifier: Some(Arc::new(notifier)),
+            shared: Arc::default(),
+        }
+    }
+
+    pub fn set_templates(&self, cfg: &NotificationsConfig) {
+        *self.shared.templates.lock().unwrap() = Some(cfg.clone());
+    }
+
+    /// Whether any category has a template, so callers can skip working out variables otherwise.
+    pub fn templates_enabled(&self) -> bool {
+        self.shared
+            .templates
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|cfg| {
+                Category::ALL.iter().any(|c| {
+                    let cfg = cfg.get(*c);
+                    !cfg.summary.is_empty() || !cfg.body.is_empty()
+                })
+            })
+    }
+
+    pub fn set_context(&self, vars: Vec<(&'static str, String)>) {
+        *self.shared.context.lock().unwrap() = vars;
+    }
+
+    pub fn set_battery(&self, level: u8, state: BatteryState) {
+        *self.shared.battery.lock().unwrap() = Some((level, state));
+    }
+
+    /// How to speak low and critical alerts, if at all.
+    pub fn set_speech(&self, cfg: &SpeechConfig) {
+        *self.shared.speech.lock().unwrap() = Some(cfg.clone());
+    }
+
+    /// Holds back all but critical notifications while set, for snoozing, quiet hours and games.
+    pub fn set_silenced(&self, silenced: bool) {
+        self.shared.silenced.store(silenced, Ordering::Relaxed);
+    }
+
+    /// Stops notifications from being closed when dropped from now on, so that they stay up after
+    /// we exit.
+    pub fn leave_open(&self) {
+        self.shared.leave_open.store(true, Ordering::Relaxed);
+    }
+}
+
 pub struct SingleNotification {
+    sink: Sink,
+    // Identifies this notification to relays when broadcasting
+    key: u64,
     hnd: Option<NotificationHandle>,
     summary: Option<String>,
+    body: String,
+    hints: Vec<Hint>,
+    // The category it was last shown with, for the history
+    category: Option<Category>,
+    // Template variables specific to this notification
+    vars: Vec<(&'static str, String)>,
+    // The last summary held back while silenced, so the history isn't filled with it every cycle
+    silenced_summary: Option<String>,
+    // The level of the battery or device it's about, if not the combined one
+    level: Option<(u8, BatteryState)>,
+    // The server's ID for a bubble we showed before restarting, to replace or close
+    adopted: Option<u32>,
+}
+
+fn next_key() -> u64 {
+    static NEXT_KEY: AtomicU64 = AtomicU64::new(0);
+    NEXT_KEY.fetch_add(1, Ordering::Relaxed)
 }
 
 impl SingleNotification {
-    pub fn show(&mut self, summary: String, urgency: Urgency) {
-        if self.summary.as_ref() != Some(&summary) {
-            self.close();
-            trace!("Creating notification for {}", summary);
-            self.hnd = Notification::default()
-                .summary(&summary)
+    pub fn new(sink: &Sink) -> Self {
+        Self {
+            sink: sink.clone(),
+            key: next_key(),
+            hnd: None,
+            summary: None,
+            body: String::new(),
+            hints: Vec::new(),
+            category: None,
+            vars: Vec::new(),
+            silenced_summary: None,
+            level: None,
+            adopted: None,
+        }
+    }
+
+    pub fn with_hint(mut self, hint: Hint) -> Self {
+        self.hints.push(hint);
+        self
+    }
+
+    /// Replaces the hints, from the next time it's shown.
+    pub fn set_hints(&mut self, hints: Vec<Hint>) {
+        self.hints = hints;
+    }
+
+    /// Sets template variables specific to this notification, like the device it's about.
+    pub fn set_vars(&mut self, vars: Vec<(&'static str, String)>) {
+        self.vars = vars;
+    }
+
+    /// Sets the level of the particular battery or device this is about, for its icon and progress.
+    pub fn set_level(&mut self, level: u8, state: BatteryState) {
+        self.level = Some((level, state));
+    }
+
+    /// The server's ID for the bubble, if there is one.
+    pub fn id(&self) -> Option<u32> {
+        self.hnd
+            .as_ref()
+            .map(NotificationHandle::id)
+            .or(self.adopted)
+    }
+
+    /// Takes over a bubble shown before we restarted, so that showing replaces it and closing
+    /// closes it.
+    pub fn adopt(&mut self, id: u32) {
+        self.adopted = Some(id);
+    }
+
+    /// Whether the notification is currently meant to be up, even if the server failed to show it.
+    pub const fn is_shown(&self) -> bool {
+        self.summary.is_some()
+    }
+
+    pub fn show(&mut self, summary: String, style: impl Into<Style>) {
+        self.show_with_body(summary, String::new(), style);
+    }
+
+    pub fn show_with_body(&mut self, summary: String, body: String, style: impl Into<Style>) {
+        let Style {
+            urgency,
+            timeout,
+            category,
+            stack,
+        } = style.into();
+        // Detaching needs self, so hold our own reference
+        let shared = Arc::clone(&self.sink.shared);
+        let shared = &*shared;
+        let (summary, body) = apply_templates(shared, category, summary, body, &self.vars);
+        if self.summary.as_ref() == Some(&summary) && self.body == body {
+            return;
+        }
+        let critical = matches!(
+            category,
+            Some(Category::Critical | Category::BluetoothCritical)
+        );
+        if shared.silenced.load(Ordering::Relaxed) && !critical {
+            // Left unshown, so that it's shown if still wanted once the silence is over
+            if self.silenced_summary.as_ref() != Some(&summary) {
+                trace!(
+                    "Silenced, not showing notification for {}: {}",
+                    summary,
+                    body
+                );
+                record(category, &summary, "silenced");
+                self.silenced_summary = Some(summary);
+            }
+            return;
+        }
+        self.silenced_summary = None;
+        // Only new alerts are spoken, not every change in their level or time remaining
+        if matches!(category, Some(Category::Low | Category::Critical))
+            && self.summary.as_ref() != Some(&summary)
+        {
+            let level = self
+                .level
+                .map(|(level, _)| level)
+                .or_else(|| shared.battery.lock().unwrap().map(|(level, _)| level));
+            let speech = shared.speech.lock().unwrap().clone();
+            if let Some(speech) = speech {
+                match level {
+                    Some(level) => speech::say(&speech, &format!("{summary}, {level}%")),
+                    None => speech::say(&speech, &summary),
+                }
+            }
+        }
+        self.category = category;
+        if stack && self.summary.is_some() {
+            self.detach();
+        }
+
+        if let Some(notifier) = &self.sink.notifier {
+            trace!("Relaying notification for {}: {}", summary, body);
+            notifier.show(self.key, &summary, &body, urgency, timeout, &self.hints);
+            record(category, &summary, "relayed");
+            self.summary = Some(summary);
+            self.body = body;
+            return;
+        }
+
+        let (disp_summary, disp_body) = render(&summary, &body);
+        // Remotes and UPSes have batteries of their own, so our level means nothing there
+        let level = self.level.or_else(|| {
+            (!matches!(category, Some(Category::Remote | Category::Ups)))
+                .then(|| *shared.battery.lock().unwrap())
+                .flatten()
+        });
+        let (icon, value) = appearance(shared, category, level);
+        if let Some(hnd) = self.hnd.as_mut() {
+            // Replace the existing bubble in place to avoid flicker
+            trace!("Updating notification to {}: {}", summary, body);
+            hnd.summary(&disp_summary)
+                .body(&disp_body)
+                .icon(icon.as_deref().unwrap_or_default())
                 .urgency(urgency)
+                .timeout(timeout);
+            if let Some(value) = value {
+                hnd.hint(Hint::CustomInt("value".to_string(), value.into()));
+            }
+            hnd.update();
+            record(category, &summary, "updated");
+        } else {
+            trace!("Creating notification for {}: {}", summary, body);
+            let mut notif = Notification::default();
+            if let Some(id) = self.adopted.take() {
+                notif.id(id);
+            }
+            notif
+                .summary(&disp_summary)
+                .body(&disp_body)
+                .urgency(urgency)
+                .timeout(timeout);
+            for hint in &self.hints {
+                notif.hint(hint.clone());
+            }
+            if let Some(icon) = &icon {
+                notif.icon(icon);
+            }
+            if let Some(value) = value {
+                notif.hint(Hint::CustomInt("value".to_string(), value.into()));
+            }
+            let buttons = buttons_for(shared, category);
+            for (idx, button) in buttons.iter().enumerate() {
+                notif.action(&idx.to_string(), &button.label);
+            }
+            self.hnd = notif
                 .show()
                 .map_err(|err| error!("error showing notification: {err}"))
                 .ok();
-            self.summary = Some(summary)
+            if let Some(hnd) = self.hnd.as_ref().filter(|_| !buttons.is_empty()) {
+                buttons::shown(hnd.id(), buttons);
+            }
+            let outcome = if self.hnd.is_some() {
+                "shown"
+            } else {
+                "failed"
+            };
+            record(category, &summary, outcome);
         }
+        self.summary = Some(summary);
+        self.body = body;
+    }
+
+    /// Lets go of the current bubble without closing it, so the next show creates another. It's
+    /// left to the server (or relay) to expire it, and we never close it ourselves.
+    fn detach(&mut self) {
+        self.hnd = None;
+        // Relays keep a notification per key, so a new key gets a new bubble there too
+        self.key = next_key();
+        self.summary = None;
+        self.body.clear();
     }
 
     pub fn close(&mut self) {
+        if let Some(notifier) = &self.sink.notifier {
+            if let Some(summary) = self.summary.take() {
+                notifier.close(self.key);
+                record(self.category, &summary, "closed");
+            }
+            self.body.clear();
+            return;
+        }
+
         if let Some(hnd) = self.hnd.take() {
             if let Some(summary) = self.summary.take() {
                 trace!("Closing notification for {}", summary);
+                record(self.category, &summary, "closed");
             }
+            self.body.clear();
+            buttons::closed(hnd.id());
             hnd.close();
+        } else if let Some(id) = self.adopted.take() {
+            close_by_id(id);
         }
     }
 }
 
+/// Closes a bubble we have no handle for, since it was shown before we restarted.
+#[cfg(feature = "dbus")]
+fn close_by_id(id: u32) {
+    let res = zbus::blocking::Connection::session().and_then(|conn| {
+        conn.call_method(
+            Some("org.freedesktop.Notifications"),
+            "/org/freedesktop/Notifications",
+            Some("org.freedesktop.Notifications"),
+            "CloseNotification",
+            &(id,),
+        )
+        .map(drop)
+    });
+    if let Err(err) = res {
+        error!("failed to close notification {id}: {err}");
+    }
+}
+
+/// Without D-Bus, we'll have to leave it to the server to expire.
+#[cfg(not(feature = "dbus"))]
+fn close_by_id(_id: u32) {}
+
 impl Drop for SingleNotification {
     fn drop(&mut self) {
-        self.close();
+        if !self.sink.shared.leave_open.load(Ordering::Relaxed) {
+            self.close();
+        }
     }
 }
@@ -0,0 +1,66 @@
+//! Drives an on-screen display bar like wob or xob, which read one percentage per line on stdin,
+//! for people who don't run a notification daemon at all.
+
+use log::{error, info};
+use std::io::Write;
+use std::process::{Child, Command, Stdio};
+
+pub struct Osd {
+    cmd: String,
+    child: Option<Child>,
+}
+
+impl Osd {
+    pub const fn new(cmd: String) -> Self {
+        Self { cmd, child: None }
+    }
+
+    fn spawn(&self) -> Option<Child> {
+        info!("Starting OSD command '{}'", self.cmd);
+        Command::new("sh")
+            .args(["-c", &self.cmd])
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|err| error!("failed to start OSD command '{}': {err}", self.cmd))
+            .ok()
+    }
+
+    /// Shows `level` on the OSD, starting (or restarting) the OSD command if needed.
+    pub fn show(&mut self, level: u8) {
+        if self.cmd.is_empty() {
+            return;
+        }
+        // Try once more with a fresh process if the old one went away
+        for _ in 0..2 {
+            let child = match self.child.as_mut() {
+                Some(child) => child,
+                None => match self.spawn() {
+                    Some(child) => self.child.insert(child),
+                    None => return,
+                },
+            };
+            let written = child.stdin.as_mut().is_some_and(|stdin| {
+                writeln!(stdin, "{level}")
+                    .and_then(|()| stdin.flush())
+                    .is_ok()
+            });
+            if written {
+                return;
+            }
+            if let Some(mut child) = self.child.take() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+}
+
+impl Drop for Osd {
+    fn drop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            // Closing stdin is enough for wob and xob to exit
+            drop(child.stdin.take());
+            let _ = child.wait();
+        }
+    }
+}
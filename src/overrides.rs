@@ -0,0 +1,160 @@
+//! Config overrides from outside the config file, given as dotted key paths like
+//! `events.sleep.command`.
+
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::env;
+use toml::{Table, Value};
+
+use crate::cli;
+
+const ENV_PREFIX: &str = "BATTERY_NOTIFY_";
+
+/// Tables which take arbitrary keys, rather than a fixed set of them.
+pub const MAPS: &[&str] = &[
+    "batteries",
+    "chargers",
+    "remotes",
+    "bluetooth.devices",
+    "bluetooth.classes",
+];
+
+#[derive(Debug, Clone)]
+pub struct Override {
+    pub key: String,
+    pub value: String,
+    /// Where it came from, for error messages.
+    pub source: String,
+}
+
+/// Overrides from `BATTERY_NOTIFY_*` environment variables. Nested keys are separated with a double
+/// underscore, so `BATTERY_NOTIFY_EVENTS__SLEEP__RETRIES` sets `events.sleep.retries`.
+pub fn from_env() -> Vec<Override> {
+    let mut overrides: Vec<_> = env::vars()
+        .filter(|(name, _)| !cli::EVENT_ENV.iter().any(|(var, _)| var == name))
+        .filter_map(|(name, value)| {
+            let key = name
+                .strip_prefix(ENV_PREFIX)?
+                .to_lowercase()
+                .replace("__", ".");
+            Some(Override {
+                key,
+                value,
+                source: name,
+            })
+        })
+        .collect();
+    overrides.sort_by(|a, b| a.key.cmp(&b.key));
+    overrides
+}
+
+/// Parses a value given on its own as TOML, falling back to treating it as a bare string.
+fn parse_value(raw: &str) -> Value {
+    toml::from_str::<Table>(&format!("v = {raw}"))
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
+
+fn set(table: &mut Table, ov: &Override) -> Result<()> {
+    let unknown = || format!("unknown config key {} (from {})", ov.key, ov.source);
+    let (parent_path, leaf) = match ov.key.rsplit_once('.') {
+        Some((parent, leaf)) => (Some(parent), leaf),
+        None => (None, ov.key.as_str()),
+    };
+
+    let mut parent = table;
+    for part in parent_path.into_iter().flat_map(|p| p.split('.')) {
+        parent = match parent.get_mut(part) {
+            Some(Value::Table(t)) => t,
+            _ => bail!(unknown()),
+        };
+    }
+
+    let value = match parent.get(leaf) {
+        // Strings are common enough (and ambiguous enough, for things like commands) that we
+        // don't want to require quoting them. Commands can also be a table for a built in action.
+        Some(Value::String(_)) => match parse_value(&ov.value) {
+            table @ Value::Table(_) => table,
+            _ => Value::String(ov.value.clone()),
+        },
+        Some(Value::Table(_)) => bail!("{} is a table, set its keys instead", ov.key),
+        Some(_) => parse_value(&ov.value),
+        None if parent_path.is_some_and(|p| MAPS.contains(&p)) => parse_value(&ov.value),
+        None => bail!(unknown()),
+    };
+    parent.insert(leaf.to_string(), value);
+    Ok(())
+}
+
+/// Applies `overrides` on top of `cfg`, in order.
+pub fn apply<T: Serialize + DeserializeOwned>(cfg: T, overrides: &[Override]) -> Result<T> {
+    if overrides.is_empty() {
+        return Ok(cfg);
+    }
+    let mut table = Table::try_from(cfg)?;
+    for ov in overrides {
+        set(&mut table, ov)?;
+    }
+    Value::Table(table)
+        .try_into()
+        .context("invalid config after applying overrides")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ov(key: &str, value: &str) -> Override {
+        Override {
+            key: key.to_string(),
+            value: value.to_string(),
+            source: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_value_types() {
+        assert_eq!(parse_value("30"), Value::Integer(30));
+        assert_eq!(parse_value("true"), Value::Boolean(true));
+        assert_eq!(
+            parse_value("[1, 2]"),
+            Value::Array(vec![1.into(), 2.into()])
+        );
+        assert_eq!(parse_value("'quoted'"), Value::String("quoted".to_string()));
+        assert_eq!(
+            parse_value("systemctl suspend"),
+            Value::String("systemctl suspend".to_string())
+        );
+    }
+
+    #[test]
+    fn set_dotted_keys() {
+        let mut table: Table = toml::from_str(
+            "low_pct = 40\n\
+             [events.sleep]\n\
+             command = 'systemctl suspend'\n\
+             [bluetooth.classes]\n",
+        )
+        .unwrap();
+        set(&mut table, &ov("low_pct", "20")).unwrap();
+        // Strings don't need quoting, even when they'd parse as something else
+        set(&mut table, &ov("events.sleep.command", "true")).unwrap();
+        set(
+            &mut table,
+            &ov("bluetooth.classes.mouse", "{ low_pct = 5 }"),
+        )
+        .unwrap();
+        assert_eq!(table["low_pct"], Value::Integer(20));
+        assert_eq!(table["events"]["sleep"]["command"].as_str(), Some("true"));
+        assert_eq!(
+            table["bluetooth"]["classes"]["mouse"]["low_pct"],
+            Value::Integer(5)
+        );
+
+        assert!(set(&mut table, &ov("lwo_pct", "20")).is_err());
+        assert!(set(&mut table, &ov("events.sleep", "x")).is_err());
+        assert!(set(&mut table, &ov("nope.low_pct", "20")).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+//! Asks the main loop to load the config again when the file changes, or when we get SIGHUP.
+
+use anyhow::{Context, Result};
+use cancellable_timer::Canceller;
+use log::{debug, error, info};
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+
+use crate::service::Request;
+
+/// The write end of the pipe the SIGHUP handler pokes, since it can't do much else safely.
+static SIGHUP_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    let fd = SIGHUP_PIPE.load(Ordering::Relaxed);
+    // SAFETY: write is async-signal-safe, and if the pipe is full a reload is already pending
+    unsafe { libc::write(fd, [0u8].as_ptr().cast(), 1) };
+}
+
+fn request_reload(tx: &Sender<Request>, canceller: &Canceller) -> bool {
+    if tx.send(Request::Reload).is_err() {
+        // The main loop is gone, so there's nobody left to reload
+        return false;
+    }
+    // If we fail to cancel, we'll just reload at the next interval
+    let _ = canceller.cancel();
+    true
+}
+
+/// Reads from `fd` until it fails, calling `on_read` with what was read each time.
+fn read_loop(fd: &OwnedFd, what: &str, mut on_read: impl FnMut(&[u8]) -> bool) {
+    let mut buf = [0u8; 4096];
+    loop {
+        // SAFETY: buf is valid for writes of its whole length
+        let len = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            error!("stopped watching for {what}: {err}");
+            return;
+        }
+        if !on_read(&buf[..len as usize]) {
+            return;
+        }
+    }
+}
+
+fn check(ret: libc::c_int, what: &str) -> Result<libc::c_int> {
+    if ret == -1 {
+        return Err(io::Error::last_os_error()).with_context(|| format!("failed to {what}"));
+    }
+    Ok(ret)
+}
+
+fn owned(fd: RawFd) -> OwnedFd {
+    // SAFETY: only called with fds we just created, so nothing else owns them
+    unsafe { OwnedFd::from_raw_fd(fd) }
+}
+
+/// ctrlc also handles SIGHUP with its termination feature, so this has to be set up after it.
+fn spawn_sighup(tx: Sender<Request>, canceller: Canceller) -> Result<()> {
+    let mut fds = [0; 2];
+    // SAFETY: fds has room for the two fds pipe2 writes
+    check(
+        unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) },
+        "create SIGHUP pipe",
+    )?;
+    let (read, write) = (owned(fds[0]), owned(fds[1]));
+    // Only the read end should block, so we never hang in the handler
+    // SAFETY: plain fcntl on an fd we own
+    check(
+        unsafe { libc::fcntl(read.as_raw_fd(), libc::F_SETFL, 0) },
+        "make SIGHUP pipe blocking",
+    )?;
+    // The handler uses this for the rest of our life, so it must never be closed
+    SIGHUP_PIPE.store(write.into_raw_fd(), Ordering::Relaxed);
+
+    // SAFETY: sigaction is plain data, for which all zeroes is valid, and on_sighup only does
+    // async-signal-safe things
+    unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = on_sighup as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        check(
+            libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut()),
+            "handle SIGHUP",
+        )?;
+    }
+
+    thread::Builder::new()
+        .name("sighup".to_string())
+        .spawn(move || {
+            read_loop(&read, "SIGHUP", |_| {
+                info!("Got SIGHUP, reloading config");
+                request_reload(&tx, &canceller)
+            });
+        })?;
+    Ok(())
+}
+
+/// Whether a buffer of inotify events has any for `name`.
+fn touches(mut events: &[u8], name: &OsStr) -> bool {
+    let header = mem::size_of::<libc::inotify_event>();
+    while events.len() >= header {
+        // SAFETY: we checked there's a whole header, and read_unaligned doesn't need alignment
+        let event: libc::inotify_event =
+            unsafe { std::ptr::read_unaligned(events.as_ptr().cast()) };
+        let end = (header + event.len as usize).min(events.len());
+        // The name is NUL padded
+        let event_name = events[header..end].split(|b| *b == 0).next().unwrap_or(&[]);
+        if event_name == name.as_bytes() {
+            return true;
+        }
+        events = &events[end..];
+    }
+    false
+}
+
+fn spawn_inotify(path: &Path, tx: Sender<Request>, canceller: Canceller) -> Result<()> {
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        anyhow::bail!("no directory to watch for {}", path.display());
+    };
+    let name = name.to_owned();
+    // SAFETY: plain syscall, we check the result before using it
+    let fd = owned(check(
+        unsafe { libc::inotify_init1(libc::IN_CLOEXEC) },
+        "initialise inotify",
+    )?);
+    let dir_c = CString::new(dir.as_os_str().as_bytes())?;
+    // Watch the directory rather than the file, since editors often replace it with a new one
+    // SAFETY: dir_c is a valid C string, and fd is an inotify fd we own
+    check(
+        unsafe {
+            libc::inotify_add_watch(
+                fd.as_raw_fd(),
+                dir_c.as_ptr(),
+                libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO,
+            )
+        },
+        &format!("watch {}", dir.display()),
+    )?;
+
+    thread::Builder::new()
+        .name("inotify".to_string())
+        .spawn(move || {
+            read_loop(&fd, "config changes", |events| {
+                if !touches(events, &name) {
+                    return true;
+                }
+                debug!("Config file changed, reloading");
+                request_reload(&tx, &canceller)
+            });
+        })?;
+    Ok(())
+}
+
+/// Sends Request::Reload through `tx` and wakes up the main loop through `canceller` whenever the
+/// config at `path` is written, or we get SIGHUP.
+pub fn spawn(path: &Path, tx: Sender<Request>, canceller: Canceller) -> Result<()> {
+    spawn_sighup(tx.clone(), canceller.clone())?;
+    spawn_inotify(path, tx, canceller)
+}
@@ -0,0 +1,251 @@
+//! Other machines we watch by running a command (usually over SSH) which prints their power state,
+//! like UPS-backed servers or headless laptops used as build machines.
+
+use anyhow::{bail, Context, Result};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::heavy::Heavy;
+use crate::json::Json;
+use crate::notification::{SingleNotification, Sink, Style};
+use crate::system::BatteryState;
+
+/// How many checks in a row have to fail before we say a remote is unreachable, so a dropped SSH
+/// connection or two doesn't notify.
+const UNREACHABLE_AFTER: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemoteConfig {
+    /// Prints `battery-notify status --json`, or a level optionally followed by a state.
+    pub command: String,
+    pub interval_secs: u64,
+    pub low_pct: u8,
+}
+
+impl Default for RemoteConfig {
+    fn default() -> Self {
+        Self {
+            command: String::new(),
+            interval_secs: 60,
+            low_pct: 20,
+        }
+    }
+}
+
+/// What a remote told us about its power.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct RemoteState {
+    level: Option<u8>,
+    /// Lowercase, like "discharging".
+    state: Option<String>,
+    ac_online: Option<bool>,
+}
+
+impl RemoteState {
+    fn on_battery(&self) -> bool {
+        self.ac_online == Some(false) || self.state.as_deref() == Some("discharging")
+    }
+}
+
+/// Parses what a remote's command printed. That's either `battery-notify status --json`, or
+/// something like `cat capacity status` in a battery's sysfs directory.
+fn parse_output(output: &str) -> Result<RemoteState> {
+    let output = output.trim();
+    if output.starts_with('{') {
+        let json = Json::parse(output).context("invalid JSON")?;
+        let combined = json.get("combined");
+        return Ok(RemoteState {
+            level: combined
+                .and_then(|c| c.get("level")?.as_i64())
+                .and_then(|level| u8::try_from(level).ok()),
+            state: combined
+                .and_then(|c| c.get("state")?.as_str())
+                .map(str::to_string),
+            ac_online: json.get("ac_online").and_then(Json::as_bool),
+        });
+    }
+    let mut words = output.split_whitespace();
+    let Some(level) = words.next() else {
+        bail!("no output");
+    };
+    let level: u8 = level
+        .trim_end_matches('%')
+        .parse()
+        .with_context(|| format!("expected a battery level, got '{level}'"))?;
+    let state = words.collect::<Vec<_>>().join(" ").to_lowercase();
+    Ok(RemoteState {
+        level: Some(level.min(100)),
+        state: (!state.is_empty()).then_some(state),
+        ac_online: None,
+    })
+}
+
+fn check(command: &str) -> Result<RemoteState> {
+    let output = Command::new("sh")
+        .args(["-c", command])
+        .output()
+        .with_context(|| format!("failed to run '{command}'"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("'{command}' failed ({}): {}", output.status, stderr.trim());
+    }
+    parse_output(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// The latest result from a remote's checker thread, taken by the next update.
+type Latest = Arc<Mutex<Option<Result<RemoteState, String>>>>;
+
+/// Runs `cfg.command` every `cfg.interval_secs` on its own thread, since SSH can take seconds (or
+/// much longer, if the host is down) and we don't want to hold up checking this machine. The
+/// thread exits once nobody else holds `latest`.
+fn spawn_checker(name: &str, cfg: RemoteConfig, latest: Latest, heavy: Heavy) -> Result<()> {
+    let interval = Duration::from_secs(cfg.interval_secs.max(1));
+    thread::Builder::new()
+        .name(format!("remote-{name}"))
+        .spawn(move || {
+            while Arc::strong_count(&latest) > 1 {
+                if heavy.skip("remotes", &cfg.command) {
+                    thread::sleep(interval);
+                    continue;
+                }
+                let res = check(&cfg.command).map_err(|err| format!("{err:#}"));
+                *latest.lock().unwrap() = Some(res);
+                thread::sleep(interval);
+            }
+        })?;
+    Ok(())
+}
+
+struct Remote {
+    cfg: RemoteConfig,
+    latest: Latest,
+    failures: u32,
+    /// On battery or low, since there's only ever one of those worth showing.
+    notif: SingleNotification,
+    unreachable_notif: SingleNotification,
+    alarm: Option<&'static str>,
+}
+
+/// Keeps track of every configured remote, notifying when one goes on battery, runs low, or can't
+/// be reached.
+pub struct RemoteMonitor {
+    heavy: Heavy,
+    sink: Sink,
+    remotes: BTreeMap<String, Remote>,
+}
+
+impl RemoteMonitor {
+    pub fn new(remotes: &BTreeMap<String, RemoteConfig>, heavy: &Heavy, sink: &Sink) -> Self {
+        let remotes = remotes
+            .iter()
+            .filter(|(_, cfg)| !cfg.command.is_empty())
+            .filter_map(|(name, cfg)| {
+                let latest = Latest::default();
+                if let Err(err) = spawn_checker(name, cfg.clone(), latest.clone(), heavy.clone()) {
+                    error!("failed to start checking remote {name}: {err}");
+                    return None;
+                }
+                let remote = Remote {
+                    cfg: cfg.clone(),
+                    latest,
+                    failures: 0,
+                    notif: SingleNotification::new(sink),
+                    unreachable_notif: SingleNotification::new(sink),
+                    alarm: None,
+                };
+                Some((name.clone(), remote))
+            })
+            .collect();
+        Self {
+            heavy: heavy.clone(),
+            sink: sink.clone(),
+            remotes,
+        }
+    }
+
+    /// What to check for holding off on the checkers, for starting afresh on reload.
+    pub const fn heavy(&self) -> &Heavy {
+        &self.heavy
+    }
+
+    /// Where notifications go, for starting afresh on reload.
+    pub const fn sink(&self) -> &Sink {
+        &self.sink
+    }
+
+    /// Acts on whatever the remotes have told us since last time.
+    pub fn update(&mut self, style: Style) {
+        for (name, remote) in &mut self.remotes {
+            let Some(res) = remote.latest.lock().unwrap().take() else {
+                continue;
+            };
+            let state = match res {
+                Ok(state) => state,
+                Err(err) => {
+                    remote.failures += 1;
+                    warn!(remote = name.as_str(); "Failed to check remote {name}: {err}");
+                    if remote.failures == UNREACHABLE_AFTER {
+                        remote.unreachable_notif.show_with_body(
+                            format!("{name} unreachable"),
+                            err,
+                            style,
+                        );
+                        remote.alarm = Some("remote_unreachable");
+                    }
+                    continue;
+                }
+            };
+            if remote.failures >= UNREACHABLE_AFTER {
+                info!("Remote {name} reachable again");
+                remote.unreachable_notif.close();
+            }
+            remote.failures = 0;
+            info!(
+                remote = name.as_str(), level:? = state.level, state:? = state.state,
+                ac_online:? = state.ac_online;
+                "Remote status: {state:?}"
+            );
+
+            if let Some(level) = state.level {
+                let bat_state = if state.state.as_deref() == Some("charging") {
+                    BatteryState::Charging
+                } else {
+                    BatteryState::Discharging
+                };
+                remote.notif.set_level(level, bat_state);
+            }
+            let level = state.level.map(|l| format!("{l}%")).unwrap_or_default();
+            let low = state
+                .level
+                .is_some_and(|l| l <= remote.cfg.low_pct && state.on_battery());
+            remote.alarm = if low {
+                remote
+                    .notif
+                    .show_with_body(format!("{name} battery low"), level, style);
+                Some("remote_low")
+            } else if state.on_battery() {
+                remote
+                    .notif
+                    .show_with_body(format!("{name} on battery"), level, style);
+                Some("remote_on_battery")
+            } else {
+                remote.notif.close();
+                None
+            };
+        }
+    }
+
+    /// Like "remote_on_battery:buildbox".
+    pub fn alarms(&self) -> Vec<String> {
+        self.remotes
+            .iter()
+            .filter_map(|(name, remote)| Some(format!("{}:{name}", remote.alarm?)))
+            .collect()
+    }
+}
@@ -0,0 +1,46 @@
+use anyhow::{Context, Result};
+use std::fs;
+use std::time::Duration;
+
+const WAKEALARM: &str = "/sys/class/rtc/rtc0/wakealarm";
+
+/// Programs the RTC to wake the machine after `after`, replacing any existing alarm. Writing
+/// wakealarm usually needs root.
+pub fn set_wake_alarm(after: Duration) -> Result<()> {
+    // The kernel refuses to change an alarm which is already set, so clear it first
+    fs::write(WAKEALARM, "0").with_context(|| format!("failed to clear {WAKEALARM}"))?;
+    fs::write(WAKEALARM, format!("+{}", after.as_secs()))
+        .with_context(|| format!("failed to write {WAKEALARM}"))?;
+    Ok(())
+}
+
+/// Clears any wake alarm, so that the machine isn't woken for one we no longer want.
+pub fn clear_wake_alarm() -> Result<()> {
+    fs::write(WAKEALARM, "0").with_context(|| format!("failed to clear {WAKEALARM}"))
+}
+
+fn clock(id: libc::clockid_t) -> Option<Duration> {
+    // SAFETY: timespec is plain data, for which all zeroes is valid, and clock_gettime only writes
+    // to it
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+    if unsafe { libc::clock_gettime(id, &mut ts) } != 0 {
+        return None;
+    }
+    Some(Duration::new(
+        ts.tv_sec.try_into().ok()?,
+        ts.tv_nsec.try_into().ok()?,
+    ))
+}
+
+/// Time since boot, not counting time spent suspended.
+pub fn monotonic() -> Option<Duration> {
+    clock(libc::CLOCK_MONOTONIC)
+}
+
+/// How long the machine has spent suspended since boot. CLOCK_BOOTTIME keeps counting while
+/// suspended and CLOCK_MONOTONIC doesn't, so this is how far apart they've drifted.
+pub fn suspended_time() -> Option<Duration> {
+    // Read monotonic first, otherwise the time between the reads could make this negative
+    let monotonic = clock(libc::CLOCK_MONOTONIC)?;
+    clock(libc::CLOCK_BOOTTIME)?.checked_sub(monotonic)
+}
@@ -0,0 +1,109 @@
+use anyhow::Result;
+use std::fmt::Display;
+use std::thread;
+use std::time::Duration;
+
+use crate::notification::{self, Category, SingleNotification, Sink};
+use crate::{bluetooth, monitors, system, Config};
+
+/// How long to leave the test notifications up before closing them.
+const NOTIFICATION_DURATION: Duration = Duration::from_secs(10);
+
+fn report<T, E: Display>(what: &str, res: Result<T, E>, describe: impl FnOnce(T) -> String) {
+    match res {
+        Ok(val) => println!("ok    {what}: {}", describe(val)),
+        Err(err) => println!("FAIL  {what}: {err}"),
+    }
+}
+
+/// Checks that everything we depend on is reachable, shows one notification of each kind, and
+/// prints the commands we would run, so a new config can be checked in one go.
+pub fn run(cfg: &Config) -> Result<()> {
+    println!("Connectivity:");
+    report("batteries", system::get_batteries(), |bats| {
+        format!("{} found", bats.len())
+    });
+    report("AC", system::get_ac_online(), |online| match online {
+        Some(true) => "online".to_string(),
+        Some(false) => "offline".to_string(),
+        None => "no mains power supply found".to_string(),
+    });
+    report(
+        "notification server",
+        notify_rust::get_server_information(),
+        |info| format!("{} {} by {}", info.name, info.version, info.vendor),
+    );
+    if cfg.bluetooth_low_pct != 0 || cfg.bluetooth_critical_pct != 0 {
+        report("BlueZ", bluetooth::get_battery_levels(), |bbats| {
+            format!("{} devices with battery information", bbats.len())
+        });
+    }
+    if cfg.warn_on_mons_with_no_ac > 0 {
+        report(
+            "Monitors",
+            monitors::get_nr_connected(cfg.monitors_backend),
+            |conn| format!("{conn} monitors connected"),
+        );
+    }
+
+    println!("\nCommands (not run):");
+    // Only ever run if we set the RTC to wake us up
+    let after_wake = if cfg.events.sleep.rtc_wake_secs != 0 {
+        cfg.events.sleep.after_wake_command.to_string()
+    } else {
+        String::new()
+    };
+    let mut cmds: Vec<_> = [
+        ("events.sleep.command", cfg.events.sleep.command.to_string()),
+        ("events.sleep.after_wake_command", after_wake),
+        (
+            "events.sleep.inhibit_command",
+            cfg.events.sleep.inhibit_command.clone(),
+        ),
+        (
+            "bluetooth_critical_command",
+            cfg.bluetooth_critical_command.clone(),
+        ),
+        ("stale_data_command", cfg.stale_data_command.clone()),
+    ]
+    .map(|(name, cmd)| (name.to_string(), cmd))
+    .into();
+    for category in Category::ALL {
+        let name = serde_plain::to_string(category).unwrap();
+        for button in &cfg.notifications.get(*category).buttons {
+            cmds.push((
+                format!("notifications.{name}.buttons '{}'", button.label),
+                button.command.to_string(),
+            ));
+        }
+    }
+    for (name, cmd) in cmds {
+        if !cmd.is_empty() {
+            println!("      {name}: {cmd}");
+        }
+    }
+
+    println!("\nNotifications:");
+    // Make sure capabilities are logged before we start showing things
+    notification::capabilities();
+    let sink = Sink::default();
+    sink.set_templates(&cfg.notifications);
+    let notifs = Category::ALL
+        .iter()
+        .map(|category| {
+            let name = serde_plain::to_string(category).unwrap();
+            let mut notif = SingleNotification::new(&sink);
+            notif.show_with_body(
+                format!("Test {name} notification"),
+                "This is a test from battery-notify self-test".to_string(),
+                cfg.notifications.style(*category),
+            );
+            println!("      showed {name}");
+            notif
+        })
+        .collect::<Vec<_>>();
+    thread::sleep(NOTIFICATION_DURATION);
+    drop(notifs);
+
+    Ok(())
+}
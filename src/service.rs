@@ -0,0 +1,414 @@
+use anyhow::Result;
+use cancellable_timer::Canceller;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::notification::Category;
+
+/// Something another program asked us to do over D-Bus, handled by the main loop.
+#[derive(Debug)]
+#[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+pub enum Request {
+    Refresh,
+    Suspend,
+    TestNotify(Category),
+    /// Stop all checks and actions, until resumed or for the given time.
+    Pause(Option<Duration>),
+    Resume,
+    /// Have the battery full by this long from now, instead of at charge_full_by, or clear that.
+    ChargeFullBy(Option<Duration>),
+    /// Use this low_pct until restarted.
+    SetLowPct(u8),
+    /// Use this profile whatever its conditions, or go back to picking one by them.
+    SetProfile(Option<String>),
+    /// Load the config file again, since it changed or we got SIGHUP.
+    Reload,
+    /// Hold back all but critical notifications for the given time, or stop doing so.
+    Snooze(Option<Duration>),
+}
+
+/// What the main loop publishes about itself for other programs to see.
+#[derive(Debug, Default)]
+#[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+pub struct Status {
+    /// The combined level and state as of the last check.
+    pub level: u8,
+    pub state: String,
+    /// The low_pct in use, which SetLowPct may have changed from the config.
+    pub low_pct: u8,
+    /// The sleep_pct in use, which low_pct has to stay above.
+    pub sleep_pct: u8,
+    /// Alerts currently live, like "low", "monitors" or "bluetooth_low:Mouse".
+    pub alarms: Vec<String>,
+    /// "Monitoring", "Paused", or "Paused until" a time.
+    pub pause: String,
+    /// "Notifying", "Game running", or "Snoozed until" or "Quiet hours until" a time.
+    pub silence: String,
+    /// The profile in use, or empty if none.
+    pub profile: String,
+    /// How many times a heavy command was deferred or skipped near sleep_pct.
+    pub held_heavy_commands: u64,
+    /// How long each phase of the last full cycle took, followed by "total".
+    pub cycle_timings: Vec<(&'static str, Duration)>,
+    /// What we're using for each kind of check or output, as from version::backends().
+    pub backends: Vec<(&'static str, &'static str)>,
+}
+
+pub type SharedStatus = Arc<Mutex<Status>>;
+
+#[cfg(feature = "dbus")]
+const NAME: &str = "org.cdown.BatteryNotify";
+#[cfg(feature = "dbus")]
+const PATH: &str = "/org/cdown/BatteryNotify";
+
+#[cfg(feature = "dbus")]
+pub struct Service {
+    conn: zbus::blocking::Connection,
+    // The properties we last told listeners about, so we only signal actual changes
+    published: Mutex<Option<(u8, String, u8)>>,
+}
+
+#[cfg(feature = "dbus")]
+impl Service {
+    /// Emits PropertiesChanged for any of Level, State and LowPct which changed since last time.
+    /// The values are read back through the properties, so `status` mustn't be locked.
+    pub fn publish(&self, status: &SharedStatus) {
+        let current = {
+            let status = status.lock().unwrap();
+            (status.level, status.state.clone(), status.low_pct)
+        };
+        let mut published = self.published.lock().unwrap();
+        let prev = published.as_ref();
+        if prev == Some(&current) {
+            return;
+        }
+        let res = self
+            .conn
+            .object_server()
+            .interface::<_, Interface>(PATH)
+            .and_then(|iface_ref| {
+                let iface = iface_ref.get();
+                let emitter = iface_ref.signal_emitter();
+                zbus::block_on(async {
+                    if prev.map(|p| p.0) != Some(current.0) {
+                        iface.level_changed(emitter).await?;
+                    }
+                    if prev.map(|p| &p.1) != Some(&current.1) {
+                        iface.state_changed(emitter).await?;
+                    }
+                    if prev.map(|p| p.2) != Some(current.2) {
+                        iface.low_pct_changed(emitter).await?;
+                    }
+                    Ok(())
+                })
+            });
+        match res {
+            Ok(()) => *published = Some(current),
+            Err(err) => log::error!("failed to emit PropertiesChanged: {err}"),
+        }
+    }
+}
+
+#[cfg(feature = "dbus")]
+struct Interface {
+    tx: Sender<Request>,
+    canceller: Canceller,
+    status: SharedStatus,
+}
+
+#[cfg(feature = "dbus")]
+impl Interface {
+    fn send(&self, req: Request) -> zbus::fdo::Result<()> {
+        self.tx
+            .send(req)
+            .map_err(|err| zbus::fdo::Error::Failed(err.to_string()))?;
+        // If we fail to cancel, the request will be handled at the next wakeup anyway
+        let _ = self.canceller.cancel();
+        Ok(())
+    }
+}
+
+#[cfg(feature = "dbus")]
+#[zbus::interface(name = "org.cdown.BatteryNotify")]
+impl Interface {
+    /// Check battery state now rather than waiting for the next interval.
+    fn refresh(&self) -> zbus::fdo::Result<()> {
+        self.send(Request::Refresh)
+    }
+
+    /// Run the configured sleep action, as if we reached sleep_pct.
+    fn suspend(&self) -> zbus::fdo::Result<()> {
+        self.send(Request::Suspend)
+    }
+
+    /// The combined battery level as of the last check.
+    #[zbus(property)]
+    fn level(&self) -> u8 {
+        self.status.lock().unwrap().level
+    }
+
+    /// The combined battery state as of the last check, like "discharging" or "at threshold".
+    #[zbus(property)]
+    fn state(&self) -> String {
+        self.status.lock().unwrap().state.clone()
+    }
+
+    /// The low_pct in use, including any change made with SetLowPct.
+    #[zbus(property)]
+    fn low_pct(&self) -> u8 {
+        self.status.lock().unwrap().low_pct
+    }
+
+    /// Use this low_pct instead of the configured one until restarted, or 0 to disable it.
+    fn set_low_pct(&self, pct: u8) -> zbus::fdo::Result<()> {
+        if pct > 100 {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "low_pct must be at most 100, not {pct}"
+            )));
+        }
+        let sleep_pct = self.status.lock().unwrap().sleep_pct;
+        if pct != 0 && pct <= sleep_pct {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "low_pct must be above sleep_pct ({sleep_pct}), not {pct}"
+            )));
+        }
+        self.send(Request::SetLowPct(pct))
+    }
+
+    /// Alerts currently live, so that bars can show a warning without duplicating our thresholds.
+    /// Bluetooth ones include the device, like "bluetooth_low:Mouse".
+    fn active_alarms(&self) -> Vec<String> {
+        self.status.lock().unwrap().alarms.clone()
+    }
+
+    /// Whether checks are paused: "Monitoring", "Paused" (until Resume), or "Paused until" a time.
+    fn pause_state(&self) -> String {
+        self.status.lock().unwrap().pause.clone()
+    }
+
+    /// Hold back all but critical notifications for this many seconds, or stop doing so if 0.
+    fn snooze(&self, secs: u64) -> zbus::fdo::Result<()> {
+        self.send(Request::Snooze(
+            (secs > 0).then(|| Duration::from_secs(secs)),
+        ))
+    }
+
+    /// Whether notifications are held back: "Notifying", "Game running", or "Snoozed until" or
+    /// "Quiet hours until" a time.
+    fn silence_state(&self) -> String {
+        self.status.lock().unwrap().silence.clone()
+    }
+
+    /// Use this profile whatever its conditions, or go back to picking one by them if empty.
+    fn set_profile(&self, name: &str) -> zbus::fdo::Result<()> {
+        self.send(Request::SetProfile(
+            (!name.is_empty()).then(|| name.to_string()),
+        ))
+    }
+
+    /// The profile in use, or empty if none.
+    fn active_profile(&self) -> String {
+        self.status.lock().unwrap().profile.clone()
+    }
+
+    /// How many times a command in heavy_commands was deferred or skipped while at or below
+    /// sleep_pct, since we started.
+    fn held_heavy_commands(&self) -> u64 {
+        self.status.lock().unwrap().held_heavy_commands
+    }
+
+    /// How long each phase of the last full cycle took in microseconds, followed by "total", to find
+    /// what's slow when cycles overrun.
+    fn cycle_timings(&self) -> Vec<(String, u64)> {
+        self.status
+            .lock()
+            .unwrap()
+            .cycle_timings
+            .iter()
+            .map(|(phase, took)| (phase.to_string(), crate::timing::duration_us(*took)))
+            .collect()
+    }
+
+    /// The most recent notifications, oldest first, as (time, category, summary, outcome). Outcome
+    /// is one of "shown", "updated", "relayed", "silenced", "failed" or "closed".
+    fn notification_history(&self) -> Vec<(String, String, String, String)> {
+        crate::notification::history()
+            .into_iter()
+            .map(|entry| {
+                (
+                    crate::clock::utc_time(entry.time),
+                    entry
+                        .category
+                        .map(|c| serde_plain::to_string(&c).unwrap())
+                        .unwrap_or_default(),
+                    entry.summary,
+                    entry.outcome.to_string(),
+                )
+            })
+            .collect()
+    }
+
+    /// (version, git hash or "", enabled features, (kind, backend) pairs), so bug reports and
+    /// inventories can tell exactly what's running.
+    fn build_info(&self) -> crate::version::BuildInfo {
+        (
+            crate::version::VERSION.to_string(),
+            crate::version::GIT_HASH.unwrap_or_default().to_string(),
+            crate::version::enabled_features()
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            self.status
+                .lock()
+                .unwrap()
+                .backends
+                .iter()
+                .map(|(kind, backend)| (kind.to_string(), backend.to_string()))
+                .collect(),
+        )
+    }
+
+    /// The most recent log lines, for debug dumps.
+    fn recent_logs(&self) -> Vec<String> {
+        crate::logging::recent()
+    }
+
+    /// Show a test notification for the given category, like "low" or "bluetooth_low".
+    fn test_notify(&self, category: &str) -> zbus::fdo::Result<()> {
+        let category = serde_plain::from_str(category)
+            .map_err(|_| zbus::fdo::Error::InvalidArgs(format!("unknown category: {category}")))?;
+        self.send(Request::TestNotify(category))
+    }
+
+    /// Stop all checks and actions for this many seconds, or until Resume if 0.
+    fn pause(&self, secs: u64) -> zbus::fdo::Result<()> {
+        self.send(Request::Pause(
+            (secs > 0).then(|| Duration::from_secs(secs)),
+        ))
+    }
+
+    /// Undo Pause.
+    fn resume(&self) -> zbus::fdo::Result<()> {
+        self.send(Request::Resume)
+    }
+
+    /// Have the battery full in this many seconds, instead of at charge_full_by, or go back to
+    /// charge_full_by if 0.
+    fn charge_full_by(&self, secs: u64) -> zbus::fdo::Result<()> {
+        self.send(Request::ChargeFullBy(
+            (secs > 0).then(|| Duration::from_secs(secs)),
+        ))
+    }
+}
+
+/// Exposes org.cdown.BatteryNotify on the session bus. Requests are passed to the main loop through
+/// `tx`, `canceller` is used to wake it up, and `status` is what it last published.
+#[cfg(feature = "dbus")]
+pub fn start(
+    tx: Sender<Request>,
+    canceller: Canceller,
+    status: SharedStatus,
+) -> Result<Option<Service>> {
+    let conn = zbus::blocking::connection::Builder::session()?
+        .name(NAME)?
+        .serve_at(
+            PATH,
+            Interface {
+                tx,
+                canceller,
+                status,
+            },
+        )?
+        .build()?;
+    Ok(Some(Service {
+        conn,
+        published: Mutex::new(None),
+    }))
+}
+
+/// Whether the daemon has the bus name. Calling it would start it if it's D-Bus activatable, which
+/// isn't wanted just to look at it.
+#[cfg(feature = "dbus")]
+pub fn running() -> Result<bool> {
+    let conn = zbus::blocking::Connection::session()?;
+    let reply = conn.call_method(
+        Some("org.freedesktop.DBus"),
+        "/org/freedesktop/DBus",
+        Some("org.freedesktop.DBus"),
+        "NameHasOwner",
+        &(NAME,),
+    )?;
+    Ok(reply.body().deserialize()?)
+}
+
+/// Calls a method on the running daemon's interface.
+#[cfg(feature = "dbus")]
+pub fn call<B>(method: &str, body: &B) -> Result<zbus::Message>
+where
+    B: serde::Serialize + zbus::zvariant::DynamicType,
+{
+    let conn = zbus::blocking::Connection::session()?;
+    Ok(conn.call_method(Some(NAME), PATH, Some(NAME), method, body)?)
+}
+
+#[cfg(feature = "dbus")]
+pub fn pause(duration: Option<Duration>) -> Result<()> {
+    call("Pause", &(duration.map_or(0, |d| d.as_secs().max(1)),))?;
+    Ok(())
+}
+
+#[cfg(feature = "dbus")]
+pub fn resume() -> Result<()> {
+    call("Resume", &())?;
+    Ok(())
+}
+
+#[cfg(feature = "dbus")]
+pub fn snooze(duration: Option<Duration>) -> Result<()> {
+    call("Snooze", &(duration.map_or(0, |d| d.as_secs().max(1)),))?;
+    Ok(())
+}
+
+#[cfg(feature = "dbus")]
+pub fn set_profile(name: Option<&str>) -> Result<()> {
+    call("SetProfile", &(name.unwrap_or_default(),))?;
+    Ok(())
+}
+
+#[cfg(not(feature = "dbus"))]
+pub struct Service;
+
+#[cfg(not(feature = "dbus"))]
+impl Service {
+    pub fn publish(&self, _status: &SharedStatus) {}
+}
+
+#[cfg(not(feature = "dbus"))]
+pub fn start(
+    _tx: Sender<Request>,
+    _canceller: Canceller,
+    _status: SharedStatus,
+) -> Result<Option<Service>> {
+    Ok(None)
+}
+
+#[cfg(not(feature = "dbus"))]
+pub fn pause(_duration: Option<Duration>) -> Result<()> {
+    anyhow::bail!("built without D-Bus support, can't talk to the daemon")
+}
+
+#[cfg(not(feature = "dbus"))]
+pub fn resume() -> Result<()> {
+    anyhow::bail!("built without D-Bus support, can't talk to the daemon")
+}
+
+#[cfg(not(feature = "dbus"))]
+pub fn snooze(_duration: Option<Duration>) -> Result<()> {
+    anyhow::bail!("built without D-Bus support, can't talk to the daemon")
+}
+
+#[cfg(not(feature = "dbus"))]
+pub fn set_profile(_name: Option<&str>) -> Result<()> {
+    anyhow::bail!("built without D-Bus support, can't talk to the daemon")
+}
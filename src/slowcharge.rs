@@ -0,0 +1,91 @@
+use crate::notification::{SingleNotification, Sink, Style};
+use crate::system::{Battery, BatteryState};
+use log::{info, warn};
+use std::time::{Duration, Instant};
+
+/// Near full, charging tapers off by design, so a low rate there doesn't mean anything.
+const MAX_LEVEL_PCT: u8 = 80;
+
+/// Watches for charging which barely makes progress, for example through a USB-C hub which is also
+/// powering a lot of peripherals.
+pub struct SlowChargeMonitor {
+    min_uw: u64,
+    sustain: Duration,
+    // When the rate first dropped below min_uw, if it's still there
+    slow_since: Option<Instant>,
+    notified: bool,
+    notif: SingleNotification,
+}
+
+impl SlowChargeMonitor {
+    pub fn new(min_watts: f64, sustain: Duration, sink: &Sink) -> Self {
+        Self {
+            min_uw: (min_watts * 1_000_000.0) as u64,
+            sustain,
+            slow_since: None,
+            notified: false,
+            notif: SingleNotification::new(sink),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.slow_since = None;
+        self.notified = false;
+        self.notif.close();
+    }
+
+    pub const fn is_active(&self) -> bool {
+        self.notif.is_shown()
+    }
+
+    /// `global` is the combined battery, and `now` the start of this cycle.
+    pub fn update(&mut self, global: &Battery, now: Instant, style: Style) {
+        let power_uw = match global.power_uw {
+            Some(uw)
+                if self.min_uw != 0
+                    && global.state == BatteryState::Charging
+                    && global.level() < MAX_LEVEL_PCT =>
+            {
+                uw
+            }
+            _ => {
+                self.reset();
+                return;
+            }
+        };
+
+        if power_uw >= self.min_uw {
+            if self.notified {
+                info!("Charging rate recovered to {:.1} W", watts(power_uw));
+            }
+            self.reset();
+            return;
+        }
+
+        let since = *self.slow_since.get_or_insert(now);
+        if now.duration_since(since) >= self.sustain {
+            if !self.notified {
+                warn!(
+                    event = "slow_charge", power_uw = power_uw;
+                    "Charging at only {:.1} W for at least {}",
+                    watts(power_uw),
+                    humantime::format_duration(self.sustain)
+                );
+                self.notified = true;
+            }
+            // Keep the measured rate in the notification up to date
+            self.notif.show_with_body(
+                "Charging very slowly".to_string(),
+                format!(
+                    "Only {:.1} W net is reaching the battery, check what else the charger is powering",
+                    watts(power_uw)
+                ),
+                style,
+            );
+        }
+    }
+}
+
+fn watts(uw: u64) -> f64 {
+    uw as f64 / 1_000_000.0
+}
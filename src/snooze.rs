@@ -0,0 +1,155 @@
+//! Silences all but critical notifications for a while when asked, every day during quiet hours, or
+//! while a game is running. Checks and actions like the sleep command carry on as usual, unlike
+//! with a pause.
+
+use anyhow::{Context, Result};
+use log::info;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::clock::{self, local_secs_of_day, parse_time_of_day, DAY_SECS};
+use crate::notification::Sink;
+
+/// A daily range of local time, which may span midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct QuietHours {
+    start: u64,
+    end: u64,
+}
+
+impl QuietHours {
+    /// Parses "HH:MM-HH:MM", or None if empty.
+    fn parse(range: &str) -> Result<Option<Self>> {
+        if range.is_empty() {
+            return Ok(None);
+        }
+        let (start, end) = range
+            .split_once('-')
+            .with_context(|| format!("invalid range '{range}', expected HH:MM-HH:MM"))?;
+        let (Some(start), Some(end)) = (parse_time_of_day(start)?, parse_time_of_day(end)?) else {
+            anyhow::bail!("invalid range '{range}', expected HH:MM-HH:MM");
+        };
+        Ok(Some(Self { start, end }))
+    }
+
+    /// How long until quiet hours end, if `secs_of_day` is within them.
+    fn remaining(self, secs_of_day: u64) -> Option<Duration> {
+        let since_start = (secs_of_day + DAY_SECS - self.start) % DAY_SECS;
+        let len = (self.end + DAY_SECS - self.start) % DAY_SECS;
+        (since_start < len).then(|| Duration::from_secs(len - since_start))
+    }
+}
+
+/// Checks `quiet_hours` from the config, for Config::validate.
+pub fn validate(quiet_hours: &str) -> Result<()> {
+    QuietHours::parse(quiet_hours)
+        .context("invalid quiet_hours")
+        .map(drop)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Why {
+    Snoozed,
+    Gaming,
+    QuietHours,
+}
+
+pub struct Silence {
+    sink: Sink,
+    quiet_hours: Option<QuietHours>,
+    snooze_until: Option<Instant>,
+    gaming: bool,
+    // Why we're currently silent, if we are, to log when that changes
+    active: Option<Why>,
+    // When the current silence ends, as of the last update, for status
+    until: Option<SystemTime>,
+}
+
+impl Silence {
+    /// Silences notifications through `sink` (and its clones) when it's time.
+    pub fn new(quiet_hours: &str, sink: &Sink) -> Result<Self> {
+        Ok(Self {
+            sink: sink.clone(),
+            quiet_hours: QuietHours::parse(quiet_hours).context("invalid quiet_hours")?,
+            snooze_until: None,
+            gaming: false,
+            active: None,
+            until: None,
+        })
+    }
+
+    pub fn set_quiet_hours(&mut self, quiet_hours: &str) -> Result<()> {
+        self.quiet_hours = QuietHours::parse(quiet_hours).context("invalid quiet_hours")?;
+        Ok(())
+    }
+
+    /// Silences notifications for `duration` from `now`, or stops a snooze early if None.
+    pub fn snooze(&mut self, duration: Option<Duration>, now: Instant) {
+        self.snooze_until = duration.map(|duration| now + duration);
+        match duration {
+            Some(duration) => info!(
+                "Snoozing notifications for {}",
+                humantime::format_duration(duration)
+            ),
+            None => info!("Snooze cancelled"),
+        }
+    }
+
+    /// Whether a game is registered with GameMode, as of the last check.
+    pub fn set_gaming(&mut self, gaming: bool) {
+        self.gaming = gaming;
+    }
+
+    /// When a snooze ends, so the caller can check again then rather than at the next interval.
+    pub const fn snooze_end(&self) -> Option<Instant> {
+        self.snooze_until
+    }
+
+    /// Works out whether we should be silent at `now` (and `wall`, for quiet hours), and silences
+    /// or unsilences notifications to match.
+    pub fn update(&mut self, now: Instant, wall: SystemTime) -> bool {
+        if self.snooze_until.is_some_and(|until| now >= until) {
+            info!("Snooze expired");
+            self.snooze_until = None;
+        }
+        let quiet = self
+            .quiet_hours
+            .zip(local_secs_of_day(wall))
+            .and_then(|(hours, secs)| hours.remaining(secs));
+        // A snooze is reported over quiet hours, since it's what someone asked for just now. A game
+        // has no end we know of
+        let (why, until) = match (self.snooze_until, quiet) {
+            (Some(until), _) => (
+                Some(Why::Snoozed),
+                Some(wall + until.saturating_duration_since(now)),
+            ),
+            (None, _) if self.gaming => (Some(Why::Gaming), None),
+            (None, Some(left)) => (Some(Why::QuietHours), Some(wall + left)),
+            (None, None) => (None, None),
+        };
+        if why != self.active {
+            match why {
+                Some(Why::QuietHours) => {
+                    info!("In quiet hours, only showing critical notifications")
+                }
+                Some(Why::Snoozed) => info!("Snoozed, only showing critical notifications"),
+                Some(Why::Gaming) => info!("GameMode active, only showing critical notifications"),
+                None => info!("Showing all notifications again"),
+            }
+            self.active = why;
+        }
+        self.until = until;
+        self.sink.set_silenced(why.is_some());
+        why.is_some()
+    }
+
+    /// "Notifying", or why and until when we aren't.
+    pub fn status(&self) -> String {
+        let until = clock::local_time;
+        match (self.active, self.until) {
+            (Some(Why::Snoozed), Some(time)) => format!("Snoozed until {}", until(time)),
+            (Some(Why::QuietHours), Some(time)) => format!("Quiet hours until {}", until(time)),
+            (Some(Why::Gaming), _) => "Game running".to_string(),
+            _ => "Notifying".to_string(),
+        }
+    }
+}
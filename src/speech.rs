@@ -0,0 +1,84 @@
+//! Speaks low and critical battery alerts through speech-dispatcher's spd-say, so they're heard
+//! whatever the notification daemon does with them (or if there isn't one).
+
+use anyhow::{bail, Result};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::process::Command;
+use std::thread;
+
+/// speech-dispatcher's message priorities, from most to least urgent.
+const PRIORITIES: &[&str] = &["important", "message", "text", "notification", "progress"];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpeechConfig {
+    pub enabled: bool,
+    /// spd-say's --voice-type, like "female1", or empty for the default.
+    pub voice: String,
+    /// One of PRIORITIES.
+    pub priority: String,
+    /// spd-say's --language, like "en", or empty for the default.
+    pub language: String,
+    /// From -100 to 100, 0 being normal speed.
+    pub rate: i8,
+}
+
+impl Default for SpeechConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            voice: String::new(),
+            priority: "important".to_string(),
+            language: String::new(),
+            rate: 0,
+        }
+    }
+}
+
+/// Checks [speech] from the config, for Config::validate.
+pub fn validate(cfg: &SpeechConfig) -> Result<()> {
+    if !PRIORITIES.contains(&cfg.priority.as_str()) {
+        bail!(
+            "unknown speech.priority '{}', expected one of {}",
+            cfg.priority,
+            PRIORITIES.join(", ")
+        );
+    }
+    if !(-100..=100).contains(&cfg.rate) {
+        bail!("speech.rate must be between -100 and 100");
+    }
+    Ok(())
+}
+
+/// Says `text`, if `cfg` enables it. spd-say usually returns as soon as it's queued, but it can take
+/// a while to start speech-dispatcher first, so it runs on a thread of its own.
+pub fn say(cfg: &SpeechConfig, text: &str) {
+    if !cfg.enabled {
+        return;
+    }
+    let mut cmd = Command::new("spd-say");
+    cmd.args(["--priority", &cfg.priority]);
+    if !cfg.voice.is_empty() {
+        cmd.args(["--voice-type", &cfg.voice]);
+    }
+    if !cfg.language.is_empty() {
+        cmd.args(["--language", &cfg.language]);
+    }
+    if cfg.rate != 0 {
+        cmd.args(["--rate", &cfg.rate.to_string()]);
+    }
+    // So text starting with a dash isn't taken as an option
+    cmd.args(["--", text]);
+    info!("Speaking '{text}'");
+    let res = thread::Builder::new()
+        .name("speech".to_string())
+        .spawn(move || match cmd.status() {
+            Ok(status) if !status.success() => error!("spd-say failed: {status}"),
+            Ok(_) => {}
+            Err(err) => error!("failed to run spd-say: {err}"),
+        });
+    if let Err(err) = res {
+        error!("failed to start speaking: {err}");
+    }
+}
@@ -0,0 +1,107 @@
+//! Things worth remembering across restarts, kept in `$XDG_STATE_HOME/battery-notify/state.toml`.
+
+use anyhow::{Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crate::system::BatteryState;
+
+/// Why and when we last ran the sleep command, so that people can tell our suspends apart from
+/// other causes after the fact.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LastSuspend {
+    pub time: String,
+    pub reason: String,
+    /// The combined battery level at the time, if we'd read it.
+    pub level: Option<u8>,
+    pub sleep_pct: u8,
+    pub succeeded: bool,
+    /// What held the command off last before it ran, like "Sleep inhibited by backup", and for how
+    /// many seconds since we first wanted to sleep.
+    pub deferred_by: Option<String>,
+    pub deferred_secs: Option<u64>,
+    /// Sleep inhibitors still held when it ran, which we went ahead regardless of at
+    /// inhibit_floor_pct.
+    #[serde(default)]
+    pub inhibitors: Vec<String>,
+}
+
+/// What the daemon was in the middle of, per restore_state.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Runtime {
+    /// The kernel's boot ID, since none of this means anything after a reboot.
+    pub boot_id: String,
+    /// The last battery state we notified about.
+    pub shown_state: Option<BatteryState>,
+    /// Whether the battery was low, and whether we'd notified about it being "low" or "critical".
+    pub low: bool,
+    pub low_event: Option<String>,
+    pub tiers_fired: usize,
+    /// When a snooze ends, in seconds since the Unix epoch.
+    pub snooze_until: Option<u64>,
+    /// The notification server's ID for each notification we had up, like "low", so that they're
+    /// replaced rather than shown again alongside.
+    pub notifications: BTreeMap<String, u32>,
+}
+
+pub fn boot_id() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/random/boot_id")
+        .ok()
+        .map(|id| id.trim().to_string())
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct State {
+    pub last_suspend: Option<LastSuspend>,
+    /// Batteries we've warned about being below health_warn_pct, and their health at the time, so
+    /// that the warning isn't repeated every login.
+    pub health_warned: BTreeMap<String, u8>,
+    pub runtime: Option<Runtime>,
+}
+
+fn path() -> Result<PathBuf> {
+    let dirs = directories::BaseDirs::new().context("failed to find home directory")?;
+    Ok(dirs
+        .state_dir()
+        .unwrap_or_else(|| dirs.cache_dir())
+        .join("battery-notify")
+        .join("state.toml"))
+}
+
+pub fn load() -> Result<State> {
+    let path = path()?;
+    match fs::read_to_string(&path) {
+        // A crash or power loss at the wrong moment can still leave it empty or cut short, and
+        // losing what we remembered is better than refusing to start
+        Ok(content) => Ok(toml::from_str(&content).unwrap_or_else(|err| {
+            warn!("Ignoring unparsable {}: {err}", path.display());
+            State::default()
+        })),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(State::default()),
+        Err(err) => Err(err).with_context(|| format!("failed to read {}", path.display())),
+    }
+}
+
+impl State {
+    /// Writes the state out, via a temporary file so that we never leave a partial one behind. This
+    /// is often right before suspending, so it's synced to disk before the rename, and the rename
+    /// after, in case we never wake.
+    pub fn save(&self) -> Result<()> {
+        let path = path()?;
+        let dir = path.parent().context("state path has no directory")?;
+        fs::create_dir_all(dir)?;
+        let tmp = path.with_extension("toml.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(toml::to_string(self)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        File::open(dir)?.sync_all()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,478 @@
+//! Shows how everything we watch looks right now, and what the running daemon is up to, by asking
+//! it over D-Bus.
+
+use anyhow::Result;
+use std::time::{Duration, SystemTime};
+
+use crate::engine::Report;
+use crate::json::{or_error, Json};
+use crate::system::{self, Battery, BatteryInfo};
+use crate::theme::ThemeConfig;
+use crate::{bluetooth, clock, estimate, monitors, state, version, Config};
+
+/// What the running daemon told us about itself.
+struct Daemon {
+    /// "Monitoring", "Paused", or "Paused until ...".
+    pause: String,
+    /// "Notifying", "Game running", "Snoozed until ..." or "Quiet hours until ...". Older daemons
+    /// don't have this.
+    silence: Option<String>,
+    /// Empty if none. Older daemons don't have this.
+    profile: Option<String>,
+    alarms: Vec<String>,
+    /// (time, category, summary, outcome), only fetched with --history.
+    history: Vec<(String, String, String, String)>,
+    /// Daemons older than BuildInfo don't have this.
+    build: Option<version::BuildInfo>,
+}
+
+#[cfg(feature = "dbus")]
+fn daemon(history: bool) -> Result<Daemon> {
+    use crate::service;
+
+    if !service::running()? {
+        anyhow::bail!("not running");
+    }
+    Ok(Daemon {
+        pause: service::call("PauseState", &())?.body().deserialize()?,
+        silence: service::call("SilenceState", &())
+            .and_then(|reply| Ok(reply.body().deserialize()?))
+            .ok(),
+        profile: service::call("ActiveProfile", &())
+            .and_then(|reply| Ok(reply.body().deserialize()?))
+            .ok(),
+        alarms: service::call("ActiveAlarms", &())?.body().deserialize()?,
+        history: if history {
+            service::call("NotificationHistory", &())?
+                .body()
+                .deserialize()?
+        } else {
+            Vec::new()
+        },
+        build: service::call("BuildInfo", &())
+            .and_then(|reply| Ok(reply.body().deserialize()?))
+            .ok(),
+    })
+}
+
+#[cfg(not(feature = "dbus"))]
+fn daemon(_history: bool) -> Result<Daemon> {
+    anyhow::bail!("built without D-Bus support, can't talk to the daemon")
+}
+
+fn watts(uw: u64) -> f64 {
+    uw as f64 / 1_000_000.0
+}
+
+fn battery_json(bat: &Battery) -> Vec<(String, Json)> {
+    [
+        ("name", Json::from(bat.name.as_str())),
+        ("level", Json::from(bat.level())),
+        (
+            "state",
+            Json::from(system::battery_state_to_lowercase_name(bat.state)),
+        ),
+        ("energy_now_uwh", Json::from(bat.now_uwh())),
+        ("energy_full_uwh", Json::from(bat.full_uwh())),
+        ("energy_full_design_uwh", Json::from(bat.design_uwh)),
+        ("health_pct", Json::from(bat.health())),
+        ("power_uw", Json::from(bat.power_uw)),
+        ("voltage_uv", Json::from(bat.voltage_uv)),
+        ("cycle_count", Json::from(bat.cycle_count)),
+        ("start_threshold", Json::from(bat.start_threshold)),
+        ("end_threshold", Json::from(bat.end_threshold)),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect()
+}
+
+fn bluetooth_json(bbats: &[bluetooth::BluetoothBattery]) -> Json {
+    Json::Arr(
+        bbats
+            .iter()
+            .map(|bbat| {
+                Json::obj([
+                    ("name", Json::from(bbat.name.as_str())),
+                    ("address", Json::from(bbat.address.clone())),
+                    ("level", Json::from(bbat.level)),
+                    ("class", Json::from(bbat.class.clone())),
+                    ("age_secs", Json::from(bbat.age.map(|age| age.as_secs()))),
+                    ("stale", Json::from(bbat.age.map(|_| bbat.stale))),
+                ])
+            })
+            .collect(),
+    )
+}
+
+/// One line of `--output json`. Alongside our own fields, text, tooltip, class and percentage are
+/// what waybar's custom modules expect, so it can be used there directly. The icon and colour come
+/// from `theme`.
+pub fn report_json(report: &Report, theme: &ThemeConfig) -> Json {
+    let level = report.global.level();
+    let state = report.global.state;
+    let state_name = system::battery_state_to_lowercase_name(state);
+    let remaining = report
+        .remaining
+        .map(|r| humantime::format_duration(r).to_string());
+    let mut tooltip = match &remaining {
+        Some(r) => format!("{state_name}, about {r} remaining"),
+        None => state_name.to_string(),
+    };
+    if theme.bluetooth_tooltip {
+        for bbat in &report.bluetooth {
+            tooltip.push_str(&format!("\n{}: {}%", bbat.name, bbat.level));
+            if bbat.stale {
+                tooltip.push_str(" (stale)");
+            }
+        }
+    }
+    let mut class = vec![state_name.replace(' ', "-")];
+    class.extend(report.alarms.iter().cloned());
+    Json::obj([
+        ("text", Json::from(theme.text(level, state))),
+        ("tooltip", Json::from(tooltip)),
+        ("class", Json::from(class)),
+        ("percentage", Json::from(level)),
+        ("level", Json::from(level)),
+        ("state", Json::from(state_name)),
+        ("icon", Json::from(theme.icon(level, state))),
+        ("color", Json::from(theme.color(level, state))),
+        (
+            "remaining_secs",
+            Json::from(report.remaining.map(|r| r.as_secs())),
+        ),
+        ("drain_pct_per_hour", Json::from(report.drain_rate)),
+        (
+            "ac",
+            Json::from(system::ac_state_to_lowercase_name(report.ac)),
+        ),
+        (
+            "batteries",
+            Json::Arr(
+                report
+                    .batteries
+                    .iter()
+                    .map(|bat| Json::Obj(battery_json(bat)))
+                    .collect(),
+            ),
+        ),
+        ("bluetooth", bluetooth_json(&report.bluetooth)),
+        ("alarms", Json::from(report.alarms.clone())),
+    ])
+}
+
+fn battery_text(bat: &Battery, info: &BatteryInfo) -> String {
+    let mut out = format!(
+        "  {}: {}%, {}, {:.1}/{:.1} Wh",
+        bat.name,
+        bat.level(),
+        system::battery_state_to_lowercase_name(bat.state),
+        watts(bat.now_uwh()),
+        watts(bat.full_uwh()),
+    );
+    if let Some(power) = bat.power_uw {
+        out.push_str(&format!(", {:.1} W", watts(power)));
+    }
+    if let Some(voltage) = bat.voltage_uv {
+        out.push_str(&format!(", {:.2} V", voltage as f64 / 1_000_000.0));
+    }
+    if let Some(cycles) = bat.cycle_count {
+        out.push_str(&format!(", {cycles} cycles"));
+    }
+    if let (Some(health), Some(design)) = (bat.health(), bat.design_uwh) {
+        out.push_str(&format!(
+            "\n    health {health}% of {:.1} Wh design",
+            watts(design)
+        ));
+    }
+    match (bat.start_threshold, bat.end_threshold) {
+        (Some(start), Some(end)) => {
+            out.push_str(&format!("\n    charge thresholds {start}-{end}%"))
+        }
+        (None, Some(end)) => out.push_str(&format!("\n    charge threshold {end}%")),
+        _ => {}
+    }
+    let pack: Vec<_> = [
+        &info.technology,
+        &info.manufacturer,
+        &info.model_name,
+        &info.serial_number,
+    ]
+    .into_iter()
+    .flatten()
+    .map(String::as_str)
+    .collect();
+    if !pack.is_empty() {
+        out.push_str(&format!("\n    {}", pack.join(", ")));
+    }
+    out
+}
+
+fn remaining_text(remaining: Option<Duration>) -> String {
+    remaining
+        .map(|r| format!(", about {} remaining", humantime::format_duration(r)))
+        .unwrap_or_default()
+}
+
+/// Prints batteries, AC, Bluetooth devices and monitors as we see them now, the last suspend we
+/// caused, and what the daemon is up to. Bluetooth and monitors are only checked if the config
+/// would have the daemon check them.
+pub fn run(cfg: &Config, history: bool, json: bool) -> Result<()> {
+    let batteries = system::get_batteries()?;
+    let infos: Vec<_> = batteries
+        .iter()
+        .map(|bat| system::get_battery_info(&bat.name))
+        .collect();
+    let global = (!batteries.is_empty()).then(|| system::get_global_battery(&batteries));
+    let remaining = global.as_ref().and_then(estimate::one_off);
+    let ac_online = system::get_ac_online();
+    let bbats = (cfg.bluetooth_low_pct != 0
+        || cfg.bluetooth_critical_pct != 0
+        || cfg.bluetooth.has_overrides())
+    .then(|| {
+        bluetooth::get_battery_levels().map(|mut bbats| {
+            bbats.retain(|bbat| !cfg.bluetooth.ignored(bbat));
+            bbats
+        })
+    });
+    let mons = (cfg.warn_on_mons_with_no_ac > 0 && !cfg.system_mode)
+        .then(|| monitors::get_nr_connected(cfg.monitors_backend));
+    let last_suspend = state::load()?.last_suspend;
+    let daemon = daemon(history);
+
+    if json {
+        let now = SystemTime::now();
+        let out = Json::obj([
+            ("time", Json::from(clock::utc_time(now))),
+            ("local_time", Json::from(clock::local_time(now))),
+            (
+                "version",
+                Json::obj([
+                    ("version", Json::from(version::VERSION)),
+                    ("git_hash", Json::from(version::GIT_HASH)),
+                    ("features", Json::from(version::enabled_features())),
+                ]),
+            ),
+            (
+                "batteries",
+                Json::Arr(
+                    batteries
+                        .iter()
+                        .zip(&infos)
+                        .map(|(bat, info)| {
+                            let mut fields = battery_json(bat);
+                            fields.extend(
+                                [
+                                    ("technology", &info.technology),
+                                    ("manufacturer", &info.manufacturer),
+                                    ("model_name", &info.model_name),
+                                    ("serial_number", &info.serial_number),
+                                ]
+                                .map(|(k, v)| (k.to_string(), Json::from(v.clone()))),
+                            );
+                            Json::Obj(fields)
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                "combined",
+                global.as_ref().map_or(Json::Null, |global| {
+                    Json::obj([
+                        ("level", Json::from(global.level())),
+                        (
+                            "state",
+                            Json::from(system::battery_state_to_lowercase_name(global.state)),
+                        ),
+                        ("remaining_secs", Json::from(remaining.map(|r| r.as_secs()))),
+                    ])
+                }),
+            ),
+            ("ac_online", or_error(ac_online, Json::from)),
+            (
+                "bluetooth",
+                bbats.map_or(Json::Null, |res| {
+                    or_error(res, |bbats| bluetooth_json(&bbats))
+                }),
+            ),
+            (
+                "monitors",
+                mons.map_or(Json::Null, |res| or_error(res, Json::from)),
+            ),
+            (
+                "last_suspend",
+                last_suspend.map_or(Json::Null, |last| {
+                    Json::obj([
+                        ("local_time", Json::from(clock::localize(&last.time))),
+                        ("time", Json::from(last.time)),
+                        ("reason", Json::from(last.reason)),
+                        ("level", Json::from(last.level)),
+                        ("sleep_pct", Json::from(last.sleep_pct)),
+                        ("succeeded", Json::from(last.succeeded)),
+                        ("deferred_by", Json::from(last.deferred_by)),
+                        ("deferred_secs", Json::from(last.deferred_secs)),
+                        ("inhibitors", Json::from(last.inhibitors)),
+                    ])
+                }),
+            ),
+            (
+                "daemon",
+                or_error(daemon, |daemon| {
+                    let mut fields = vec![
+                        ("pause".to_string(), Json::from(daemon.pause)),
+                        ("alarms".to_string(), Json::from(daemon.alarms)),
+                    ];
+                    if let Some(silence) = daemon.silence {
+                        fields.push(("silence".to_string(), Json::from(silence)));
+                    }
+                    if let Some(profile) = daemon.profile {
+                        fields.push(("profile".to_string(), Json::from(profile)));
+                    }
+                    if let Some((version, git_hash, features, backends)) = daemon.build {
+                        let backends = backends
+                            .into_iter()
+                            .map(|(kind, backend)| (kind, Json::from(backend)))
+                            .collect();
+                        fields.push((
+                            "build".to_string(),
+                            Json::obj([
+                                ("version", Json::from(version)),
+                                ("git_hash", Json::from(git_hash)),
+                                ("features", Json::from(features)),
+                                ("backends", Json::Obj(backends)),
+                            ]),
+                        ));
+                    }
+                    if history {
+                        let history = daemon
+                            .history
+                            .into_iter()
+                            .map(|(time, category, summary, outcome)| {
+                                Json::obj([
+                                    ("local_time", Json::from(clock::localize(&time))),
+                                    ("time", Json::from(time)),
+                                    ("category", Json::from(category)),
+                                    ("summary", Json::from(summary)),
+                                    ("outcome", Json::from(outcome)),
+                                ])
+                            })
+                            .collect();
+                        fields.push(("history".to_string(), Json::Arr(history)));
+                    }
+                    Json::Obj(fields)
+                }),
+            ),
+        ]);
+        println!("{out}");
+        return Ok(());
+    }
+
+    println!("Batteries:");
+    if batteries.is_empty() {
+        println!("  none");
+    }
+    for (bat, info) in batteries.iter().zip(&infos) {
+        println!("{}", battery_text(bat, info));
+    }
+    if let Some(global) = &global {
+        println!(
+            "Combined: {}%, {}{}",
+            global.level(),
+            system::battery_state_to_lowercase_name(global.state),
+            remaining_text(remaining)
+        );
+    }
+    match ac_online {
+        Ok(Some(true)) => println!("AC: online"),
+        Ok(Some(false)) => println!("AC: offline"),
+        Ok(None) => println!("AC: no mains power supply found"),
+        Err(err) => println!("AC: {err}"),
+    }
+    match bbats {
+        Some(Ok(bbats)) if bbats.is_empty() => println!("Bluetooth: no devices with batteries"),
+        Some(Ok(bbats)) => {
+            println!("Bluetooth:");
+            for bbat in bbats {
+                println!("  {}: {}%", bbat.name, bbat.level);
+            }
+        }
+        Some(Err(err)) => println!("Bluetooth: {err}"),
+        None => {}
+    }
+    match mons {
+        Some(Ok(conn)) => println!("Monitors: {conn} connected"),
+        Some(Err(err)) => println!("Monitors: {err}"),
+        None => {}
+    }
+    if let Some(last) = last_suspend {
+        let level = last.level.map(|l| format!(" at {l}%")).unwrap_or_default();
+        let failed = if last.succeeded { "" } else { " (failed)" };
+        println!(
+            "Last suspend: {}{level}, {}{failed}",
+            clock::localize(&last.time),
+            last.reason
+        );
+        if let (Some(why), Some(secs)) = (&last.deferred_by, last.deferred_secs) {
+            println!(
+                "  held off for {} before that: {why}",
+                humantime::format_duration(Duration::from_secs(secs))
+            );
+        }
+        if !last.inhibitors.is_empty() {
+            println!(
+                "  went ahead despite inhibitors: {}",
+                last.inhibitors.join(", ")
+            );
+        }
+    }
+
+    let daemon = match daemon {
+        Ok(daemon) => daemon,
+        Err(err) => {
+            println!("Daemon: not reachable ({err})");
+            return Ok(());
+        }
+    };
+    println!("Daemon: {}", daemon.pause);
+    if let Some(silence) = &daemon.silence {
+        println!("  Notifications: {silence}");
+    }
+    if let Some(profile) = daemon.profile.filter(|p| !p.is_empty()) {
+        println!("  Profile: {profile}");
+    }
+    if let Some((version, git_hash, features, backends)) = daemon.build {
+        let git_hash = if git_hash.is_empty() {
+            String::new()
+        } else {
+            format!(" ({git_hash})")
+        };
+        println!(
+            "  battery-notify {version}{git_hash}, built with {}",
+            features.join(", ")
+        );
+        let backends: Vec<_> = backends
+            .iter()
+            .map(|(kind, backend)| format!("{kind} {backend}"))
+            .collect();
+        println!("  using {}", backends.join(", "));
+    }
+    if daemon.alarms.is_empty() {
+        println!("Active alarms: none");
+    } else {
+        println!("Active alarms: {}", daemon.alarms.join(", "));
+    }
+
+    if history {
+        println!("\nRecent notifications:");
+        if daemon.history.is_empty() {
+            println!("  none");
+        }
+        for (time, category, summary, outcome) in daemon.history {
+            let category = if category.is_empty() { "-" } else { &category };
+            let time = clock::localize(&time);
+            println!("  {time}  {category:<18} {outcome:<7}  {summary}");
+        }
+    }
+    Ok(())
+}
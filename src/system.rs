@@ -1,8 +1,19 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::ffi::OsStr;
 use std::fs;
 use std::path::Path;
+use std::thread;
+
+pub const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+/// How far below charge_control_end_threshold we still consider a battery held at threshold, since
+/// firmware usually stops a little short and lets the battery settle.
+const THRESHOLD_SLACK_PCT: u8 = 5;
+
+/// How far above the design capacity a reported full capacity can be before we stop believing it.
+/// New packs often report a little over design, but failing ones can report several times it.
+const MAX_FULL_OVER_DESIGN_PCT: u64 = 150;
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
 pub enum BatteryState {
@@ -21,19 +32,112 @@ pub enum BatteryState {
 
 #[derive(Debug)]
 pub struct Battery {
+    pub name: String,
     pub state: BatteryState,
     now_uwh: u64,
     full_uwh: u64,
+    /// The rate of charge or discharge, if the driver tells us.
+    pub power_uw: Option<u64>,
+    pub start_threshold: Option<u8>,
+    pub end_threshold: Option<u8>,
+    /// The full capacity the driver reported, if it was implausible and we used the design capacity
+    /// instead.
+    pub implausible_full_uwh: Option<u64>,
+    /// What the battery could hold when new, if the driver tells us.
+    pub design_uwh: Option<u64>,
+    /// The voltage right now, if the driver tells us.
+    pub voltage_uv: Option<u64>,
+    /// How many charge cycles the battery has been through, if the driver counts them.
+    pub cycle_count: Option<u32>,
 }
 
 impl Battery {
+    /// A battery with energy in whole percent of a 50Wh pack, for tests.
+    #[cfg(test)]
+    pub fn new(name: &str, state: BatteryState, level: u8) -> Self {
+        Self {
+            name: name.to_string(),
+            state,
+            now_uwh: u64::from(level) * 500_000,
+            full_uwh: 50_000_000,
+            power_uw: None,
+            start_threshold: None,
+            end_threshold: None,
+            implausible_full_uwh: None,
+            design_uwh: None,
+            voltage_uv: None,
+            cycle_count: None,
+        }
+    }
+
     pub const fn level(&self) -> u8 {
+        // Only the stand in for no batteries at all has nothing to be full of
+        if self.full_uwh == 0 {
+            return 0;
+        }
         let mut level = (self.now_uwh * 100) / self.full_uwh;
         if level > 100 {
             level = 100;
         }
         level as _
     }
+
+    /// The raw energy reading, which drivers should update as the battery drains.
+    pub const fn now_uwh(&self) -> u64 {
+        self.now_uwh
+    }
+
+    pub const fn full_uwh(&self) -> u64 {
+        self.full_uwh
+    }
+
+    /// Like level(), but without rounding to whole percentage points.
+    pub fn level_precise(&self) -> f64 {
+        (self.now_uwh as f64 * 100.0 / self.full_uwh as f64).min(100.0)
+    }
+
+    /// Whether the battery's level is consistent with firmware holding it at its charge threshold.
+    /// If the thresholds can't be read, we have nothing to contradict the firmware with.
+    pub fn level_at_threshold(&self) -> bool {
+        let level = self.level();
+        match (self.start_threshold, self.end_threshold) {
+            (Some(start), _) => level >= start,
+            (None, Some(end)) => level.saturating_add(THRESHOLD_SLACK_PCT) >= end,
+            (None, None) => true,
+        }
+    }
+
+    /// How much of its design capacity the battery can still hold, as a percentage. None if we
+    /// don't know the design capacity, or didn't believe the full capacity.
+    pub fn health(&self) -> Option<u8> {
+        if self.implausible_full_uwh.is_some() {
+            return None;
+        }
+        let design = self.design_uwh?;
+        Some(u8::try_from(self.full_uwh * 100 / design).unwrap_or(u8::MAX))
+    }
+}
+
+/// Identifying details of a battery pack, so that trends can be tied to a physical pack across
+/// swaps.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BatteryInfo {
+    pub technology: Option<String>,
+    pub manufacturer: Option<String>,
+    pub model_name: Option<String>,
+    pub serial_number: Option<String>,
+}
+
+/// A UPS the kernel exposes itself, as a power_supply of type UPS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ups {
+    pub name: String,
+    pub level: Option<u8>,
+    pub state: BatteryState,
+    /// Whether it's running from mains, if it says.
+    pub online: Option<bool>,
+    /// How long it can keep going on battery at the present load, if it says.
+    pub time_to_empty_secs: Option<u64>,
 }
 
 pub fn read_battery_file(dir: &Path, file: impl AsRef<str>) -> Result<String> {
@@ -44,12 +148,48 @@ pub fn read_battery_file(dir: &Path, file: impl AsRef<str>) -> Result<String> {
     Ok(content)
 }
 
+/// Anything the kernel adds in future is as good as Unknown to us.
 pub fn name_to_battery_state(name: &str) -> BatteryState {
-    serde_plain::from_str(name).unwrap()
+    match name {
+        "Discharging" => BatteryState::Discharging,
+        "Charging" => BatteryState::Charging,
+        "Not charging" => BatteryState::NotCharging,
+        "Full" => BatteryState::Full,
+        _ => BatteryState::Unknown,
+    }
+}
+
+/// For use in the middle of a sentence.
+pub const fn battery_state_to_lowercase_name(state: BatteryState) -> &'static str {
+    match state {
+        BatteryState::Discharging => "discharging",
+        BatteryState::Charging => "charging",
+        BatteryState::NotCharging => "not charging",
+        BatteryState::Full => "full",
+        BatteryState::Unknown => "unknown",
+        BatteryState::AtThreshold => "at threshold",
+        BatteryState::Invalid => "invalid",
+    }
 }
 
-pub fn battery_state_to_name(state: BatteryState) -> String {
-    serde_plain::to_string(&state).unwrap()
+/// Where to get the voltage from to convert charge or current, best first. The present voltage is
+/// what we want, but some drivers don't expose it (or report 0). The open circuit voltage is close
+/// to it, and failing that, the design minimum at least gets us a level, since the voltage cancels
+/// out when dividing now by full.
+const VOLTAGE_FILES: &[&str] = &["voltage_now", "voltage_ocv", "voltage_min_design"];
+
+/// The battery's voltage in µV, from the first of VOLTAGE_FILES which has a usable one.
+fn read_battery_voltage(dir: &Path) -> Result<u64> {
+    VOLTAGE_FILES
+        .iter()
+        .find_map(|file| {
+            read_battery_file(dir, file)
+                .ok()?
+                .parse()
+                .ok()
+                .filter(|&uv: &u64| uv > 0)
+        })
+        .with_context(|| format!("none of {} are usable", VOLTAGE_FILES.join(", ")))
 }
 
 /// Some drivers expose µAh (charge), some drivers expose µWh (energy), some drivers expose both.
@@ -59,23 +199,106 @@ pub fn read_battery_file_energy_or_charge(dir: &Path, partial_file: &str) -> Res
         return Ok(uwh?.parse()?);
     }
 
-    let voltage: u64 = read_battery_file(dir, "voltage_now")?.parse()?;
+    let voltage = read_battery_voltage(dir)?;
     let uah: u64 = read_battery_file(dir, "charge_".to_string() + partial_file)?.parse()?;
-    Ok((uah * voltage) / 1000)
+    Ok((uah * voltage) / 1_000_000)
+}
+
+/// Like energy and charge, some drivers expose power directly, and some only current.
+pub fn read_battery_power(dir: &Path) -> Option<u64> {
+    if let Ok(uw) = read_battery_file(dir, "power_now") {
+        return uw.parse().ok();
+    }
+    let voltage = read_battery_voltage(dir).ok()?;
+    let ua: u64 = read_battery_file(dir, "current_now").ok()?.parse().ok()?;
+    Some((ua * voltage) / 1_000_000)
+}
+
+/// The full capacity to use, the reported one if we didn't believe it, and the design capacity.
+/// Failing packs can report zero (which would have us divide by zero) or many times the design
+/// capacity (which would pin the level near 0), so fall back to the design capacity then.
+fn plausible_full_uwh(dir: &Path) -> Result<(u64, Option<u64>, Option<u64>)> {
+    let full = read_battery_file_energy_or_charge(dir, "full")?;
+    let design = read_battery_file_energy_or_charge(dir, "full_design")
+        .ok()
+        .filter(|&design| design > 0);
+    match design {
+        Some(design) if full == 0 || full > design * MAX_FULL_OVER_DESIGN_PCT / 100 => {
+            Ok((design, Some(full), Some(design)))
+        }
+        None if full == 0 => anyhow::bail!("full capacity is 0, and no design capacity to use"),
+        _ => Ok((full, None, design)),
+    }
 }
 
 pub fn read_battery_dir(dir: impl AsRef<Path>) -> Result<Battery> {
     let dir = dir.as_ref();
+    let (full_uwh, implausible_full_uwh, design_uwh) = plausible_full_uwh(dir)?;
 
     Ok(Battery {
+        name: dir
+            .file_name()
+            .and_then(OsStr::to_str)
+            .unwrap_or_default()
+            .to_string(),
         state: name_to_battery_state(&read_battery_file(dir, "status")?),
         now_uwh: read_battery_file_energy_or_charge(dir, "now")?,
-        full_uwh: read_battery_file_energy_or_charge(dir, "full")?,
+        full_uwh,
+        power_uw: read_battery_power(dir),
+        start_threshold: read_battery_file(dir, "charge_control_start_threshold")
+            .ok()
+            .and_then(|t| t.parse().ok()),
+        end_threshold: read_battery_file(dir, "charge_control_end_threshold")
+            .ok()
+            .and_then(|t| t.parse().ok()),
+        implausible_full_uwh,
+        design_uwh,
+        voltage_uv: read_battery_file(dir, "voltage_now")
+            .ok()
+            .and_then(|uv| uv.parse().ok())
+            .filter(|&uv| uv > 0),
+        // Drivers which don't count cycles often say 0 rather than leaving the file out
+        cycle_count: read_battery_file(dir, "cycle_count")
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .filter(|&count| count > 0),
     })
 }
 
+fn write_threshold(name: &str, file: &str, pct: u8) -> Result<()> {
+    let path = Path::new(POWER_SUPPLY_DIR).join(name).join(file);
+    fs::write(&path, pct.to_string()).with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Sets charge_control_end_threshold, which usually needs root or a udev rule granting access.
+pub fn set_end_threshold(name: &str, pct: u8) -> Result<()> {
+    write_threshold(name, "charge_control_end_threshold", pct)
+}
+
+/// Sets charge_control_start_threshold, with the same access caveats as set_end_threshold.
+pub fn set_start_threshold(name: &str, pct: u8) -> Result<()> {
+    write_threshold(name, "charge_control_start_threshold", pct)
+}
+
+pub fn get_battery_info(name: &str) -> BatteryInfo {
+    let dir = Path::new(POWER_SUPPLY_DIR).join(name);
+    // Some drivers pad these with spaces, or leave them empty rather than absent
+    let read = |file| {
+        read_battery_file(&dir, file)
+            .ok()
+            .map(|val| val.trim().to_string())
+            .filter(|val| !val.is_empty())
+    };
+    BatteryInfo {
+        technology: read("technology"),
+        manufacturer: read("manufacturer"),
+        model_name: read("model_name"),
+        serial_number: read("serial_number"),
+    }
+}
+
 pub fn get_batteries() -> Result<Vec<Battery>> {
-    Ok(fs::read_dir("/sys/class/power_supply")?
+    let dirs: Vec<_> = fs::read_dir(POWER_SUPPLY_DIR)?
         .filter_map(std::result::Result::ok)
         .map(|e| e.path())
         .filter(|p| {
@@ -84,13 +307,33 @@ pub fn get_batteries() -> Result<Vec<Battery>> {
                 .unwrap_or("")
                 .starts_with("BAT")
         })
-        .map(read_battery_dir)
-        .filter_map(std::result::Result::ok)
-        .collect::<Vec<Battery>>())
+        // A UPS powers the machine from outside, so its level has no place in the combined one
+        .filter(|p| read_battery_file(p, "type").map_or(true, |kind| kind != "UPS"))
+        .collect();
+    if dirs.len() < 2 {
+        return Ok(dirs
+            .into_iter()
+            .filter_map(|dir| read_battery_dir(dir).ok())
+            .collect());
+    }
+    // Some drivers go to the embedded controller for every attribute, which can take tens of
+    // milliseconds each, so don't make batteries wait on each other
+    Ok(thread::scope(|s| {
+        let readers: Vec<_> = dirs
+            .iter()
+            .map(|dir| s.spawn(move || read_battery_dir(dir)))
+            .collect();
+        readers
+            .into_iter()
+            .filter_map(|reader| reader.join().ok()?.ok())
+            .collect()
+    }))
 }
 
 pub fn get_global_battery(batteries: &[Battery]) -> Battery {
-    let state = if batteries.iter().any(|b| b.state == BatteryState::Charging) {
+    let state = if batteries.is_empty() {
+        BatteryState::Unknown
+    } else if batteries.iter().any(|b| b.state == BatteryState::Charging) {
         BatteryState::Charging
     } else if batteries
         .iter()
@@ -104,15 +347,201 @@ pub fn get_global_battery(batteries: &[Battery]) -> Battery {
             || b.state == BatteryState::NotCharging
             || b.state == BatteryState::Full
     }) {
-        // Confusingly some laptops set "Unknown" instead of "Not charging" when at threshold
-        BatteryState::AtThreshold
+        // Confusingly some laptops set "Unknown" instead of "Not charging" when at threshold, but
+        // make sure the levels agree before blaming it on the threshold
+        if batteries
+            .iter()
+            .all(|b| b.state == BatteryState::Full || b.level_at_threshold())
+        {
+            BatteryState::AtThreshold
+        } else {
+            BatteryState::Unknown
+        }
     } else {
         BatteryState::Discharging
     };
 
     Battery {
+        name: "global".to_string(),
         state,
         now_uwh: batteries.iter().map(|b| b.now_uwh).sum(),
         full_uwh: batteries.iter().map(|b| b.full_uwh).sum(),
+        // Only the batteries charging count towards the charge rate, and vice versa
+        power_uw: batteries
+            .iter()
+            .filter(|b| b.state == state)
+            .map(|b| b.power_uw)
+            .sum(),
+        start_threshold: None,
+        end_threshold: None,
+        implausible_full_uwh: None,
+        // Only if we know every battery's, and believed their full capacities, or health would
+        // be skewed
+        design_uwh: batteries
+            .iter()
+            .map(|b| b.design_uwh.filter(|_| b.implausible_full_uwh.is_none()))
+            .sum::<Option<u64>>()
+            .filter(|&design| design > 0),
+        // These only make sense for a single pack
+        voltage_uv: None,
+        cycle_count: None,
+    }
+}
+
+/// Whether mains power is connected, going by the mains supplies themselves rather than what the
+/// batteries say, which some firmware is slow to update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcState {
+    Online,
+    Offline,
+    /// There are no mains supplies we can read.
+    Unknown,
+}
+
+impl From<Option<bool>> for AcState {
+    fn from(online: Option<bool>) -> Self {
+        match online {
+            Some(true) => Self::Online,
+            Some(false) => Self::Offline,
+            None => Self::Unknown,
+        }
+    }
+}
+
+pub const fn ac_state_to_lowercase_name(state: AcState) -> &'static str {
+    match state {
+        AcState::Online => "online",
+        AcState::Offline => "offline",
+        AcState::Unknown => "unknown",
+    }
+}
+
+/// Returns whether any mains power supply is online, or None if there are no mains supplies we can
+/// read.
+pub fn get_ac_online() -> Result<Option<bool>> {
+    let mut online = None;
+    for entry in fs::read_dir(POWER_SUPPLY_DIR)? {
+        let dir = entry?.path();
+        // Some adapters (usually named AC, ACAD or similar) don't say what type they are
+        let mains = match read_battery_file(&dir, "type") {
+            Ok(kind) => kind == "Mains",
+            Err(_) => dir
+                .file_name()
+                .and_then(OsStr::to_str)
+                .is_some_and(|name| name.starts_with("AC")),
+        };
+        if mains {
+            if let Ok(val) = read_battery_file(&dir, "online") {
+                online = Some(online.unwrap_or(false) || val == "1");
+            }
+        }
+    }
+    Ok(online)
+}
+
+#[cfg(test)]
+thread_local! {
+    /// What get_upses returns instead of reading sysfs, for tests.
+    pub static FAKE_UPSES: std::cell::RefCell<Option<Vec<Ups>>> = const { std::cell::RefCell::new(None) };
+}
+
+pub fn get_upses() -> Result<Vec<Ups>> {
+    #[cfg(test)]
+    if let Some(upses) = FAKE_UPSES.with(|fake| fake.borrow().clone()) {
+        return Ok(upses);
+    }
+    let mut upses = Vec::new();
+    for entry in fs::read_dir(POWER_SUPPLY_DIR)? {
+        let dir = entry?.path();
+        let read = |file| read_battery_file(&dir, file).ok();
+        if read("type").as_deref() != Some("UPS") {
+            continue;
+        }
+        upses.push(Ups {
+            name: dir
+                .file_name()
+                .and_then(OsStr::to_str)
+                .unwrap_or_default()
+                .to_string(),
+            level: read("capacity")
+                .and_then(|c| c.parse::<u8>().ok())
+                .map(|c| c.min(100)),
+            state: read("status").map_or(BatteryState::Unknown, |s| name_to_battery_state(&s)),
+            online: read("online").map(|online| online == "1"),
+            time_to_empty_secs: read("time_to_empty_now")
+                .and_then(|t| t.parse().ok())
+                .filter(|&t| t > 0),
+        });
+    }
+    upses.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(upses)
+}
+
+/// For profiles which only apply on one machine.
+pub fn hostname() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|name| name.trim().to_string())
+}
+
+pub fn get_power_supply_names() -> Result<Vec<String>> {
+    let mut names = fs::read_dir(POWER_SUPPLY_DIR)?
+        .filter_map(std::result::Result::ok)
+        .filter_map(|e| e.file_name().into_string().ok())
+        .collect::<Vec<_>>();
+    names.sort();
+    Ok(names)
+}
+
+/// Reads every attribute of a power supply, for diagnostics.
+pub fn dump_power_supply(name: &str) -> Result<Vec<(String, String)>> {
+    let dir = Path::new(POWER_SUPPLY_DIR).join(name);
+    let mut attrs = fs::read_dir(&dir)?
+        .filter_map(std::result::Result::ok)
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_file()))
+        .filter_map(|e| {
+            let attr = e.file_name().into_string().ok()?;
+            let val = read_battery_file(&dir, &attr).ok()?;
+            Some((attr, val))
+        })
+        .collect::<Vec<_>>();
+    attrs.sort();
+    Ok(attrs)
+}
+
+/// On machines which drain one battery fully before touching the others, returns the battery
+/// currently being drained, and the combined level of the rest held in reserve.
+pub fn get_active_battery(batteries: &[Battery]) -> Option<(&Battery, u8)> {
+    let active = batteries
+        .iter()
+        .filter(|b| b.state == BatteryState::Discharging)
+        .min_by_key(|b| b.level())?;
+    let (now_uwh, full_uwh) = batteries
+        .iter()
+        .filter(|b| b.name != active.name)
+        .fold((0, 0), |(now, full), b| {
+            (now + b.now_uwh, full + b.full_uwh)
+        });
+    if full_uwh == 0 {
+        return None;
+    }
+    Some((active, ((now_uwh * 100) / full_uwh).min(100) as _))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn charge_converted_to_energy() {
+        let dir =
+            std::env::temp_dir().join(format!("battery-notify-charge-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // 3000mAh at 12V is 36Wh
+        fs::write(dir.join("charge_now"), "3000000\n").unwrap();
+        fs::write(dir.join("voltage_now"), "12000000\n").unwrap();
+        let uwh = read_battery_file_energy_or_charge(&dir, "now");
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(uwh.unwrap(), 36_000_000);
     }
 }
@@ -0,0 +1,52 @@
+//! A tiny template language for notification text. `{name}` is replaced with the variable of that
+//! name, and anything else, including unknown names, is left alone.
+
+/// Renders `template`. When a variable is given more than once, the last one wins, so that more
+/// specific values can be layered on top.
+pub fn render(template: &str, vars: &[(&str, String)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let var = after.find('}').and_then(|end| {
+            let (_, val) = vars.iter().rev().find(|(name, _)| *name == &after[..end])?;
+            Some((val, end))
+        });
+        match var {
+            Some((val, end)) => {
+                out.push_str(val);
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_fills_placeholders() {
+        let vars = [
+            ("level", "5".to_string()),
+            ("state", "discharging".to_string()),
+        ];
+        assert_eq!(render("{level}% and {state}", &vars), "5% and discharging");
+        assert_eq!(render("{nope} {level", &vars), "{nope} {level");
+        assert_eq!(render("{{level}}", &vars), "{5}");
+        assert_eq!(render("", &vars), "");
+    }
+
+    #[test]
+    fn render_last_value_wins() {
+        let vars = [("level", "5".to_string()), ("level", "7".to_string())];
+        assert_eq!(render("{level}", &vars), "7");
+    }
+}
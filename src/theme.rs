@@ -0,0 +1,67 @@
+//! Icons and colours for `--output json`, so status bars can show them without scripts of their
+//! own to pick them.
+
+use serde::{Deserialize, Serialize};
+
+use crate::system::BatteryState;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ColorThreshold {
+    pub pct: u8,
+    pub color: String,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+    /// From empty to full, each covering an equal share of the range.
+    pub icons: Vec<String>,
+    /// Shown instead of the above while charging, if not empty.
+    pub charging_icon: String,
+    /// The colour for the lowest pct the level is at or below.
+    pub colors: Vec<ColorThreshold>,
+    /// Used instead of the above while charging, if not empty.
+    pub charging_color: String,
+    /// Wrap the text in a Pango span with the colour, for bars like waybar which show markup.
+    pub pango_markup: bool,
+    /// List Bluetooth devices in the tooltip too.
+    pub bluetooth_tooltip: bool,
+}
+
+impl ThemeConfig {
+    /// The icon for this level and state, or "" without any icons configured.
+    pub fn icon(&self, level: u8, state: BatteryState) -> &str {
+        if state == BatteryState::Charging && !self.charging_icon.is_empty() {
+            return &self.charging_icon;
+        }
+        let Some(last) = self.icons.len().checked_sub(1) else {
+            return "";
+        };
+        let idx = usize::from(level.min(100)) * self.icons.len() / 101;
+        &self.icons[idx.min(last)]
+    }
+
+    /// The colour for this level and state, if there's one which applies.
+    pub fn color(&self, level: u8, state: BatteryState) -> Option<&str> {
+        if state == BatteryState::Charging && !self.charging_color.is_empty() {
+            return Some(&self.charging_color);
+        }
+        self.colors
+            .iter()
+            .filter(|threshold| level <= threshold.pct)
+            .min_by_key(|threshold| threshold.pct)
+            .map(|threshold| threshold.color.as_str())
+    }
+
+    /// The bar text for this level and state, with the icon first if there is one.
+    pub fn text(&self, level: u8, state: BatteryState) -> String {
+        let text = match self.icon(level, state) {
+            "" => format!("{level}%"),
+            icon => format!("{icon} {level}%"),
+        };
+        match self.color(level, state) {
+            Some(color) if self.pango_markup => format!("<span color=\"{color}\">{text}</span>"),
+            _ => text,
+        }
+    }
+}
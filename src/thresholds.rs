@@ -0,0 +1,120 @@
+//! Keeps the firmware's charge start and stop thresholds where the user wants them. Some firmware
+//! forgets them after a battery swap or losing power entirely, so they're checked every cycle.
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+
+use crate::system::{self, Battery};
+
+/// Checks that thresholds from the config or command line make sense together. 0 means to leave
+/// that threshold alone.
+pub fn validate(start: u8, end: u8) -> Result<()> {
+    if start > 100 || end > 100 {
+        bail!("charge thresholds must be between 0 and 100, not {start} and {end}");
+    }
+    if start != 0 && end != 0 && start >= end {
+        bail!("charge start threshold ({start}) must be below the end threshold ({end})");
+    }
+    Ok(())
+}
+
+/// Writes whichever of `start` and `end` are non-zero, supported by `bat`, and differ from what it
+/// has now. Returns whether anything was written.
+///
+/// The kernel rejects a start threshold at or above the end threshold, so the order matters: when
+/// moving both down past the current start, start has to go first, otherwise end does.
+pub fn apply(bat: &Battery, start: u8, end: u8) -> Result<bool> {
+    let set_start = start != 0 && bat.start_threshold.is_some_and(|cur| cur != start);
+    let set_end = end != 0 && bat.end_threshold.is_some_and(|cur| cur != end);
+    let start_first = bat
+        .start_threshold
+        .is_some_and(|cur| end != 0 && end <= cur);
+    if set_start && start_first {
+        system::set_start_threshold(&bat.name, start)?;
+    }
+    if set_end {
+        system::set_end_threshold(&bat.name, end)?;
+    }
+    if set_start && !start_first {
+        system::set_start_threshold(&bat.name, start)?;
+    }
+    Ok(set_start || set_end)
+}
+
+/// Whether `bat` exposes any charge thresholds at all.
+pub const fn supported(bat: &Battery) -> bool {
+    bat.start_threshold.is_some() || bat.end_threshold.is_some()
+}
+
+pub struct ThresholdEnforcer {
+    start: u8,
+    end: u8,
+    // Cleared once writing fails, since it won't start working until the permissions change
+    can_write: bool,
+}
+
+impl ThresholdEnforcer {
+    pub fn new(start: u8, end: u8) -> Result<Self> {
+        validate(start, end)?;
+        Ok(Self {
+            start,
+            end,
+            can_write: true,
+        })
+    }
+
+    /// Puts back any thresholds on `batteries` which have drifted from the configured ones.
+    pub fn update(&mut self, batteries: &[Battery]) {
+        if !self.can_write || (self.start == 0 && self.end == 0) {
+            return;
+        }
+        for bat in batteries.iter().filter(|b| supported(b)) {
+            match apply(bat, self.start, self.end) {
+                Ok(true) => info!(
+                    battery = bat.name.as_str(), start = self.start, end = self.end;
+                    "Set {} charge thresholds (was {:?} to {:?})",
+                    bat.name, bat.start_threshold, bat.end_threshold
+                ),
+                Ok(false) => {}
+                Err(err) => {
+                    warn!(
+                        "failed to set {} charge thresholds, not trying again: {err:#}",
+                        bat.name
+                    );
+                    self.can_write = false;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Sets the thresholds on all batteries which support them, for `battery-notify set-threshold`.
+pub fn run(start: u8, end: u8) -> Result<()> {
+    if start == 0 || end == 0 {
+        bail!("set-threshold needs both a start and an end threshold above 0");
+    }
+    validate(start, end)?;
+    let batteries: Vec<_> = system::get_batteries()?
+        .into_iter()
+        .filter(supported)
+        .collect();
+    if batteries.is_empty() {
+        bail!("no batteries with charge thresholds found");
+    }
+    for bat in &batteries {
+        apply(bat, start, end)?;
+        if bat.start_threshold.is_some() {
+            println!(
+                "{}: charging starts below {start}% and stops at {end}%",
+                bat.name
+            );
+        } else {
+            println!(
+                "{}: charging stops at {end}% (no start threshold)",
+                bat.name
+            );
+        }
+    }
+    Ok(())
+}
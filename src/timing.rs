@@ -0,0 +1,50 @@
+//! Keeps track of where the time goes in each cycle, so that watchdog trips and sluggish checks can
+//! be pinned on something.
+
+use log::warn;
+use std::time::{Duration, Instant};
+
+pub struct CycleTimer {
+    start: Instant,
+    last: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl CycleTimer {
+    pub fn new(start: Instant) -> Self {
+        Self {
+            start,
+            last: start,
+            phases: Vec::new(),
+        }
+    }
+
+    /// Records the time since the previous mark (or the start of the cycle) against `phase`.
+    pub fn mark(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        self.phases.push((phase, now - self.last));
+        self.last = now;
+    }
+
+    /// Warns if the cycle took longer than `budget` (unless it's zero), blaming the slowest phase.
+    /// Returns how long each phase took, followed by the total.
+    pub fn finish(self, budget: Duration) -> Vec<(&'static str, Duration)> {
+        let total = self.last - self.start;
+        if !budget.is_zero() && total > budget {
+            if let Some((slowest, took)) = self.phases.iter().max_by_key(|(_, took)| *took) {
+                warn!(
+                    event = "cycle_overrun", cycle_us = duration_us(total),
+                    phase = *slowest, phase_us = duration_us(*took);
+                    "Cycle took {total:?}, over the budget of {budget:?}, mostly in {slowest} ({took:?})"
+                );
+            }
+        }
+        let mut phases = self.phases;
+        phases.push(("total", total));
+        phases
+    }
+}
+
+pub fn duration_us(duration: Duration) -> u64 {
+    u64::try_from(duration.as_micros()).unwrap_or(u64::MAX)
+}
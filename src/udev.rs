@@ -0,0 +1,141 @@
+//! Listens for power supply uevents from the kernel, so that we notice things like plugging in the
+//! charger straight away, rather than at the next interval. Polling carries on regardless, since
+//! not every driver sends uevents for every change.
+
+use anyhow::{Context, Result};
+use cancellable_timer::Canceller;
+use log::{debug, error};
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Plugging in a charger has the kernel send several uevents in a row, for the mains supply and
+/// then each battery, so wait this long after the first for the rest before waking the main loop.
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// The multicast group the kernel sends uevents to, as opposed to the one udevd rebroadcasts them
+/// on after processing.
+const KERNEL_GROUP: u32 = 1;
+
+fn open_socket() -> Result<OwnedFd> {
+    // SAFETY: plain syscall, we check the result before using it
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            libc::NETLINK_KOBJECT_UEVENT,
+        )
+    };
+    if fd == -1 {
+        return Err(io::Error::last_os_error()).context("failed to open uevent socket");
+    }
+    // SAFETY: we just created it, so nothing else owns it
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // SAFETY: sockaddr_nl is plain data, for which all zeroes is valid
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as _;
+    addr.nl_groups = KERNEL_GROUP;
+    // SAFETY: addr is a valid sockaddr_nl, and we pass its real size
+    let ret = unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            std::ptr::addr_of!(addr).cast(),
+            mem::size_of_val(&addr) as _,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error()).context("failed to bind uevent socket");
+    }
+    Ok(fd)
+}
+
+/// Uevents are a header line followed by NUL separated KEY=value pairs.
+fn is_power_supply(msg: &[u8]) -> bool {
+    msg.split(|b| *b == 0)
+        .any(|field| field == b"SUBSYSTEM=power_supply")
+}
+
+/// Waits for the next uevent, returning whether it was (or might have been) for a power supply.
+fn recv_power_supply(fd: &OwnedFd, buf: &mut [u8]) -> io::Result<bool> {
+    loop {
+        // SAFETY: buf is valid for writes of its whole length
+        let len = unsafe { libc::recv(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+        if len >= 0 {
+            return Ok(is_power_supply(&buf[..len as usize]));
+        }
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::Interrupted => continue,
+            // The kernel had more than we could buffer, which is fine since we only care that
+            // something changed
+            _ if err.raw_os_error() == Some(libc::ENOBUFS) => return Ok(true),
+            _ => return Err(err),
+        }
+    }
+}
+
+/// Whether `fd` has something to read within `timeout`.
+fn readable_within(fd: &OwnedFd, timeout: Duration) -> io::Result<bool> {
+    let mut pfd = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // Rounded up, so we don't spin on a timeout of less than a millisecond
+    let ms = timeout
+        .as_micros()
+        .div_ceil(1000)
+        .try_into()
+        .unwrap_or(i32::MAX);
+    // SAFETY: pfd is a single valid pollfd, and we say so
+    match unsafe { libc::poll(&mut pfd, 1, ms) } {
+        -1 => {
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::Interrupted => Ok(false),
+                _ => Err(err),
+            }
+        }
+        ready => Ok(ready > 0),
+    }
+}
+
+/// Wakes up the main loop through `canceller` once for each burst of power supply uevents within
+/// DEBOUNCE. Only returns if reading them fails.
+fn listen(fd: &OwnedFd, canceller: &Canceller) -> io::Result<()> {
+    let mut buf = [0u8; 8192];
+    loop {
+        if !recv_power_supply(fd, &mut buf)? {
+            continue;
+        }
+        // Swallow the rest of the burst. The window isn't extended by each one, so a chatty driver
+        // can't hold off the check forever.
+        let deadline = Instant::now() + DEBOUNCE;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() || !readable_within(fd, left)? {
+                break;
+            }
+            recv_power_supply(fd, &mut buf)?;
+        }
+        debug!("Power supply changed, checking now");
+        // If we fail to cancel, we'll just notice at the next interval
+        let _ = canceller.cancel();
+    }
+}
+
+/// Wakes up the main loop through `canceller` whenever a power supply changes.
+pub fn spawn(canceller: Canceller) -> Result<()> {
+    let fd = open_socket()?;
+    thread::Builder::new()
+        .name("uevent".to_string())
+        .spawn(move || {
+            if let Err(err) = listen(&fd, &canceller) {
+                error!("stopped listening for uevents: {err}");
+            }
+        })?;
+    Ok(())
+}
@@ -0,0 +1,447 @@
+//! UPSes powering this machine, either ones the kernel exposes as power_supply devices of type UPS,
+//! or with the nut feature, ones Network UPS Tools' upsd knows about. We notify when one goes on
+//! battery and again when it's running low, running [events.ups_on_battery] and
+//! [events.ups_low_runtime] as it does.
+
+use anyhow::Result;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::clock::Clock;
+use crate::command;
+use crate::config::{EventsConfig, UpsEvent};
+use crate::notification::{SingleNotification, Sink, Style};
+use crate::system::{self, BatteryState};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpsConfig {
+    /// On battery at or below this level counts as running low. 0 disables.
+    pub low_pct: u8,
+    /// On battery with this little runtime left counts as running low. 0 disables.
+    pub low_runtime_secs: u64,
+    /// UPSes to ask upsd about, like "myups@localhost" or "myups@nas:3493".
+    pub nut: Vec<String>,
+    pub nut_interval_secs: u64,
+}
+
+impl Default for UpsConfig {
+    fn default() -> Self {
+        Self {
+            low_pct: 30,
+            low_runtime_secs: 300,
+            nut: Vec::new(),
+            nut_interval_secs: 15,
+        }
+    }
+}
+
+/// What a UPS told us about itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Reading {
+    level: Option<u8>,
+    on_battery: bool,
+    runtime_secs: Option<u64>,
+    /// The UPS itself says its battery is low, whatever our thresholds say.
+    low_battery: bool,
+}
+
+impl From<&system::Ups> for Reading {
+    fn from(ups: &system::Ups) -> Self {
+        Self {
+            level: ups.level,
+            on_battery: ups.online == Some(false) || ups.state == BatteryState::Discharging,
+            runtime_secs: ups.time_to_empty_secs,
+            low_battery: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Condition {
+    Online,
+    OnBattery,
+    LowRuntime,
+}
+
+impl Condition {
+    fn from_reading(reading: &Reading, cfg: &UpsConfig) -> Self {
+        if !reading.on_battery {
+            return Self::Online;
+        }
+        let low_level = cfg.low_pct != 0 && reading.level.is_some_and(|l| l <= cfg.low_pct);
+        let low_runtime = cfg.low_runtime_secs != 0
+            && reading
+                .runtime_secs
+                .is_some_and(|secs| secs <= cfg.low_runtime_secs);
+        if reading.low_battery || low_level || low_runtime {
+            Self::LowRuntime
+        } else {
+            Self::OnBattery
+        }
+    }
+}
+
+struct Unit {
+    condition: Condition,
+    notif: SingleNotification,
+}
+
+/// Keeps track of every UPS, from sysfs each update and from NUT as its pollers report back.
+pub struct UpsMonitor {
+    commands: command::Context,
+    sink: Sink,
+    units: BTreeMap<String, Unit>,
+    nut: nut::Pollers,
+}
+
+impl UpsMonitor {
+    pub fn new(cfg: &UpsConfig, commands: &command::Context, sink: &Sink) -> Self {
+        Self {
+            commands: commands.clone(),
+            sink: sink.clone(),
+            units: BTreeMap::new(),
+            nut: nut::Pollers::new(cfg),
+        }
+    }
+
+    /// Where commands get their context, for starting afresh on reload.
+    pub const fn commands(&self) -> &command::Context {
+        &self.commands
+    }
+
+    /// Where notifications go, for starting afresh on reload.
+    pub const fn sink(&self) -> &Sink {
+        &self.sink
+    }
+
+    /// Checks every UPS, running events for any which went on battery or low, and retrying failed
+    /// commands after waiting on `clock`.
+    pub fn update(
+        &mut self,
+        cfg: &UpsConfig,
+        events: &EventsConfig,
+        style: Style,
+        clock: &mut dyn Clock,
+    ) {
+        let sysfs = system::get_upses().unwrap_or_else(|err| {
+            error!("Failed to read UPSes: {err}");
+            Vec::new()
+        });
+        let mut readings: Vec<_> = sysfs
+            .iter()
+            .map(|ups| (ups.name.clone(), Reading::from(ups)))
+            .collect();
+        readings.extend(self.nut.take());
+
+        // Kernel UPSes go away when unplugged, but NUT ones are only updated when polled
+        let nut = self.nut.names();
+        self.units.retain(|name, unit| {
+            let present = nut.contains(name) || sysfs.iter().any(|ups| &ups.name == name);
+            if !present {
+                info!(ups = name.as_str(); "UPS {name} went away");
+                unit.notif.close();
+            }
+            present
+        });
+
+        for (name, reading) in readings {
+            let unit = self.units.entry(name.clone()).or_insert_with(|| Unit {
+                condition: Condition::Online,
+                notif: SingleNotification::new(&self.sink),
+            });
+            let condition = Condition::from_reading(&reading, cfg);
+            if let Some(level) = reading.level {
+                let state = if reading.on_battery {
+                    BatteryState::Discharging
+                } else {
+                    BatteryState::Charging
+                };
+                unit.notif.set_level(level, state);
+            }
+            let prev = std::mem::replace(&mut unit.condition, condition);
+            if prev != condition {
+                info!(
+                    ups = name.as_str(), level:? = reading.level,
+                    runtime_secs:? = reading.runtime_secs, condition:? = condition;
+                    "UPS {name} is now {condition:?}"
+                );
+            }
+            if prev == Condition::Online && condition != Condition::Online {
+                run_event(
+                    &self.commands,
+                    "ups_on_battery",
+                    &events.ups_on_battery,
+                    &name,
+                    &reading,
+                    clock,
+                );
+            }
+            if prev != Condition::LowRuntime && condition == Condition::LowRuntime {
+                run_event(
+                    &self.commands,
+                    "ups_low_runtime",
+                    &events.ups_low_runtime,
+                    &name,
+                    &reading,
+                    clock,
+                );
+            }
+            let body = describe(&reading);
+            match condition {
+                Condition::Online => unit.notif.close(),
+                Condition::OnBattery => {
+                    unit.notif
+                        .show_with_body(format!("UPS {name} on battery"), body, style);
+                }
+                Condition::LowRuntime => {
+                    unit.notif
+                        .show_with_body(format!("UPS {name} running low"), body, style);
+                }
+            }
+        }
+    }
+
+    /// Like "ups_on_battery:myups@localhost".
+    pub fn alarms(&self) -> Vec<String> {
+        self.units
+            .iter()
+            .filter_map(|(name, unit)| {
+                let alarm = match unit.condition {
+                    Condition::Online => return None,
+                    Condition::OnBattery => "ups_on_battery",
+                    Condition::LowRuntime => "ups_low_runtime",
+                };
+                Some(format!("{alarm}:{name}"))
+            })
+            .collect()
+    }
+}
+
+/// Runs `event`'s command for event `name`, with BATTERY_NOTIFY_BATTERY and BATTERY_NOTIFY_LEVEL
+/// about the UPS rather than our own batteries.
+fn run_event(
+    commands: &command::Context,
+    name: &str,
+    event: &UpsEvent,
+    ups: &str,
+    reading: &Reading,
+    clock: &mut dyn Clock,
+) {
+    let command = &event.command;
+    if command.is_empty() {
+        return;
+    }
+    info!(event = name; "Running {name} command");
+    let level = reading.level.map(|l| l.to_string()).unwrap_or_default();
+    let runtime = reading
+        .runtime_secs
+        .map(|secs| secs.to_string())
+        .unwrap_or_default();
+    command.run_with_retries(
+        commands,
+        &[
+            ("BATTERY_NOTIFY_EVENT", name),
+            ("BATTERY_NOTIFY_BATTERY", ups),
+            ("BATTERY_NOTIFY_LEVEL", &level),
+            ("BATTERY_NOTIFY_STATE", "discharging"),
+            ("BATTERY_NOTIFY_TIME_REMAINING", &runtime),
+        ],
+        event.retries,
+        Duration::from_secs(event.retry_delay_secs),
+        clock,
+    );
+}
+
+/// Like "85%, 23m left".
+fn describe(reading: &Reading) -> String {
+    let level = reading.level.map(|level| format!("{level}%"));
+    let runtime = reading.runtime_secs.map(|secs| {
+        // Seconds are just noise at this scale
+        let secs = if secs >= 60 { secs - secs % 60 } else { secs };
+        format!(
+            "{} left",
+            humantime::format_duration(Duration::from_secs(secs))
+        )
+    });
+    [level, runtime]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Checks [ups] from the config, for Config::validate.
+pub fn validate(cfg: &UpsConfig) -> Result<()> {
+    if !cfg.nut.is_empty() && !cfg!(feature = "nut") {
+        anyhow::bail!("ups.nut is set, but built without NUT support");
+    }
+    for target in &cfg.nut {
+        nut::parse_target(target)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "nut")]
+mod nut {
+    use anyhow::{bail, Context, Result};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpStream;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{Reading, UpsConfig};
+    use log::{error, warn};
+
+    const DEFAULT_PORT: u16 = 3493;
+
+    /// upsd usually answers straight away, so anything longer means it's stuck.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Splits "ups@host:port" into its parts, with the host defaulting to localhost, and the port to
+    /// upsd's.
+    pub fn parse_target(target: &str) -> Result<(String, String, u16)> {
+        let (ups, addr) = target.split_once('@').unwrap_or((target, "localhost"));
+        if ups.is_empty() || addr.is_empty() {
+            bail!("invalid ups.nut entry '{target}', expected something like 'myups@localhost'");
+        }
+        let (host, port) = match addr.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .with_context(|| format!("invalid port in ups.nut entry '{target}'"))?,
+            ),
+            None => (addr, DEFAULT_PORT),
+        };
+        Ok((ups.to_string(), host.to_string(), port))
+    }
+
+    /// Asks upsd for every variable of `ups`, and picks out the ones we care about.
+    fn query(target: &str) -> Result<Reading> {
+        let (ups, host, port) = parse_target(target)?;
+        let stream = TcpStream::connect((host.as_str(), port))
+            .with_context(|| format!("failed to connect to upsd on {host}:{port}"))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        (&stream).write_all(format!("LIST VAR {ups}\nLOGOUT\n").as_bytes())?;
+
+        let mut reading = Reading::default();
+        let prefix = format!("VAR {ups} ");
+        for line in BufReader::new(&stream).lines() {
+            let line = line?;
+            if let Some(err) = line.strip_prefix("ERR ") {
+                bail!("upsd said {err} for {ups}");
+            }
+            if line.starts_with("END LIST VAR") {
+                return Ok(reading);
+            }
+            let Some((var, value)) = line
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.split_once(' '))
+            else {
+                continue;
+            };
+            let value = value.trim_matches('"');
+            match var {
+                "battery.charge" => {
+                    reading.level = value.parse::<f64>().ok().map(|c| c.min(100.0) as u8);
+                }
+                "battery.runtime" => reading.runtime_secs = value.parse().ok(),
+                "ups.status" => {
+                    let flags: Vec<_> = value.split_whitespace().collect();
+                    reading.on_battery = flags.contains(&"OB");
+                    reading.low_battery = flags.contains(&"LB");
+                }
+                _ => {}
+            }
+        }
+        bail!("upsd closed the connection before listing {ups}")
+    }
+
+    /// The latest result from a poller, taken by the next update.
+    type Latest = Arc<Mutex<Option<Result<Reading, String>>>>;
+
+    /// One thread per UPS, polling every nut_interval_secs, since upsd can be on another machine.
+    /// Each exits once its Latest is dropped.
+    pub struct Pollers {
+        latest: Vec<(String, Latest)>,
+    }
+
+    impl Pollers {
+        pub fn new(cfg: &UpsConfig) -> Self {
+            let interval = Duration::from_secs(cfg.nut_interval_secs.max(1));
+            let latest = cfg
+                .nut
+                .iter()
+                .filter_map(|target| {
+                    let latest = Latest::default();
+                    let (target_, latest_) = (target.clone(), latest.clone());
+                    let res =
+                        thread::Builder::new()
+                            .name(format!("nut-{target}"))
+                            .spawn(move || {
+                                while Arc::strong_count(&latest_) > 1 {
+                                    let res = query(&target_).map_err(|err| format!("{err:#}"));
+                                    *latest_.lock().unwrap() = Some(res);
+                                    thread::sleep(interval);
+                                }
+                            });
+                    if let Err(err) = res {
+                        error!("failed to start polling UPS {target}: {err}");
+                        return None;
+                    }
+                    Some((target.clone(), latest))
+                })
+                .collect();
+            Self { latest }
+        }
+
+        pub fn names(&self) -> Vec<String> {
+            self.latest.iter().map(|(name, _)| name.clone()).collect()
+        }
+
+        /// Whatever the pollers have come back with since last time.
+        pub fn take(&self) -> Vec<(String, Reading)> {
+            self.latest
+                .iter()
+                .filter_map(|(name, latest)| match latest.lock().unwrap().take()? {
+                    Ok(reading) => Some((name.clone(), reading)),
+                    Err(err) => {
+                        warn!(ups = name.as_str(); "Failed to poll UPS {name}: {err}");
+                        None
+                    }
+                })
+                .collect()
+        }
+    }
+}
+
+#[cfg(not(feature = "nut"))]
+mod nut {
+    use anyhow::Result;
+
+    use super::{Reading, UpsConfig};
+
+    pub fn parse_target(_target: &str) -> Result<(String, String, u16)> {
+        anyhow::bail!("built without NUT support")
+    }
+
+    pub struct Pollers;
+
+    impl Pollers {
+        pub const fn new(_cfg: &UpsConfig) -> Self {
+            Self
+        }
+
+        pub const fn names(&self) -> Vec<String> {
+            Vec::new()
+        }
+
+        pub const fn take(&self) -> Vec<(String, Reading)> {
+            Vec::new()
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! What was compiled in and what's in use, for `--version --verbose`, `status`, and BuildInfo over
+//! D-Bus, so bug reports show exactly what's running.
+
+use crate::{logging, monitors, Config};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Set by build.rs when built from a git checkout.
+pub const GIT_HASH: Option<&str> = option_env!("BATTERY_NOTIFY_GIT_HASH");
+
+/// (version, git hash or "", enabled features, (kind, backend) pairs), as sent over D-Bus.
+pub type BuildInfo = (String, String, Vec<String>, Vec<(String, String)>);
+
+/// Every optional feature, and whether this build has it.
+pub const FEATURES: &[(&str, bool)] = &[
+    ("mons", cfg!(feature = "mons")),
+    ("wayland", cfg!(feature = "wayland")),
+    ("bluetooth", cfg!(feature = "bluetooth")),
+    ("gamemode", cfg!(feature = "gamemode")),
+    ("dbus", cfg!(feature = "dbus")),
+    ("nut", cfg!(feature = "nut")),
+];
+
+/// Like "battery-notify 0.3.5 (1a2b3c4d5e6f)".
+pub fn short() -> String {
+    match GIT_HASH {
+        Some(hash) => format!("battery-notify {VERSION} ({hash})"),
+        None => format!("battery-notify {VERSION}"),
+    }
+}
+
+pub fn enabled_features() -> Vec<&'static str> {
+    FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// How each kind of check or output would be done with this config, like ("monitors", "wayland").
+/// Anything turned off in the config is "disabled", and anything this build lacks is "not built".
+pub fn backends(cfg: &Config) -> Vec<(&'static str, &'static str)> {
+    let pick = |built: bool, enabled: bool, name| match (built, enabled) {
+        (false, _) => "not built",
+        (true, false) => "disabled",
+        (true, true) => name,
+    };
+    let bluetooth = cfg.bluetooth_low_pct != 0
+        || cfg.bluetooth_critical_pct != 0
+        || cfg.bluetooth.has_overrides();
+    vec![
+        (
+            "notifications",
+            if cfg.system_mode { "relay" } else { "session" },
+        ),
+        (
+            "monitors",
+            if cfg.warn_on_mons_with_no_ac == 0 || cfg.system_mode {
+                "disabled"
+            } else {
+                monitors::backend(cfg.monitors_backend)
+            },
+        ),
+        (
+            "bluetooth",
+            pick(cfg!(feature = "bluetooth"), bluetooth, "bluez"),
+        ),
+        (
+            "gamemode",
+            pick(
+                cfg!(feature = "gamemode"),
+                cfg.gamemode_defer_notifications && !cfg.system_mode,
+                "gamemode",
+            ),
+        ),
+        (
+            "dbus_service",
+            pick(cfg!(feature = "dbus"), cfg.dbus_service, "session bus"),
+        ),
+        (
+            "power_supply",
+            if cfg.watch_uevents {
+                "uevents"
+            } else {
+                "polling"
+            },
+        ),
+        ("logging", logging::destination()),
+    ]
+}
+
+/// The full picture, for `--version --verbose`.
+pub fn verbose(cfg: &Config) -> String {
+    let mut out = short();
+    out.push_str(&format!("\nfeatures: {}", enabled_features().join(", ")));
+    let disabled: Vec<_> = FEATURES
+        .iter()
+        .filter(|(_, enabled)| !enabled)
+        .map(|(name, _)| *name)
+        .collect();
+    if !disabled.is_empty() {
+        out.push_str(&format!("\nnot built: {}", disabled.join(", ")));
+    }
+    out.push_str("\nbackends:");
+    for (kind, backend) in backends(cfg) {
+        out.push_str(&format!("\n  {kind}: {backend}"));
+    }
+    out
+}
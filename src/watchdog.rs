@@ -0,0 +1,75 @@
+use anyhow::Result;
+use log::error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Pings the systemd watchdog from a separate thread, so that the watchdog timeout doesn't have to
+/// scale with the polling interval. Pings stop once a single loop iteration has been busy for
+/// longer than the timeout, which lets systemd catch us if something wedges.
+pub struct Watchdog {
+    epoch: Instant,
+    // Milliseconds since epoch plus one when the current iteration started, or 0 when idle
+    busy_since: Arc<AtomicU64>,
+}
+
+impl Watchdog {
+    /// Starts pinging every `timeout / 2`. Nothing is started if systemd isn't watching us, and a
+    /// zero `timeout` tells systemd to stop watching.
+    pub fn spawn(timeout: Duration) -> Result<Self> {
+        let epoch = Instant::now();
+        let busy_since = Arc::new(AtomicU64::new(0));
+        let bs_for_thr = busy_since.clone();
+        let watchdog = Self { epoch, busy_since };
+
+        // systemd sets this when the unit has WatchdogSec, otherwise there's nobody to ping
+        if std::env::var_os("WATCHDOG_USEC").is_none() {
+            return Ok(watchdog);
+        }
+
+        sd_notify::notify(
+            false,
+            &[sd_notify::NotifyState::WatchdogUsec(
+                timeout.as_micros().try_into()?,
+            )],
+        )?;
+        if timeout.is_zero() {
+            return Ok(watchdog);
+        }
+
+        thread::Builder::new()
+            .name("watchdog".to_string())
+            .spawn(move || loop {
+                thread::sleep(timeout / 2);
+                let busy_since = bs_for_thr.load(Ordering::Relaxed);
+                if busy_since != 0 {
+                    let busy_for = epoch
+                        .elapsed()
+                        .saturating_sub(Duration::from_millis(busy_since - 1));
+                    if busy_for > timeout {
+                        continue;
+                    }
+                }
+                if let Err(err) = sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]) {
+                    error!("Failed to ping watchdog: {err}");
+                }
+            })?;
+
+        Ok(watchdog)
+    }
+
+    pub fn busy(&self) {
+        let now: u64 = self
+            .epoch
+            .elapsed()
+            .as_millis()
+            .try_into()
+            .unwrap_or(u64::MAX - 1);
+        self.busy_since.store(now + 1, Ordering::Relaxed);
+    }
+
+    pub fn idle(&self) {
+        self.busy_since.store(0, Ordering::Relaxed);
+    }
+}